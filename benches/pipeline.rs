@@ -0,0 +1,108 @@
+//! Time taken by `fingered` to answer queries, from the request to the end of the reply
+//!
+//! Queries are sent over TCP to the daemon built by Cargo, one connection each like finger clients
+//! do, so the numbers cover accepting the connection, parsing the request, rendering and writing
+//! the reply. Run with `cargo bench --bench pipeline`; Criterion compares each run to the previous
+//! one (kept in `target/criterion`), and `-- --save-baseline <NAME>` / `-- --baseline <NAME>`
+//! compare a change against a saved run.
+//!
+//! The allocations made along the same pipeline are checked by the tests of `src/allocations.rs`.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// 200 users, with a long info of 50 lines each
+fn users() -> String {
+    let mut toml = String::new();
+    for index in 0..200 {
+        let long_info = format!("Line of the plan of user {index}\n").repeat(50);
+        toml.push_str(&format!(
+            "[users.user{index}]\ninfo = \"User {index}\"\nlong-info = '''\n{long_info}'''\n\n"
+        ));
+    }
+    toml
+}
+
+const QUERIES: &[(&str, &[u8])] = &[
+    ("user", b"user42\r\n"),
+    ("verbose user", b"/W user42\r\n"),
+    ("unknown user", b"carol\r\n"),
+    ("listing", b"\r\n"),
+    ("forwarding", b"user42@example.com\r\n"),
+];
+
+/// Daemon listening on a TCP socket, killed when dropped with its config
+struct Daemon {
+    child: Child,
+    addr: SocketAddr,
+    dir: PathBuf,
+}
+
+impl Daemon {
+    fn start() -> Self {
+        let dir = std::env::temp_dir().join(format!("fingered-bench-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("users.toml"), users()).unwrap();
+
+        // Ask the OS for a free port, released right before the daemon binds it
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_fingered"))
+            .arg(addr.to_string())
+            .arg("--users-file")
+            .arg(dir.join("users.toml"))
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        let started = Instant::now();
+        while TcpStream::connect(addr).is_err() {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "daemon not listening"
+            );
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        Self { child, addr, dir }
+    }
+
+    fn query(&self, request: &[u8], reply: &mut Vec<u8>) {
+        let mut stream = TcpStream::connect(self.addr).unwrap();
+        stream.write_all(request).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        reply.clear();
+        stream.read_to_end(reply).unwrap();
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn queries(c: &mut Criterion) {
+    let daemon = Daemon::start();
+    let mut reply = Vec::new();
+    let mut group = c.benchmark_group("query");
+
+    for (name, request) in QUERIES {
+        daemon.query(request, &mut reply);
+        group.throughput(Throughput::Bytes(reply.len() as u64));
+        group.bench_function(*name, |b| b.iter(|| daemon.query(request, &mut reply)));
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, queries);
+criterion_main!(benches);
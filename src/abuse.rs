@@ -0,0 +1,214 @@
+//! Abuse policy: each request gets a score, and high scores get the request delayed, denied or
+//! its client banned
+//!
+//! ```toml
+//! [abuse]
+//! rules = [
+//!     { peers = ["192.0.2.0/24"], score = 50 },
+//!     { users = ["^(root|admin|test)$"], score = 30 },
+//!     { requests-per-minute = 30, score = 60 },
+//!     { peers = ["10.0.0.0/8"], score = -100 },
+//! ]
+//! delay-score = 30
+//! delay = 2000
+//! deny-score = 60
+//! ban-score = 100
+//! ```
+//!
+//! A request scores the sum of the rules it matches, plus what the `score_request` hook of the
+//! scripts returns (see [crate::scripting]). A rule matches when all of its conditions do: the
+//! client is in one of its `peers`, one of the requested usernames (the empty string for listings)
+//! matches one of its `users`, and the client made more than `requests-per-minute` requests within
+//! the current minute, this one included. Conditions that are omitted always match, but clients
+//! without an IP address never match `peers` or `requests-per-minute`.
+//!
+//! Requests scoring at least `ban-score` are denied and their client is banned right away,
+//! requests scoring at least `deny-score` are denied, and requests scoring at least `delay-score`
+//! are answered after `delay` milliseconds. Denied requests are logged and count as strikes like
+//! the other denials (see [crate::ban]), so the `deny` rules, the ban list and the filters of
+//! external tools keep working alongside the policy. Without any of these scores, the policy is
+//! disabled and requests aren't counted.
+
+use crate::clock::SharedClock;
+use ipnet::IpNet;
+use regex::bytes::RegexSet;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Window in which the requests of a client are counted
+const WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct AbusePolicy {
+    /// Rules adding their score to the requests they match
+    pub rules: Vec<ScoreRule>,
+
+    /// Score from which requests are answered after [AbusePolicy::delay]
+    pub delay_score: Option<i64>,
+
+    /// Milliseconds by which requests are delayed (1000 by default)
+    pub delay: u64,
+
+    /// Score from which requests are denied
+    pub deny_score: Option<i64>,
+
+    /// Score from which requests are denied and their client is banned for the `ban-time` of
+    /// [crate::ban::BanConfig]
+    pub ban_score: Option<i64>,
+}
+
+impl Default for AbusePolicy {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            delay_score: None,
+            delay: 1000,
+            deny_score: None,
+            ban_score: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ScoreRule {
+    /// Networks of the clients matched, all clients if empty
+    #[serde(default)]
+    pub peers: Vec<IpNet>,
+
+    /// Regular expressions matched against the requested usernames, all requests if empty
+    #[serde(
+        default,
+        deserialize_with = "crate::config::deserialize_regex_set",
+        serialize_with = "crate::config::serialize_regex_set"
+    )]
+    pub users: RegexSet,
+
+    /// Number of requests within a minute above which clients are matched
+    pub requests_per_minute: Option<u32>,
+
+    /// Added to the score of matched requests, negative to trust them more
+    pub score: i64,
+}
+
+/// What's done about a request, depending on its score
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+    Allow,
+    Delay(Duration),
+    Deny,
+    Ban,
+}
+
+impl AbusePolicy {
+    /// Whether requests are scored at all
+    pub fn is_enabled(&self) -> bool {
+        self.delay_score.is_some() || self.deny_score.is_some() || self.ban_score.is_some()
+    }
+
+    /// Sum of the scores of the rules matched by a request for `names` from a client at `ip`,
+    /// which made `requests` requests within the current minute
+    pub fn score<'n>(
+        &self,
+        ip: Option<IpAddr>,
+        names: impl Iterator<Item = &'n str> + Clone,
+        requests: u32,
+    ) -> i64 {
+        (self.rules.iter())
+            .filter(|rule| rule.matches(ip, names.clone(), requests))
+            .map(|rule| rule.score)
+            .sum()
+    }
+
+    /// Action for a request with `score`
+    pub fn action(&self, score: i64) -> Action {
+        let reached =
+            |threshold: Option<i64>| threshold.is_some_and(|threshold| score >= threshold);
+        if reached(self.ban_score) {
+            Action::Ban
+        } else if reached(self.deny_score) {
+            Action::Deny
+        } else if reached(self.delay_score) {
+            Action::Delay(Duration::from_millis(self.delay))
+        } else {
+            Action::Allow
+        }
+    }
+}
+
+impl ScoreRule {
+    fn matches<'n>(
+        &self,
+        ip: Option<IpAddr>,
+        mut names: impl Iterator<Item = &'n str>,
+        requests: u32,
+    ) -> bool {
+        let peer = self.peers.is_empty()
+            || ip.is_some_and(|ip| self.peers.iter().any(|net| net.contains(&ip)));
+        let frequent = match self.requests_per_minute {
+            Some(max) => ip.is_some() && requests > max,
+            None => true,
+        };
+        peer && frequent
+            && (self.users.is_empty() || names.any(|name| self.users.is_match(name.as_bytes())))
+    }
+}
+
+/// Number of requests made by each client within the current minute, kept across config reloads
+#[derive(Debug, Default)]
+pub struct RequestCounts {
+    counts: Mutex<Counts>,
+    clock: SharedClock,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    clients: HashMap<IpAddr, Count>,
+    pruned_at: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Count {
+    since: Instant,
+    requests: u32,
+}
+
+impl RequestCounts {
+    /// Counts whose minutes are timed by `clock`
+    pub fn with_clock(clock: SharedClock) -> Self {
+        Self {
+            counts: Mutex::default(),
+            clock,
+        }
+    }
+
+    /// Count a request from `ip`, and return the number of requests it made within the current
+    /// minute, this one included
+    pub fn record(&self, ip: IpAddr) -> u32 {
+        let now = self.clock.now();
+        let mut counts = self.counts.lock().unwrap();
+
+        // Forget about clients that have been quiet for a whole window, once per window
+        if (counts.pruned_at).is_none_or(|at| now.duration_since(at) >= WINDOW) {
+            (counts.clients).retain(|_, count| now.duration_since(count.since) < WINDOW);
+            counts.pruned_at = Some(now);
+        }
+
+        let count = counts.clients.entry(ip).or_insert(Count {
+            since: now,
+            requests: 0,
+        });
+        if now.duration_since(count.since) >= WINDOW {
+            *count = Count {
+                since: now,
+                requests: 0,
+            };
+        }
+        count.requests += 1;
+        count.requests
+    }
+}
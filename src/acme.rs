@@ -0,0 +1,909 @@
+//! Certificate of the TLS listener, issued and renewed by an ACME server (e.g. Let's Encrypt)
+//!
+//! ```toml
+//! [tls.acme]
+//! domains = ["example.com", "finger.example.com"]
+//! contact = "admin@example.com"
+//! state-dir = "/var/lib/fingered/acme"
+//! terms-of-service-agreed = true
+//! ```
+//!
+//! The server checks that each domain is ours with [HTTP-01] challenges, fetching
+//! `http://<domain>/.well-known/acme-challenge/<token>`, which the metrics listener answers (see
+//! [crate::metrics]): it has to be reachable on port 80 of every domain, e.g. with
+//! `--metrics-bind-to [::]:80`.
+//!
+//! The account key, the certificate and its key are kept in the state directory. The certificate
+//! is checked every [CHECK_INTERVAL], and a new one is ordered when it's missing, expires in less
+//! than [RENEW_BEFORE] or doesn't cover every domain. The TLS listener serves it after the ones of
+//! `certificates`, and picks it up like them when it changes.
+//!
+//! [HTTP-01]: https://datatracker.ietf.org/doc/html/rfc8555#section-8.3
+
+use crate::config::Config;
+use crate::state::ServerState;
+use crate::tls::CertificateFiles;
+use base64::engine::general_purpose::{STANDARD as BASE64, URL_SAFE_NO_PAD as BASE64_URL};
+use base64::Engine;
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncWriteExt;
+
+/// Path of the HTTP-01 challenges, followed by their token
+pub const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+/// Time between checks of the certificate, and between attempts to get one
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Remaining validity under which the certificate is renewed
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Delay between checks of a pending authorization or order, and max number of checks
+const POLL_DELAY: Duration = Duration::from_secs(2);
+const MAX_POLLS: u32 = 30;
+
+const ACCOUNT_KEY: &str = "account.key";
+const CERTIFICATE: &str = "cert.pem";
+const CERTIFICATE_KEY: &str = "key.pem";
+
+const BAD_NONCE: &str = "urn:ietf:params:acme:error:badNonce";
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct AcmeConfig {
+    /// Names the certificate is valid for, each of them resolving to this host
+    pub domains: Vec<String>,
+
+    /// Email address the ACME server may write to about the certificate, e.g. before it expires
+    #[serde(default)]
+    pub contact: Option<String>,
+
+    /// Directory URL of the ACME server (default: Let's Encrypt)
+    #[serde(default = "default_directory")]
+    pub directory: String,
+
+    /// Directory where the account key, the certificate and its key are kept
+    pub state_dir: PathBuf,
+
+    /// Whether the terms of service of the ACME server are agreed to, which it requires
+    #[serde(default)]
+    pub terms_of_service_agreed: bool,
+}
+
+fn default_directory() -> String {
+    "https://acme-v02.api.letsencrypt.org/directory".into()
+}
+
+impl AcmeConfig {
+    /// Files of the issued certificate, which may not exist yet
+    pub fn files(&self) -> CertificateFiles {
+        CertificateFiles {
+            cert: self.state_dir.join(CERTIFICATE),
+            key: self.state_dir.join(CERTIFICATE_KEY),
+        }
+    }
+}
+
+/// Key authorizations of the pending HTTP-01 challenges, by token
+#[derive(Debug, Default)]
+pub struct Challenges(Mutex<HashMap<String, String>>);
+
+impl Challenges {
+    /// Body of `http://<domain>/.well-known/acme-challenge/<token>`
+    pub fn key_authorization(&self, token: &str) -> Option<String> {
+        self.0.lock().unwrap().get(token).cloned()
+    }
+
+    pub fn insert(&self, token: String, key_authorization: String) {
+        self.0.lock().unwrap().insert(token, key_authorization);
+    }
+
+    fn remove(&self, token: &str) {
+        self.0.lock().unwrap().remove(token);
+    }
+}
+
+/// Get a certificate when the one of the config needs to be issued or renewed, forever
+#[instrument(skip_all)]
+pub async fn renew(config: Arc<Config>, state: Arc<ServerState>) {
+    loop {
+        let acme = config.get().await.tls.acme.clone();
+        if let Some(acme) = acme {
+            if needs_certificate(&acme).await {
+                match issue(&acme, &state.acme_challenges).await {
+                    Ok(()) => info!("ACME certificate issued for {}", acme.domains.join(", ")),
+                    Err(err) => warn!("cannot get an ACME certificate: {err}"),
+                }
+            }
+        }
+
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
+
+/// Whether the certificate is missing, expires soon, or doesn't cover all the domains
+async fn needs_certificate(acme: &AcmeConfig) -> bool {
+    let Ok(chain) = tokio::fs::read(acme.files().cert).await else {
+        return true;
+    };
+    let Some(Ok(cert)) = CertificateDer::pem_slice_iter(&chain).next() else {
+        return true;
+    };
+
+    let expires_soon =
+        not_after(&cert).is_none_or(|not_after| not_after < SystemTime::now() + RENEW_BEFORE);
+    let covers_domains = acme.domains.iter().all(|domain| {
+        ServerName::try_from(domain.as_str())
+            .is_ok_and(|name| crate::tls::is_valid_for(&cert, &name))
+    });
+    expires_soon || !covers_domains
+}
+
+/// Order a certificate for the domains, answering its challenges, and write it with its key
+async fn issue(acme: &AcmeConfig, challenges: &Challenges) -> Result<(), BoxError> {
+    if acme.domains.is_empty() {
+        return Err("no domains in the config".into());
+    }
+    if !acme.terms_of_service_agreed {
+        return Err("the terms of service of the ACME server must be agreed to".into());
+    }
+
+    tokio::fs::create_dir_all(&acme.state_dir).await?;
+    let rng = SystemRandom::new();
+    let account_key = account_key(&acme.state_dir.join(ACCOUNT_KEY), &rng).await?;
+    let mut client = Client::new(&acme.directory, account_key, rng).await?;
+
+    // Answered with the existing account if the key already has one
+    let contact = (acme.contact.iter())
+        .map(|email| format!("mailto:{email}"))
+        .collect::<Vec<_>>();
+    let new_account = client.directory.new_account.clone();
+    let payload = json!({ "termsOfServiceAgreed": true, "contact": contact });
+    let account = client.post(&new_account, Some(&payload)).await?;
+    client.account = Some(account.location.ok_or("no account URL")?);
+
+    let identifiers = (acme.domains.iter())
+        .map(|domain| json!({ "type": "dns", "value": domain }))
+        .collect::<Vec<_>>();
+    let new_order = client.directory.new_order.clone();
+    let payload = json!({ "identifiers": identifiers });
+    let order = client.post(&new_order, Some(&payload)).await?;
+    let order_url = order.location.clone().ok_or("no order URL")?;
+    let order = order.json::<Order>()?;
+
+    for authorization in &order.authorizations {
+        client.authorize(authorization, challenges).await?;
+    }
+
+    let key = EcdsaKeyPair::generate_pkcs8(
+        &ring::signature::ECDSA_P256_SHA256_ASN1_SIGNING,
+        &client.rng,
+    )?;
+    let csr = csr(&acme.domains, key.as_ref(), &client.rng)?;
+    let payload = json!({ "csr": BASE64_URL.encode(csr) });
+    client.post(&order.finalize, Some(&payload)).await?;
+
+    let order = client
+        .wait::<Order>(&order_url, &["pending", "ready", "processing"])
+        .await?;
+    let certificate = match (order.status.as_str(), order.certificate) {
+        ("valid", Some(certificate)) => certificate,
+        (status, _) => return Err(format!("order {status}").into()),
+    };
+    let chain = client.post(&certificate, None).await?.body;
+
+    let files = acme.files();
+    write_file(
+        &files.key,
+        pem("PRIVATE KEY", key.as_ref()).as_bytes(),
+        0o600,
+    )
+    .await?;
+    write_file(&files.cert, &chain, 0o644).await?;
+    Ok(())
+}
+
+/// Read the key of the ACME account, generating it the first time
+async fn account_key(path: &Path, rng: &SystemRandom) -> Result<EcdsaKeyPair, BoxError> {
+    let pkcs8 = match tokio::fs::read(path).await {
+        Ok(pem) => PrivatePkcs8KeyDer::from_pem_slice(&pem)
+            .map_err(|err| format!("{}: {err}", path.display()))?
+            .secret_pkcs8_der()
+            .to_vec(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            let alg = &ring::signature::ECDSA_P256_SHA256_FIXED_SIGNING;
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(alg, rng)?.as_ref().to_vec();
+            write_file(path, pem("PRIVATE KEY", &pkcs8).as_bytes(), 0o600).await?;
+            pkcs8
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    let alg = &ring::signature::ECDSA_P256_SHA256_FIXED_SIGNING;
+    Ok(EcdsaKeyPair::from_pkcs8(alg, &pkcs8, rng)?)
+}
+
+/// Replace `path` with a file of `contents`, without ever leaving it half written
+async fn write_file(path: &Path, contents: &[u8], mode: u32) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(&tmp)
+        .await?;
+    file.write_all(contents).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+fn pem(label: &str, der: &[u8]) -> String {
+    let base64 = BASE64.encode(der);
+    let mut pem = format!("-----BEGIN {label}-----\n");
+    for line in base64.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).unwrap());
+        pem.push('\n');
+    }
+    pem + &format!("-----END {label}-----\n")
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: String,
+    error: Option<Problem>,
+}
+
+/// Error answered by the ACME server
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Problem {
+    #[serde(rename = "type")]
+    kind: String,
+    detail: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Status {
+    status: String,
+}
+
+/// Successful response of the ACME server
+struct Reply {
+    location: Option<String>,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn json<T: DeserializeOwned>(&self) -> Result<T, BoxError> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+}
+
+struct Client {
+    http: reqwest::Client,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    nonce: Option<String>,
+
+    /// URL of the account, identifying the key once it's registered
+    account: Option<String>,
+}
+
+impl Client {
+    async fn new(directory: &str, key: EcdsaKeyPair, rng: SystemRandom) -> Result<Self, BoxError> {
+        let http = reqwest::Client::new();
+        let response = http.get(directory).send().await?.error_for_status()?;
+        let directory = serde_json::from_slice(&response.bytes().await?)?;
+        Ok(Self {
+            http,
+            directory,
+            key,
+            rng,
+            nonce: None,
+            account: None,
+        })
+    }
+
+    /// Public account key, as a JWK whose members are sorted like RFC 7638 thumbprints want
+    fn jwk(&self) -> String {
+        let point = self.key.public_key().as_ref();
+        let (x, y) = point[1..].split_at(32);
+        format!(
+            r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+            BASE64_URL.encode(x),
+            BASE64_URL.encode(y),
+        )
+    }
+
+    fn thumbprint(&self) -> String {
+        let digest = ring::digest::digest(&ring::digest::SHA256, self.jwk().as_bytes());
+        BASE64_URL.encode(digest)
+    }
+
+    /// Request body of `payload` for `url`, signed with the account key (ES256 JWS)
+    fn sign(&self, url: &str, nonce: &str, payload: &str) -> Result<String, BoxError> {
+        let key = match &self.account {
+            Some(account) => format!(r#""kid":{}"#, json!(account)),
+            None => format!(r#""jwk":{}"#, self.jwk()),
+        };
+        let protected = format!(
+            r#"{{"alg":"ES256",{key},"nonce":{},"url":{}}}"#,
+            json!(nonce),
+            json!(url),
+        );
+        let protected = BASE64_URL.encode(protected);
+        let payload = BASE64_URL.encode(payload);
+        let signature = self
+            .key
+            .sign(&self.rng, format!("{protected}.{payload}").as_bytes())?;
+        let signature = BASE64_URL.encode(signature);
+        Ok(
+            json!({ "protected": protected, "payload": payload, "signature": signature })
+                .to_string(),
+        )
+    }
+
+    /// POST `payload` to `url`, or POST-as-GET it without a payload
+    async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<&serde_json::Value>,
+    ) -> Result<Reply, BoxError> {
+        let payload = payload.map(ToString::to_string).unwrap_or_default();
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+
+            let response = (self.http.post(url))
+                .header(reqwest::header::CONTENT_TYPE, "application/jose+json")
+                .body(self.sign(url, &nonce, &payload)?)
+                .send()
+                .await?;
+            self.nonce = header(&response, "Replay-Nonce");
+            let status = response.status();
+            let location = header(&response, "Location");
+            let body = response.bytes().await?.to_vec();
+            if status.is_success() {
+                return Ok(Reply { location, body });
+            }
+
+            let problem = serde_json::from_slice::<Problem>(&body).unwrap_or_default();
+            // Nonces can expire, a fresh one is given with the error
+            if problem.kind == BAD_NONCE && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(format!("{url}: {status} {}", problem.detail).into());
+        }
+    }
+
+    async fn new_nonce(&self) -> Result<String, BoxError> {
+        let response = self.http.head(&self.directory.new_nonce).send().await?;
+        let response = response.error_for_status()?;
+        Ok(header(&response, "Replay-Nonce").ok_or("no nonce")?)
+    }
+
+    /// POST-as-GET `url` until its status isn't one of `pending`
+    async fn wait<T: DeserializeOwned>(
+        &mut self,
+        url: &str,
+        pending: &[&str],
+    ) -> Result<T, BoxError> {
+        for _ in 0..MAX_POLLS {
+            let reply = self.post(url, None).await?;
+            if !pending.contains(&reply.json::<Status>()?.status.as_str()) {
+                return reply.json();
+            }
+            tokio::time::sleep(POLL_DELAY).await;
+        }
+        Err(format!("{url}: still pending after {MAX_POLLS} checks").into())
+    }
+
+    /// Prove that we control the domain of an authorization, with its HTTP-01 challenge
+    async fn authorize(&mut self, url: &str, challenges: &Challenges) -> Result<(), BoxError> {
+        let authorization = self.post(url, None).await?.json::<Authorization>()?;
+        let domain = authorization.identifier.value;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+
+        let challenge = (authorization.challenges.iter())
+            .find(|challenge| challenge.kind == "http-01")
+            .ok_or_else(|| format!("{domain}: no HTTP-01 challenge"))?;
+        let key_authorization = format!("{}.{}", challenge.token, self.thumbprint());
+        challenges.insert(challenge.token.clone(), key_authorization);
+        let result = self.validate(url, &challenge.url).await;
+        challenges.remove(&challenge.token);
+        result.map_err(|err| format!("{domain}: {err}").into())
+    }
+
+    async fn validate(&mut self, url: &str, challenge: &str) -> Result<(), BoxError> {
+        self.post(challenge, Some(&json!({}))).await?;
+        let authorization = self.wait::<Authorization>(url, &["pending"]).await?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+
+        let problem =
+            (authorization.challenges.iter()).find_map(|challenge| challenge.error.as_ref());
+        let detail = problem.map_or("", |problem| &problem.detail);
+        Err(format!("authorization {}: {detail}", authorization.status).into())
+    }
+}
+
+fn header(response: &reqwest::Response, name: &str) -> Option<String> {
+    let value = response.headers().get(name)?.to_str().ok()?;
+    Some(value.to_owned())
+}
+
+/// Certificate signing request (PKCS#10) of `domains`, signed with the PKCS#8 P-256 key
+fn csr(domains: &[String], pkcs8: &[u8], rng: &SystemRandom) -> Result<Vec<u8>, BoxError> {
+    const SEQUENCE: u8 = 0x30;
+    const SET: u8 = 0x31;
+    const EC_PUBLIC_KEY: &[u8] = &[0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+    const PRIME256V1: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+    const ECDSA_WITH_SHA256: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+    const EXTENSION_REQUEST: &[u8] = &[
+        0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x0e,
+    ];
+    const SUBJECT_ALT_NAME: &[u8] = &[0x06, 0x03, 0x55, 0x1d, 0x11];
+
+    let alg = &ring::signature::ECDSA_P256_SHA256_ASN1_SIGNING;
+    let key = EcdsaKeyPair::from_pkcs8(alg, pkcs8, rng)?;
+
+    let algorithm = der(SEQUENCE, &[EC_PUBLIC_KEY, PRIME256V1].concat());
+    let public_key = der(0x03, &[&[0], key.public_key().as_ref()].concat());
+    let names = (domains.iter())
+        .flat_map(|domain| der(0x82, domain.as_bytes()))
+        .collect::<Vec<_>>();
+    let extension = der(
+        SEQUENCE,
+        &[SUBJECT_ALT_NAME, &der(0x04, &der(SEQUENCE, &names))].concat(),
+    );
+    let attribute = der(
+        SEQUENCE,
+        &[EXTENSION_REQUEST, &der(SET, &der(SEQUENCE, &extension))].concat(),
+    );
+
+    // Version 0, empty subject (the names are only in the extension), key, attributes
+    let info = der(
+        SEQUENCE,
+        &[
+            &[0x02, 0x01, 0x00][..],
+            &der(SEQUENCE, &[]),
+            &der(SEQUENCE, &[algorithm, public_key].concat()),
+            &der(0xa0, &attribute),
+        ]
+        .concat(),
+    );
+    let signature = key.sign(rng, &info)?;
+    let signature = der(0x03, &[&[0], signature.as_ref()].concat());
+    Ok(der(
+        SEQUENCE,
+        &[info, der(SEQUENCE, ECDSA_WITH_SHA256), signature].concat(),
+    ))
+}
+
+/// DER element of `tag` around `contents`
+fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut element = vec![tag];
+    match contents.len() {
+        len @ 0..=0x7f => element.push(len as u8),
+        len => {
+            let len = len.to_be_bytes();
+            let len = &len[len.iter().position(|&byte| byte != 0).unwrap()..];
+            element.push(0x80 | len.len() as u8);
+            element.extend(len);
+        }
+    }
+    element.extend(contents);
+    element
+}
+
+/// Split the first DER element of `input` into its tag, its contents and the rest of `input`
+fn read_der(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&len, mut input) = input.split_first()?;
+    let len = match len {
+        0..=0x7f => usize::from(len),
+        0x81..=0x84 => {
+            let (len, rest) = input.split_at_checked(usize::from(len & 0x7f))?;
+            input = rest;
+            (len.iter()).fold(0, |len, &byte| len << 8 | usize::from(byte))
+        }
+        _ => return None,
+    };
+    let (contents, rest) = input.split_at_checked(len)?;
+    Some((tag, contents, rest))
+}
+
+/// Expiry date of a DER certificate
+fn not_after(cert: &[u8]) -> Option<SystemTime> {
+    let (_, cert, _) = read_der(cert)?;
+    let (_, mut fields, _) = read_der(cert)?;
+    // Skip the version (if any), serial number, signature algorithm and issuer
+    if fields.first() == Some(&0xa0) {
+        fields = read_der(fields)?.2;
+    }
+    for _ in 0..3 {
+        fields = read_der(fields)?.2;
+    }
+    let (_, validity, _) = read_der(fields)?;
+    let (_, _, validity) = read_der(validity)?;
+
+    let (tag, time, _) = read_der(validity)?;
+    let time = std::str::from_utf8(time).ok()?.strip_suffix('Z')?;
+    let time = match tag {
+        // UTCTime, YYMMDDHHMMSS, whose years go from 1950 to 2049
+        0x17 if time.len() == 12 && time.is_ascii() => match &time[..2] < "50" {
+            true => format!("20{time}"),
+            false => format!("19{time}"),
+        },
+        // GeneralizedTime, YYYYMMDDHHMMSS
+        0x18 if time.len() == 14 && time.is_ascii() => time.to_owned(),
+        _ => return None,
+    };
+    let (date, time) = time.split_at(8);
+    humantime::parse_rfc3339(&format!(
+        "{}-{}-{}T{}:{}:{}Z",
+        &date[..4],
+        &date[4..6],
+        &date[6..],
+        &time[..2],
+        &time[2..4],
+        &time[4..],
+    ))
+    .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// Certificate of `a.example`, valid until 2126
+    const CERTIFICATE: &str = include_str!("tls/a.example.pem");
+
+    fn fixture_der() -> CertificateDer<'static> {
+        CertificateDer::from_pem_slice(CERTIFICATE.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn reads_the_expiry_of_certificates() {
+        let expiry = not_after(&fixture_der()).unwrap();
+        assert_eq!(
+            expiry,
+            humantime::parse_rfc3339("2126-09-24T03:16:46Z").unwrap()
+        );
+        assert_eq!(not_after(b"\x30\x03\x02\x01\x00"), None);
+    }
+
+    #[test]
+    fn signs_requests_for_the_account_key() {
+        let rng = SystemRandom::new();
+        let alg = &ring::signature::ECDSA_P256_SHA256_FIXED_SIGNING;
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(alg, &rng).unwrap();
+        let key = EcdsaKeyPair::from_pkcs8(alg, pkcs8.as_ref(), &rng).unwrap();
+        let public_key = key.public_key().as_ref().to_vec();
+        let mut client = Client {
+            http: reqwest::Client::new(),
+            directory: Directory {
+                new_nonce: String::new(),
+                new_account: String::new(),
+                new_order: String::new(),
+            },
+            key,
+            rng,
+            nonce: None,
+            account: None,
+        };
+
+        let verify = |body: &str| {
+            let body = serde_json::from_str::<serde_json::Value>(body).unwrap();
+            let [protected, payload, signature] =
+                ["protected", "payload", "signature"].map(|key| body[key].as_str().unwrap());
+            let public_key = ring::signature::UnparsedPublicKey::new(
+                &ring::signature::ECDSA_P256_SHA256_FIXED,
+                &public_key,
+            );
+            let signature = BASE64_URL.decode(signature).unwrap();
+            public_key
+                .verify(format!("{protected}.{payload}").as_bytes(), &signature)
+                .unwrap();
+            let protected = BASE64_URL.decode(protected).unwrap();
+            let protected = serde_json::from_slice::<serde_json::Value>(&protected).unwrap();
+            (protected, BASE64_URL.decode(payload).unwrap())
+        };
+
+        let (protected, payload) = verify(&client.sign("https://ca/new", "n1", "{}").unwrap());
+        assert_eq!(protected["alg"], "ES256");
+        assert_eq!(protected["nonce"], "n1");
+        assert_eq!(protected["url"], "https://ca/new");
+        assert_eq!(protected["jwk"]["kty"], "EC");
+        assert_eq!(protected["kid"], serde_json::Value::Null);
+        assert_eq!(payload, b"{}");
+
+        // The thumbprint is the hash of the JWK with its members sorted and no whitespace
+        let jwk = serde_json::to_string(&protected["jwk"]).unwrap();
+        let digest = ring::digest::digest(&ring::digest::SHA256, jwk.as_bytes());
+        assert_eq!(client.thumbprint(), BASE64_URL.encode(digest));
+
+        client.account = Some("https://ca/account/1".into());
+        let (protected, payload) = verify(&client.sign("https://ca/order", "n2", "").unwrap());
+        assert_eq!(protected["kid"], "https://ca/account/1");
+        assert_eq!(protected["jwk"], serde_json::Value::Null);
+        assert_eq!(payload, b"");
+    }
+
+    #[test]
+    fn encodes_signed_csrs() {
+        let rng = SystemRandom::new();
+        let alg = &ring::signature::ECDSA_P256_SHA256_ASN1_SIGNING;
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(alg, &rng).unwrap();
+        let domains = ["example.com".to_owned(), "finger.example.com".to_owned()];
+        let csr = csr(&domains, pkcs8.as_ref(), &rng).unwrap();
+
+        let (tag, request, rest) = read_der(&csr).unwrap();
+        assert_eq!((tag, rest), (0x30, &[][..]));
+        let (_, _, info_rest) = read_der(request).unwrap();
+        let info = &request[..request.len() - info_rest.len()];
+        let (_, algorithm, signature) = read_der(info_rest).unwrap();
+        assert_eq!(
+            algorithm,
+            &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02]
+        );
+        let (tag, signature, _) = read_der(signature).unwrap();
+        assert_eq!((tag, signature[0]), (0x03, 0));
+
+        let key = EcdsaKeyPair::from_pkcs8(alg, pkcs8.as_ref(), &rng).unwrap();
+        let public_key = ring::signature::UnparsedPublicKey::new(
+            &ring::signature::ECDSA_P256_SHA256_ASN1,
+            key.public_key().as_ref(),
+        );
+        public_key.verify(info, &signature[1..]).unwrap();
+
+        let contains = |needle: &[u8]| info.windows(needle.len()).any(|window| window == needle);
+        assert!(contains(b"\x82\x0bexample.com"));
+        assert!(contains(b"\x82\x12finger.example.com"));
+        assert!(contains(key.public_key().as_ref()));
+    }
+
+    #[test]
+    fn encodes_long_der_lengths() {
+        let contents = vec![0; 300];
+        let element = der(0x04, &contents);
+        assert_eq!(element[..4], [0x04, 0x82, 0x01, 0x2c]);
+        assert_eq!(read_der(&element), Some((0x04, &contents[..], &[][..])));
+    }
+
+    /// Steps reached by the client on the fake ACME server
+    #[derive(Debug, Default)]
+    struct FakeServer {
+        nonces: u32,
+        refused_nonce: bool,
+        account: bool,
+        validated: bool,
+        finalized: bool,
+    }
+
+    /// Answer one request of the ACME client, like a CA issuing [CERTIFICATE]
+    fn fake_reply(
+        server: &Mutex<FakeServer>,
+        challenges: &Challenges,
+        base: &str,
+        method: &str,
+        path: &str,
+        body: &[u8],
+    ) -> (&'static str, Option<String>, String) {
+        let mut server = server.lock().unwrap();
+        server.nonces += 1;
+        let payload = match serde_json::from_slice::<serde_json::Value>(body) {
+            Ok(body) => {
+                let protected = body["protected"].as_str().unwrap();
+                let protected = BASE64_URL.decode(protected).unwrap();
+                let protected = serde_json::from_slice::<serde_json::Value>(&protected).unwrap();
+                assert_eq!(protected["url"], format!("{base}{path}"));
+                // The first nonce is refused, like an expired one
+                if !server.refused_nonce {
+                    server.refused_nonce = true;
+                    let problem = json!({ "type": BAD_NONCE, "detail": "expired" });
+                    return ("400 Bad Request", None, problem.to_string());
+                }
+                BASE64_URL
+                    .decode(body["payload"].as_str().unwrap())
+                    .unwrap()
+            }
+            Err(_) => Vec::new(),
+        };
+
+        let order = |status: &str| {
+            json!({
+                "status": status,
+                "authorizations": [format!("{base}/authz")],
+                "finalize": format!("{base}/finalize"),
+                "certificate": format!("{base}/cert"),
+            })
+            .to_string()
+        };
+        let authorization = |status: &str| {
+            json!({
+                "status": status,
+                "identifier": { "type": "dns", "value": "a.example" },
+                "challenges": [
+                    { "type": "dns-01", "url": format!("{base}/dns"), "token": "dns" },
+                    { "type": "http-01", "url": format!("{base}/challenge"), "token": "tok" },
+                ],
+            })
+            .to_string()
+        };
+
+        match (method, path) {
+            ("GET", "/directory") => {
+                let directory = json!({
+                    "newNonce": format!("{base}/nonce"),
+                    "newAccount": format!("{base}/account"),
+                    "newOrder": format!("{base}/order"),
+                });
+                ("200 OK", None, directory.to_string())
+            }
+            ("HEAD", "/nonce") => ("200 OK", None, String::new()),
+            ("POST", "/account") => {
+                server.account = true;
+                (
+                    "201 Created",
+                    Some(format!("{base}/account/1")),
+                    "{}".into(),
+                )
+            }
+            ("POST", "/order") if server.account => {
+                let location = Some(format!("{base}/order/1"));
+                ("201 Created", location, order("pending"))
+            }
+            ("POST", "/authz") => match server.validated {
+                true => ("200 OK", None, authorization("valid")),
+                false => ("200 OK", None, authorization("pending")),
+            },
+            ("POST", "/challenge") => {
+                let key_authorization = challenges.key_authorization("tok").unwrap();
+                server.validated = key_authorization.starts_with("tok.");
+                ("200 OK", None, "{}".into())
+            }
+            ("POST", "/finalize") if server.validated => {
+                let payload = serde_json::from_slice::<serde_json::Value>(&payload).unwrap();
+                let csr = BASE64_URL.decode(payload["csr"].as_str().unwrap()).unwrap();
+                server.finalized = read_der(&csr).is_some();
+                ("200 OK", None, order("processing"))
+            }
+            ("POST", "/order/1") if server.finalized => ("200 OK", None, order("valid")),
+            ("POST", "/cert") if server.finalized => ("200 OK", None, CERTIFICATE.into()),
+            _ => ("403 Forbidden", None, "{}".into()),
+        }
+    }
+
+    /// Serve the fake ACME server forever, one request per connection
+    async fn serve_fake(listener: TcpListener, challenges: Arc<Challenges>) {
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let server = Mutex::new(FakeServer::default());
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (input, mut output) = stream.split();
+            let mut input = BufReader::new(input);
+
+            let mut request_line = String::new();
+            input.read_line(&mut request_line).await.unwrap();
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                input.read_line(&mut header).await.unwrap();
+                let header = header.trim_end().to_ascii_lowercase();
+                if header.is_empty() {
+                    break;
+                }
+                if let Some(value) = header.strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            input.read_exact(&mut body).await.unwrap();
+
+            let mut parts = request_line.split_ascii_whitespace();
+            let (method, path) = (parts.next().unwrap(), parts.next().unwrap());
+            let (status, location, body) =
+                fake_reply(&server, &challenges, &base, method, path, &body);
+            let nonce = server.lock().unwrap().nonces;
+            let location = location.map_or(String::new(), |url| format!("Location: {url}\r\n"));
+            let response = format!(
+                "HTTP/1.1 {status}\r\nReplay-Nonce: n{nonce}\r\n{location}Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len(),
+            );
+            output.write_all(response.as_bytes()).await.unwrap();
+            output.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn issues_certificates() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let challenges = Arc::new(Challenges::default());
+        tokio::task::spawn(serve_fake(listener, Arc::clone(&challenges)));
+
+        let dir = std::env::temp_dir().join(format!("fingered-acme-{}", std::process::id()));
+        let mut acme = AcmeConfig {
+            domains: vec!["a.example".into()],
+            contact: Some("admin@a.example".into()),
+            directory: format!("http://{addr}/directory"),
+            state_dir: dir.clone(),
+            terms_of_service_agreed: false,
+        };
+        assert!(needs_certificate(&acme).await);
+        assert!(issue(&acme, &challenges).await.is_err());
+
+        acme.terms_of_service_agreed = true;
+        let result = issue(&acme, &challenges).await;
+        let cert = std::fs::read_to_string(acme.files().cert);
+        let key = std::fs::read(acme.files().key);
+        let renewed = !needs_certificate(&acme).await;
+        acme.domains.push("b.example".into());
+        let renewed_other_domains = !needs_certificate(&acme).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        result.unwrap();
+        assert_eq!(cert.unwrap(), CERTIFICATE);
+        assert!(PrivatePkcs8KeyDer::from_pem_slice(&key.unwrap()).is_ok());
+        assert_eq!(challenges.key_authorization("tok"), None);
+        assert!(renewed);
+        assert!(!renewed_other_domains);
+    }
+}
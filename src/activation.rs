@@ -0,0 +1,73 @@
+//! Sockets passed by the service manager (systemd socket activation)
+//!
+//! Descriptors are given by `LISTEN_FDS`, and optionally named by `LISTEN_FDNAMES` (e.g. with
+//! `FileDescriptorName=` in systemd socket units). A descriptor named after a listener role is used
+//! for that role, unless an address is given for it on the command line:
+//! - `finger`: the finger listener (TCP or Unix), which otherwise takes the first descriptor
+//! - `whois`: the WHOIS listener (TCP or Unix)
+//! - `admin`: the admin socket (Unix only)
+
+use crate::listener::{AnyListener, AnySocketAddr};
+use listenfd::ListenFd;
+use std::io;
+
+pub struct Activation {
+    fds: ListenFd,
+    names: Vec<String>,
+}
+
+impl Activation {
+    pub fn from_env() -> Self {
+        let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+        Self {
+            fds: ListenFd::from_env(),
+            names: names.split(':').map(str::to_owned).collect(),
+        }
+    }
+
+    /// Index of the descriptor named `name`
+    pub fn index(&self, name: &str) -> Option<usize> {
+        (self.names.iter())
+            .position(|fd_name| fd_name == name)
+            .filter(|index| *index < self.fds.len())
+    }
+
+    /// Take the TCP or Unix listener at `index`, if there's one that wasn't taken yet
+    pub fn take_listener(
+        &mut self,
+        index: usize,
+    ) -> io::Result<Option<(AnyListener, AnySocketAddr)>> {
+        if let Ok(Some(tcp)) = self.fds.take_tcp_listener(index) {
+            tcp.set_nonblocking(true)?;
+            let local_addr = tcp.local_addr()?;
+            let listener = tokio::net::TcpListener::from_std(tcp)?;
+            return Ok(Some((listener.into(), local_addr.into())));
+        }
+
+        #[cfg(all(unix, feature = "unix-socket"))]
+        if let Some(unix) = self.take_unix_listener(index)? {
+            let path = (unix.local_addr()?.as_pathname())
+                .ok_or_else(|| io::Error::other("unnamed unix socket"))?
+                .to_owned();
+            let listener = AnyListener::Unix(tokio::net::UnixListener::from_std(unix)?);
+            return Ok(Some((listener, AnySocketAddr::Unix(path))));
+        }
+
+        Ok(None)
+    }
+
+    /// Take the Unix listener at `index`, if there's one that wasn't taken yet
+    #[cfg(all(unix, feature = "unix-socket"))]
+    pub fn take_unix_listener(
+        &mut self,
+        index: usize,
+    ) -> io::Result<Option<std::os::unix::net::UnixListener>> {
+        match self.fds.take_unix_listener(index) {
+            Ok(Some(unix)) => {
+                unix.set_nonblocking(true)?;
+                Ok(Some(unix))
+            }
+            _ => Ok(None),
+        }
+    }
+}
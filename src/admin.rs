@@ -0,0 +1,555 @@
+//! Local control socket for orchestration tools
+//!
+//! Each connection carries a single command: a first line naming it, followed by its payload until
+//! the client shuts down its writing half. The server answers with `OK` or `ERROR: <reason>` on
+//! the first line, possibly followed by output.
+//!
+//! Programs can use the CBOR mode instead, picked when the connection starts with a CBOR map. The
+//! client sends any number of requests, each a map with the version of the envelope `v` (currently
+//! [CBOR_VERSION]), an `id` given back in the response, the `command` and its `payload` (a text
+//! string, empty if omitted), and shuts down its writing half. The server answers each with a map
+//! holding `v`, `id`, `ok` and either the `output` or the `error` of the command, as text strings:
+//!
+//! ```text
+//! {"v": 1, "id": 7, "command": "maintenance", "payload": "on"}
+//! {"v": 1, "id": 7, "ok": true, "output": ""}
+//! ```
+//!
+//! Commands:
+//! - `merge`: the payload is a TOML fragment with a `users` table (same syntax as `users.toml`)
+//!   whose entries are added to the live config, replacing existing users of the same name, and
+//!   an optional `remove` list of usernames to delete. Merged changes are lost on the next reload
+//!   from the config file.
+//! - `config`: no payload. The output is the live config in TOML, as loaded with the command line
+//!   overrides, backend and store users and merged changes, with its secrets redacted (see
+//!   [Users::redacted]).
+//! - `maintenance`: the payload is `on` to answer every query with the maintenance reply instead
+//!   of real data (see [Users::maintenance_reply]), or `off` to go back to normal. Maintenance mode
+//!   survives reloads, but not restarts.
+//! - `log-level`: the payload is a level (`error`, `warn`, `info`, `debug` or `trace`) forced on
+//!   the daemon's events over the configured filter, or `config` to go back to it (see
+//!   [crate::logging::force_level]).
+//! - `dump-users --json`: no payload. The output is a JSON object with the `generation` of the
+//!   live config and its `users` (those of namespaces included), each with its settings that
+//!   matter to clients and the replies it gets right now, with its secrets redacted (see
+//!   [DumpedUser]). JSON is the only format so far, but has to be asked for.
+//! - `candidate <PERCENT>% [NETWORK...]`: the payload is a whole config (same syntax as
+//!   `users.toml`, without the command line overrides) serving the given percentage of requests,
+//!   picked at random, and all requests from clients in the networks (e.g.
+//!   `candidate 5% 192.0.2.0/24`), while the live config serves the others. `candidate off` stops
+//!   using it, and `candidate` without arguments outputs its generation and rollout. See
+//!   [Config::set_candidate].
+
+use crate::config::{Config, ConfigPatch, Rollout, User, Users};
+use crate::redact::Audience;
+use crate::schedule::LocalTime;
+use crate::state::ServerState;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+/// Max length of a command and its payload, in bytes
+const SANE_COMMAND_LENGTH: u64 = 1024 * 1024;
+
+/// Version of the envelope of CBOR requests and responses
+const CBOR_VERSION: u32 = 1;
+
+#[derive(serde::Deserialize)]
+struct CborRequest {
+    v: u32,
+    id: Option<u64>,
+    command: String,
+    #[serde(default)]
+    payload: String,
+}
+
+#[derive(serde::Serialize)]
+struct CborResponse {
+    v: u32,
+    id: Option<u64>,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Output of `dump-users --json`
+#[derive(serde::Serialize)]
+struct DumpedUsers<'a> {
+    generation: u64,
+    users: Vec<DumpedUser<'a>>,
+}
+
+/// User in the output of `dump-users --json`, as shown at the time of the dump
+#[derive(serde::Serialize)]
+struct DumpedUser<'a> {
+    name: &'a str,
+    /// Namespace of the user, `null` at the top level
+    domain: Option<&'a str>,
+    listed: bool,
+    hidden: bool,
+    tags: &'a [Arc<str>],
+    uid: Option<u32>,
+    /// [User::updated] date, or modification time of the config
+    last_modified: Option<String>,
+    /// Whether one of the [User::schedule] entries is shown instead of the user
+    scheduled: bool,
+    proxy_to: Option<&'a str>,
+    /// Replies to the short and verbose queries of external clients, unless they're relayed to
+    /// [User::proxy_to] or picked among [User::fortune] entries
+    reply: Option<String>,
+    verbose_reply: Option<String>,
+}
+
+impl<'a> DumpedUser<'a> {
+    /// Dump `user` of the namespace `domain`, with the snippets of `namespace`. Replies are framed
+    /// like the router frames them, but scripting hooks aren't run.
+    fn new(
+        name: &'a str,
+        domain: Option<&'a str>,
+        user: &'a User,
+        namespace: &Users,
+        config_modified: Option<SystemTime>,
+        now: LocalTime,
+    ) -> Self {
+        let shown = user.at(now);
+        let reply = |verbose| {
+            if shown.proxy_to.is_some() || shown.fortunes.is_some() {
+                return None;
+            }
+            let audience = Audience {
+                verbose,
+                internal: false,
+            };
+            let (info, signature) = shown.reply(name, &namespace.snippets, audience);
+            let (prefix, suffix) = shown.frame(name, config_modified);
+            let parts = [
+                prefix.as_deref(),
+                Some(&info),
+                signature.as_deref(),
+                suffix.as_deref(),
+            ];
+            Some(parts.into_iter().flatten().collect())
+        };
+
+        Self {
+            name,
+            domain,
+            listed: user.is_listed(),
+            hidden: user.hidden,
+            tags: &user.tags,
+            uid: user.uid,
+            last_modified: shown.modified(config_modified),
+            scheduled: !std::ptr::eq(shown, user),
+            proxy_to: shown.proxy_to.as_deref(),
+            reply: reply(false),
+            verbose_reply: reply(true),
+        }
+    }
+}
+
+impl CborResponse {
+    fn new(id: Option<u64>, result: Result<String, String>) -> Self {
+        let (output, error) = match result {
+            Ok(output) => (Some(output), None),
+            Err(error) => (None, Some(error)),
+        };
+        Self {
+            v: CBOR_VERSION,
+            id,
+            ok: error.is_none(),
+            output,
+            error,
+        }
+    }
+}
+
+/// Bind the admin socket at `path`, only accessible to the daemon's user
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Accept and serve admin connections forever
+pub async fn serve(listener: UnixListener, config: Arc<Config>, state: Arc<ServerState>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                error!("cannot accept admin connection: {err}");
+                continue;
+            }
+        };
+
+        let config = Arc::clone(&config);
+        let state = Arc::clone(&state);
+        tokio::task::spawn(async move {
+            if let Err(err) = handle(stream, &config, &state).await {
+                warn!("admin connection failed: {err}");
+            }
+        });
+    }
+}
+
+#[instrument(skip_all)]
+async fn handle(mut stream: UnixStream, config: &Config, state: &ServerState) -> io::Result<()> {
+    let (input, mut output) = stream.split();
+    let mut input = BufReader::new(input.take(SANE_COMMAND_LENGTH));
+
+    // Map headers are never the first byte of a command name
+    if matches!(input.fill_buf().await?.first(), Some(0xa0..=0xbf)) {
+        let mut requests = Vec::new();
+        input.read_to_end(&mut requests).await?;
+        let responses = handle_cbor(&requests, config, state).await;
+        output.write_all(&responses).await?;
+        return output.shutdown().await;
+    }
+
+    let mut command = String::new();
+    input.read_line(&mut command).await?;
+    let mut payload = String::new();
+    input.read_to_string(&mut payload).await?;
+
+    match run(command.trim(), &payload, config, state).await {
+        Ok(reply) => {
+            output.write_all(b"OK\n").await?;
+            output.write_all(reply.as_bytes()).await?;
+        }
+        Err(err) => {
+            output
+                .write_all(format!("ERROR: {err}\n").as_bytes())
+                .await?;
+        }
+    }
+
+    output.shutdown().await
+}
+
+/// Responses to the CBOR `requests`, stopping at the first one that can't be decoded
+async fn handle_cbor(mut requests: &[u8], config: &Config, state: &ServerState) -> Vec<u8> {
+    let mut responses = Vec::new();
+    while !requests.is_empty() {
+        let response = match ciborium::from_reader::<CborRequest, _>(&mut requests) {
+            Ok(request) if request.v != CBOR_VERSION => {
+                let error = format!("unsupported version {}", request.v);
+                CborResponse::new(request.id, Err(error))
+            }
+            Ok(request) => {
+                let result = run(&request.command, &request.payload, config, state).await;
+                CborResponse::new(request.id, result)
+            }
+            Err(err) => {
+                requests = &[];
+                CborResponse::new(None, Err(format!("invalid request: {err}")))
+            }
+        };
+        ciborium::into_writer(&response, &mut responses).unwrap();
+    }
+    responses
+}
+
+/// Output of `command`, given its `payload`
+async fn run(
+    command: &str,
+    payload: &str,
+    config: &Config,
+    state: &ServerState,
+) -> Result<String, String> {
+    let mut words = command.split_whitespace();
+    let name = words.next().unwrap_or_default();
+    match (name, &words.collect::<Vec<_>>()[..]) {
+        ("merge", []) => merge(config, payload).await,
+        ("config", []) => dump(config).await,
+        ("maintenance", []) => maintenance(state, payload),
+        ("log-level", []) => log_level(payload),
+        ("dump-users", ["--json"]) => dump_users(config, state).await,
+        ("dump-users", _) => Err("expected \"dump-users --json\"".to_owned()),
+        ("candidate", args) => candidate(config, state, args, payload).await,
+        _ => Err(format!("unknown command {command:?}")),
+    }
+}
+
+async fn dump_users(config: &Config, state: &ServerState) -> Result<String, String> {
+    let users = config.get().await.redacted();
+    let now = LocalTime::new(state.clock.system_now(), users.utc_offset);
+
+    let top_level = users
+        .users
+        .iter()
+        .map(|(name, user)| (None, name, user, &users));
+    let namespaced = (users.domains.iter()).flat_map(|(domain, namespace)| {
+        (namespace.users.iter())
+            .map(move |(name, user)| (Some(domain.as_str()), name, user, namespace))
+    });
+    let dumped = DumpedUsers {
+        generation: users.generation,
+        users: (top_level.chain(namespaced))
+            .map(|(domain, name, user, namespace)| {
+                DumpedUser::new(name, domain, user, namespace, users.modified, now)
+            })
+            .collect(),
+    };
+
+    let mut json = serde_json::to_string(&dumped).map_err(|err| err.to_string())?;
+    json.push('\n');
+    Ok(json)
+}
+
+async fn dump(config: &Config) -> Result<String, String> {
+    let users = config.get().await.redacted();
+    toml::to_string(&users).map_err(|err| err.to_string())
+}
+
+async fn candidate(
+    config: &Config,
+    state: &ServerState,
+    args: &[&str],
+    payload: &str,
+) -> Result<String, String> {
+    let (percent, networks) = match args {
+        [] => {
+            return Ok(match config.candidate().await {
+                Some((users, rollout)) => {
+                    format!("generation {} serving {rollout}\n", users.generation)
+                }
+                None => "no candidate\n".to_owned(),
+            })
+        }
+        ["off"] => {
+            config.set_candidate(None).await;
+            warn!("candidate config dropped");
+            return Ok(String::new());
+        }
+        [percent, networks @ ..] => (percent, networks),
+    };
+
+    let rollout = Rollout {
+        percent: (percent.strip_suffix('%'))
+            .and_then(|percent| percent.parse().ok())
+            .filter(|percent| *percent <= 100)
+            .ok_or_else(|| format!("expected a percentage like \"5%\", not {percent:?}"))?,
+        networks: (networks.iter())
+            .map(|net| net.parse().map_err(|_| format!("invalid network {net:?}")))
+            .collect::<Result<_, _>>()?,
+    };
+    let mut users = Users::parse(payload).map_err(|err| err.message().to_owned())?;
+    users.modified = Some(state.clock.system_now());
+
+    warn!("candidate config serving {rollout}");
+    config.set_candidate(Some((users, rollout))).await;
+    Ok(String::new())
+}
+
+fn maintenance(state: &ServerState, payload: &str) -> Result<String, String> {
+    let enabled = match payload.trim() {
+        "on" => true,
+        "off" => false,
+        payload => return Err(format!("expected \"on\" or \"off\", not {payload:?}")),
+    };
+
+    state.maintenance.store(enabled, Ordering::Relaxed);
+    match enabled {
+        true => warn!("maintenance mode on"),
+        false => warn!("maintenance mode off"),
+    }
+    Ok(String::new())
+}
+
+fn log_level(payload: &str) -> Result<String, String> {
+    let level = match payload.trim() {
+        "config" => None,
+        level => Some(
+            level
+                .parse()
+                .map_err(|_| format!("unknown level {level:?}"))?,
+        ),
+    };
+
+    crate::logging::force_level(level)?;
+    match level {
+        Some(level) => warn!("log level forced to {level}"),
+        None => warn!("log level back to the config"),
+    }
+    Ok(String::new())
+}
+
+async fn merge(config: &Config, payload: &str) -> Result<String, String> {
+    let patch = toml::from_str::<ConfigPatch>(payload).map_err(|err| err.message().to_owned())?;
+    info!(
+        "merging {} user(s) and removing {} user(s)",
+        patch.users.len(),
+        patch.remove.len(),
+    );
+
+    config.update(|users| patch.apply(users)).await?;
+    Ok(String::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SharedClock;
+
+    #[tokio::test]
+    async fn answers_cbor_requests() {
+        let config =
+            Config::new_parsed("users.alice = \"Alice\"", None, SharedClock::default()).unwrap();
+        let state = ServerState::default();
+
+        let mut requests = Vec::new();
+        for (id, command, payload) in [(1, "maintenance", "on"), (2, "reboot", "")] {
+            let request = ciborium::Value::Map(vec![
+                ("v".into(), CBOR_VERSION.into()),
+                ("id".into(), id.into()),
+                ("command".into(), command.into()),
+                ("payload".into(), payload.into()),
+            ]);
+            ciborium::into_writer(&request, &mut requests).unwrap();
+        }
+        let responses = handle_cbor(&requests, &config, &state).await;
+
+        let mut responses = &responses[..];
+        let decode = |responses: &mut &[u8]| {
+            let response: ciborium::Value = ciborium::from_reader(responses).unwrap();
+            let field = |name: &str| {
+                let map = response.as_map().unwrap();
+                let value = map.iter().find(|(key, _)| key.as_text() == Some(name));
+                value.map(|(_, value)| value.clone())
+            };
+            (field("id"), field("ok"), field("error"))
+        };
+        assert_eq!(
+            decode(&mut responses),
+            (Some(1.into()), Some(true.into()), None)
+        );
+        let (id, ok, error) = decode(&mut responses);
+        assert_eq!((id, ok), (Some(2.into()), Some(false.into())));
+        assert_eq!(error, Some("unknown command \"reboot\"".into()));
+        assert!(responses.is_empty());
+        assert!(state.maintenance.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn serves_candidate_to_its_rollout() {
+        let config =
+            Config::new_parsed("users.alice = \"Alice\"", None, SharedClock::default()).unwrap();
+        let state = ServerState::default();
+        let tester = Some("192.0.2.1".parse().unwrap());
+        let other = Some("198.51.100.1".parse().unwrap());
+        let info = |users: Arc<Users>| users.users["alice"].info.as_deref().unwrap().to_owned();
+
+        let candidate = "users.alice = \"New Alice\"";
+        run("candidate 0% 192.0.2.0/24", candidate, &config, &state)
+            .await
+            .unwrap();
+        assert_eq!(info(config.get_for(tester).await), "New Alice\r\n");
+        assert_eq!(info(config.get_for(other).await), "Alice\r\n");
+
+        // Reloads of the live config keep the candidate
+        config
+            .load("users.alice = \"Alice 2\"", None)
+            .await
+            .unwrap();
+        assert_eq!(info(config.get_for(tester).await), "New Alice\r\n");
+        assert_eq!(info(config.get_for(other).await), "Alice 2\r\n");
+
+        run("candidate 100%", candidate, &config, &state)
+            .await
+            .unwrap();
+        assert_eq!(info(config.get_for(other).await), "New Alice\r\n");
+        let status = run("candidate", "", &config, &state).await.unwrap();
+        assert!(status.ends_with("serving 100% of requests\n"), "{status}");
+
+        run("candidate off", "", &config, &state).await.unwrap();
+        assert_eq!(info(config.get_for(tester).await), "Alice 2\r\n");
+
+        let error = run("candidate 150%", candidate, &config, &state).await;
+        assert_eq!(
+            error.unwrap_err(),
+            "expected a percentage like \"5%\", not \"150%\""
+        );
+    }
+
+    #[tokio::test]
+    async fn dumps_users_as_json() {
+        let toml = r#"
+            users.alice = { info = "Alice", long-info = "Alice Doe", prefix = "[{name}]\n", tags = ["staff"] }
+            users.bob = { proxy-to = "bob@example.com" }
+            domains."example.org".users.alice = "Other Alice"
+        "#;
+        let config = Config::new_parsed(toml, None, SharedClock::default()).unwrap();
+        let state = ServerState::default();
+
+        let output = run("dump-users --json", "", &config, &state).await.unwrap();
+        let dump: serde_json::Value = serde_json::from_str(&output).unwrap();
+        let users = dump["users"].as_array().unwrap();
+        let user = |domain: Option<&str>, name: &str| {
+            let user = users
+                .iter()
+                .find(|user| user["name"] == name && user["domain"].as_str() == domain);
+            user.unwrap().clone()
+        };
+
+        let alice = user(None, "alice");
+        assert_eq!(alice["tags"], serde_json::json!(["staff"]));
+        assert_eq!(alice["reply"], "[alice]\r\nAlice\r\n");
+        assert_eq!(alice["verbose_reply"], "[alice]\r\nAlice Doe\r\n");
+        assert_eq!(user(None, "bob")["proxy_to"], "bob@example.com");
+        assert_eq!(user(None, "bob")["reply"], serde_json::Value::Null);
+        assert_eq!(
+            user(Some("example.org"), "alice")["reply"],
+            "Other Alice\r\n"
+        );
+
+        let error = run("dump-users", "", &config, &state).await.unwrap_err();
+        assert_eq!(error, "expected \"dump-users --json\"");
+    }
+
+    #[tokio::test]
+    async fn refuses_merges_with_unknown_snippets() {
+        let toml = "snippets.footer = \"Bye\"\nusers.alice = \"Alice\"";
+        let config = Config::new_parsed(toml, None, SharedClock::default()).unwrap();
+        let state = ServerState::default();
+
+        let patch = "users.bob = \"Bob\\n{snippet:header}\"";
+        let error = run("merge", patch, &config, &state).await.unwrap_err();
+        assert_eq!(error, "user \"bob\": unknown snippet \"header\"");
+        assert!(!config.get().await.users.contains_key("bob"));
+
+        let patch = "users.bob = \"Bob\\n{snippet:footer}\"";
+        run("merge", patch, &config, &state).await.unwrap();
+        assert!(config.get().await.users.contains_key("bob"));
+    }
+
+    #[tokio::test]
+    async fn dumps_config_without_secrets() {
+        let toml = r#"
+            backend-options = { token = "secret-1" }
+            snippets.office = "{internal}\nRoom secret-2\n{/internal}"
+            domains."example.org" = { backend-options = { password = "secret-3" }, users = {} }
+
+            [users.alice]
+            info = "Alice\n{internal}\nsecret-4\n{verbose}\nsecret-5\n{/verbose}\n{/internal}\nBye"
+            contact.email = "alice@example.com"
+            contact.phone = { value = "secret-6", privacy = "internal" }
+        "#;
+        let config = Config::new_parsed(toml, None, SharedClock::default()).unwrap();
+        let state = ServerState::default();
+
+        let output = run("config", "", &config, &state).await.unwrap();
+        assert!(!output.contains("secret"), "{output}");
+        assert!(output.contains("alice@example.com"));
+        let dump = toml::from_str::<Users>(&output).unwrap();
+        assert_eq!(
+            dump.users["alice"].info.as_deref(),
+            Some("Alice\r\n{internal}\r\n<redacted>\r\n{/internal}\r\nBye\r\n")
+        );
+    }
+}
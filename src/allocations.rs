@@ -0,0 +1,112 @@
+//! Allocation counts of the request pipeline, so that refactors can't make it allocate more
+//! unnoticed
+//!
+//! Tests run with a global allocator counting the allocations made by each thread. Each case
+//! answers a query with [Router::handle], from and to memory, on a runtime driven by the thread of
+//! the test, and checks the allocations made for it against the budget of the case. Budgets are the
+//! counts measured when they were set: lower them when a change allocates less, and only raise them
+//! knowingly. The test fails listing the counts of the cases over their budget.
+
+use crate::config::Users;
+use crate::context::RequestContext;
+use crate::router::Router;
+use crate::state::ServerState;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::time::Duration;
+
+struct Counting;
+
+thread_local! {
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+// SAFETY: allocations are forwarded to the system allocator, counting them doesn't allocate
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+const CONFIG: &str = r#"
+    users.alice = { info = "Alice", long-info = "Alice Doe\nRoom 42" }
+    users.bob = "Bob"
+"#;
+
+struct Case {
+    query: &'static [u8],
+    /// Max number of allocations made to answer the query
+    budget: u64,
+}
+
+const CASES: &[Case] = &[
+    Case {
+        query: b"alice\r\n",
+        budget: 6,
+    },
+    Case {
+        query: b"/W alice\r\n",
+        budget: 7,
+    },
+    Case {
+        query: b"carol\r\n",
+        budget: 7,
+    },
+    Case {
+        query: b"\r\n",
+        budget: 7,
+    },
+    Case {
+        query: b"alice@example.com\r\n",
+        budget: 13,
+    },
+];
+
+/// Number of allocations made by this thread to answer `query`
+fn count_allocations(runtime: &tokio::runtime::Runtime, users: &Users, query: &[u8]) -> u64 {
+    let ctx = RequestContext::new("tcp", &"test", None, Duration::ZERO);
+    let state = ServerState::default();
+    let router = Router::new(&ctx, users, &state);
+    let mut reply = Vec::with_capacity(1024);
+
+    let before = ALLOCATIONS.with(Cell::get);
+    runtime
+        .block_on(router.handle(&mut &query[..], &mut reply))
+        .unwrap();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+#[test]
+fn pipeline_stays_within_allocation_budgets() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let users = Users::parse(CONFIG).unwrap();
+    let mut over_budget = Vec::new();
+
+    for case in CASES {
+        // The first query initializes what's lazily initialized, once per process
+        count_allocations(&runtime, &users, case.query);
+        let allocations = count_allocations(&runtime, &users, case.query);
+        if allocations > case.budget {
+            let query = format!("{:?}", String::from_utf8_lossy(case.query));
+            over_budget.push(format!("{query}: {allocations} > {}", case.budget));
+        }
+    }
+
+    assert!(over_budget.is_empty(), "{}", over_budget.join("\n"));
+}
@@ -0,0 +1,129 @@
+use crate::escape::Escaped;
+use std::fmt::Display;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::Mutex;
+
+/// Log of every request received and every byte replied, for reviewing what was disclosed
+///
+/// When the file grows past its max size, it's renamed with a `.1` suffix (replacing the previous
+/// one) and a new file is started.
+pub struct AuditLog {
+    path: PathBuf,
+    max_size: u64,
+    file: Mutex<(File, u64)>,
+}
+
+impl AuditLog {
+    pub async fn open(path: &Path, max_size: u64) -> io::Result<Self> {
+        let file = Self::open_file(path).await?;
+        let size = file.metadata().await?.len();
+
+        Ok(Self {
+            path: path.to_owned(),
+            max_size,
+            file: Mutex::new((file, size)),
+        })
+    }
+
+    async fn open_file(path: &Path) -> io::Result<File> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+    }
+
+    /// Append an entry for one exchange with `peer`, answered by the config `generation`
+    pub async fn record(
+        &self,
+        peer: &(dyn Display + Sync),
+        generation: u64,
+        request: &[u8],
+        reply: &[u8],
+    ) -> io::Result<()> {
+        let mut entry = format!(
+            "=== {} {peer} config={generation} request={} reply={} bytes\n",
+            humantime::format_rfc3339_millis(SystemTime::now()),
+            Escaped(request),
+            reply.len(),
+        )
+        .into_bytes();
+        entry.extend_from_slice(reply);
+        if !reply.ends_with(b"\n") {
+            entry.push(b'\n');
+        }
+
+        let mut guard = self.file.lock().await;
+        let (file, size) = &mut *guard;
+
+        if *size > 0 && *size + entry.len() as u64 > self.max_size {
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+            tokio::fs::rename(&self.path, rotated).await?;
+            *file = Self::open_file(&self.path).await?;
+            *size = 0;
+        }
+
+        file.write_all(&entry).await?;
+        file.flush().await?;
+        *size += entry.len() as u64;
+
+        Ok(())
+    }
+}
+
+/// Stream wrapper keeping a copy of every byte that goes through it
+pub struct Recording<T> {
+    inner: T,
+    pub recorded: Vec<u8>,
+}
+
+impl<T> Recording<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            recorded: Vec::new(),
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Recording<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let before = buf.filled().len();
+        std::task::ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.recorded.extend_from_slice(&buf.filled()[before..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Recording<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let written = std::task::ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.recorded.extend_from_slice(&buf[..written]);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,83 @@
+//! Backends providing more users to a namespace, selected by its `backend` key
+//!
+//! Backends are registered in [BACKENDS] under their name, with a constructor reading their
+//! options from the `backend-options` table of the namespace. Their users are read each time the
+//! config is loaded, and added to those of the config file, which take precedence. Custom stores
+//! can be plugged in by implementing [UserSource] in a module (usually behind a feature) and
+//! registering it.
+
+use crate::config::User;
+use serde::Deserialize;
+use std::io;
+use std::path::PathBuf;
+
+/// Store of users, created from the options of a namespace
+pub trait UserSource {
+    /// Read the users, in listing order
+    fn users(&self) -> Result<Vec<(String, User)>, String>;
+}
+
+type Constructor = fn(toml::Table) -> Result<Box<dyn UserSource>, String>;
+
+/// Available backends, by name
+static BACKENDS: &[(&str, Constructor)] = &[("directory", Directory::open)];
+
+/// Create the source of the backend `name`
+pub fn open(name: &str, options: toml::Table) -> Result<Box<dyn UserSource>, String> {
+    let (_, constructor) = (BACKENDS.iter())
+        .find(|(backend, _)| *backend == name)
+        .ok_or_else(|| {
+            let names = BACKENDS.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+            format!("unknown backend {name:?}, expected one of {names:?}")
+        })?;
+
+    constructor(options)
+}
+
+/// Directory where each file is a user, named after the file, whose info is the file's content
+///
+/// Hidden files (starting with `.`) are skipped, and users are listed alphabetically.
+struct Directory {
+    path: PathBuf,
+}
+
+impl Directory {
+    fn open(options: toml::Table) -> Result<Box<dyn UserSource>, String> {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Options {
+            path: PathBuf,
+        }
+
+        let options = toml::Value::Table(options)
+            .try_into::<Options>()
+            .map_err(|err| err.message().to_owned())?;
+        Ok(Box::new(Self { path: options.path }))
+    }
+
+    fn read(&self) -> io::Result<Vec<(String, User)>> {
+        let mut users = Vec::new();
+        for entry in std::fs::read_dir(&self.path)? {
+            let entry = entry?;
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if name.starts_with('.') || !entry.file_type()?.is_file() {
+                continue;
+            }
+
+            let info = std::fs::read_to_string(entry.path())?;
+            users.push((name, User::from_info(info)));
+        }
+
+        users.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(users)
+    }
+}
+
+impl UserSource for Directory {
+    fn users(&self) -> Result<Vec<(String, User)>, String> {
+        self.read()
+            .map_err(|err| format!("cannot read {}: {err}", self.path.display()))
+    }
+}
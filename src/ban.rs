@@ -0,0 +1,193 @@
+use crate::clock::SharedClock;
+use crate::listener::Peer;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Target of the log lines emitted for denied requests, for filtering them
+pub const LOG_TARGET: &str = "fingered::abuse";
+
+/// Why a request was denied
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Denial {
+    /// The request matched one of the `deny` rules
+    DenyRule,
+    /// The request asked for forwarding to another host
+    Forwarding,
+    /// The request asked for a user listing, which is disabled
+    Listing,
+    /// The requested user doesn't exist
+    UnknownUser,
+    /// The request didn't end within the max request length
+    TooLong,
+    /// The request isn't a valid finger query
+    Malformed,
+    /// The request scored at least the `deny-score` of the abuse policy
+    Policy,
+    /// The request scored at least the `ban-score` of the abuse policy, banning its client
+    PolicyBan,
+}
+
+impl Display for Denial {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::DenyRule => "deny-rule",
+            Self::Forwarding => "forwarding",
+            Self::Listing => "listing",
+            Self::UnknownUser => "unknown-user",
+            Self::TooLong => "too-long",
+            Self::Malformed => "malformed",
+            Self::Policy => "policy",
+            Self::PolicyBan => "policy-ban",
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct BanConfig {
+    /// Line logged for each denied request, where `{ip}` and `{reason}` are substituted
+    ///
+    /// `{peer}` (address or Unix socket credentials of the client), `{pid}`, `{uid}` and `{gid}`
+    /// (credentials of a Unix socket client) are also substituted, with `-` for unknown values.
+    pub log_format: String,
+
+    /// Number of denied requests within `find-time` after which a client is banned
+    ///
+    /// 0 (default) disables the internal ban list, leaving banning to external tools.
+    pub max_strikes: u32,
+
+    /// Window in seconds in which denied requests are counted
+    pub find_time: u64,
+
+    /// Duration in seconds of a ban
+    pub ban_time: u64,
+}
+
+impl Default for BanConfig {
+    fn default() -> Self {
+        Self {
+            log_format: "denied {reason} request from {ip}".into(),
+            max_strikes: 0,
+            find_time: 600,
+            ban_time: 3600,
+        }
+    }
+}
+
+impl BanConfig {
+    fn format(&self, peer: &Peer, denial: Denial) -> String {
+        fn or_dash(value: Option<impl ToString>) -> String {
+            value.map_or_else(|| "-".to_owned(), |value| value.to_string())
+        }
+
+        let credentials = peer.credentials();
+        self.log_format
+            .replace("{ip}", &or_dash(peer.ip()))
+            .replace("{peer}", &peer.to_string())
+            .replace("{pid}", &or_dash(credentials.and_then(|c| c.pid)))
+            .replace("{uid}", &or_dash(credentials.map(|c| c.uid)))
+            .replace("{gid}", &or_dash(credentials.map(|c| c.gid)))
+            .replace("{reason}", &denial.to_string())
+    }
+}
+
+struct Offender {
+    strikes: u32,
+    first_strike: Instant,
+    banned_until: Option<Instant>,
+}
+
+/// Clients that are temporarily refused because of repeated denied requests
+#[derive(Default)]
+pub struct BanList {
+    offenders: Mutex<HashMap<IpAddr, Offender>>,
+    clock: SharedClock,
+}
+
+impl BanList {
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let offenders = self.offenders.lock().unwrap();
+        matches!(
+            offenders.get(&ip),
+            Some(Offender { banned_until: Some(until), .. }) if *until > self.clock.now()
+        )
+    }
+
+    /// Log a denied request from `peer`, and count it toward banning the client if it has an IP
+    ///
+    /// [Denial::PolicyBan] bans the client right away, even if the ban list is disabled.
+    pub fn report(&self, config: &BanConfig, peer: &Peer, denial: Denial) {
+        warn!(target: LOG_TARGET, "{}", config.format(peer, denial));
+
+        let Some(ip) = peer.ip() else {
+            return;
+        };
+        let banned_by_policy = denial == Denial::PolicyBan;
+        if config.max_strikes == 0 && !banned_by_policy {
+            return;
+        }
+
+        let now = self.clock.now();
+        let find_time = Duration::from_secs(config.find_time);
+        let mut offenders = self.offenders.lock().unwrap();
+
+        // Forget about clients that have been quiet for long enough
+        offenders.retain(|_, offender| match offender.banned_until {
+            Some(until) => until > now,
+            None => now.duration_since(offender.first_strike) < find_time,
+        });
+
+        let offender = offenders.entry(ip).or_insert(Offender {
+            strikes: 0,
+            first_strike: now,
+            banned_until: None,
+        });
+
+        if offender.banned_until.is_some() {
+            return;
+        }
+
+        offender.strikes += 1;
+        if banned_by_policy || offender.strikes >= config.max_strikes {
+            offender.banned_until = Some(now + Duration::from_secs(config.ban_time));
+            warn!(target: LOG_TARGET, "banned {ip} for {}s", config.ban_time);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::time::SystemTime;
+
+    #[test]
+    fn lifts_bans_after_the_ban_time() {
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+        let ban_list = BanList {
+            clock: clock.clone().into(),
+            ..BanList::default()
+        };
+        let config = BanConfig {
+            max_strikes: 2,
+            ban_time: 60,
+            ..BanConfig::default()
+        };
+        let peer = Peer::Tcp("192.0.2.1:1079".parse().unwrap());
+        let ip = peer.ip().unwrap();
+
+        ban_list.report(&config, &peer, Denial::Malformed);
+        assert!(!ban_list.is_banned(ip));
+        ban_list.report(&config, &peer, Denial::Malformed);
+        assert!(ban_list.is_banned(ip));
+
+        clock.advance(Duration::from_secs(59));
+        assert!(ban_list.is_banned(ip));
+        clock.advance(Duration::from_secs(1));
+        assert!(!ban_list.is_banned(ip));
+    }
+}
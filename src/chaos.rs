@@ -0,0 +1,98 @@
+//! Fault injection for resilience testing, only built with the `testing` feature
+//!
+//! Faults are configured with environment variables, read once at startup:
+//! - `FINGERED_CHAOS_DELAY_MS`: max random delay before handling each connection
+//! - `FINGERED_CHAOS_DROP`: probability (0 to 1) of closing a connection without replying
+//! - `FINGERED_CHAOS_PARTIAL_WRITE`: probability (0 to 1) of each write only sending part of its
+//!   buffer
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::AsyncWrite;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Chaos {
+    max_delay: Duration,
+    drop: f64,
+    partial_write: f64,
+}
+
+impl Chaos {
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(name: &str) -> Option<T> {
+            std::env::var(name).ok()?.parse().ok()
+        }
+
+        let chaos = Self {
+            max_delay: Duration::from_millis(var("FINGERED_CHAOS_DELAY_MS").unwrap_or(0)),
+            drop: var("FINGERED_CHAOS_DROP").unwrap_or(0.0),
+            partial_write: var("FINGERED_CHAOS_PARTIAL_WRITE").unwrap_or(0.0),
+        };
+
+        if chaos.max_delay > Duration::ZERO || chaos.drop > 0.0 || chaos.partial_write > 0.0 {
+            warn!("fault injection enabled: {chaos:?}");
+        }
+
+        chaos
+    }
+
+    /// Wait for a random delay, and return false if the connection should be dropped
+    pub async fn before_connection(&self) -> bool {
+        if self.max_delay > Duration::ZERO {
+            tokio::time::sleep(self.max_delay.mul_f64(random())).await;
+        }
+
+        if random() < self.drop {
+            debug!("chaos: dropping connection");
+            return false;
+        }
+
+        true
+    }
+
+    pub fn wrap<W>(&self, inner: W) -> ChaoticWriter<W> {
+        ChaoticWriter {
+            inner,
+            partial_write: self.partial_write,
+        }
+    }
+}
+
+/// Random number in `[0, 1)`
+fn random() -> f64 {
+    let bits = RandomState::new().build_hasher().finish() >> 11;
+    bits as f64 / (1u64 << 53) as f64
+}
+
+/// Writer that randomly writes less than it's given
+pub struct ChaoticWriter<W> {
+    inner: W,
+    partial_write: f64,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for ChaoticWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut len = buf.len();
+        if len > 1 && random() < self.partial_write {
+            len = 1 + (random() * (len - 1) as f64) as usize;
+        }
+
+        Pin::new(&mut self.inner).poll_write(cx, &buf[..len])
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
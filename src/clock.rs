@@ -0,0 +1,95 @@
+//! Source of the current time for the features that depend on it, replaceable in tests
+//!
+//! Scheduled info texts, bans, cooldowns of expensive long infos, the upstream reply cache, rate
+//! limiters (see [crate::throttle]), statistics and the load times of the config read the time
+//! from a [SharedClock] instead of asking the OS, so that tests can use a [ManualClock] and move it
+//! forward instead of sleeping.
+
+use std::fmt::{Debug, Formatter};
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+
+pub trait Clock: Send + Sync {
+    /// Monotonic time, for durations and expiry dates
+    fn now(&self) -> Instant;
+
+    /// Time of the calendar, for dates and times of day
+    fn system_now(&self) -> SystemTime;
+}
+
+/// Time of the OS
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// [Clock] shared by the parts of the server that keep time, the [SystemClock] by default
+#[derive(Clone)]
+pub struct SharedClock(Arc<dyn Clock>);
+
+impl Default for SharedClock {
+    fn default() -> Self {
+        Self(Arc::new(SystemClock))
+    }
+}
+
+impl<C: Clock + 'static> From<C> for SharedClock {
+    fn from(clock: C) -> Self {
+        Self(Arc::new(clock))
+    }
+}
+
+impl Deref for SharedClock {
+    type Target = dyn Clock;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+impl Debug for SharedClock {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SharedClock")
+    }
+}
+
+/// Clock that only moves when [advanced](ManualClock::advance), shared by its clones
+#[cfg(test)]
+#[derive(Clone)]
+pub struct ManualClock(Arc<std::sync::Mutex<(Instant, SystemTime)>>);
+
+#[cfg(test)]
+impl ManualClock {
+    /// Clock stopped at `system_now`
+    pub fn new(system_now: SystemTime) -> Self {
+        Self(Arc::new(std::sync::Mutex::new((
+            Instant::now(),
+            system_now,
+        ))))
+    }
+
+    pub fn advance(&self, by: std::time::Duration) {
+        let mut now = self.0.lock().unwrap();
+        now.0 += by;
+        now.1 += by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.0.lock().unwrap().0
+    }
+
+    fn system_now(&self) -> SystemTime {
+        self.0.lock().unwrap().1
+    }
+}
@@ -1,66 +1,1404 @@
+use crate::abuse::AbusePolicy;
+use crate::ban::BanConfig;
+use crate::clock::SharedClock;
+use crate::contact::Contact;
+use crate::expensive::ExpensiveLongInfo;
+use crate::fortune::{Fortune, FortuneOrder, Fortunes};
+use crate::logging::LoggingConfig;
+use crate::memory::{Estimate, Interner};
+use crate::redact::{self, Audience};
+use crate::schedule::{LocalTime, Scheduled, UtcOffset};
+#[cfg(feature = "scripting")]
+use crate::scripting::Hooks;
+use crate::snippet;
+#[cfg(feature = "tls")]
+use crate::tls::TlsConfig;
+use crate::validate::Charset;
+use indexmap::IndexMap;
+use ipnet::IpNet;
+use regex::bytes::RegexSet;
+use serde::de::Error as _;
 use serde::{Deserialize, Deserializer};
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::net::IpAddr;
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::SystemTime;
+use tokio::sync::{Mutex, RwLock};
+
+/// Shown instead of secret values when the config is dumped
+#[cfg(all(unix, feature = "unix-socket"))]
+const REDACTED: &str = "<redacted>";
 
 #[derive(Default)]
 pub struct Config {
     lock: RwLock<Arc<Users>>,
+
+    /// Users serving the requests covered by their [Rollout] instead of the ones of `lock`
+    candidate: RwLock<Option<(Arc<Users>, Rollout)>>,
+
+    layers: Mutex<Layers>,
+}
+
+/// Requests served by a candidate config, see [Config::set_candidate]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Rollout {
+    /// Percentage of the requests served by the candidate, picked at random
+    pub percent: u8,
+
+    /// Clients whose requests are all served by the candidate
+    pub networks: Vec<IpNet>,
+}
+
+impl Rollout {
+    /// Whether a request from `ip` is served by the candidate
+    fn covers(&self, ip: Option<IpAddr>) -> bool {
+        ip.is_some_and(|ip| self.networks.iter().any(|net| net.contains(&ip)))
+            || crate::random(100) < u64::from(self.percent)
+    }
+}
+
+impl std::fmt::Display for Rollout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}% of requests", self.percent)?;
+        for net in &self.networks {
+            write!(f, ", {net}")?;
+        }
+        Ok(())
+    }
+}
+
+/// What the current [Users] are computed from
+#[derive(Default)]
+struct Layers {
+    /// Users loaded from the config file, possibly modified by [Config::update]
+    base: Users,
+
+    /// Users from a dynamic store, taking precedence over the ones of [Layers::base]
+    overlay: HashMap<String, User>,
+
+    /// Long info texts read from the [Users::plans_dir]
+    plans: HashMap<String, Plan>,
+
+    /// Info texts pushed by local users, replacing those of the users they own
+    pushed: HashMap<String, Pushed>,
+
+    /// Users replacing [Layers::base] for the requests covered by the [Rollout], merged with the
+    /// same layers
+    candidate: Option<(Users, Rollout)>,
+
+    /// [Users::generation] of the last snapshot
+    generation: u64,
+
+    /// Time at which snapshots are taken
+    clock: SharedClock,
+}
+
+impl Layers {
+    /// New snapshot of the users, with the next [Users::generation]
+    fn merged(&mut self) -> Users {
+        let users = self.apply(self.base.clone());
+        self.next_generation(users)
+    }
+
+    /// New snapshot of the candidate users, if any, with the next [Users::generation]
+    fn merged_candidate(&mut self) -> Option<(Arc<Users>, Rollout)> {
+        let (base, rollout) = self.candidate.clone()?;
+        let users = self.apply(base);
+        Some((Arc::new(self.next_generation(users)), rollout))
+    }
+
+    fn next_generation(&mut self, mut users: Users) -> Users {
+        self.generation += 1;
+        users.set_generation(self.generation, self.clock.system_now());
+        users
+    }
+
+    /// `users` with the overlay, plans and pushed texts applied
+    fn apply(&self, mut users: Users) -> Users {
+        let mut overlay = self.overlay.clone().into_iter().collect::<Vec<_>>();
+        overlay.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        users.users.extend(overlay);
+        for (name, plan) in &self.plans {
+            let owner = (users.users.get_mut(name))
+                .filter(|user| plan.owner.is_none_or(|uid| user.uid == Some(uid)));
+            if let Some(user) = owner {
+                plan.apply(name, user);
+            }
+        }
+        for (name, pushed) in &self.pushed {
+            let owner = (users.users.get_mut(name)).filter(|user| user.uid == Some(pushed.uid));
+            if let Some(user) = owner {
+                pushed.apply(name, user);
+            }
+        }
+        users
+    }
+}
+
+impl Config {
+    /// Config of `users`, whose snapshots are timed by `clock`
+    pub fn new(users: Users, clock: SharedClock) -> Self {
+        let mut layers = Layers {
+            base: users,
+            overlay: HashMap::new(),
+            plans: HashMap::new(),
+            pushed: HashMap::new(),
+            candidate: None,
+            generation: 0,
+            clock,
+        };
+        Self {
+            lock: RwLock::new(Arc::new(layers.merged())),
+            candidate: RwLock::new(None),
+            layers: Mutex::new(layers),
+        }
+    }
+
+    pub fn new_parsed(
+        toml: &str,
+        modified: Option<SystemTime>,
+        clock: SharedClock,
+    ) -> Result<Self, toml::de::Error> {
+        let mut users = Users::parse(toml)?;
+        users.modified = modified;
+        Ok(Self::new(users, clock))
+    }
+
+    pub async fn get(&self) -> Arc<Users> {
+        self.lock.read().await.clone()
+    }
+
+    /// Users serving a request from `ip`: the candidate ones if their [Rollout] covers it, the
+    /// current ones otherwise
+    pub async fn get_for(&self, ip: Option<IpAddr>) -> Arc<Users> {
+        if let Some((users, rollout)) = &*self.candidate.read().await {
+            if rollout.covers(ip) {
+                return users.clone();
+            }
+        }
+        self.get().await
+    }
+
+    /// Candidate users and the requests they serve, if any
+    #[cfg(all(unix, feature = "unix-socket"))]
+    pub async fn candidate(&self) -> Option<(Arc<Users>, Rollout)> {
+        self.candidate.read().await.clone()
+    }
+
+    pub async fn set(&self, users: Users) {
+        let mut layers = self.layers.lock().await;
+        layers.base = users;
+        self.publish(&mut layers).await;
+    }
+
+    /// Serve the requests covered by `rollout` with `users` instead of the current users, or stop
+    /// if `None`
+    ///
+    /// The candidate is merged with the same store users, plans and pushed texts as the current
+    /// users, and survives reloads of the config file, but not restarts.
+    #[cfg(all(unix, feature = "unix-socket"))]
+    pub async fn set_candidate(&self, candidate: Option<(Users, Rollout)>) {
+        let mut layers = self.layers.lock().await;
+        layers.candidate = candidate;
+        *self.candidate.write().await = layers.merged_candidate();
+    }
+
+    /// Replace the current users and candidate users with new snapshots of the `layers`
+    async fn publish(&self, layers: &mut Layers) {
+        let users = layers.merged();
+        self.publish_merged(layers, users).await;
+    }
+
+    /// Replace the current users with `users`, a snapshot of the `layers`, and the candidate users
+    /// with a new snapshot
+    async fn publish_merged(&self, layers: &mut Layers, users: Users) {
+        let candidate = layers.merged_candidate();
+        *self.lock.write().await = Arc::new(users);
+        *self.candidate.write().await = candidate;
+    }
+
+    pub async fn load(
+        &self,
+        toml: &str,
+        modified: Option<SystemTime>,
+    ) -> Result<(), toml::de::Error> {
+        let mut users = Users::parse(toml)?;
+        users.modified = modified;
+        self.set(users).await;
+        Ok(())
+    }
+
+    /// Replace the current users with a modified copy, unless it exceeds the [Limits] or includes
+    /// unknown snippets
+    #[cfg(all(unix, feature = "unix-socket"))]
+    pub async fn update(&self, f: impl FnOnce(&mut Users)) -> Result<(), String> {
+        let mut layers = self.layers.lock().await;
+        let mut base = layers.base.clone();
+        f(&mut base);
+        base.intern();
+        base.check_limits(&base.limits)?;
+        base.check_snippets()?;
+        layers.base = base;
+        self.publish(&mut layers).await;
+        Ok(())
+    }
+
+    /// Apply `change` to the texts pushed by the local user `uid` for the user it owns (see
+    /// [User::uid]), returning its name
+    ///
+    /// Pushed texts survive reloads of the config file, as long as the user is still owned by `uid`.
+    #[cfg(all(unix, feature = "unix-socket"))]
+    pub async fn push(&self, uid: u32, change: impl FnOnce(&mut Pushed)) -> Result<String, String> {
+        let mut layers = self.layers.lock().await;
+        let name = {
+            let users = self.lock.read().await;
+            let mut owned = (users.users.iter()).filter(|(_, user)| user.uid == Some(uid));
+            match (owned.next(), owned.next()) {
+                (Some((name, _)), None) => name.clone(),
+                (None, _) => return Err(format!("no user is owned by uid {uid}")),
+                (Some(_), Some(_)) => return Err(format!("several users are owned by uid {uid}")),
+            }
+        };
+
+        let previous = layers.pushed.clone();
+        let pushed = (layers.pushed.entry(name.clone())).or_insert_with(|| Pushed {
+            uid,
+            info: None,
+            long_info: None,
+        });
+        pushed.uid = uid;
+        change(pushed);
+        if pushed.info.is_none() && pushed.long_info.is_none() {
+            layers.pushed.remove(&name);
+        }
+
+        let users = layers.merged();
+        if let Err(err) = users.check_limits(&users.limits) {
+            layers.pushed = previous;
+            return Err(err);
+        }
+        self.publish_merged(&mut layers, users).await;
+        Ok(name)
+    }
+
+    /// Replace the plans read from the [Users::plans_dir], unless they exceed the [Limits]
+    ///
+    /// Plans are checked one by one before, see [Plan::check_limits], so this only fails when they
+    /// make the users take too much memory together.
+    pub async fn set_plans(&self, plans: HashMap<String, Plan>) -> Result<(), String> {
+        let mut layers = self.layers.lock().await;
+        let previous = std::mem::replace(&mut layers.plans, plans);
+        let users = layers.merged();
+        if let Err(err) = users.check_limits(&users.limits) {
+            layers.plans = previous;
+            return Err(err);
+        }
+        self.publish_merged(&mut layers, users).await;
+        Ok(())
+    }
+
+    /// Replace the users provided by a dynamic store, which survive reloads of the config file
+    #[cfg(feature = "kv-store")]
+    pub async fn set_overlay(&self, overlay: HashMap<String, User>) {
+        let mut layers = self.layers.lock().await;
+        layers.overlay = overlay;
+        self.publish(&mut layers).await;
+    }
+}
+
+impl From<Users> for Config {
+    fn from(value: Users) -> Self {
+        Self::new(value, SharedClock::default())
+    }
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Users {
+    /// If true (default), the server allows enumerating users
+    ///
+    /// When this property is true, users can be individually unlisted with [User::unlisted].
+    #[serde(default = "value::r#true")]
+    pub enable_index: bool,
+
+    /// If true, queries for nonexistent users get a "did you mean" suggestion of a listed user
+    ///
+    /// Suggestions are only made from listed users, and only if [Users::enable_index] is true.
+    #[serde(default)]
+    pub suggest_users: bool,
+
+    /// Names under which this server is reachable
+    ///
+    /// Requests forwarded to one of these hosts (e.g. `user@example.com`) are answered locally
+    /// instead of being denied as forwarding requests. Comparison is case-insensitive.
+    #[serde(default)]
+    pub hostnames: Vec<String>,
+
+    /// Announcement sent before the user listing, or instead of it if [Users::enable_index] is false
+    ///
+    /// Its line endings are fixed like those of [User::info].
+    #[serde(default, deserialize_with = "deserialize_crlf_string")]
+    pub motd: Option<String>,
+
+    /// Blocks of text included in info texts by name, see [crate::snippet]
+    ///
+    /// Their line endings are fixed like those of [User::info]. Namespaces inherit the snippets of
+    /// the top level that they don't define themselves.
+    #[serde(default, deserialize_with = "deserialize_snippets")]
+    pub snippets: HashMap<String, String>,
+
+    /// Reply to requests that aren't valid finger queries (`Malformed finger query` by default)
+    ///
+    /// Its line endings are fixed like those of [User::info]. Only read at the top level.
+    #[serde(default, deserialize_with = "deserialize_crlf_string")]
+    pub malformed_reply: Option<String>,
+
+    /// Reply to every query in maintenance mode (`Down for maintenance, please try again later` by
+    /// default)
+    ///
+    /// Maintenance mode is turned on with the `maintenance` command of the admin socket, or by
+    /// creating the [Users::maintenance_file]. Its line endings are fixed like those of
+    /// [User::info]. Only read at the top level.
+    #[serde(default, deserialize_with = "deserialize_crlf_string")]
+    pub maintenance_reply: Option<String>,
+
+    /// Reply to requests asking to be forwarded to other hosts (`Finger forwarding service denied`
+    /// by default), e.g. `Forwarding to '{host}' denied`
+    ///
+    /// `{host}` is replaced by the first host the request would be forwarded to, and `{chain}` by
+    /// all of them as written in the request (e.g. `example.com@example.org`). Its line endings are
+    /// fixed like those of [User::info]. Only read at the top level.
+    #[serde(default, deserialize_with = "deserialize_crlf_string")]
+    pub forwarding_reply: Option<String>,
+
+    /// File whose existence turns maintenance mode on, checked every second (disabled if omitted)
+    ///
+    /// Only read at the top level.
+    pub maintenance_file: Option<PathBuf>,
+
+    /// Directory of `<username>.txt` files replacing the long info of users, see [crate::plans]
+    /// (disabled if omitted)
+    ///
+    /// Only read at the top level.
+    pub plans_dir: Option<PathBuf>,
+
+    /// Translations of the messages of the server, keyed by language (e.g. `fr`)
+    ///
+    /// Only read at the top level.
+    #[serde(default)]
+    pub messages: HashMap<String, Messages>,
+
+    /// Language of the messages of the server for each kind of listener, among [Users::messages]
+    /// (English if unset)
+    ///
+    /// Only read at the top level.
+    #[serde(default)]
+    pub language: Languages,
+
+    /// How requests are read on each kind of listener
+    ///
+    /// Only read at the top level.
+    #[serde(default)]
+    pub requests: RequestReadings,
+
+    /// Max number of bytes per second sent to a single client, 0 (default) meaning unlimited
+    #[serde(default)]
+    pub write_rate: u32,
+
+    /// Max number of bytes per second sent to all clients combined, 0 (default) meaning unlimited
+    #[serde(default)]
+    pub total_write_rate: u32,
+
+    /// Milliseconds that user queries take at least, whether the user exists or not (0 by default)
+    ///
+    /// Together with [Users::reply_jitter], this hides from response times whether an unlisted
+    /// user exists. It should be longer than the slowest lookup. Only read at the top level.
+    #[serde(default)]
+    pub min_reply_time: u64,
+
+    /// Max number of milliseconds randomly added to the time user queries take (0 by default)
+    #[serde(default)]
+    pub reply_jitter: u64,
+
+    /// Offset from UTC of the times of [User::schedule], e.g. `+02:00` (UTC by default)
+    ///
+    /// Only read at the top level.
+    #[serde(default)]
+    pub utc_offset: UtcOffset,
+
+    /// Seconds after which a request that isn't answered yet is abandoned (30 by default, 0 to wait
+    /// forever)
+    ///
+    /// This covers reading the request, querying upstream servers and writing the reply to slow
+    /// clients. Only read at the top level.
+    #[serde(default = "value::request_timeout")]
+    pub request_timeout: u64,
+
+    /// Seconds a client has to finish sending its request line (10 by default, 0 to leave it to
+    /// [Users::request_timeout])
+    ///
+    /// Clients that don't send a newline in time get an error reply instead of being disconnected
+    /// without one. Only read at the top level.
+    #[serde(default = "value::line_timeout")]
+    pub line_timeout: u64,
+
+    /// Seconds for which replies of upstream servers (see [User::proxy_to]) are cached
+    #[serde(default = "value::upstream_cache_ttl")]
+    pub upstream_cache_ttl: u64,
+
+    /// Seconds for which failures to reach upstream servers are cached
+    #[serde(default = "value::upstream_negative_cache_ttl")]
+    pub upstream_negative_cache_ttl: u64,
+
+    /// Username that returns server statistics instead of user info (disabled by default)
+    ///
+    /// This name takes precedence over a user of the same name.
+    pub stats_target: Option<String>,
+
+    /// Username that returns usage information generated from the config, like the queries it
+    /// answers, instead of user info (disabled by default)
+    ///
+    /// This name takes precedence over a user of the same name.
+    pub help_target: Option<String>,
+
+    /// If true, the replies of the [Users::stats_target] and [Users::help_target] end with the
+    /// name and version of the server (false by default)
+    ///
+    /// Other replies never disclose them. Only read at the top level.
+    #[serde(default)]
+    pub disclose_version: bool,
+
+    /// Prefix of the usernames that list the users with a tag instead (disabled by default)
+    ///
+    /// For instance, with `tag.`, querying `tag.staff` lists the users tagged with `staff`, following
+    /// the same rules as the full listing. Usernames starting with this prefix are shadowed.
+    pub tag_listing_prefix: Option<String>,
+
+    /// If set, every reply is checked for CRLF line endings, a final CRLF, and printable
+    /// characters of this charset (`ascii` or `utf-8`), and problems are logged as warnings
+    ///
+    /// Meant to catch mistakes in the config or scripts. Only read at the top level.
+    pub validate_replies: Option<Charset>,
+
+    /// Charset of the replies to clients that don't ask for UTF-8 with the "/U" flag (see
+    /// [Request::utf8](crate::request::Request::utf8))
+    ///
+    /// With `utf-8` (default), texts are sent as they are. With `ascii`, they're transliterated
+    /// (see [crate::transliterate]), which breaks their signatures. Only read at the top level.
+    #[serde(default)]
+    pub legacy_charset: Charset,
+
+    /// Networks of the clients that can see the internal sections of info texts (see
+    /// [crate::redact]), e.g. `["10.0.0.0/8", "::1/128"]`
+    ///
+    /// Only read at the top level.
+    #[serde(default)]
+    pub internal_networks: Vec<IpNet>,
+
+    /// Verbose replies allowed, forced or forbidden for each kind of listener
+    ///
+    /// Only read at the top level.
+    #[serde(default)]
+    pub verbose: VerbosePolicies,
+
+    /// Reply to queries without a username for each family of client addresses, e.g. to only show
+    /// the index to IPv6 clients and keep IPv4 scanners away from it
+    ///
+    /// Only read at the top level.
+    #[serde(default)]
+    pub default_replies: DefaultReplies,
+
+    /// Regular expressions matched against the raw request line (including its CRLF)
+    ///
+    /// Requests matching any of them are answered as if the requested user didn't exist.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_regex_set",
+        serialize_with = "serialize_regex_set"
+    )]
+    pub deny: RegexSet,
+
+    /// Logging and banning of clients whose requests are denied
+    #[serde(default)]
+    pub ban: BanConfig,
+
+    /// Scoring of requests, and what's done about the ones scoring high, see [crate::abuse]
+    ///
+    /// Only read at the top level.
+    #[serde(default)]
+    pub abuse: AbusePolicy,
+
+    /// Certificates of the TLS listener, see [crate::tls]
+    ///
+    /// Only read at the top level.
+    #[cfg(feature = "tls")]
+    #[serde(default)]
+    pub tls: TlsConfig,
+
+    /// Connections kept open for more requests, when clients ask for it
+    ///
+    /// Only read at the top level.
+    #[serde(default)]
+    pub persistent_connections: PersistentConnections,
+
+    /// Levels, format and output of the daemon's logs, only read at the top level
+    #[serde(default)]
+    pub logging: LoggingConfig,
+
+    /// Caps on the size of the config, checked when it's loaded, and on the size of requests
+    ///
+    /// Only read at the top level, and applied to each namespace separately.
+    #[serde(default)]
+    pub limits: Limits,
+
+    /// If true, verbose replies start with a `Last-Modified:` line (false by default)
+    ///
+    /// The date is the one of [User::updated], or the modification time of the config file. Only
+    /// read at the top level.
+    #[serde(default)]
+    pub last_modified_header: bool,
+
+    /// Last modification time of the config file, if known
+    #[serde(skip)]
+    pub modified: Option<SystemTime>,
+
+    /// Number of this snapshot of the users, starting at 1 and increased each time the config is
+    /// reloaded or modified (see [Config])
+    ///
+    /// It's shown in stats replies and logs, and can be included in info texts as the
+    /// [snippet::GENERATION] snippet. Only set at the top level.
+    #[serde(skip)]
+    pub generation: u64,
+
+    /// When this snapshot of the users was made
+    #[serde(skip)]
+    pub loaded_at: Option<SystemTime>,
+
+    /// Order of the users in listings
+    #[serde(default)]
+    pub listing_order: ListingOrder,
+
+    /// Max number of users per listing reply, 0 (default) meaning unlimited
+    ///
+    /// When a listing is cut, it ends with a hint telling how to query the next page.
+    #[serde(default)]
+    pub listing_page_size: usize,
+
+    /// If true, requests may name several users separated by spaces (e.g. `alice bob`), and get
+    /// their replies one after the other
+    ///
+    /// Otherwise, such requests are malformed. Only read at the top level.
+    #[serde(default)]
+    pub multi_user_queries: bool,
+
+    /// Lines sent between the replies to a request naming several users (an empty line by default)
+    ///
+    /// Its line endings are fixed like those of [User::info]. Only read at the top level.
+    #[serde(default, deserialize_with = "deserialize_crlf_string")]
+    pub multi_user_separator: Option<String>,
+
+    /// Prefix of the usernames that query a page of the listing, e.g. `page.2` (default `page.`)
+    ///
+    /// Only used if [Users::listing_page_size] is set. Usernames starting with this prefix followed
+    /// by a page number are shadowed.
+    #[serde(default = "value::listing_page_prefix")]
+    pub listing_page_prefix: String,
+
+    /// Lua scripts customizing the replies, see [crate::scripting]
+    ///
+    /// Only read at the top level, but the hooks also apply to namespaces.
+    #[cfg(feature = "scripting")]
+    #[serde(default)]
+    pub scripts: Vec<PathBuf>,
+
+    /// Hooks defined by [Users::scripts], if any
+    #[cfg(feature = "scripting")]
+    #[serde(skip)]
+    pub hooks: Option<Arc<Hooks>>,
+
+    /// Name of the backend providing more users, e.g. `directory` (see [crate::backend])
+    pub backend: Option<String>,
+
+    /// Options of [Users::backend], e.g. `{ path = "/var/lib/fingered/users" }`
+    #[serde(default)]
+    pub backend_options: toml::Table,
+
+    /// Users, in the order of the config file
+    #[serde(deserialize_with = "deserialize_users")]
+    pub users: IndexMap<String, User>,
+
+    /// Separate sets of users, each queried with `user@domain`, keyed by domain
+    ///
+    /// Each namespace has its own settings (e.g. `enable-index`), but settings that apply to the
+    /// connection rather than to the query (e.g. `deny`, `ban` or the write rates) are only read
+    /// at the top level. Domains are compared case-insensitively.
+    #[serde(default)]
+    pub domains: HashMap<String, Users>,
+}
+
+impl Users {
+    /// Parse a config file with the users of its backends, check it against its [Limits] and load
+    /// its scripts
+    pub fn parse(toml: &str) -> Result<Self, toml::de::Error> {
+        let mut users = toml::from_str::<Self>(toml)?;
+        users.load_backends().map_err(toml::de::Error::custom)?;
+        users.inherit_snippets(&HashMap::new());
+        users.check_snippets().map_err(toml::de::Error::custom)?;
+        users.intern();
+        users
+            .check_limits(&users.limits)
+            .map_err(toml::de::Error::custom)?;
+
+        #[cfg(feature = "scripting")]
+        if !users.scripts.is_empty() {
+            let hooks = Hooks::load(&users.scripts).map_err(toml::de::Error::custom)?;
+            users.set_hooks(Arc::new(hooks));
+        }
+
+        Ok(users)
+    }
+
+    /// Set the [Users::hooks] of these users and of every namespace
+    #[cfg(feature = "scripting")]
+    fn set_hooks(&mut self, hooks: Arc<Hooks>) {
+        for namespace in self.domains.values_mut() {
+            namespace.set_hooks(hooks.clone());
+        }
+        self.hooks = Some(hooks);
+    }
+
+    /// Add the `inherited` snippets to those of these users, and pass them all to every namespace
+    fn inherit_snippets(&mut self, inherited: &HashMap<String, String>) {
+        for (name, snippet) in inherited {
+            if !self.snippets.contains_key(name) {
+                self.snippets.insert(name.clone(), snippet.clone());
+            }
+        }
+        for namespace in self.domains.values_mut() {
+            namespace.inherit_snippets(&self.snippets);
+        }
+    }
+
+    /// Line giving the [Users::generation] of these users and when they were loaded
+    pub fn render_generation(&self) -> String {
+        let loaded_at = match self.loaded_at {
+            Some(time) => humantime::format_rfc3339_seconds(time).to_string(),
+            None => "never".to_owned(),
+        };
+        format!(
+            "Config generation: {} (loaded {loaded_at})\r\n",
+            self.generation
+        )
+    }
+
+    /// Mark these users as the snapshot number `generation` of the config, loaded at `loaded_at`
+    pub fn set_generation(&mut self, generation: u64, loaded_at: SystemTime) {
+        self.generation = generation;
+        self.loaded_at = Some(loaded_at);
+        self.insert_generation_snippet(&self.render_generation());
+    }
+
+    /// Set the [snippet::GENERATION] snippet of these users and of every namespace to `line`
+    fn insert_generation_snippet(&mut self, line: &str) {
+        (self.snippets).insert(snippet::GENERATION.to_owned(), line.to_owned());
+        for namespace in self.domains.values_mut() {
+            namespace.insert_generation_snippet(line);
+        }
+    }
+
+    /// Check that the info texts of these users and of every namespace only include known snippets
+    fn check_snippets(&self) -> Result<(), String> {
+        for (name, user) in &self.users {
+            let mut texts = vec![user.info(), user.long_info()];
+            texts.extend(user.schedule.iter().map(|scheduled| scheduled.user.info()));
+            texts.extend(
+                user.schedule
+                    .iter()
+                    .map(|scheduled| scheduled.user.long_info()),
+            );
+            for snippet in texts.into_iter().flat_map(crate::snippet::includes) {
+                if !self.snippets.contains_key(snippet) && snippet != snippet::GENERATION {
+                    return Err(format!("user {name:?}: unknown snippet {snippet:?}"));
+                }
+            }
+        }
+
+        for (domain, namespace) in &self.domains {
+            (namespace.check_snippets()).map_err(|err| format!("domain {domain:?}: {err}"))?;
+        }
+
+        Ok(())
+    }
+
+    /// Add the users of the [Users::backend] of these users and of every namespace
+    fn load_backends(&mut self) -> Result<(), String> {
+        if let Some(backend) = &self.backend {
+            let source = crate::backend::open(backend, self.backend_options.clone())
+                .map_err(|err| format!("backend {backend:?}: {err}"))?;
+            for (name, mut user) in source.users()? {
+                if self.users.contains_key(&name) {
+                    continue;
+                }
+                user.fix_crlf();
+                user.sign(&name);
+                user.prerender();
+                self.users.insert(name, user);
+            }
+        }
+
+        for (domain, namespace) in &mut self.domains {
+            (namespace.load_backends()).map_err(|err| format!("domain {domain:?}: {err}"))?;
+        }
+
+        Ok(())
+    }
+
+    /// Copy of these users and of every namespace with their secrets redacted, to be shown: the
+    /// decrypted info texts, the internal sections and contact fields, and the backend options
+    /// (which may hold credentials)
+    #[cfg(all(unix, feature = "unix-socket"))]
+    pub fn redacted(&self) -> Users {
+        let mut users = self.clone();
+        users.redact();
+        users
+    }
+
+    #[cfg(all(unix, feature = "unix-socket"))]
+    fn redact(&mut self) {
+        for (_, value) in self.backend_options.iter_mut() {
+            *value = toml::Value::from(REDACTED);
+        }
+        for snippet in self.snippets.values_mut() {
+            *snippet = redact::redact_internal(snippet, REDACTED);
+        }
+        for user in self.users.values_mut() {
+            user.redact();
+        }
+        for namespace in self.domains.values_mut() {
+            namespace.redact();
+        }
+    }
+
+    /// Check these users and those of every namespace against `limits`
+    pub fn check_limits(&self, limits: &Limits) -> Result<(), String> {
+        fn check(size: usize, max: usize, what: impl FnOnce() -> String) -> Result<(), String> {
+            match max {
+                0 => Ok(()),
+                max if size > max => Err(format!("{} is {size}, more than {max}", what())),
+                _ => Ok(()),
+            }
+        }
+
+        check(self.users.len(), limits.max_users, || {
+            "number of users".into()
+        })?;
+        check(self.estimated_size(), limits.max_memory, || {
+            "estimated memory used by the users".into()
+        })?;
+
+        for (name, user) in &self.users {
+            let info_size = user.info.as_ref().map_or(0, |info| info.len());
+            check(info_size, limits.max_info_size, || {
+                format!("size of the info of user {name:?}")
+            })?;
+            let long_info_size = user.long_info.as_ref().map_or(0, |info| info.len());
+            check(long_info_size, limits.max_long_info_size, || {
+                format!("size of the long-info of user {name:?}")
+            })?;
+        }
+
+        for (domain, namespace) in &self.domains {
+            (namespace.check_limits(limits)).map_err(|err| format!("domain {domain:?}: {err}"))?;
+        }
+
+        Ok(())
+    }
+
+    /// Make the equal tags and info texts of these users and of every namespace share their memory
+    pub fn intern(&mut self) {
+        self.intern_with(&mut Interner::default());
+    }
+
+    fn intern_with(&mut self, interner: &mut Interner) {
+        for user in self.users.values_mut() {
+            user.intern(interner);
+        }
+        for namespace in self.domains.values_mut() {
+            namespace.intern_with(interner);
+        }
+    }
+
+    /// Estimated heap memory used by these users and every namespace, in bytes
+    ///
+    /// Only the names, texts, signatures and replies of users are counted, which make up most of
+    /// it for large user sets.
+    pub fn estimated_size(&self) -> usize {
+        let mut estimate = Estimate::default();
+        self.estimate(&mut estimate);
+        estimate.bytes()
+    }
+
+    fn estimate(&self, estimate: &mut Estimate) {
+        for (name, user) in &self.users {
+            estimate.add(size_of::<User>() + name.len());
+            user.estimate(estimate);
+        }
+        for (domain, namespace) in &self.domains {
+            estimate.add(size_of::<Users>() + domain.len());
+            namespace.estimate(estimate);
+        }
+    }
+
+    pub fn find(&self, name: &str) -> Option<&User> {
+        self.users.get(name)
+    }
+
+    /// Listed user with the closest name to `name`, if close enough to be a typo of it
+    pub fn suggestion(&self, name: &str) -> Option<&str> {
+        if !self.suggest_users || !self.enable_index {
+            return None;
+        }
+
+        // Allow one typo in short names, two in longer ones
+        let max_distance = if name.chars().count() < 4 { 1 } else { 2 };
+        (self.users.iter())
+            .filter(|(_, user)| user.is_listed())
+            .map(|(listed, _)| (edit_distance(name, listed), listed.as_str()))
+            .filter(|(distance, _)| (1..=max_distance).contains(distance))
+            .min()
+            .map(|(_, listed)| listed)
+    }
+
+    /// Users to enumerate in listings, in the configured [Users::listing_order]
+    pub fn listing(&self) -> Vec<(&str, &User)> {
+        let mut listing = (self.users.iter())
+            .filter(|(_, user)| user.is_listed())
+            .map(|(name, user)| (name.as_str(), user))
+            .collect::<Vec<_>>();
+
+        match self.listing_order {
+            ListingOrder::Config => {}
+            ListingOrder::Alphabetical => listing.sort_by_key(|(name, _)| *name),
+            ListingOrder::Updated => {
+                // Most recent first, then users without a date in config order
+                listing.sort_by_cached_key(|(_, user)| std::cmp::Reverse(user.updated_at()))
+            }
+        }
+
+        #[cfg(feature = "scripting")]
+        if let Some(hooks) = &self.hooks {
+            let names = listing.iter().map(|(name, _)| *name).collect();
+            if let Some(names) = hooks.on_list(names) {
+                listing = (names.iter())
+                    .filter_map(|name| listing.iter().find(|(listed, _)| listed == name))
+                    .copied()
+                    .collect();
+            }
+        }
+
+        listing
+    }
+
+    /// Page of the listing queried with `username` (see [Users::listing_page_prefix]), from 1
+    pub fn listing_page_number(&self, username: &str) -> Option<usize> {
+        if self.listing_page_size == 0 {
+            return None;
+        }
+
+        let page = username.strip_prefix(&self.listing_page_prefix)?;
+        page.parse().ok().filter(|&page| page > 0)
+    }
+
+    /// Reply to a query for a page of the listing (from 1), ending with a hint if there are more
+    ///
+    /// Verbose listings show the [User::updated] date of each user.
+    pub fn render_listing_page(&self, page: usize, verbose: bool) -> String {
+        let listing = self.listing();
+        let (skip, take) = match self.listing_page_size {
+            0 => (0, listing.len()),
+            size => (size.saturating_mul(page - 1), size),
+        };
+
+        let page_listing = listing.iter().skip(skip).take(take).copied();
+        let mut reply = render_names(page_listing.collect(), verbose);
+
+        let remaining = listing.len().saturating_sub(skip.saturating_add(take));
+        if remaining > 0 {
+            let next = format!("{}{}", self.listing_page_prefix, page + 1);
+            reply.push_str(&format!(
+                "-- {remaining} more user(s), finger {next} for the next page --\r\n"
+            ));
+        }
+
+        reply
+    }
+
+    /// Reply to a query for the listing of the users with `tag`, see [Users::render_listing_page]
+    pub fn render_tag_listing(&self, tag: &str, verbose: bool) -> String {
+        let listing = (self.listing().into_iter())
+            .filter(|(_, user)| user.tags.iter().any(|user_tag| **user_tag == *tag))
+            .collect();
+        render_names(listing, verbose)
+    }
+
+    /// Translation picked by `pick` of a message sent to clients of `listener`, if there's one in
+    /// their [Users::language]
+    pub fn message(&self, listener: &str, pick: fn(&Messages) -> &Option<String>) -> Option<&str> {
+        let messages = self.messages.get(self.language.get(listener)?)?;
+        pick(messages).as_deref()
+    }
+
+    /// Reply to a query for the [Users::help_target], listing the queries that this config answers
+    ///
+    /// `index` tells whether the client gets the listing when querying no user, and `verbose` is
+    /// the policy of the listener it's connected to.
+    pub fn render_help(&self, index: bool, verbose: VerbosePolicy) -> String {
+        let host = self.hostnames.first().map_or("<host>", String::as_str);
+        let mut queries = vec![(format!("finger <user>@{host}"), "info of a user".to_owned())];
+
+        match verbose {
+            VerbosePolicy::Allow => queries.push((
+                format!("finger -l <user>@{host}"),
+                "long info of a user".to_owned(),
+            )),
+            VerbosePolicy::Always => queries[0].1.push_str(" (always the long info)"),
+            VerbosePolicy::Never => {}
+        }
+        if index {
+            queries.push((format!("finger @{host}"), "list of users".to_owned()));
+        }
+        if self.enable_index && self.listing_page_size > 0 {
+            queries.push((
+                format!("finger {}<n>@{host}", self.listing_page_prefix),
+                format!(
+                    "page <n> of the list, {} users per page",
+                    self.listing_page_size
+                ),
+            ));
+        }
+        if self.multi_user_queries {
+            queries.push((
+                format!("finger '<user> <user>...'@{host}"),
+                "info of several users".to_owned(),
+            ));
+        }
+        if let Some(prefix) = self
+            .tag_listing_prefix
+            .as_ref()
+            .filter(|_| self.enable_index)
+        {
+            queries.push((
+                format!("finger {prefix}<tag>@{host}"),
+                "list of users tagged <tag>".to_owned(),
+            ));
+        }
+        if !self.domains.is_empty() {
+            let mut domains = self.domains.keys().map(String::as_str).collect::<Vec<_>>();
+            domains.sort_unstable();
+            queries.push((
+                format!("finger <user>@<domain>@{host}"),
+                format!("info of a user of <domain>: {}", domains.join(", ")),
+            ));
+        }
+        if let Some(stats_target) = &self.stats_target {
+            queries.push((
+                format!("finger {stats_target}@{host}"),
+                "server statistics".to_owned(),
+            ));
+        }
+
+        let width = queries
+            .iter()
+            .map(|(query, _)| query.len())
+            .max()
+            .unwrap_or(0);
+        let mut reply = String::from("Queries answered by this server:\r\n");
+        for (query, description) in queries {
+            reply.push_str(&format!("  {query:width$}  {description}\r\n"));
+        }
+
+        if let Charset::Ascii = self.legacy_charset {
+            reply
+                .push_str("Replies are transliterated to ASCII, unless queries start with /U:\r\n");
+            reply.push_str(&format!("  finger '/U <user>'@{host}\r\n"));
+        }
+
+        if let Some(public_key) = crate::signing::public_key() {
+            reply.push_str("Replies are signed with this minisign public key:\r\n");
+            reply.push_str(&format!("  {public_key}\r\n"));
+        }
+
+        reply
+    }
+
+    /// Fortune files of the users (see [User::fortune]) of every namespace
+    pub fn fortune_files(&self) -> Vec<PathBuf> {
+        fn user_files(user: &User) -> Vec<PathBuf> {
+            let scheduled = user.schedule.iter().flat_map(|s| user_files(&s.user));
+            user.fortune.iter().cloned().chain(scheduled).collect()
+        }
+
+        let users = self.users.values().flat_map(user_files);
+        let namespaces = self.domains.values().flat_map(Users::fortune_files);
+        users.chain(namespaces).collect()
+    }
+
+    /// Whether any user, in any namespace, is relayed to an upstream server
+    pub fn has_upstreams(&self) -> bool {
+        self.users.values().any(|user| user.proxy_to.is_some())
+            || self.domains.values().any(Users::has_upstreams)
+    }
+
+    /// Find the namespace of [Users::domains] named `domain`
+    pub fn namespace(&self, domain: &str) -> Option<&Users> {
+        let domain = domain.strip_suffix('.').unwrap_or(domain);
+        self.domains
+            .iter()
+            .find(|(name, _)| {
+                name.strip_suffix('.')
+                    .unwrap_or(name)
+                    .eq_ignore_ascii_case(domain)
+            })
+            .map(|(_, namespace)| namespace)
+    }
+
+    /// Tag whose users are listed when querying `username`, see [Users::tag_listing_prefix]
+    pub fn tag_listing<'a>(&self, username: &'a str) -> Option<&'a str> {
+        username.strip_prefix(self.tag_listing_prefix.as_deref()?)
+    }
+
+    /// Whether a client at `ip` is in one of the [Users::internal_networks]
+    pub fn is_internal(&self, ip: Option<IpAddr>) -> bool {
+        ip.is_some_and(|ip| self.internal_networks.iter().any(|net| net.contains(&ip)))
+    }
+
+    /// Whether `host` is one of the configured [Users::hostnames]
+    pub fn is_local_host(&self, host: &str) -> bool {
+        let host = host.strip_suffix('.').unwrap_or(host);
+        self.hostnames.iter().any(|hostname| {
+            let hostname = hostname.strip_suffix('.').unwrap_or(hostname);
+            hostname.eq_ignore_ascii_case(host)
+        })
+    }
 }
 
-impl Config {
-    pub fn new(users: Users) -> Self {
+/// Caps protecting against configs too large for the machine, 0 meaning unlimited (default)
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Limits {
+    /// Max number of users (of a single namespace)
+    pub max_users: usize,
+
+    /// Max size of [User::info] in bytes, once decrypted and with line endings fixed
+    pub max_info_size: usize,
+
+    /// Max size of [User::long_info] in bytes, once decrypted and with line endings fixed
+    pub max_long_info_size: usize,
+
+    /// Max memory used by the users and every namespace in bytes, as estimated by
+    /// [Users::estimated_size]
+    pub max_memory: usize,
+
+    /// Max number of users named by a single request (see [Users::multi_user_queries])
+    ///
+    /// Unlike the other limits, it's checked for each request, which is malformed past it.
+    pub max_users_per_request: usize,
+}
+
+/// Extension of the protocol for clients sending many queries, e.g. internal tooling
+///
+/// A request with the "/P" flag (see [Request::persistent](crate::request::Request::persistent))
+/// gets its reply followed by a `.` line, the dots starting its lines doubled, and the connection
+/// stays open for another request. The [Users::request_timeout] still bounds the whole connection.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct PersistentConnections {
+    /// Max number of requests answered on a connection (0 by default: the "/P" flag is ignored)
+    pub max_requests: usize,
+
+    /// Seconds waited for the next request before closing the connection (default: 5)
+    pub idle_timeout: u64,
+}
+
+impl Default for PersistentConnections {
+    fn default() -> Self {
         Self {
-            lock: RwLock::new(Arc::new(users)),
+            max_requests: 0,
+            idle_timeout: 5,
         }
     }
+}
+
+#[derive(Clone, Copy, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ListingOrder {
+    /// Order of the config file, users of a dynamic store coming last (default)
+    #[default]
+    Config,
+
+    /// Alphabetical order of usernames
+    Alphabetical,
+
+    /// Most recently updated first (see [User::updated])
+    Updated,
+}
+
+/// Whether verbose (`/W`) replies are sent on each kind of listener
+///
+/// The kind of a listener is the `listener` field of a [crate::context::RequestContext].
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VerbosePolicies {
+    /// Finger listener bound to a TCP address
+    pub tcp: VerbosePolicy,
+
+    /// Finger listener bound to a Unix socket
+    pub unix: VerbosePolicy,
+
+    /// Finger client served in inetd mode
+    pub inetd: VerbosePolicy,
 
-    pub fn new_parsed(toml: &str) -> Result<Self, toml::de::Error> {
-        Ok(Self::new(toml::from_str(toml)?))
+    /// WHOIS listener, whose replies are verbose unless forbidden
+    pub whois: VerbosePolicy,
+}
+
+impl VerbosePolicies {
+    /// Policy of the kind of listener `listener`
+    pub fn get(&self, listener: &str) -> VerbosePolicy {
+        match listener {
+            "tcp" => self.tcp,
+            "unix" => self.unix,
+            "inetd" => self.inetd,
+            "whois" => self.whois,
+            _ => VerbosePolicy::Allow,
+        }
     }
 
-    pub async fn get(&self) -> Arc<Users> {
-        self.lock.read().await.clone()
+    /// Whether the reply to a query received by `listener` is verbose, given what it asked for
+    pub fn apply(&self, listener: &str, verbose: bool) -> bool {
+        match self.get(listener) {
+            VerbosePolicy::Allow => verbose,
+            VerbosePolicy::Always => true,
+            VerbosePolicy::Never => false,
+        }
     }
+}
 
-    pub async fn set(&self, users: Users) {
-        *self.lock.write().await = Arc::new(users);
+#[derive(Clone, Copy, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum VerbosePolicy {
+    /// Verbose replies to queries asking for them (default)
+    #[default]
+    Allow,
+
+    /// Verbose replies to every query
+    Always,
+
+    /// Short replies to every query, e.g. to keep long info off a public listener
+    Never,
+}
+
+/// How requests are read on each kind of listener, see [Users::requests]
+///
+/// Each setting of a listener that isn't set falls back to the one of `default`.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RequestReadings {
+    pub default: RequestReading,
+    pub tcp: RequestReading,
+    pub unix: RequestReading,
+    pub inetd: RequestReading,
+    pub whois: RequestReading,
+}
+
+impl RequestReadings {
+    fn get(&self, listener: &str) -> Option<&RequestReading> {
+        match listener {
+            "tcp" => Some(&self.tcp),
+            "unix" => Some(&self.unix),
+            "inetd" => Some(&self.inetd),
+            "whois" => Some(&self.whois),
+            _ => None,
+        }
     }
 
-    pub async fn load(&self, toml: &str) -> Result<(), toml::de::Error> {
-        self.set(toml::from_str(toml)?).await;
-        Ok(())
+    /// Max length in bytes of the requests received by `listener`
+    pub fn max_length(&self, listener: &str) -> u64 {
+        (self.get(listener).and_then(|reading| reading.max_length))
+            .or(self.default.max_length)
+            .map_or(crate::SANE_REQUEST_LENGTH, NonZeroU64::get)
+    }
+
+    /// Capacity in bytes of the buffer the requests received by `listener` are read through
+    pub fn buffer_size(&self, listener: &str) -> usize {
+        (self.get(listener).and_then(|reading| reading.buffer_size))
+            .or(self.default.buffer_size)
+            .map_or(crate::READ_BUFFER_SIZE, NonZeroUsize::get)
     }
 }
 
-impl From<Users> for Config {
-    fn from(value: Users) -> Self {
-        Self {
-            lock: RwLock::new(Arc::new(value)),
+#[derive(Clone, Copy, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RequestReading {
+    /// Max length of a request line in bytes, past which it's refused (1024 by default)
+    ///
+    /// Longer requests may be needed by extensions, like long chains of hosts to relay to.
+    pub max_length: Option<NonZeroU64>,
+
+    /// Capacity in bytes of the buffer requests are read through (8 KiB by default)
+    ///
+    /// Smaller buffers save memory on constrained machines, at the cost of more reads for long
+    /// requests. On `seqpacket` listeners, packets longer than the buffer are cut short.
+    pub buffer_size: Option<NonZeroUsize>,
+}
+
+/// Language of the messages of the server for each kind of listener, see [Users::language]
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Languages {
+    /// Language of the listeners that don't have their own
+    pub default: Option<String>,
+
+    pub tcp: Option<String>,
+    pub unix: Option<String>,
+    pub inetd: Option<String>,
+    pub whois: Option<String>,
+}
+
+impl Languages {
+    /// Language of the messages sent on the kind of listener `listener`
+    pub fn get(&self, listener: &str) -> Option<&str> {
+        let language = match listener {
+            "tcp" => &self.tcp,
+            "unix" => &self.unix,
+            "inetd" => &self.inetd,
+            "whois" => &self.whois,
+            _ => &None,
+        };
+        language
+            .as_ref()
+            .or(self.default.as_ref())
+            .map(String::as_str)
+    }
+}
+
+/// Translations of the messages of the server in a language, each replacing the English one if set
+///
+/// Their line endings are fixed like those of [User::info].
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Messages {
+    /// `User not found`
+    #[serde(deserialize_with = "deserialize_crlf_string")]
+    pub user_not_found: Option<String>,
+
+    /// `Did you mean "{name}"?`, where `{name}` is replaced by the suggested user
+    #[serde(deserialize_with = "deserialize_crlf_string")]
+    pub did_you_mean: Option<String>,
+
+    /// `Finger forwarding service denied`, or [Users::forwarding_reply], with the same variables
+    #[serde(deserialize_with = "deserialize_crlf_string")]
+    pub no_forwarding: Option<String>,
+
+    /// `Finger online user list denied`
+    #[serde(deserialize_with = "deserialize_crlf_string")]
+    pub no_listing: Option<String>,
+
+    /// `Malformed finger query`, or [Users::malformed_reply]
+    #[serde(deserialize_with = "deserialize_crlf_string")]
+    pub malformed: Option<String>,
+
+    /// `Request too long`
+    #[serde(deserialize_with = "deserialize_crlf_string")]
+    pub too_long: Option<String>,
+
+    /// `No newline received`
+    #[serde(deserialize_with = "deserialize_crlf_string")]
+    pub no_newline: Option<String>,
+
+    /// `Upstream finger server unreachable`
+    #[serde(deserialize_with = "deserialize_crlf_string")]
+    pub upstream_failed: Option<String>,
+
+    /// `Down for maintenance, please try again later`, or [Users::maintenance_reply]
+    #[serde(deserialize_with = "deserialize_crlf_string")]
+    pub maintenance: Option<String>,
+
+    /// `% No entries found`, sent by the WHOIS listener
+    #[serde(deserialize_with = "deserialize_crlf_string")]
+    pub no_entries: Option<String>,
+}
+
+/// Reply to queries without a username for each family of client addresses
+#[derive(Clone, Copy, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct DefaultReplies {
+    /// Clients connected over IPv4, including IPv4-mapped IPv6 addresses
+    pub ipv4: DefaultReply,
+
+    /// Clients connected over IPv6
+    pub ipv6: DefaultReply,
+
+    /// Clients without an IP address: Unix socket, inetd and replayed clients
+    pub local: DefaultReply,
+}
+
+impl DefaultReplies {
+    /// Reply to a query without a username from a client of `family` (see
+    /// [crate::context::RequestContext::family])
+    pub fn get(&self, family: &str) -> DefaultReply {
+        match family {
+            "ipv4" => self.ipv4,
+            "ipv6" => self.ipv6,
+            "local" => self.local,
+            _ => DefaultReply::Index,
         }
     }
 }
 
-#[derive(Clone, Debug, Default, serde::Deserialize)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
 #[serde(rename_all = "kebab-case")]
-pub struct Users {
-    /// If true (default), the server allows enumerating users
-    ///
-    /// When this property is true, users can be individually unlisted with [User::unlisted].
-    #[serde(default = "value::r#true")]
-    pub enable_index: bool,
+pub enum DefaultReply {
+    /// The MOTD followed by the first page of the listing, if [Users::enable_index] (default)
+    #[default]
+    Index,
 
-    #[serde(deserialize_with = "deserialize_users")]
-    pub users: HashMap<String, User>,
+    /// Only the MOTD, or a denial if there's none
+    Motd,
+
+    /// A denial, as if the listing was disabled
+    Deny,
 }
 
-impl Users {
-    pub fn find(&self, name: &str) -> Option<&User> {
-        self.users.get(name)
+/// Partial update of [Users], in the same syntax as the config file
+#[cfg(all(unix, feature = "unix-socket"))]
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConfigPatch {
+    /// Users to add, replacing existing users of the same name
+    #[serde(default, deserialize_with = "deserialize_users")]
+    pub users: IndexMap<String, User>,
+
+    /// Names of users to delete
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+#[cfg(all(unix, feature = "unix-socket"))]
+impl ConfigPatch {
+    pub fn apply(self, users: &mut Users) {
+        for name in &self.remove {
+            users.users.shift_remove(name);
+        }
+
+        users.users.extend(self.users);
     }
 }
 
-#[derive(Clone, Debug, serde::Deserialize)]
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct User {
     /// Automatically convert newlines in `info` and `long_info` to CRLF, and add an ending CRLF if one is missing
@@ -68,24 +1406,284 @@ pub struct User {
     pub fix_crlf: bool,
 
     /// Plain text returned when querying this user
-    pub info: Option<String>,
+    pub info: Option<Arc<str>>,
 
     /// Plain text returned when querying this user in verbose mode
-    pub long_info: Option<String>,
+    pub long_info: Option<Arc<str>>,
 
     /// If true, this user won't be enumerated when a listing is requested
     #[serde(default)]
     pub unlisted: bool,
+
+    /// If true, this user is never enumerated and looks nonexistent to clients outside of
+    /// [Users::internal_networks]
+    #[serde(default)]
+    pub hidden: bool,
+
+    /// Categories of this user, used to list only some users (see [Users::tag_listing_prefix])
+    #[serde(default)]
+    pub tags: Vec<Arc<str>>,
+
+    /// Contact details sent after the info texts, each to the queries allowed by its privacy level
+    /// (see [crate::contact])
+    pub contact: Option<Contact>,
+
+    /// Detached signature (e.g. PGP armor) sent after [User::info], instead of a generated one
+    ///
+    /// See [crate::signing] for generated signatures. Its line endings are fixed like those of
+    /// [User::info].
+    pub signature: Option<String>,
+
+    /// Detached signature sent after [User::long_info], instead of a generated one
+    pub long_signature: Option<String>,
+
+    /// Lines sent before the info texts of this user, e.g. `--- Plan of {name} ---`
+    ///
+    /// `{name}` is replaced by the name of the user, and `{updated}` by its [User::updated] date
+    /// or the modification time of the config (`unknown` if neither is known). Its line endings
+    /// are fixed like those of [User::info].
+    pub prefix: Option<String>,
+
+    /// Lines sent after the info texts and signature of this user, with the variables of
+    /// [User::prefix], e.g. `--- plan last edited {updated} ---`
+    pub suffix: Option<String>,
+
+    /// Local user (by uid) allowed to replace the info texts of this user through the user socket
+    /// (see [crate::push]) and its plan file (see [crate::plans])
+    ///
+    /// Each uid should own a single user. Ignored in [User::schedule] entries.
+    pub uid: Option<u32>,
+
+    /// Date (and optionally time) of the last change of this user's info, e.g. `2024-03-01` or
+    /// `2024-05-01T12:00:00Z`
+    ///
+    /// It's shown in verbose listings, and used by [ListingOrder::Updated] and
+    /// [Users::last_modified_header]. Times without an offset are taken as UTC.
+    pub updated: Option<toml::value::Datetime>,
+
+    /// Limits on the verbose queries for this user, if its long info is expensive to render (see
+    /// [crate::expensive])
+    pub expensive_long_info: Option<ExpensiveLongInfo>,
+
+    /// Finger server (`host` or `host:port`) to relay queries for this user to
+    ///
+    /// When set, `info` and `long_info` are ignored and the upstream server's reply is sent as-is.
+    pub proxy_to: Option<String>,
+
+    /// Variants of this user used at some times of the week instead (see [crate::schedule])
+    ///
+    /// The first entry matching the time of the query is used. Only their settings that affect
+    /// replies (info texts, contact, signatures, prefix and suffix, `updated` and `proxy-to`) are
+    /// read.
+    #[serde(default)]
+    pub schedule: Vec<Scheduled>,
+
+    /// Fortune file whose entries are sent instead of the info texts (see [crate::fortune])
+    ///
+    /// The file is read each time the config is loaded. Its entries are fixed and signed like
+    /// [User::info].
+    pub fortune: Option<PathBuf>,
+
+    /// Whether fortunes are picked at random (`random`, default) or in order (`sequential`)
+    #[serde(default)]
+    pub fortune_order: FortuneOrder,
+
+    /// Entries of [User::fortune], once read
+    #[serde(skip)]
+    pub fortunes: Option<Arc<Fortunes>>,
+
+    /// Whether some info texts were encrypted in the config, so that they're kept out of dumps
+    #[serde(skip)]
+    pub encrypted: bool,
+
+    /// Replies rendered once the user is loaded, if they're the same for every query
+    #[serde(skip)]
+    pub prerendered: Option<Prerendered>,
+}
+
+/// Info texts pushed by the local user owning a user, see [Config::push]
+#[derive(Clone, Debug)]
+pub struct Pushed {
+    /// Local user who pushed the texts, which must still own the user for them to be used
+    uid: u32,
+
+    pub info: Option<Arc<str>>,
+    pub long_info: Option<Arc<str>>,
+}
+
+impl Pushed {
+    fn apply(&self, name: &str, user: &mut User) {
+        user.replace_texts(name, self.info.as_ref(), self.long_info.as_ref());
+    }
+}
+
+/// Long info read from a plan file, see [crate::plans]
+#[derive(Clone, Debug)]
+pub struct Plan {
+    /// Local user owning the file, which must own the user for it to be used, or `None` if the
+    /// file is owned by the owner of the directory
+    owner: Option<u32>,
+
+    long_info: Arc<str>,
+}
+
+impl Plan {
+    pub fn new(owner: Option<u32>, long_info: String) -> Self {
+        Self {
+            owner,
+            long_info: long_info.into(),
+        }
+    }
+
+    fn apply(&self, name: &str, user: &mut User) {
+        user.replace_texts(name, None, Some(&self.long_info));
+    }
+
+    /// Check the long info of the user `name` of `users` against their [Limits], once replaced by
+    /// this plan
+    pub fn check_limits(&self, name: &str, users: &Users) -> Result<(), String> {
+        let Some(mut user) = users.users.get(name).cloned() else {
+            return Ok(());
+        };
+        self.apply(name, &mut user);
+        let size = user
+            .long_info
+            .as_ref()
+            .map_or(0, |long_info| long_info.len());
+        match users.limits.max_long_info_size {
+            0 => Ok(()),
+            max if size > max => Err(format!("size of the long-info is {size}, more than {max}")),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Replies of a user whose info texts are sent as they are, with their signature, see
+/// [User::prerender]
+#[derive(Clone, Debug)]
+pub struct Prerendered {
+    info: Arc<[u8]>,
+    long_info: Arc<[u8]>,
+}
+
+impl Prerendered {
+    /// Reply to a query, verbose or not
+    pub fn get(&self, verbose: bool) -> &[u8] {
+        match verbose {
+            false => &self.info,
+            true => &self.long_info,
+        }
+    }
 }
 
 impl User {
     pub fn from_info(info: String) -> Self {
         Self {
             fix_crlf: true,
-            info: Some(info),
+            info: Some(info.into()),
             long_info: None,
             unlisted: false,
+            hidden: false,
+            tags: Vec::new(),
+            contact: None,
+            signature: None,
+            long_signature: None,
+            prefix: None,
+            suffix: None,
+            uid: None,
+            updated: None,
+            expensive_long_info: None,
+            proxy_to: None,
+            schedule: Vec::new(),
+            fortune: None,
+            fortune_order: FortuneOrder::default(),
+            fortunes: None,
+            encrypted: false,
+            prerendered: None,
+        }
+    }
+
+    /// Replace the info texts of this user (named `name`) that are given, fixing and signing them
+    /// like those of the config
+    fn replace_texts(&mut self, name: &str, info: Option<&Arc<str>>, long_info: Option<&Arc<str>>) {
+        let fix = |text: &Arc<str>| match self.fix_crlf {
+            true => {
+                let mut text = String::from(&**text);
+                fix_string_crlf(&mut text);
+                Arc::from(text)
+            }
+            false => Arc::clone(text),
+        };
+
+        // Signatures of the replaced texts are generated again
+        if let Some(info) = info {
+            self.info = Some(fix(info));
+            self.signature = None;
+            if self.long_info.is_none() {
+                self.long_signature = None;
+            }
+        }
+        if let Some(long_info) = long_info {
+            self.long_info = Some(fix(long_info));
+            self.long_signature = None;
+        }
+        self.sign(name);
+        self.prerender();
+    }
+
+    /// Text to reply to a query for this user (named `name`) with, and its signature
+    ///
+    /// This is the info or long info depending on whether the query is verbose, unless this user
+    /// has fortunes, with its included `snippets` expanded (see [crate::snippet]), the sections
+    /// that `audience` can't see removed (see [crate::redact]) and the [User::contact] fields it can
+    /// see appended. Texts with includes, sections or contact fields are signed for each query.
+    pub fn reply(
+        &self,
+        name: &str,
+        snippets: &HashMap<String, String>,
+        audience: Audience,
+    ) -> (Cow<'_, str>, Option<Cow<'_, str>>) {
+        let (text, signature) = match self.fortunes.as_ref().and_then(|fortunes| fortunes.pick()) {
+            Some(fortune) => (fortune.text.as_str(), fortune.signature.as_deref()),
+            None => match audience.verbose {
+                false => (self.info(), self.signature(false)),
+                true => (self.long_info(), self.signature(true)),
+            },
+        };
+
+        let contact = (self.contact.as_ref())
+            .map(|contact| contact.render(audience))
+            .filter(|contact| !contact.is_empty());
+
+        let text = match snippet::has_includes(text) {
+            true => Cow::from(snippet::expand(text, snippets)),
+            false if !redact::has_sections(text) && contact.is_none() => {
+                return (text.into(), signature.map(Cow::from));
+            }
+            false => Cow::from(text),
+        };
+
+        let mut text = redact::render(&text, audience);
+        if let Some(contact) = contact {
+            if !text.is_empty() && !text.ends_with('\n') {
+                text.push_str("\r\n");
+            }
+            text.push_str(&contact);
         }
+        let signature = crate::signing::sign(&text, name).map(Cow::from);
+        (text.into(), signature)
+    }
+
+    /// Whether this user is enumerated in listings, see [User::unlisted] and [User::hidden]
+    pub fn is_listed(&self) -> bool {
+        !self.unlisted && !self.hidden
+    }
+
+    /// This user as it's shown at `now`: its first matching [User::schedule] entry, or itself
+    pub fn at(&self, now: LocalTime) -> &User {
+        (self.schedule.iter())
+            .find(|scheduled| scheduled.matches(now))
+            .map_or(self, |scheduled| &scheduled.user)
     }
 
     pub fn info(&self) -> &str {
@@ -122,44 +1720,358 @@ impl User {
         }
     }
 
-    /// Try to replace single LF with CRLF, and add a final CRLF, for each info text
+    /// Parse a single user in the long config syntax, fixing it like the ones of `users.toml`
+    #[cfg(feature = "kv-store")]
+    pub fn from_toml(name: &str, toml: &str) -> Result<Self, String> {
+        let mut user = toml::from_str::<Self>(toml).map_err(|err| err.message().to_owned())?;
+        user.decrypt().map_err(|err| err.to_string())?;
+        user.fix_crlf();
+        user.sign(name);
+        user.prerender();
+        user.load_fortunes(name)?;
+        Ok(user)
+    }
+
+    /// [User::updated] as seconds since the Unix epoch, to compare dates with different offsets
+    pub fn updated_at(&self) -> Option<i64> {
+        let updated = self.updated.as_ref()?;
+        let date = updated.date?;
+        let time = updated.time.map_or(0, |time| {
+            i64::from(time.hour) * 3600 + i64::from(time.minute) * 60 + i64::from(time.second)
+        });
+        let offset = match updated.offset {
+            Some(toml::value::Offset::Custom { minutes }) => i64::from(minutes) * 60,
+            Some(toml::value::Offset::Z) | None => 0,
+        };
+
+        let days = days_since_epoch(i64::from(date.year), date.month.into(), date.day.into());
+        Some(days * 86400 + time - offset)
+    }
+
+    /// `Last-Modified:` line sent before the verbose info (see [Users::last_modified_header])
+    ///
+    /// `config_modified` is used when this user has no [User::updated] date.
+    pub fn last_modified_header(&self, config_modified: Option<SystemTime>) -> Option<String> {
+        let modified = self.modified(config_modified)?;
+        Some(format!("Last-Modified: {modified}\r\n"))
+    }
+
+    /// [User::updated] date, or `config_modified` if this user has none
+    pub fn modified(&self, config_modified: Option<SystemTime>) -> Option<String> {
+        match (&self.updated, config_modified) {
+            (Some(updated), _) => Some(updated.to_string()),
+            (None, Some(modified)) => Some(humantime::format_rfc3339_seconds(modified).to_string()),
+            (None, None) => None,
+        }
+    }
+
+    /// [User::prefix] and [User::suffix] of this user (named `name`), with their variables replaced
+    pub fn frame(
+        &self,
+        name: &str,
+        config_modified: Option<SystemTime>,
+    ) -> (Option<String>, Option<String>) {
+        let fill = |template: &String| {
+            let mut line = template.replace("{name}", name);
+            if line.contains("{updated}") {
+                let modified = self.modified(config_modified);
+                line = line.replace("{updated}", modified.as_deref().unwrap_or("unknown"));
+            }
+            line
+        };
+        (
+            self.prefix.as_ref().map(fill),
+            self.suffix.as_ref().map(fill),
+        )
+    }
+
+    /// Signature sent after [User::info] or [User::long_info], if any
+    pub fn signature(&self, verbose: bool) -> Option<&str> {
+        match verbose {
+            false => self.signature.as_deref(),
+            true => self.long_signature.as_deref(),
+        }
+    }
+
+    /// Sign the info texts with the daemon's signing key, unless signatures were provided
+    pub fn sign(&mut self, name: &str) {
+        if self.signature.is_none() {
+            self.signature = crate::signing::sign(self.info(), name);
+        }
+        if self.long_signature.is_none() {
+            self.long_signature = crate::signing::sign(self.long_info(), name);
+        }
+        for scheduled in &mut self.schedule {
+            scheduled.user.sign(name);
+        }
+    }
+
+    /// Render the replies of this user and of its [User::schedule] entries once and for all, if
+    /// [User::reply] would always give the same ones
+    ///
+    /// It must be called once the info texts are fixed and signed.
+    pub fn prerender(&mut self) {
+        let is_static = |text: &str| !snippet::has_includes(text) && !redact::has_sections(text);
+        let prerendered = self.fortune.is_none()
+            && self.proxy_to.is_none()
+            && self.contact.is_none()
+            && self.prefix.is_none()
+            && self.suffix.is_none()
+            && is_static(self.info())
+            && is_static(self.long_info());
+
+        self.prerendered = prerendered.then(|| {
+            let render = |text: &str, signature: Option<&str>| {
+                let mut reply = text.as_bytes().to_vec();
+                reply.extend_from_slice(signature.unwrap_or_default().as_bytes());
+                Arc::from(reply)
+            };
+            Prerendered {
+                info: render(self.info(), self.signature(false)),
+                long_info: render(self.long_info(), self.signature(true)),
+            }
+        });
+
+        for scheduled in &mut self.schedule {
+            scheduled.user.prerender();
+        }
+    }
+
+    fn intern(&mut self, interner: &mut Interner) {
+        for text in [&mut self.info, &mut self.long_info].into_iter().flatten() {
+            interner.intern(text);
+        }
+        for tag in &mut self.tags {
+            interner.intern(tag);
+        }
+        for scheduled in &mut self.schedule {
+            scheduled.user.intern(interner);
+        }
+    }
+
+    fn estimate(&self, estimate: &mut Estimate) {
+        for text in [&self.info, &self.long_info].into_iter().flatten() {
+            estimate.add_shared(text);
+        }
+        for tag in &self.tags {
+            estimate.add(size_of::<Arc<str>>());
+            estimate.add_shared(tag);
+        }
+        let lines = [
+            &self.signature,
+            &self.long_signature,
+            &self.prefix,
+            &self.suffix,
+        ];
+        for line in lines.into_iter().flatten() {
+            estimate.add(line.len());
+        }
+        if let Some(prerendered) = &self.prerendered {
+            estimate.add_shared(&prerendered.info);
+            estimate.add_shared(&prerendered.long_info);
+        }
+        if let Some(fortunes) = &self.fortunes {
+            if estimate.add_shared(fortunes) {
+                fortunes.estimate(estimate);
+            }
+        }
+        for scheduled in &self.schedule {
+            estimate.add(size_of::<Scheduled>());
+            scheduled.user.estimate(estimate);
+        }
+    }
+
+    /// Read the [User::fortune] file, fixing and signing its entries like the info texts
+    pub fn load_fortunes(&mut self, name: &str) -> Result<(), String> {
+        if let Some(path) = &self.fortune {
+            let content = std::fs::read_to_string(path)
+                .map_err(|err| format!("cannot read {}: {err}", path.display()))?;
+            let entries = (crate::fortune::parse(&content).into_iter())
+                .map(|mut text| {
+                    if self.fix_crlf {
+                        fix_string_crlf(&mut text);
+                    }
+                    let signature = crate::signing::sign(&text, name);
+                    Fortune { text, signature }
+                })
+                .collect();
+            self.fortunes = Some(Arc::new(Fortunes::new(entries, self.fortune_order)));
+        }
+
+        for scheduled in &mut self.schedule {
+            scheduled.user.load_fortunes(name)?;
+        }
+
+        Ok(())
+    }
+
+    /// Decrypt the info texts that are encrypted (see [crate::secret])
+    pub fn decrypt(&mut self) -> Result<(), crate::secret::Error> {
+        for info in [&mut self.info, &mut self.long_info].into_iter().flatten() {
+            if info.starts_with(crate::secret::PREFIX) {
+                self.encrypted = true;
+                let mut decrypted = String::from(&**info);
+                crate::secret::decrypt_in_place(&mut decrypted)?;
+                *info = decrypted.into();
+            }
+        }
+        for scheduled in &mut self.schedule {
+            scheduled.user.decrypt()?;
+        }
+
+        Ok(())
+    }
+
+    /// Replace the info texts with [REDACTED] if they were encrypted, and else their internal
+    /// sections and contact fields
+    #[cfg(all(unix, feature = "unix-socket"))]
+    fn redact(&mut self) {
+        for info in [&mut self.info, &mut self.long_info].into_iter().flatten() {
+            if self.encrypted {
+                *info = Arc::from(REDACTED);
+            } else if redact::has_sections(info) {
+                *info = Arc::from(redact::redact_internal(info, REDACTED));
+            }
+        }
+        if let Some(contact) = &mut self.contact {
+            contact.redact_internal(REDACTED);
+        }
+        for scheduled in &mut self.schedule {
+            scheduled.user.redact();
+        }
+    }
+
+    /// Try to replace single LF with CRLF, and add a final CRLF, for each info text, signature,
+    /// prefix and suffix
     pub fn fix_crlf(&mut self) {
         if self.fix_crlf {
-            if let Some(info) = &mut self.info {
-                fix_string_crlf(info);
+            for info in [&mut self.info, &mut self.long_info].into_iter().flatten() {
+                let mut text = String::from(&**info);
+                fix_string_crlf(&mut text);
+                *info = text.into();
             }
-
-            if let Some(long_info) = &mut self.long_info {
-                fix_string_crlf(long_info);
+            let lines = [
+                &mut self.signature,
+                &mut self.long_signature,
+                &mut self.prefix,
+                &mut self.suffix,
+            ];
+            for line in lines.into_iter().flatten() {
+                fix_string_crlf(line);
             }
         }
+        for scheduled in &mut self.schedule {
+            scheduled.user.fix_crlf();
+        }
     }
 }
 
-fn deserialize_users<'de, D: Deserializer<'de>>(de: D) -> Result<HashMap<String, User>, D::Error> {
+fn deserialize_users<'de, D: Deserializer<'de>>(de: D) -> Result<IndexMap<String, User>, D::Error> {
     #[derive(serde::Deserialize)]
     #[serde(untagged)]
     enum Either {
         String(String),
-        User(User),
+        User(Box<User>),
     }
 
-    HashMap::<String, Either>::deserialize(de).map(|hm| {
+    IndexMap::<String, Either>::deserialize(de).and_then(|hm| {
         hm.into_iter()
             .map(|(key, value)| {
                 let mut user = match value {
                     Either::String(info) => User::from_info(info),
-                    Either::User(user) => user,
+                    Either::User(user) => *user,
                 };
 
+                user.decrypt()
+                    .map_err(|err| D::Error::custom(format!("user {key:?}: {err}")))?;
                 user.fix_crlf();
+                user.sign(&key);
+                user.prerender();
+                user.load_fortunes(&key)
+                    .map_err(|err| D::Error::custom(format!("user {key:?}: {err}")))?;
 
-                (key, user)
+                Ok((key, user))
             })
             .collect()
     })
 }
 
+fn deserialize_crlf_string<'de, D: Deserializer<'de>>(de: D) -> Result<Option<String>, D::Error> {
+    let mut string = String::deserialize(de)?;
+    fix_string_crlf(&mut string);
+    Ok(Some(string))
+}
+
+fn deserialize_snippets<'de, D: Deserializer<'de>>(
+    de: D,
+) -> Result<HashMap<String, String>, D::Error> {
+    let mut snippets = HashMap::<String, String>::deserialize(de)?;
+    snippets.values_mut().for_each(fix_string_crlf);
+    Ok(snippets)
+}
+
+pub(crate) fn serialize_regex_set<S: serde::Serializer>(
+    set: &RegexSet,
+    ser: S,
+) -> Result<S::Ok, S::Error> {
+    ser.collect_seq(set.patterns())
+}
+
+pub(crate) fn deserialize_regex_set<'de, D: Deserializer<'de>>(
+    de: D,
+) -> Result<RegexSet, D::Error> {
+    let patterns = Vec::<String>::deserialize(de)?;
+    RegexSet::new(patterns).map_err(D::Error::custom)
+}
+
+/// One line per user of `listing`, followed by its [User::updated] date if `verbose`
+fn render_names(listing: Vec<(&str, &User)>, verbose: bool) -> String {
+    let width = listing
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0);
+
+    let mut reply = String::new();
+    for (name, user) in listing {
+        match (verbose, &user.updated) {
+            (true, Some(updated)) => reply.push_str(&format!("{name:width$}  {updated}")),
+            _ => reply.push_str(name),
+        }
+        reply.push_str("\r\n");
+    }
+    reply
+}
+
+/// Number of days from 1970-01-01 to a date of the proleptic Gregorian calendar
+fn days_since_epoch(year: i64, month: i64, day: i64) -> i64 {
+    // Years starting in March, so that leap days end them
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Levenshtein distance between `a` and `b`, in characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(a != *b);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
 fn fix_str_crlf(str: &str) -> String {
     str.lines().flat_map(|line| [line, "\r\n"]).collect()
 }
@@ -179,4 +2091,24 @@ mod value {
     pub fn r#true() -> bool {
         true
     }
+
+    pub fn request_timeout() -> u64 {
+        30
+    }
+
+    pub fn line_timeout() -> u64 {
+        10
+    }
+
+    pub fn upstream_cache_ttl() -> u64 {
+        60
+    }
+
+    pub fn upstream_negative_cache_ttl() -> u64 {
+        10
+    }
+
+    pub fn listing_page_prefix() -> String {
+        "page.".into()
+    }
 }
@@ -0,0 +1,113 @@
+//! Structured contact details of a user, sent after its info text
+//!
+//! Each field of [User::contact] is either a plain value, sent to every query, or a table giving
+//! its privacy level, which decides who it's sent to like the sections of [crate::redact]:
+//!
+//! ```toml
+//! [users.alice.contact]
+//! email = "alice@example.com"
+//! xmpp = { value = "alice@example.com", privacy = "verbose" }
+//! phone = { value = "+33 6 12 34 56 78", privacy = "internal" }
+//! ```
+//!
+//! [User::contact]: crate::config::User::contact
+
+use crate::redact::Audience;
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Contact {
+    pub email: Option<Field>,
+    pub xmpp: Option<Field>,
+    pub fediverse: Option<Field>,
+    pub phone: Option<Field>,
+}
+
+impl Contact {
+    /// Lines of the fields that `audience` can see, with CRLF line endings
+    pub fn render(&self, audience: Audience) -> String {
+        let fields = [
+            ("Email:", &self.email),
+            ("XMPP:", &self.xmpp),
+            ("Fediverse:", &self.fediverse),
+            ("Phone:", &self.phone),
+        ];
+
+        let mut rendered = String::new();
+        for (label, field) in fields {
+            let Some(field) = field.as_ref().filter(|field| field.is_visible_to(audience)) else {
+                continue;
+            };
+            rendered.push_str(&format!("{label:10} {}\r\n", field.value()));
+        }
+        rendered
+    }
+
+    /// Replace the values of the fields only sent to internal clients with `replacement`
+    #[cfg(all(unix, feature = "unix-socket"))]
+    pub fn redact_internal(&mut self, replacement: &str) {
+        for field in [
+            &mut self.email,
+            &mut self.xmpp,
+            &mut self.fediverse,
+            &mut self.phone,
+        ] {
+            if let Some(Field::Restricted {
+                value,
+                privacy: Privacy::Internal,
+            }) = field
+            {
+                *value = replacement.to_owned();
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(untagged)]
+pub enum Field {
+    /// Value sent to every query
+    Public(String),
+
+    Restricted {
+        value: String,
+        #[serde(default)]
+        privacy: Privacy,
+    },
+}
+
+impl Field {
+    pub fn value(&self) -> &str {
+        match self {
+            Self::Public(value) | Self::Restricted { value, .. } => value,
+        }
+    }
+
+    fn is_visible_to(&self, audience: Audience) -> bool {
+        match self {
+            Self::Public(_) => true,
+            Self::Restricted { privacy, .. } => match privacy {
+                Privacy::Public => true,
+                Privacy::Verbose => audience.verbose,
+                Privacy::Internal => audience.internal,
+            },
+        }
+    }
+}
+
+/// Queries a [Field] is sent to
+#[derive(Clone, Copy, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Privacy {
+    /// Every query (default)
+    #[default]
+    Public,
+
+    /// Verbose queries
+    Verbose,
+
+    /// Queries from clients of [Users::internal_networks]
+    ///
+    /// [Users::internal_networks]: crate::config::Users::internal_networks
+    Internal,
+}
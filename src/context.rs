@@ -0,0 +1,82 @@
+//! Context of a single request, passed to everything that takes part in answering it
+//!
+//! Its deadline bounds the whole exchange with the client: [RequestContext::enforce] cancels the
+//! handling (including upstream queries) once it's reached, and code that can't be cancelled, like
+//! script hooks, is given the deadline to stop by itself.
+
+use std::fmt::Display;
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Id of the next request
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug)]
+pub struct RequestContext {
+    /// Number of the request since startup, to tell requests apart in logs
+    pub id: u64,
+
+    /// Kind of listener that received the request: `tcp` or `unix` for finger listeners, `whois`,
+    /// `inetd` or `replay`
+    pub listener: &'static str,
+
+    /// Address of the client, or where the request comes from (e.g. `inetd`)
+    pub peer: String,
+
+    /// IP address of the client, if connected over TCP
+    pub ip: Option<IpAddr>,
+
+    /// Instant past which the request is abandoned
+    pub deadline: Instant,
+}
+
+impl RequestContext {
+    /// Context of a request received now, abandoned after `timeout` (0 meaning never)
+    pub fn new(
+        listener: &'static str,
+        peer: &dyn Display,
+        ip: Option<IpAddr>,
+        timeout: Duration,
+    ) -> Self {
+        // Far enough to never be reached, but not so far that adding to it overflows
+        const NEVER: Duration = Duration::from_secs(100 * 365 * 24 * 3600);
+
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            listener,
+            peer: peer.to_string(),
+            ip,
+            deadline: Instant::now() + if timeout.is_zero() { NEVER } else { timeout },
+        }
+    }
+
+    /// Family of the client's address, see [family]
+    pub fn family(&self) -> &'static str {
+        family(self.ip)
+    }
+
+    /// Run `future`, failing with [io::ErrorKind::TimedOut] if it isn't done by the deadline
+    pub async fn enforce<T>(&self, future: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+        match tokio::time::timeout_at(self.deadline, future).await {
+            Ok(result) => result,
+            Err(_) => {
+                debug!("request {} timed out", self.id);
+                Err(io::ErrorKind::TimedOut.into())
+            }
+        }
+    }
+}
+
+/// Family of a client's address `ip`: `ipv4` (including IPv4-mapped IPv6 addresses), `ipv6`, or
+/// `local` for clients without an IP address
+pub fn family(ip: Option<IpAddr>) -> &'static str {
+    match ip.map(|ip| ip.to_canonical()) {
+        Some(IpAddr::V4(_)) => "ipv4",
+        Some(IpAddr::V6(_)) => "ipv6",
+        None => "local",
+    }
+}
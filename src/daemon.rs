@@ -0,0 +1,55 @@
+//! Classic double-fork daemonization, for systems without a service supervisor
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+
+/// Detach from the controlling terminal and keep running in the background
+///
+/// The parent process exits successfully once the daemon is forked. Stdin is redirected to
+/// `/dev/null`, stdout and stderr are appended to `log_file`, and the working directory is changed
+/// to `/`, so every relative path must be resolved before calling this.
+///
+/// Must be called before any thread is spawned (i.e. before the async runtime is started).
+pub fn daemonize(log_file: &Path) -> io::Result<()> {
+    let null = File::open("/dev/null")?;
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_file)?;
+
+    fork_and_exit_parent()?;
+    // SAFETY: no preconditions
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Fork again so the daemon isn't a session leader and can never reacquire a terminal
+    fork_and_exit_parent()?;
+
+    std::env::set_current_dir("/")?;
+
+    redirect(&null, libc::STDIN_FILENO)?;
+    redirect(&log, libc::STDOUT_FILENO)?;
+    redirect(&log, libc::STDERR_FILENO)?;
+
+    Ok(())
+}
+
+fn fork_and_exit_parent() -> io::Result<()> {
+    // SAFETY: the process is single-threaded at this point (see `daemonize`)
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        // SAFETY: `_exit` skips destructors and atexit handlers, which belong to the child now
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+fn redirect(file: &File, fd: libc::c_int) -> io::Result<()> {
+    // SAFETY: both descriptors are valid for the duration of the call
+    match unsafe { libc::dup2(file.as_raw_fd(), fd) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,63 @@
+//! Escaping of bytes sent by clients before they're logged
+//!
+//! Requests are logged to terminals, log files and the audit log, where raw control characters
+//! could inject ANSI sequences or start fake lines. [Escaped] writes them quoted, with anything
+//! that isn't a visible character escaped, in the syntax read back by [crate::replay].
+
+use std::fmt::{Debug, Display, Formatter, Write};
+
+/// Bytes displayed in double quotes, with control characters, invisible characters (like
+/// bidirectional overrides) and non-UTF-8 bytes escaped
+pub struct Escaped<'a>(pub &'a [u8]);
+
+impl Display for Escaped<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_char('"')?;
+        for chunk in self.0.utf8_chunks() {
+            for c in chunk.valid().chars() {
+                match c {
+                    '\\' | '"' => write!(f, "\\{c}")?,
+                    ' ' | '\'' => f.write_char(c)?,
+                    // Escapes ASCII control characters and non-printable Unicode characters
+                    _ => write!(f, "{}", c.escape_debug())?,
+                }
+            }
+            for byte in chunk.invalid() {
+                write!(f, "\\x{byte:02x}")?;
+            }
+        }
+        f.write_char('"')
+    }
+}
+
+impl Debug for Escaped<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_control_characters() {
+        let escaped = Escaped(b"alice\x1b[2J\r\n=== fake\0\x7f");
+        assert_eq!(
+            escaped.to_string(),
+            r#""alice\u{1b}[2J\r\n=== fake\0\u{7f}""#
+        );
+    }
+
+    #[test]
+    fn escapes_invisible_characters() {
+        let escaped = Escaped("é\u{202e}\u{2028}".as_bytes());
+        assert_eq!(escaped.to_string(), r#""é\u{202e}\u{2028}""#);
+    }
+
+    #[test]
+    fn escapes_invalid_utf8() {
+        let escaped = Escaped(b"a\xff\"b\\'");
+        assert_eq!(escaped.to_string(), r#""a\xff\"b\\'""#);
+    }
+}
@@ -0,0 +1,95 @@
+//! Limits on the verbose queries for users whose long info is expensive to render
+//!
+//! A user can mark its long info as expensive, e.g. when a script renders it, with a number of
+//! verbose queries rendered at once and a cooldown between them:
+//!
+//! ```toml
+//! [users.alice]
+//! expensive-long-info = { concurrency = 1, cooldown = 10 }
+//! ```
+//!
+//! Verbose queries beyond these limits get the short info instead, which stays instantly
+//! available, so that repeated verbose queries can't stampede a slow script.
+
+use crate::clock::SharedClock;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Limits of [crate::config::User::expensive_long_info]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ExpensiveLongInfo {
+    /// Max number of verbose queries for the user rendered at once (default: 1)
+    #[serde(default = "one")]
+    pub concurrency: usize,
+
+    /// Seconds after a verbose reply is rendered during which other verbose queries get the short
+    /// info (default: 0)
+    #[serde(default)]
+    pub cooldown: u64,
+}
+
+fn one() -> usize {
+    1
+}
+
+/// Verbose replies being rendered and last rendered for each user, kept across config reloads
+#[derive(Debug, Default)]
+pub struct Renders {
+    slots: Mutex<HashMap<String, Slot>>,
+    clock: SharedClock,
+}
+
+#[derive(Debug, Default)]
+struct Slot {
+    rendering: usize,
+    rendered_at: Option<Instant>,
+}
+
+impl Renders {
+    /// Renders whose cooldowns are timed by `clock`
+    pub fn with_clock(clock: SharedClock) -> Self {
+        Self {
+            slots: Mutex::default(),
+            clock,
+        }
+    }
+
+    /// Permission to render the long info of `name` until the guard is dropped, unless `limits`
+    /// are reached
+    pub fn start(&self, name: &str, limits: ExpensiveLongInfo) -> Option<Rendering<'_>> {
+        let mut slots = self.slots.lock().unwrap();
+        let slot = slots.entry(name.to_owned()).or_default();
+
+        let cooldown = Duration::from_secs(limits.cooldown);
+        let now = self.clock.now();
+        let cooling_down = (slot.rendered_at).is_some_and(|at| now.duration_since(at) < cooldown);
+        if slot.rendering >= limits.concurrency || cooling_down {
+            return None;
+        }
+
+        slot.rendering += 1;
+        Some(Rendering {
+            renders: self,
+            name: name.to_owned(),
+        })
+    }
+}
+
+/// Guard returned by [Renders::start]
+pub struct Rendering<'a> {
+    renders: &'a Renders,
+    name: String,
+}
+
+impl Drop for Rendering<'_> {
+    fn drop(&mut self) {
+        let mut slots = self.renders.slots.lock().unwrap();
+        if let Some(slot) = slots.get_mut(&self.name) {
+            slot.rendering -= 1;
+            slot.rendered_at = Some(self.renders.clock.now());
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! Rendering the replies of every user to static files, e.g. to publish them on a website
+//!
+//! Each user gets a `<name>.txt` (or `.html`) file with the reply to a verbose query for it, and
+//! the whole listing (regardless of pagination) goes to `index.txt` (or `.html`) if
+//! [Users::enable_index] is set. The users of each namespace are rendered the same way to a
+//! subdirectory named after its domain. Users get the info of their schedule entry active at the
+//! time of the export, framed by their prefix and suffix. Hidden users and users relayed to an
+//! upstream server are skipped.
+//!
+//! Names that can't be used as file names as they are (empty, `.`, `..`, containing a path
+//! separator, or a user named `index`) fail the export instead of writing outside of the directory
+//! or over another file.
+
+use crate::config::Users;
+use crate::redact::Audience;
+use crate::schedule::LocalTime;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum Format {
+    /// Plain text, exactly as sent to finger clients
+    Text,
+    /// HTML pages, with the listing linking to each user
+    Html,
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Text => "txt",
+            Self::Html => "html",
+        }
+    }
+}
+
+/// Render the replies of `users` to files in `dir`, which is created if needed
+pub fn run(users: &Users, dir: &Path, format: Format) -> io::Result<()> {
+    let now = LocalTime::now(users.utc_offset);
+    export(users, dir, format, now, users.modified)
+}
+
+/// Render the replies of `users` as shown at `now`, with `config_modified` the modification time
+/// of the top-level config
+fn export(
+    users: &Users,
+    dir: &Path,
+    format: Format,
+    now: LocalTime,
+    config_modified: Option<SystemTime>,
+) -> io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let extension = format.extension();
+
+    for (name, user) in &users.users {
+        // Exported files are public, like the replies to clients outside the internal networks
+        if user.hidden {
+            continue;
+        }
+        let user = user.at(now);
+        if user.proxy_to.is_some() {
+            continue;
+        }
+        check_file_name(name, "user")?;
+        if name.eq_ignore_ascii_case("index") {
+            return Err(invalid("user", name));
+        }
+
+        // Exported files are meant to be published, so internal sections are left out
+        let audience = Audience {
+            verbose: true,
+            internal: false,
+        };
+        let (info, signature) = user.reply(name, &users.snippets, audience);
+        let (prefix, suffix) = user.frame(name, config_modified);
+        let parts = [
+            prefix.as_deref(),
+            Some(&info),
+            signature.as_deref(),
+            suffix.as_deref(),
+        ];
+        let reply = parts.into_iter().flatten().collect::<String>();
+        let content = match format {
+            Format::Text => reply,
+            Format::Html => page(name, &format!("<pre>{}</pre>", escape(&reply))),
+        };
+        std::fs::write(dir.join(format!("{name}.{extension}")), content)?;
+    }
+
+    if users.enable_index {
+        let listing = users.listing();
+        let content = match format {
+            Format::Text => (listing.iter())
+                .flat_map(|(name, _)| [name, "\r\n"])
+                .collect(),
+            Format::Html => {
+                let mut items = String::new();
+                for (name, _) in &listing {
+                    let href = escape(&percent_encode(name));
+                    let name = escape(name);
+                    items.push_str(&format!("<li><a href=\"{href}.html\">{name}</a></li>\n"));
+                }
+                page("Users", &format!("<ul>\n{items}</ul>"))
+            }
+        };
+        std::fs::write(dir.join(format!("index.{extension}")), content)?;
+    }
+
+    for (domain, namespace) in &users.domains {
+        check_file_name(domain, "domain")?;
+        export(namespace, &dir.join(domain), format, now, config_modified)?;
+    }
+
+    Ok(())
+}
+
+/// Fail if `name` (of a `kind` of entry) isn't a single component of a path
+fn check_file_name(name: &str, kind: &str) -> io::Result<()> {
+    let separators = ['/', '\\', '\0'];
+    match name {
+        "" | "." | ".." => Err(invalid(kind, name)),
+        name if name.contains(separators) => Err(invalid(kind, name)),
+        _ => Ok(()),
+    }
+}
+
+fn invalid(kind: &str, name: &str) -> io::Error {
+    let message = format!("{kind} {name:?} can't be exported to a file of the same name");
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// `name` with the bytes that aren't unreserved in URLs percent-encoded
+fn percent_encode(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            byte => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+fn page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n{body}\n</body>\n</html>\n",
+        escape(title),
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exports_framed_replies_under_safe_names() {
+        let dir = std::env::temp_dir().join(format!("fingered-export-{}", std::process::id()));
+        let config = r#"
+            users."a b" = { info = "A B", prefix = "--- {name} ---\n" }
+            users.carol = { info = "Carol", hidden = true }
+        "#;
+        let users = Users::parse(config).unwrap();
+        run(&users, &dir, Format::Html).unwrap();
+
+        let user = std::fs::read_to_string(dir.join("a b.html")).unwrap();
+        assert!(user.contains("<pre>--- a b ---\r\nA B\r\n</pre>"), "{user}");
+        let index = std::fs::read_to_string(dir.join("index.html")).unwrap();
+        assert!(index.contains("<a href=\"a%20b.html\">a b</a>"), "{index}");
+        assert!(!dir.join("carol.html").exists());
+
+        for name in ["..", "index"] {
+            let users = Users::parse(&format!("users.{name:?} = \"Evil\"")).unwrap();
+            let err = run(&users, &dir, Format::Text).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,90 @@
+//! Users replying with an entry of a fortune file, like the `fortune` program prints
+//!
+//! Fortune files hold entries separated by lines containing only `%`. The file of a user is read
+//! when the config is loaded, and each query gets the next entry (looping back to the first one
+//! after the last) or a random one, depending on [FortuneOrder].
+
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FortuneOrder {
+    /// A random entry for each query (default)
+    #[default]
+    Random,
+
+    /// Entries in the order of the file
+    Sequential,
+}
+
+#[derive(Debug)]
+pub struct Fortune {
+    pub text: String,
+
+    /// Generated signature of [Fortune::text], see [crate::signing]
+    pub signature: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Fortunes {
+    entries: Vec<Fortune>,
+    order: FortuneOrder,
+
+    /// Index of the next entry, for [FortuneOrder::Sequential]
+    next: AtomicUsize,
+}
+
+impl Fortunes {
+    pub fn new(entries: Vec<Fortune>, order: FortuneOrder) -> Self {
+        Self {
+            entries,
+            order,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Count the entries in `estimate`, see [crate::memory]
+    pub fn estimate(&self, estimate: &mut crate::memory::Estimate) {
+        for entry in &self.entries {
+            estimate.add(size_of::<Fortune>() + entry.text.len());
+            estimate.add(entry.signature.as_ref().map_or(0, String::len));
+        }
+    }
+
+    /// Entry to reply with to a query, or `None` if the file has no entries
+    pub fn pick(&self) -> Option<&Fortune> {
+        if self.entries.is_empty() {
+            return None;
+        }
+
+        let index = match self.order {
+            FortuneOrder::Random => crate::random(self.entries.len() as u64) as usize,
+            FortuneOrder::Sequential => self.next.fetch_add(1, Ordering::Relaxed),
+        };
+        Some(&self.entries[index % self.entries.len()])
+    }
+}
+
+/// Split the content of a fortune file into its non-empty entries
+pub fn parse(content: &str) -> Vec<String> {
+    let mut entries = Vec::new();
+    let mut entry = String::new();
+
+    for line in content.lines() {
+        if line == "%" {
+            if !entry.trim().is_empty() {
+                entries.push(std::mem::take(&mut entry));
+            }
+            entry.clear();
+        } else {
+            entry.push_str(line);
+            entry.push('\n');
+        }
+    }
+    if !entry.trim().is_empty() {
+        entries.push(entry);
+    }
+
+    entries
+}
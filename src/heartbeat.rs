@@ -0,0 +1,56 @@
+//! Periodic summary of the daemon's activity, showing it's alive even when no one queries it
+//!
+//! The summary is logged, and sent to systemd as the status of the service (as shown by
+//! `systemctl status`) if the `NOTIFY_SOCKET` environment variable is set, which requires
+//! `NotifyAccess=main` in the unit unless it has `Type=notify`.
+//!
+//! [sd_notify]: https://www.freedesktop.org/software/systemd/man/latest/sd_notify.html
+
+use crate::stats::Stats;
+use std::time::Duration;
+
+/// Log a summary of `stats` every `interval`, forever
+#[instrument(skip_all)]
+pub async fn run(stats: &Stats, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // The first tick completes immediately, and there's nothing to tell yet
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        let summary = stats.summary();
+        info!("{summary}");
+
+        #[cfg(unix)]
+        if let Err(err) = notify(&format!("STATUS={summary}")) {
+            warn!("cannot notify systemd: {err}");
+        }
+    }
+}
+
+/// Send `state` to systemd with the [sd_notify] protocol, if `NOTIFY_SOCKET` is set
+#[cfg(unix)]
+fn notify(state: &str) -> std::io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+
+    let socket = UnixDatagram::unbound()?;
+    match path.as_encoded_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+
+    Ok(())
+}
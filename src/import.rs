@@ -0,0 +1,86 @@
+//! Generating a `users.toml` from the accounts of the system, like classic finger daemons serve
+//!
+//! Every account of the passwd file within the UID range becomes a user whose info is its login
+//! and full name (the first field of the GECOS), and whose long info adds the home directory, the
+//! shell, and the content of the `~/.project` and `~/.plan` files if they exist.
+
+use std::io;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+/// Account read from a line of the passwd file
+struct Account<'a> {
+    login: &'a str,
+    name: &'a str,
+    home: &'a str,
+    shell: &'a str,
+}
+
+/// Render the `users.toml` of the accounts in `passwd` whose UID is within `uids`
+pub fn run(passwd: &Path, uids: RangeInclusive<u32>) -> io::Result<String> {
+    let passwd = std::fs::read_to_string(passwd)?;
+    let mut toml = String::new();
+
+    for line in passwd.lines() {
+        let Some(account) = parse_account(line, &uids) else {
+            continue;
+        };
+
+        let mut long_info = format!("Login: {}\n", account.login);
+        if !account.name.is_empty() {
+            long_info.push_str(&format!("Name: {}\n", account.name));
+        }
+        long_info.push_str(&format!(
+            "Directory: {}\nShell: {}\n",
+            account.home, account.shell,
+        ));
+        for (title, file) in [("Project", ".project"), ("Plan", ".plan")] {
+            match std::fs::read_to_string(Path::new(account.home).join(file)) {
+                Ok(content) => {
+                    long_info.push_str(&format!("{title}:\n{}\n", content.trim_end()));
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => eprintln!("cannot read {file} of {:?}: {err}", account.login),
+            }
+        }
+
+        let info = match account.name {
+            "" => account.login.to_owned(),
+            name => format!("{} ({name})", account.login),
+        };
+
+        if !toml.is_empty() {
+            toml.push('\n');
+        }
+        toml.push_str(&format!("[users.{}]\n", toml_string(account.login)));
+        toml.push_str(&format!("info = {}\n", toml_string(&info)));
+        toml.push_str(&format!("long-info = {}\n", toml_string(&long_info)));
+    }
+
+    Ok(toml)
+}
+
+fn parse_account<'a>(line: &'a str, uids: &RangeInclusive<u32>) -> Option<Account<'a>> {
+    if line.starts_with('#') {
+        return None;
+    }
+
+    let mut fields = line.split(':');
+    let login = fields.next()?;
+    let uid = fields.nth(1)?.parse::<u32>().ok()?;
+    let gecos = fields.nth(1)?;
+    let home = fields.next()?;
+    let shell = fields.next()?;
+
+    uids.contains(&uid).then_some(Account {
+        login,
+        name: gecos.split(',').next().unwrap_or_default(),
+        home,
+        shell,
+    })
+}
+
+/// Quote and escape `value` as a TOML string
+fn toml_string(value: &str) -> String {
+    toml::Value::String(value.to_owned()).to_string()
+}
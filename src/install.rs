@@ -0,0 +1,154 @@
+//! Installation of this executable as a service, see `fingered install`
+//!
+//! The executable is copied to the binary directory, a service is written for the init system
+//! (systemd service and socket units, an OpenRC script, or an rc.d script), and the config file
+//! is created from `users.template.toml` unless it exists. Every path is taken under a root
+//! directory, so that packagers can stage the files somewhere else than `/`.
+//!
+//! With systemd, port 79 is bound by the socket unit, so the daemon runs as a dynamic user without
+//! any privilege. With OpenRC, it runs as `nobody` and the executable is given the
+//! `CAP_NET_BIND_SERVICE` capability with `setcap`. The rc.d script runs it as root, like other
+//! daemons of the BSDs binding privileged ports.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Config written when there's none yet
+const EXAMPLE_CONFIG: &str = include_str!("../users.template.toml");
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum Init {
+    /// `fingered.service` and `fingered.socket` units in `/etc/systemd/system`
+    Systemd,
+    /// Script in `/etc/init.d`
+    Openrc,
+    /// Script in `/usr/local/etc/rc.d`, for FreeBSD
+    RcD,
+}
+
+impl Init {
+    /// Init system of this machine: rc.d on the BSDs, OpenRC if it's running, systemd otherwise
+    pub fn detect() -> Self {
+        if cfg!(any(
+            target_os = "freebsd",
+            target_os = "openbsd",
+            target_os = "netbsd"
+        )) {
+            Self::RcD
+        } else if Path::new("/run/openrc").exists() {
+            Self::Openrc
+        } else {
+            Self::Systemd
+        }
+    }
+}
+
+pub struct Options<'a> {
+    /// Directory every other path is taken under
+    pub root: &'a Path,
+
+    /// Directory the executable is copied to
+    pub bin_dir: &'a Path,
+
+    /// Config file given to the daemon, created if it doesn't exist
+    pub users_file: &'a Path,
+
+    pub init: Init,
+
+    /// Whether to give the executable the capability to bind port 79, for [Init::Openrc]
+    pub setcap: bool,
+}
+
+/// Install the files, printing each step
+pub fn run(options: &Options) -> io::Result<()> {
+    let bin = options.bin_dir.join("fingered");
+    let staged_bin = staged(options.root, &bin);
+    fs::create_dir_all(staged_bin.parent().unwrap())?;
+    // Copy to a temporary file first, since the destination may be the running executable
+    let copy = staged_bin.with_extension("new");
+    fs::copy(std::env::current_exe()?, &copy)?;
+    fs::rename(&copy, &staged_bin)?;
+    println!("installed {}", staged_bin.display());
+
+    let users_file = options.users_file.display();
+    let bin = bin.display();
+    let services = match options.init {
+        Init::Systemd => vec![
+            (
+                "etc/systemd/system/fingered.service",
+                format!(include_str!("install/fingered.service"), bin, users_file),
+                false,
+            ),
+            (
+                "etc/systemd/system/fingered.socket",
+                include_str!("install/fingered.socket").to_owned(),
+                false,
+            ),
+        ],
+        Init::Openrc => vec![(
+            "etc/init.d/fingered",
+            format!(include_str!("install/openrc"), bin, users_file),
+            true,
+        )],
+        Init::RcD => vec![(
+            "usr/local/etc/rc.d/fingered",
+            format!(include_str!("install/rc.d"), bin, users_file),
+            true,
+        )],
+    };
+    for (path, content, executable) in services {
+        let path = options.root.join(path);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(&path, content)?;
+        #[cfg(unix)]
+        if executable {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+        }
+        #[cfg(not(unix))]
+        let _ = executable;
+        println!("wrote {}", path.display());
+    }
+
+    let staged_users_file = staged(options.root, options.users_file);
+    if staged_users_file.exists() {
+        println!("kept {}", staged_users_file.display());
+    } else {
+        fs::create_dir_all(staged_users_file.parent().unwrap())?;
+        fs::write(&staged_users_file, EXAMPLE_CONFIG)?;
+        println!("wrote {}", staged_users_file.display());
+    }
+
+    if matches!(options.init, Init::Openrc) && options.setcap {
+        let status = Command::new("setcap")
+            .arg("cap_net_bind_service=+ep")
+            .arg(&staged_bin)
+            .status();
+        match status {
+            Ok(status) if status.success() => {
+                println!("allowed {} to bind port 79", staged_bin.display());
+            }
+            Ok(status) => eprintln!("warning: setcap failed ({status}), port 79 can't be bound"),
+            Err(err) => eprintln!("warning: cannot run setcap ({err}), port 79 can't be bound"),
+        }
+    }
+
+    match options.init {
+        Init::Systemd => println!(
+            "start it with: systemctl daemon-reload && systemctl enable --now fingered.socket"
+        ),
+        Init::Openrc => {
+            println!("start it with: rc-update add fingered && rc-service fingered start")
+        }
+        Init::RcD => println!("start it with: sysrc fingered_enable=YES && service fingered start"),
+    }
+
+    Ok(())
+}
+
+/// Path of the file installed at `path`, under `root`
+fn staged(root: &Path, path: &Path) -> PathBuf {
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
@@ -0,0 +1,222 @@
+//! Users stored in Consul or etcd, applied over the ones of the config file
+//!
+//! Every key under the configured prefix is a user, named after the rest of the key, whose value
+//! is a TOML table in the long config syntax (e.g. `info = "Hi!"`). Consul is watched with
+//! blocking queries, so changes are applied as soon as they happen. etcd is polled through its
+//! JSON gateway every [ETCD_POLL_INTERVAL].
+
+use crate::config::{Config, User};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Delay before retrying after the store couldn't be reached
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Max duration of a Consul blocking query, after which it's issued again
+const CONSUL_WAIT: &str = "5m";
+
+const ETCD_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+#[derive(Clone, Debug)]
+pub enum UserStore {
+    /// `consul://host:port/prefix` or `consul+https://...`
+    Consul { base: reqwest::Url, prefix: String },
+    /// `etcd://host:port/prefix` or `etcd+https://...`
+    Etcd { base: reqwest::Url, prefix: String },
+}
+
+impl FromStr for UserStore {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = s.split_once("://").ok_or("missing scheme")?;
+        let (kind, http) = match scheme.split_once('+') {
+            Some((kind, "https")) => (kind, "https"),
+            Some((_, transport)) => return Err(format!("unsupported transport {transport:?}")),
+            None => (scheme, "http"),
+        };
+        let (authority, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        let base = reqwest::Url::parse(&format!("{http}://{authority}/"))
+            .map_err(|err| err.to_string())?;
+        let prefix = prefix.to_owned();
+
+        match kind {
+            "consul" => Ok(Self::Consul { base, prefix }),
+            "etcd" => Ok(Self::Etcd { base, prefix }),
+            kind => Err(format!(
+                "unsupported store {kind:?}, expected consul or etcd"
+            )),
+        }
+    }
+}
+
+/// Keep the users of `config` in sync with the store, forever
+#[instrument(skip_all)]
+pub async fn watch(store: UserStore, config: Arc<Config>) {
+    let client = reqwest::Client::new();
+    let mut version = None;
+
+    loop {
+        let result = match &store {
+            UserStore::Consul { base, prefix } => {
+                consul_fetch(&client, base, prefix, version).await
+            }
+            UserStore::Etcd { base, prefix } => etcd_fetch(&client, base, prefix).await,
+        };
+
+        match result {
+            Ok((new_version, entries)) if Some(new_version) != version => {
+                let prefix = match &store {
+                    UserStore::Consul { prefix, .. } | UserStore::Etcd { prefix, .. } => prefix,
+                };
+                let users = parse_users(prefix, entries);
+                info!("{} user(s) in store (version {new_version})", users.len());
+                config.set_overlay(users).await;
+                version = Some(new_version);
+            }
+            Ok(_) => {}
+            Err(err) => {
+                warn!("cannot fetch users from store: {err}");
+                tokio::time::sleep(RETRY_DELAY).await;
+                continue;
+            }
+        }
+
+        if let UserStore::Etcd { .. } = store {
+            tokio::time::sleep(ETCD_POLL_INTERVAL).await;
+        }
+    }
+}
+
+fn parse_users(prefix: &str, entries: Vec<(String, Vec<u8>)>) -> HashMap<String, User> {
+    entries
+        .into_iter()
+        .filter_map(|(key, value)| {
+            let name = key.strip_prefix(prefix)?.trim_start_matches('/');
+            if name.is_empty() || name.contains('/') {
+                return None;
+            }
+
+            let user = std::str::from_utf8(&value)
+                .map_err(|err| err.to_string())
+                .and_then(|toml| User::from_toml(name, toml));
+            match user {
+                Ok(user) => Some((name.to_owned(), user)),
+                Err(err) => {
+                    warn!("ignoring user {name:?} from store: {err}");
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Run a blocking query returning once the keys changed since `index`, or after [CONSUL_WAIT]
+async fn consul_fetch(
+    client: &reqwest::Client,
+    base: &reqwest::Url,
+    prefix: &str,
+    index: Option<u64>,
+) -> Result<(u64, Vec<(String, Vec<u8>)>), BoxError> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct Entry {
+        key: String,
+        value: Option<String>,
+    }
+
+    let mut url = base.join(&format!("v1/kv/{prefix}"))?;
+    url.query_pairs_mut()
+        .append_pair("recurse", "true")
+        .append_pair("wait", CONSUL_WAIT);
+    if let Some(index) = index {
+        url.query_pairs_mut()
+            .append_pair("index", &index.to_string());
+    }
+
+    let response = client.get(url).send().await?;
+    let new_index = (response.headers().get("X-Consul-Index"))
+        .and_then(|index| index.to_str().ok()?.parse().ok())
+        .ok_or("missing X-Consul-Index header")?;
+
+    // Consul answers 404 when no key has the prefix
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok((new_index, Vec::new()));
+    }
+
+    let entries = response.error_for_status()?.json::<Vec<Entry>>().await?;
+    let entries = entries
+        .into_iter()
+        .map(|entry| {
+            let value = BASE64.decode(entry.value.unwrap_or_default())?;
+            Ok((entry.key, value))
+        })
+        .collect::<Result<_, BoxError>>()?;
+
+    Ok((new_index, entries))
+}
+
+/// Read the keys under the prefix with etcd's v3 JSON gateway
+async fn etcd_fetch(
+    client: &reqwest::Client,
+    base: &reqwest::Url,
+    prefix: &str,
+) -> Result<(u64, Vec<(String, Vec<u8>)>), BoxError> {
+    #[derive(Deserialize)]
+    struct Range {
+        header: Header,
+        #[serde(default)]
+        kvs: Vec<Kv>,
+    }
+
+    #[derive(Deserialize)]
+    struct Header {
+        revision: String,
+    }
+
+    #[derive(Deserialize)]
+    struct Kv {
+        key: String,
+        #[serde(default)]
+        value: String,
+    }
+
+    // The range end of a prefix is the prefix with its last byte incremented
+    let mut range_end = prefix.as_bytes().to_vec();
+    match range_end.last_mut() {
+        Some(last) if *last < 0xff => *last += 1,
+        _ => range_end = vec![0],
+    }
+
+    let body = serde_json::json!({
+        "key": BASE64.encode(prefix),
+        "range_end": BASE64.encode(range_end),
+    });
+    let range = client
+        .post(base.join("v3/kv/range")?)
+        .json(&body)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<Range>()
+        .await?;
+
+    let entries = range
+        .kvs
+        .into_iter()
+        .map(|kv| {
+            let key = String::from_utf8(BASE64.decode(kv.key)?)?;
+            Ok((key, BASE64.decode(kv.value)?))
+        })
+        .collect::<Result<_, BoxError>>()?;
+
+    Ok((range.header.revision.parse()?, entries))
+}
@@ -1,8 +1,10 @@
+#[cfg(all(unix, feature = "seqpacket"))]
+use crate::seqpacket::{self, SeqPacketListener, SeqPacketStream};
 use crate::FINGER_PORT;
 use std::borrow::Borrow;
 use std::ffi::OsStr;
 use std::fmt::{Display, Formatter};
-use std::net::{AddrParseError, IpAddr, SocketAddr};
+use std::net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::str::FromStr;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::tcp::{ReadHalf, WriteHalf};
@@ -21,6 +23,10 @@ pub enum AnySocketAddr {
 
     #[cfg(all(unix, feature = "unix-socket"))]
     Unix(unix::PathBuf),
+
+    /// Unix socket of the `SOCK_SEQPACKET` type, written `seqpacket:<PATH>`
+    #[cfg(all(unix, feature = "seqpacket"))]
+    SeqPacket(unix::PathBuf),
 }
 
 impl From<SocketAddr> for AnySocketAddr {
@@ -33,11 +39,24 @@ impl TryFrom<&OsStr> for AnySocketAddr {
     type Error = AddrParseError;
 
     fn try_from(value: &OsStr) -> Result<Self, Self::Error> {
+        Self::parse_with_default_port(value, FINGER_PORT)
+    }
+}
+
+impl AnySocketAddr {
+    /// Parse a Unix socket path, or an IP address with an optional port defaulting to `port`
+    pub fn parse_with_default_port(value: &OsStr, port: u16) -> Result<Self, AddrParseError> {
         #[cfg(all(unix, feature = "unix-socket"))]
         {
             use std::os::unix::ffi::OsStrExt;
 
             let bytes = value.as_bytes();
+            #[cfg(all(unix, feature = "seqpacket"))]
+            if let Some(path) = bytes.strip_prefix(b"seqpacket:") {
+                return Ok(Self::SeqPacket(unix::PathBuf::from(OsStr::from_bytes(
+                    path,
+                ))));
+            }
             if bytes.starts_with(b"/") || bytes.starts_with(b"./") || bytes.starts_with(b"../") {
                 return Ok(Self::Unix(unix::PathBuf::from(value)));
             }
@@ -48,7 +67,7 @@ impl TryFrom<&OsStr> for AnySocketAddr {
             .ok_or_else(|| SocketAddr::from_str("").unwrap_err())?;
 
         let socket_addr = SocketAddr::from_str(str)
-            .or_else(|_| IpAddr::from_str(str).map(|addr| SocketAddr::new(addr, FINGER_PORT)));
+            .or_else(|_| IpAddr::from_str(str).map(|addr| SocketAddr::new(addr, port)));
 
         socket_addr.map(Self::Tcp)
     }
@@ -60,6 +79,32 @@ impl Display for AnySocketAddr {
             Self::Tcp(addr) => Display::fmt(&addr, f),
             #[cfg(all(unix, feature = "unix-socket"))]
             Self::Unix(path) => Display::fmt(&path.display(), f),
+            #[cfg(all(unix, feature = "seqpacket"))]
+            Self::SeqPacket(path) => write!(f, "seqpacket:{}", path.display()),
+        }
+    }
+}
+
+/// What uses an address that can't be bound because it's in use
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InUse {
+    /// A listener accepts connections on it, e.g. another instance that's still exiting
+    Live,
+    /// Nothing listens on the Unix socket file, e.g. left by a daemon that didn't exit gracefully
+    Stale,
+}
+
+impl AnySocketAddr {
+    /// Tell what uses this address, by connecting to it if it's a Unix socket path
+    pub async fn probe_in_use(&self) -> InUse {
+        match self {
+            // TCP listeners are bound with `SO_REUSEADDR`, so only a listener can use the port
+            Self::Tcp(_) => InUse::Live,
+            #[cfg(all(unix, feature = "unix-socket"))]
+            _ => match AnySocket::connect(self).await {
+                Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused => InUse::Stale,
+                _ => InUse::Live,
+            },
         }
     }
 }
@@ -68,8 +113,15 @@ impl Display for AnySocketAddr {
 pub enum AnyListener {
     Tcp(TcpListener),
 
+    /// TCP listener whose connections are made over TLS, see [crate::tls]
+    #[cfg(feature = "tls")]
+    Tls(TcpListener, tokio_rustls::TlsAcceptor),
+
     #[cfg(all(unix, feature = "unix-socket"))]
     Unix(unix::UnixListener),
+
+    #[cfg(all(unix, feature = "seqpacket"))]
+    SeqPacket(SeqPacketListener),
 }
 
 impl From<TcpListener> for AnyListener {
@@ -84,6 +136,25 @@ impl AnyListener {
             AnySocketAddr::Tcp(addr) => TcpListener::bind(addr).await.map(Self::Tcp),
             #[cfg(all(unix, feature = "unix-socket"))]
             AnySocketAddr::Unix(path) => unix::UnixListener::bind(path).map(Self::Unix),
+            #[cfg(all(unix, feature = "seqpacket"))]
+            AnySocketAddr::SeqPacket(path) => SeqPacketListener::bind(path).map(Self::SeqPacket),
+        }
+    }
+
+    /// Descriptor of the listener, if it can be passed to another process by socket activation
+    /// (see [crate::activation], which doesn't take seqpacket listeners)
+    #[cfg(all(unix, feature = "daemonize"))]
+    pub fn activation_fd(&self) -> Option<std::os::fd::RawFd> {
+        use std::os::fd::AsRawFd;
+
+        match self {
+            Self::Tcp(listener) => Some(listener.as_raw_fd()),
+            #[cfg(feature = "tls")]
+            Self::Tls(..) => None,
+            #[cfg(all(unix, feature = "unix-socket"))]
+            Self::Unix(listener) => Some(listener.as_raw_fd()),
+            #[cfg(all(unix, feature = "seqpacket"))]
+            Self::SeqPacket(_) => None,
         }
     }
 
@@ -94,11 +165,24 @@ impl AnyListener {
                 .await
                 .map(|(sock, addr)| AnySocket::Tcp(sock, addr)),
 
+            // The handshake is left to [AnySocket::handshake], so that it doesn't hold up the
+            // next connections
+            #[cfg(feature = "tls")]
+            Self::Tls(listener, acceptor) => listener.accept().await.map(|(sock, addr)| {
+                AnySocket::Tls(
+                    Box::new(TlsSocket::Handshaking(acceptor.accept(sock))),
+                    addr,
+                )
+            }),
+
             #[cfg(all(unix, feature = "unix-socket"))]
-            Self::Unix(listener) => listener
-                .accept()
-                .await
-                .map(|(sock, _)| AnySocket::Unix(sock)),
+            Self::Unix(listener) => listener.accept().await.map(|(sock, _)| {
+                let credentials = sock.peer_cred().ok().map(PeerCredentials::from);
+                AnySocket::Unix(sock, credentials)
+            }),
+
+            #[cfg(all(unix, feature = "seqpacket"))]
+            Self::SeqPacket(listener) => listener.accept().await.map(AnySocket::SeqPacket),
         }
     }
 }
@@ -106,37 +190,180 @@ impl AnyListener {
 pub enum AnySocket {
     Tcp(TcpStream, SocketAddr),
 
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsSocket>, SocketAddr),
+
     #[cfg(all(unix, feature = "unix-socket"))]
-    Unix(unix::UnixStream),
+    Unix(unix::UnixStream, Option<PeerCredentials>),
+
+    #[cfg(all(unix, feature = "seqpacket"))]
+    SeqPacket(SeqPacketStream),
 }
 
-impl AnySocket {
-    pub fn peer_display(&self) -> impl Display + Sync + Send + 'static {
-        enum PeerDisplay {
-            Tcp(SocketAddr),
-            Unix,
+/// Connection accepted by a TLS listener, before and after its handshake
+#[cfg(feature = "tls")]
+pub enum TlsSocket {
+    Handshaking(tokio_rustls::Accept<TcpStream>),
+    Established(tokio_rustls::server::TlsStream<TcpStream>),
+}
+
+/// Identity of the process at the other end of a Unix socket, as given by `SO_PEERCRED`
+#[derive(Clone, Copy, Debug)]
+pub struct PeerCredentials {
+    pub pid: Option<i32>,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+#[cfg(all(unix, feature = "unix-socket"))]
+impl From<unix::UCred> for PeerCredentials {
+    fn from(value: unix::UCred) -> Self {
+        Self {
+            pid: value.pid(),
+            uid: value.uid(),
+            gid: value.gid(),
+        }
+    }
+}
+
+/// Client of a connection, displayed as its address or as `unix(pid=…,uid=…,gid=…)`
+#[derive(Clone, Copy, Debug)]
+pub enum Peer {
+    Tcp(SocketAddr),
+
+    #[cfg(all(unix, feature = "unix-socket"))]
+    Unix(Option<PeerCredentials>),
+}
+
+impl Peer {
+    /// IP address of the peer, if connected over TCP
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Self::Tcp(addr) => Some(addr.ip()),
+            #[cfg(all(unix, feature = "unix-socket"))]
+            Self::Unix(_) => None,
+        }
+    }
+
+    /// Kind of socket the peer is connected to, `tcp` or `unix`
+    pub fn transport(&self) -> &'static str {
+        match self {
+            Self::Tcp(_) => "tcp",
+            #[cfg(all(unix, feature = "unix-socket"))]
+            Self::Unix(_) => "unix",
+        }
+    }
+
+    /// Credentials of the peer, if connected over a Unix socket and the OS told them
+    pub fn credentials(&self) -> Option<PeerCredentials> {
+        match self {
+            Self::Tcp(_) => None,
+            #[cfg(all(unix, feature = "unix-socket"))]
+            Self::Unix(credentials) => *credentials,
+        }
+    }
+}
+
+impl Display for Peer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            #[cfg(all(unix, feature = "unix-socket"))]
+            Self::Unix(None) => write!(f, "unix"),
+            #[cfg(all(unix, feature = "unix-socket"))]
+            Self::Unix(Some(PeerCredentials { pid, uid, gid })) => {
+                f.write_str("unix(")?;
+                if let Some(pid) = pid {
+                    write!(f, "pid={pid},")?;
+                }
+                write!(f, "uid={uid},gid={gid})")
+            }
         }
+    }
+}
 
-        impl Display for PeerDisplay {
-            fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-                match self {
-                    Self::Tcp(addr) => write!(f, "{addr}"),
-                    Self::Unix => write!(f, "unix"),
+impl AnySocket {
+    /// Connect to a server, replacing unspecified IP addresses (e.g. `0.0.0.0`) by loopback
+    pub async fn connect(addr: impl Borrow<AnySocketAddr>) -> std::io::Result<Self> {
+        match addr.borrow() {
+            AnySocketAddr::Tcp(addr) => {
+                let mut addr = *addr;
+                if addr.ip().is_unspecified() {
+                    addr.set_ip(match addr {
+                        SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                        SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                    });
                 }
+                TcpStream::connect(addr)
+                    .await
+                    .map(|sock| AnySocket::Tcp(sock, addr))
+            }
+            #[cfg(all(unix, feature = "unix-socket"))]
+            AnySocketAddr::Unix(path) => {
+                let sock = unix::UnixStream::connect(path).await?;
+                let credentials = sock.peer_cred().ok().map(PeerCredentials::from);
+                Ok(Self::Unix(sock, credentials))
+            }
+            #[cfg(all(unix, feature = "seqpacket"))]
+            AnySocketAddr::SeqPacket(path) => {
+                SeqPacketStream::connect(path).await.map(Self::SeqPacket)
             }
         }
+    }
 
+    /// Make the TLS handshake of a connection accepted by a TLS listener, if not made yet
+    pub async fn handshake(&mut self) -> std::io::Result<()> {
+        #[cfg(feature = "tls")]
+        if let AnySocket::Tls(tls, _) = self {
+            if let TlsSocket::Handshaking(accept) = &mut **tls {
+                **tls = TlsSocket::Established(accept.await?);
+            }
+        }
+        Ok(())
+    }
+
+    /// TCP stream of the connection, if it's made over TCP
+    pub fn tcp_stream(&self) -> Option<&TcpStream> {
+        match self {
+            AnySocket::Tcp(sock, _) => Some(sock),
+            #[cfg(feature = "tls")]
+            AnySocket::Tls(tls, _) => match &**tls {
+                TlsSocket::Handshaking(accept) => accept.get_ref(),
+                TlsSocket::Established(stream) => Some(stream.get_ref().0),
+            },
+            #[cfg(all(unix, feature = "unix-socket"))]
+            _ => None,
+        }
+    }
+
+    pub fn peer(&self) -> Peer {
         match self {
-            AnySocket::Tcp(_, addr) => PeerDisplay::Tcp(*addr),
-            AnySocket::Unix(_) => PeerDisplay::Unix,
+            AnySocket::Tcp(_, addr) => Peer::Tcp(*addr),
+            #[cfg(feature = "tls")]
+            AnySocket::Tls(_, addr) => Peer::Tcp(*addr),
+            #[cfg(all(unix, feature = "unix-socket"))]
+            AnySocket::Unix(_, credentials) => Peer::Unix(*credentials),
+            // Clients of seqpacket sockets are local, like those of stream ones
+            #[cfg(all(unix, feature = "seqpacket"))]
+            AnySocket::SeqPacket(_) => Peer::Unix(None),
         }
     }
 
-    pub fn split(&mut self) -> AnySplitSocket {
+    /// Split the connection into its reading and writing halves
+    ///
+    /// Panics if it's accepted by a TLS listener and [AnySocket::handshake] wasn't called.
+    pub fn split(&mut self) -> AnySplitSocket<'_> {
         match self {
             AnySocket::Tcp(sock, _) => AnySplitSocket::Tcp(sock.split()),
+            #[cfg(feature = "tls")]
+            AnySocket::Tls(tls, _) => match &mut **tls {
+                TlsSocket::Handshaking(_) => panic!("connection split before its TLS handshake"),
+                TlsSocket::Established(stream) => AnySplitSocket::Tls(tokio::io::split(stream)),
+            },
             #[cfg(all(unix, feature = "unix-socket"))]
-            AnySocket::Unix(sock) => AnySplitSocket::Unix(sock.split()),
+            AnySocket::Unix(sock, _) => AnySplitSocket::Unix(sock.split()),
+            #[cfg(all(unix, feature = "seqpacket"))]
+            AnySocket::SeqPacket(sock) => AnySplitSocket::SeqPacket(sock.split()),
         }
     }
 }
@@ -144,8 +371,19 @@ impl AnySocket {
 pub enum AnySplitSocket<'a> {
     Tcp((ReadHalf<'a>, WriteHalf<'a>)),
 
+    #[cfg(feature = "tls")]
+    Tls(
+        (
+            tokio::io::ReadHalf<&'a mut tokio_rustls::server::TlsStream<TcpStream>>,
+            tokio::io::WriteHalf<&'a mut tokio_rustls::server::TlsStream<TcpStream>>,
+        ),
+    ),
+
     #[cfg(all(unix, feature = "unix-socket"))]
     Unix((unix::ReadHalf<'a>, unix::WriteHalf<'a>)),
+
+    #[cfg(all(unix, feature = "seqpacket"))]
+    SeqPacket((seqpacket::ReadHalf<'a>, seqpacket::WriteHalf<'a>)),
 }
 
 impl<'a> AnySplitSocket<'a> {
@@ -157,8 +395,12 @@ impl<'a> AnySplitSocket<'a> {
     ) {
         match self {
             Self::Tcp((r, w)) => (r, w),
+            #[cfg(feature = "tls")]
+            Self::Tls((r, w)) => (r, w),
             #[cfg(all(unix, feature = "unix-socket"))]
             Self::Unix((r, w)) => (r, w),
+            #[cfg(all(unix, feature = "seqpacket"))]
+            Self::SeqPacket((r, w)) => (r, w),
         }
     }
 }
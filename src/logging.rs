@@ -0,0 +1,284 @@
+//! Daemon logging, configured by the `[logging]` section of the config
+//!
+//! The subscriber is installed at startup with the `RUST_LOG` environment variable as its filter,
+//! writing to the standard output (or the standard error in inetd mode, see [init_stderr]),
+//! then reconfigured each time the config is loaded, so that levels, format and output file can be
+//! changed with a reload.
+//!
+//! The level of the daemon's own events can also be forced over the configured one without a
+//! reload, to debug a live server: `SIGUSR1` cycles it through `info`, `debug`, `trace` and back to
+//! the configured level (see [cycle_level]), and the admin socket can set it (see [force_level]).
+//! The forced level is kept across reloads.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::{Layered, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Layer, Registry};
+
+type FilterLayer = reload::Layer<EnvFilter, Registry>;
+type OutputLayer = Box<dyn Layer<Layered<FilterLayer, Registry>> + Send + Sync>;
+
+struct Handles {
+    filter: reload::Handle<EnvFilter, Registry>,
+    output: reload::Handle<OutputLayer, Layered<FilterLayer, Registry>>,
+
+    /// Whether logs go to the standard error rather than the standard output when there's no file
+    stderr: bool,
+
+    levels: Mutex<Levels>,
+}
+
+/// What the filter of the installed subscriber is made of
+#[derive(Default)]
+struct Levels {
+    /// Directives built from the [LoggingConfig], or `None` to use `RUST_LOG`
+    configured: Option<String>,
+
+    /// Level of the daemon's events forced over [Levels::configured]
+    forced: Option<LevelFilter>,
+}
+
+impl Levels {
+    fn filter(&self) -> Result<EnvFilter, String> {
+        let filter = match &self.configured {
+            None => EnvFilter::from_default_env(),
+            Some(directives) => EnvFilter::try_new(directives).map_err(|err| err.to_string())?,
+        };
+
+        match self.forced {
+            None => Ok(filter),
+            Some(level) => {
+                let directive = format!("{}={level}", env!("CARGO_CRATE_NAME"));
+                Ok(filter.add_directive(directive.parse().map_err(|err| format!("{err}"))?))
+            }
+        }
+    }
+}
+
+/// Handles to the layers of the installed subscriber, set once by [init]
+static HANDLES: OnceLock<Handles> = OnceLock::new();
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct LoggingConfig {
+    /// Level of the events to log, e.g. `info`
+    ///
+    /// If neither this nor [LoggingConfig::modules] is set, the `RUST_LOG` environment variable
+    /// is used instead.
+    pub level: Option<String>,
+
+    /// Levels overriding [LoggingConfig::level] for some modules, e.g. `"fingered::abuse" = "warn"`
+    pub modules: BTreeMap<String, String>,
+
+    pub format: Format,
+
+    /// File to append logs to instead of the standard output
+    pub file: Option<PathBuf>,
+
+    /// Size in bytes past which the log file is rotated to `<file>.1`
+    pub max_size: u64,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: None,
+            modules: BTreeMap::new(),
+            format: Format::Full,
+            file: None,
+            max_size: 10 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Format {
+    /// One line per event, with its span context (default)
+    Full,
+    /// One shorter line per event
+    Compact,
+    /// Multiple lines per event, for humans
+    Pretty,
+    /// One JSON object per line
+    Json,
+}
+
+/// Install the global subscriber, logging to the standard output as `RUST_LOG` says
+pub fn init() {
+    install(false);
+}
+
+/// Same as [init], but logging to the standard error, for when the standard output is the socket
+pub fn init_stderr() {
+    install(true);
+}
+
+fn install(stderr: bool) {
+    let (filter, filter_handle) = reload::Layer::new(EnvFilter::from_default_env());
+    let (output, output_handle) = reload::Layer::new(default_output_layer(Format::Full, stderr));
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(output)
+        .init();
+
+    let _ = HANDLES.set(Handles {
+        filter: filter_handle,
+        output: output_handle,
+        stderr,
+        levels: Mutex::default(),
+    });
+}
+
+/// Reconfigure the subscriber installed by [init]
+pub fn apply(config: &LoggingConfig) -> Result<(), String> {
+    let Some(handles) = HANDLES.get() else {
+        return Ok(());
+    };
+
+    let configured = match (&config.level, config.modules.is_empty()) {
+        (None, true) => None,
+        (level, _) => {
+            let mut directives = level.clone().unwrap_or_else(|| "error".to_owned());
+            for (module, level) in &config.modules {
+                directives.push_str(&format!(",{module}={level}"));
+            }
+            Some(directives)
+        }
+    };
+
+    let mut levels = handles.levels.lock().unwrap();
+    let filter = Levels {
+        configured,
+        forced: levels.forced,
+    };
+
+    let output = match &config.file {
+        Some(path) => {
+            let file = RotatingFile::open(path, config.max_size)
+                .map_err(|err| format!("cannot open {}: {err}", path.display()))?;
+            output_layer(config.format, Arc::new(file), false)
+        }
+        None => default_output_layer(config.format, handles.stderr),
+    };
+
+    (handles.filter)
+        .reload(filter.filter()?)
+        .map_err(|err| err.to_string())?;
+    *levels = filter;
+    handles.output.reload(output).map_err(|err| err.to_string())
+}
+
+/// Force the level of the daemon's events over the configured filter, or go back to it with `None`
+pub fn force_level(level: Option<LevelFilter>) -> Result<(), String> {
+    let Some(handles) = HANDLES.get() else {
+        return Ok(());
+    };
+
+    let mut levels = handles.levels.lock().unwrap();
+    let filter = Levels {
+        configured: levels.configured.clone(),
+        forced: level,
+    };
+    (handles.filter)
+        .reload(filter.filter()?)
+        .map_err(|err| err.to_string())?;
+    *levels = filter;
+    Ok(())
+}
+
+/// Force the next level of `info`, `debug` and `trace`, or go back to the configured filter after
+/// `trace`, returning the new forced level
+pub fn cycle_level() -> Result<Option<LevelFilter>, String> {
+    let forced = HANDLES
+        .get()
+        .and_then(|handles| handles.levels.lock().unwrap().forced);
+    let next = match forced {
+        None => Some(LevelFilter::INFO),
+        Some(LevelFilter::INFO) => Some(LevelFilter::DEBUG),
+        Some(LevelFilter::DEBUG) => Some(LevelFilter::TRACE),
+        Some(_) => None,
+    };
+    force_level(next)?;
+    Ok(next)
+}
+
+fn default_output_layer(format: Format, stderr: bool) -> OutputLayer {
+    match stderr {
+        false => output_layer(format, io::stdout, io::stdout().is_terminal()),
+        true => output_layer(format, io::stderr, io::stderr().is_terminal()),
+    }
+}
+
+fn output_layer<W>(format: Format, writer: W, ansi: bool) -> OutputLayer
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+
+    match format {
+        Format::Full => layer.boxed(),
+        Format::Compact => layer.compact().boxed(),
+        Format::Pretty => layer.pretty().boxed(),
+        Format::Json => layer.json().boxed(),
+    }
+}
+
+/// Log file that is renamed with a `.1` suffix (replacing the previous one) when it grows past its
+/// max size, like [crate::audit::AuditLog]
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    file: Mutex<(File, u64)>,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64) -> io::Result<Self> {
+        let file = Self::open_file(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path: path.to_owned(),
+            max_size,
+            file: Mutex::new((file, size)),
+        })
+    }
+
+    fn open_file(path: &Path) -> io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+}
+
+impl Write for &RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut guard = self.file.lock().unwrap();
+        let (file, size) = &mut *guard;
+
+        if *size > 0 && *size + buf.len() as u64 > self.max_size {
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+            std::fs::rename(&self.path, rotated)?;
+            *file = RotatingFile::open_file(&self.path)?;
+            *size = 0;
+        }
+
+        let written = file.write(buf)?;
+        *size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.lock().unwrap().0.flush()
+    }
+}
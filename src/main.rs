@@ -1,58 +1,173 @@
 #[macro_use]
 extern crate tracing;
 
-use crate::config::Config;
-use crate::listener::{AnyListener, AnySocketAddr};
+use crate::activation::Activation;
+use crate::audit::{AuditLog, Recording};
+use crate::ban::{BanList, Denial};
+use crate::clock::SharedClock;
+use crate::config::{Config, Languages};
+use crate::context::RequestContext;
+use crate::escape::Escaped;
+use crate::listener::{AnyListener, AnySocket, AnySocketAddr, InUse};
+use crate::mirror::Mirror;
+use crate::reload::Reloads;
 use crate::request::Request;
+use crate::router::Router;
+use crate::shutdown::ShutdownHooks;
+use crate::source::{ConfigSource, Override};
+use crate::state::ServerState;
+use crate::stats::Stats;
+use crate::tasks::Tasks;
+use crate::throttle::{FairLimiter, RateLimiter, Throttled};
 use clap::builder::TypedValueParser;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use futures::StreamExt;
-use listenfd::ListenFd;
-use signal_hook::consts::{SIGHUP, SIGINT, SIGQUIT, SIGTERM};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGQUIT, SIGTERM, SIGUSR1};
 use signal_hook_tokio::Signals;
 use std::borrow::Borrow;
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::hash::{BuildHasher, Hash, Hasher};
 use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::io::{
-    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter,
-};
-use tokio::net::TcpListener;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::select;
-use tracing::instrument;
-use tracing_subscriber::EnvFilter;
+use tracing::{field, instrument, Span};
 
+mod abuse;
+#[cfg(feature = "acme")]
+mod acme;
+mod activation;
+#[cfg(all(unix, feature = "unix-socket"))]
+mod admin;
+#[cfg(test)]
+mod allocations;
+mod audit;
+mod backend;
+mod ban;
+#[cfg(feature = "testing")]
+mod chaos;
+mod clock;
 mod config;
+mod contact;
+mod context;
+#[cfg(all(unix, feature = "daemonize"))]
+mod daemon;
+mod escape;
+mod expensive;
+mod export;
+mod fortune;
+mod heartbeat;
+mod import;
+mod install;
+#[cfg(feature = "kv-store")]
+mod kvstore;
 mod listener;
+mod logging;
+mod memory;
+mod metrics;
+mod mirror;
+mod plans;
+mod preview;
+#[cfg(all(unix, feature = "unix-socket"))]
+mod push;
+mod redact;
+mod reload;
+mod replay;
 mod request;
+mod router;
+mod sandbox;
+mod schedule;
+#[cfg(feature = "scripting")]
+mod scripting;
+mod secret;
+mod selftest;
+#[cfg(all(unix, feature = "seqpacket"))]
+mod seqpacket;
+mod shutdown;
+mod signing;
+mod snippet;
+mod source;
+mod state;
+mod stats;
+mod tasks;
+mod tcpinfo;
+mod throttle;
+#[cfg(feature = "tls")]
+mod tls;
+mod transliterate;
+mod upstream;
+mod validate;
+mod whois;
+#[cfg(all(unix, feature = "daemonize"))]
+mod workers;
 
 const FINGER_PORT: u16 = 79;
 
-/// Max length of a request in bytes
+/// Line naming the server, only sent if [config::Users::disclose_version] is set
+const SERVER_LINE: &str = concat!("Server: fingered ", env!("CARGO_PKG_VERSION"), "\r\n");
+
+/// Max length of a request in bytes, unless configured otherwise (see [config::Users::requests])
 ///
 /// The input stream will be truncated to this limit to prevent DoS.
-const SANE_REQUEST_LENGTH: u64 = 1024;
+pub(crate) const SANE_REQUEST_LENGTH: u64 = 1024;
+
+/// Capacity of the buffer requests are read through, unless configured otherwise
+pub(crate) const READ_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Delay before trying again to bind an address in use, doubled after each try
+const BIND_RETRY_FIRST_DELAY: Duration = Duration::from_millis(100);
+
+/// Max delay between tries to bind an address in use
+const BIND_RETRY_MAX_DELAY: Duration = Duration::from_secs(5);
+
+/// How long connections still being answered are waited for when the daemon exits
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(10);
 
 /// Server-sent reply when a client includes a "@host..." forwarding request in their message
 ///
 /// Copy-pasted straight from [IETF's RFC 1288][rfc]'s suggestion.
 ///
 /// [rfc]: https://datatracker.ietf.org/doc/html/rfc1288#section-3.2.1
-const REPLY_NO_FORWARDING: &[u8] = b"Finger forwarding service denied\r\n";
+const REPLY_NO_FORWARDING: &str = "Finger forwarding service denied\r\n";
 
 /// Server-sent reply when a client tries to list users and the server denies it
 ///
 /// Copy-pasted straight from [IETF's RFC 1288][rfc]'s suggestion.
 ///
 /// [rfc]: https://datatracker.ietf.org/doc/html/rfc1288#section-3.2.2
-const REPLY_NO_LISTING: &[u8] = b"Finger online user list denied\r\n";
+pub(crate) const REPLY_NO_LISTING: &[u8] = b"Finger online user list denied\r\n";
 
 /// Server-sent reply when a client fingers a nonexistent username
 const REPLY_USER_NOT_FOUND: &[u8] = b"User not found\r\n";
 
+/// Line sent after [REPLY_USER_NOT_FOUND] when a listed user has a close name, named by `{name}`
+const REPLY_DID_YOU_MEAN: &str = "Did you mean \"{name}\"?\r\n";
+
+/// Server-sent reply when a request isn't a valid finger query, unless configured otherwise
+const REPLY_MALFORMED: &[u8] = b"Malformed finger query\r\n";
+
+/// Server-sent reply to every query in maintenance mode, unless configured otherwise
+const REPLY_MAINTENANCE: &[u8] = b"Down for maintenance, please try again later\r\n";
+
+/// Server-sent reply when a request doesn't end within its max length ([SANE_REQUEST_LENGTH] bytes
+/// by default)
+const REPLY_REQUEST_TOO_LONG: &[u8] = b"Request too long\r\n";
+
+/// Server-sent reply when a request ends, or isn't finished in time, without a newline
+const REPLY_NO_NEWLINE: &[u8] = b"No newline received\r\n";
+
 #[derive(Parser)]
 #[clap(about, version)]
 pub struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// IP address or Unix socket path to listen on
     ///
     /// May be omitted if the program is started with socket activation.
@@ -62,182 +177,1516 @@ pub struct Args {
     #[clap(value_parser = clap::builder::OsStringValueParser::new().try_map(|str| AnySocketAddr::try_from(str.as_ref())))]
     bind_to: Option<AnySocketAddr>,
 
+    /// Port to listen on instead if binding to the port of `BIND_TO` isn't permitted
+    ///
+    /// Ports below 1024 can only be bound by root or with the CAP_NET_BIND_SERVICE capability.
+    /// The same IP address is used.
+    #[clap(long, value_name = "PORT", requires = "bind_to")]
+    fallback_port: Option<u16>,
+
+    /// Keep trying to bind listeners whose address is in use for up to this number of seconds
+    ///
+    /// Useful when restarting while the previous instance is still exiting. Tries are spaced by
+    /// doubling delays of up to 5 seconds. Unix socket files that nothing listens on aren't waited
+    /// for, since they never go away by themselves.
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 0,
+        conflicts_with = "inetd"
+    )]
+    bind_retry_timeout: u64,
+
+    /// Name of the socket descriptor to listen on, among those given by socket activation
+    ///
+    /// Names are given by `LISTEN_FDNAMES` (e.g. `FileDescriptorName=` in systemd socket units).
+    /// Without this option, the first descriptor is used.
+    #[clap(long, value_name = "NAME", conflicts_with = "bind_to")]
+    listen_fd_name: Option<String>,
+
     /// Run as an inetd-compatible child process, treating stdin and stdout as a socket
     #[clap(long, conflicts_with = "bind_to")]
     inetd: bool,
 
     /// Path to the `users.toml` file
-    #[clap(long, default_value = "/etc/fingered/users.toml")]
+    #[clap(long, default_value = "/etc/fingered/users.toml", global = true)]
     users_file: PathBuf,
+
+    /// Replace a value of `users.toml`, given as a TOML dotted key and value (e.g. `users.alice.info="Hi"`)
+    ///
+    /// May be repeated. Values that aren't valid TOML are taken as strings, and the overrides are
+    /// applied again each time the config is reloaded.
+    #[clap(long, value_name = "KEY=VALUE", global = true)]
+    set: Vec<Override>,
+
+    /// Shorthand for `--set enable-index=<BOOL>`
+    #[clap(long, value_name = "BOOL", global = true)]
+    enable_index: Option<bool>,
+
+    /// URL to fetch `users.toml` from, instead of `--users-file`
+    ///
+    /// The config is fetched again on reload, unless the server says it hasn't changed.
+    #[cfg(feature = "remote-config")]
+    #[clap(long)]
+    users_url: Option<reqwest::Url>,
+
+    /// Path of a local copy of the config fetched from `--users-url`, used when it's unreachable
+    #[cfg(feature = "remote-config")]
+    #[clap(long, requires = "users_url")]
+    users_cache_file: Option<PathBuf>,
+
+    /// Consul or etcd key prefix to watch for additional users (e.g. `consul://127.0.0.1:8500/fingered/users`)
+    ///
+    /// Users from the store take precedence over the ones of the config file. Use the `consul+https` or
+    /// `etcd+https` schemes for TLS.
+    #[cfg(feature = "kv-store")]
+    #[clap(long, conflicts_with = "inetd")]
+    user_store: Option<kvstore::UserStore>,
+
+    /// Path of a file to write the daemon's process ID to, removed on exit
+    #[clap(long, conflicts_with = "inetd")]
+    pid_file: Option<PathBuf>,
+
+    /// Path of a file to record every request and the full reply sent to it
+    #[clap(long)]
+    audit_log: Option<PathBuf>,
+
+    /// Size in bytes past which the audit log is rotated to `<AUDIT_LOG>.1`
+    #[clap(long, default_value_t = 10 * 1024 * 1024)]
+    audit_log_max_size: u64,
+
+    /// Fork into the background and detach from the terminal
+    #[cfg(all(unix, feature = "daemonize"))]
+    #[clap(long, conflicts_with = "inetd")]
+    daemonize: bool,
+
+    /// File that the output of a daemonized process is appended to
+    #[cfg(all(unix, feature = "daemonize"))]
+    #[clap(long, default_value = "/dev/null")]
+    daemon_log_file: PathBuf,
+
+    /// Answer connections in this number of worker processes, supervised by this one
+    ///
+    /// A worker that crashes or is killed is started again while the others keep answering. Each
+    /// worker has its own config and statistics. See `src/workers.rs`.
+    #[cfg(all(unix, feature = "daemonize"))]
+    #[clap(long, value_name = "COUNT", conflicts_with_all = ["inetd", "self_test"])]
+    workers: Option<NonZeroUsize>,
+
+    /// Path to a file containing the key for encrypted `enc:` config values
+    #[clap(long, env = "FINGERED_SECRET_KEY_FILE")]
+    secret_key_file: Option<PathBuf>,
+
+    /// Key for encrypted `enc:` config values (prefer passing it through the environment)
+    #[clap(
+        long,
+        env = "FINGERED_SECRET_KEY",
+        hide_env_values = true,
+        conflicts_with = "secret_key_file"
+    )]
+    secret_key: Option<String>,
+
+    /// Path to a file containing the key replies are signed with (see `src/signing.rs`)
+    #[clap(long, env = "FINGERED_SIGNING_KEY_FILE")]
+    signing_key_file: Option<PathBuf>,
+
+    /// Path of a Unix socket accepting control commands (see `src/admin.rs`)
+    #[cfg(all(unix, feature = "unix-socket"))]
+    #[clap(long, conflicts_with = "inetd")]
+    admin_socket: Option<PathBuf>,
+
+    /// Path of a Unix socket where local users push their own info texts (see `src/push.rs`)
+    ///
+    /// Every local user can connect to it, but only replaces the users whose `uid` is its own.
+    #[cfg(all(unix, feature = "unix-socket"))]
+    #[clap(long, conflicts_with = "inetd")]
+    user_socket: Option<PathBuf>,
+
+    /// Check the config for changes every given number of seconds, and reload it if it changed
+    ///
+    /// Useful when the config file is replaced in ways that are hard to notice, e.g. Kubernetes
+    /// ConfigMap updates. Each delay is randomly shortened or lengthened by up to 10% so that
+    /// instances started together don't poll together.
+    #[clap(long, value_name = "SECONDS", conflicts_with = "inetd")]
+    poll_interval: Option<u64>,
+
+    /// Log a summary of the daemon's activity every given number of minutes
+    ///
+    /// It's also sent to systemd as the status of the service, see `src/heartbeat.rs`.
+    #[clap(long, value_name = "MINUTES", conflicts_with = "inetd")]
+    heartbeat_interval: Option<u64>,
+
+    /// Log the round-trip time and retransmissions of each TCP connection when it ends (Linux only)
+    ///
+    /// See `src/tcpinfo.rs`.
+    #[clap(long, conflicts_with = "inetd")]
+    log_tcp_info: bool,
+
+    /// Send a copy of each finger query to a shadow endpoint (`udp://host:port`, `tcp://host:port`,
+    /// or an HTTP(S) URL with the `remote-config` feature)
+    ///
+    /// Copies are sent in the background and dropped when the endpoint can't keep up, so that
+    /// replies are never delayed. See `src/mirror.rs`.
+    #[clap(long, value_name = "URL", conflicts_with = "inetd")]
+    mirror_to: Option<mirror::Endpoint>,
+
+    /// IP address or Unix socket path of an additional WHOIS listener (port 43 by default)
+    ///
+    /// It answers queries for the same users, see `src/whois.rs`.
+    #[clap(long, conflicts_with = "inetd", value_parser = clap::builder::OsStringValueParser::new().try_map(|str| AnySocketAddr::parse_with_default_port(&str, whois::WHOIS_PORT)))]
+    whois_bind_to: Option<AnySocketAddr>,
+
+    /// IP address and port of an HTTP listener serving the stats counters to Prometheus
+    ///
+    /// Scrape `/metrics` on it, see `src/metrics.rs`.
+    #[clap(long, value_name = "ADDRESS", conflicts_with = "inetd")]
+    metrics_bind_to: Option<SocketAddr>,
+
+    /// IP address and port of an additional listener answering finger queries over TLS
+    ///
+    /// Its certificates are set in the config, see `src/tls.rs`.
+    #[cfg(feature = "tls")]
+    #[clap(long, value_name = "ADDRESS", conflicts_with = "inetd")]
+    tls_bind_to: Option<SocketAddr>,
+
+    /// Query the daemon once it's listening, check its replies against the config, and exit
+    ///
+    /// The exit status tells whether the self-test succeeded.
+    #[clap(long, conflicts_with = "inetd")]
+    self_test: bool,
+
+    /// Kind of async runtime: one thread, enough for small machines, or a pool of worker threads
+    #[clap(long, value_enum, default_value = "multi-thread")]
+    runtime: RuntimeFlavor,
+
+    /// Number of worker threads of the multi-thread runtime (default: one per CPU core)
+    ///
+    /// The current-thread runtime ignores it.
+    #[clap(long, value_name = "COUNT")]
+    worker_threads: Option<NonZeroUsize>,
+
+    /// Max number of extra threads for blocking work like reading files (default: 512)
+    #[clap(long, value_name = "COUNT")]
+    max_blocking_threads: Option<NonZeroUsize>,
 }
 
-#[tokio::main]
-async fn main() {
-    let args = Args::parse();
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+enum RuntimeFlavor {
+    /// Everything runs on the main thread
+    CurrentThread,
+    /// Connections are spread over worker threads
+    MultiThread,
+}
 
-    if args.inetd {
-        main_inetd(args).await
-    } else {
-        tracing_subscriber::fmt()
-            .with_env_filter(EnvFilter::from_default_env())
-            .init();
+#[derive(Subcommand)]
+enum Command {
+    /// Print a new random key for encrypting config values
+    GenerateKey,
 
-        main_daemon(args).await
-    }
+    /// Encrypt standard input into an `enc:` value that can be pasted in `users.toml`
+    Encrypt,
+
+    /// Print a new random key for signing replies
+    GenerateSigningKey,
+
+    /// Print the minisign public key matching `--signing-key-file`
+    SigningPublicKey,
+
+    /// Print a `users.toml` made of the system's accounts and their `~/.plan` and `~/.project` files
+    ImportSystem {
+        /// Path of the file listing the accounts
+        #[clap(long, default_value = "/etc/passwd")]
+        passwd_file: PathBuf,
+
+        /// Lowest UID of the accounts to import
+        #[clap(long, default_value_t = 1000)]
+        min_uid: u32,
+
+        /// Highest UID of the accounts to import
+        #[clap(long, default_value_t = 60000)]
+        max_uid: u32,
+    },
+
+    /// Render the reply for every user to a directory of static files
+    Export {
+        /// Directory to write the files to, created if needed
+        dir: PathBuf,
+
+        #[clap(long, value_enum, default_value = "text")]
+        format: export::Format,
+    },
+
+    /// Send the requests of an audit log again, and report replies that changed
+    ///
+    /// Requests are handled in-process with the current config, unless `--target` is given.
+    Replay {
+        /// Path of the audit log (see `--audit-log`)
+        audit_log: PathBuf,
+
+        /// Address or Unix socket path of a running server to query instead
+        #[clap(long, value_parser = clap::builder::OsStringValueParser::new().try_map(|str| AnySocketAddr::try_from(str.as_ref())))]
+        target: Option<AnySocketAddr>,
+    },
+
+    /// Install this executable as a service, with `--users-file` created from an example
+    ///
+    /// See `src/install.rs` for what's installed for each init system.
+    Install {
+        /// Directory the files are installed under, e.g. the staging directory of a package
+        #[clap(long, default_value = "/")]
+        root: PathBuf,
+
+        /// Directory the executable is copied to
+        #[clap(long, default_value = "/usr/local/bin")]
+        bin_dir: PathBuf,
+
+        /// Init system to install a service for (default: the one of this machine)
+        #[clap(long, value_enum)]
+        init: Option<install::Init>,
+
+        /// Don't give the executable the capability to bind port 79 (only done for OpenRC)
+        #[clap(long)]
+        no_setcap: bool,
+    },
+
+    /// Print the replies to the short and verbose queries for a user, as answered with the config
+    Render {
+        /// Name of the user, or `<user>@<domain>` for a user of a namespace
+        user: String,
+
+        /// IP address of the client to answer as, e.g. to see internal sections (default: a local
+        /// client)
+        #[clap(long, value_name = "IP")]
+        from: Option<IpAddr>,
+
+        /// Print each line quoted, with its CR and LF and any control characters escaped
+        #[clap(long)]
+        escape: bool,
+    },
 }
 
-async fn main_daemon(args: Args) {
-    info!("starting daemon");
+fn main() -> ExitCode {
+    #[allow(unused_mut)]
+    let mut args = Args::parse();
 
-    let mut listen_fd = ListenFd::from_env();
-    let (server, local_addr) = if let Some(bind_to) = args.bind_to {
-        let server = match AnyListener::bind(&bind_to).await {
-            Ok(server) => server,
+    let secret_key = match args.secret_key() {
+        Ok(secret_key) => secret_key,
+        Err(err) => {
+            eprintln!("cannot load secret key: {err}");
+            std::process::exit(1);
+        }
+    };
+
+    let signer = match args.signer() {
+        Ok(signer) => signer,
+        Err(err) => {
+            eprintln!("cannot load signing key: {err}");
+            std::process::exit(1);
+        }
+    };
+
+    match &args.command {
+        Some(Command::GenerateKey) => {
+            println!("{}", secret::generate_key());
+            return ExitCode::SUCCESS;
+        }
+        Some(Command::Encrypt) => {
+            let Some(secret_key) = secret_key else {
+                eprintln!("a secret key is required to encrypt values");
+                std::process::exit(1);
+            };
+            let plaintext = io::read_to_string(io::stdin()).unwrap();
+            println!("{}", secret::encrypt(&secret_key, &plaintext));
+            return ExitCode::SUCCESS;
+        }
+        Some(Command::GenerateSigningKey) => {
+            println!("{}", signing::generate_key());
+            return ExitCode::SUCCESS;
+        }
+        Some(Command::SigningPublicKey) => {
+            let Some(signer) = signer else {
+                eprintln!("a signing key is required to print its public key");
+                std::process::exit(1);
+            };
+            println!("{}", signer.public_key());
+            return ExitCode::SUCCESS;
+        }
+        Some(Command::ImportSystem {
+            passwd_file,
+            min_uid,
+            max_uid,
+        }) => {
+            match import::run(passwd_file, *min_uid..=*max_uid) {
+                Ok(toml) => print!("{toml}"),
+                Err(err) => {
+                    eprintln!("cannot read {}: {err}", passwd_file.display());
+                    std::process::exit(1);
+                }
+            }
+            return ExitCode::SUCCESS;
+        }
+        Some(Command::Install {
+            root,
+            bin_dir,
+            init,
+            no_setcap,
+        }) => {
+            let options = install::Options {
+                root,
+                bin_dir,
+                users_file: &args.users_file,
+                init: init.unwrap_or_else(install::Init::detect),
+                setcap: !no_setcap,
+            };
+            if let Err(err) = install::run(&options) {
+                eprintln!("cannot install: {err}");
+                std::process::exit(1);
+            }
+            return ExitCode::SUCCESS;
+        }
+        Some(Command::Export { .. } | Command::Replay { .. } | Command::Render { .. }) | None => {}
+    }
+
+    if let Some(secret_key) = secret_key {
+        secret::set_key(secret_key);
+    }
+
+    if let Some(signer) = signer {
+        signing::set_signer(signer);
+    }
+
+    #[cfg(all(unix, feature = "daemonize"))]
+    let workers = match args.workers {
+        _ if workers::is_worker() => {
+            args.become_worker();
+            None
+        }
+        Some(count) => match workers::Workers::new(count) {
+            Ok(workers) => Some(workers),
             Err(err) => {
-                error!("cannot bind to {}: {err}", bind_to);
-                return;
+                eprintln!("cannot prepare workers: {err}");
+                std::process::exit(1);
             }
+        },
+        None => None,
+    };
+
+    // Forking is only sound while the process is single-threaded, so this must happen before the
+    // runtime is started
+    #[cfg(all(unix, feature = "daemonize"))]
+    if args.daemonize {
+        if let Err(err) = args.make_paths_absolute() {
+            eprintln!("cannot resolve paths: {err}");
+            std::process::exit(1);
+        }
+
+        if let Err(err) = daemon::daemonize(&args.daemon_log_file) {
+            eprintln!("cannot daemonize: {err}");
+            std::process::exit(1);
+        }
+    }
+
+    let mut runtime = match args.runtime {
+        RuntimeFlavor::CurrentThread => tokio::runtime::Builder::new_current_thread(),
+        RuntimeFlavor::MultiThread => tokio::runtime::Builder::new_multi_thread(),
+    };
+    if let Some(worker_threads) = args.worker_threads {
+        runtime.worker_threads(worker_threads.get());
+    }
+    if let Some(max_blocking_threads) = args.max_blocking_threads {
+        runtime.max_blocking_threads(max_blocking_threads.get());
+    }
+
+    runtime.enable_all().build().unwrap().block_on(async {
+        if let Some(Command::Replay { audit_log, target }) = &args.command {
+            main_replay(&args, audit_log, target.as_ref()).await
+        } else if let Some(Command::Export { dir, format }) = &args.command {
+            main_export(&args, dir, *format).await
+        } else if let Some(Command::Render { user, from, escape }) = &args.command {
+            main_render(&args, user, *from, *escape).await
+        } else if args.inetd {
+            // The standard output is the socket, so it must never receive logs
+            logging::init_stderr();
+            main_inetd(args).await
+        } else {
+            logging::init();
+            #[cfg(all(unix, feature = "daemonize"))]
+            if let Some(workers) = workers {
+                return main_supervisor(args, workers).await;
+            }
+            main_daemon(args).await
+        }
+    })
+}
+
+impl Args {
+    fn config_source(&self) -> ConfigSource {
+        #[cfg(feature = "remote-config")]
+        if let Some(url) = &self.users_url {
+            let remote =
+                source::remote::RemoteSource::new(url.clone(), self.users_cache_file.clone());
+            return ConfigSource::url(remote).with_overrides(self.overrides());
+        }
+
+        ConfigSource::file(self.users_file.clone()).with_overrides(self.overrides())
+    }
+
+    /// Values given by `--set` and its shorthands, to apply over the config
+    fn overrides(&self) -> Vec<Override> {
+        let mut overrides = self.set.clone();
+        if let Some(enable_index) = self.enable_index {
+            overrides.push(Override::new("enable-index", enable_index));
+        }
+        overrides
+    }
+
+    /// Read the key given by `--secret-key` or `--secret-key-file`, if any
+    fn secret_key(&self) -> Result<Option<chacha20poly1305::Key>, Box<dyn std::error::Error>> {
+        let encoded = match (&self.secret_key, &self.secret_key_file) {
+            (Some(secret_key), _) => secret_key.clone(),
+            (None, Some(path)) => std::fs::read_to_string(path)?,
+            (None, None) => return Ok(None),
         };
 
-        (server, bind_to)
-    } else if let Ok(Some(tcp)) = listen_fd.take_tcp_listener(0) {
-        let local_addr = tcp.local_addr().unwrap();
-        let listener = TcpListener::from_std(tcp).unwrap();
-        info!("tcp socket descriptor given on LISTEN_FDS, listening on it");
-        (listener.into(), local_addr.into())
+        Ok(Some(secret::parse_key(&encoded)?))
+    }
+
+    /// Read the key given by `--signing-key-file`, if any
+    fn signer(&self) -> Result<Option<signing::Signer>, Box<dyn std::error::Error>> {
+        match &self.signing_key_file {
+            Some(path) => Ok(Some(signing::Signer::parse(&std::fs::read_to_string(
+                path,
+            )?)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Resolve the paths given on the command line against the current working directory
+    #[cfg(all(unix, feature = "daemonize"))]
+    fn make_paths_absolute(&mut self) -> io::Result<()> {
+        self.users_file = std::path::absolute(&self.users_file)?;
+        #[cfg(feature = "remote-config")]
+        if let Some(users_cache_file) = &mut self.users_cache_file {
+            *users_cache_file = std::path::absolute(&users_cache_file)?;
+        }
+        if let Some(pid_file) = &mut self.pid_file {
+            *pid_file = std::path::absolute(&pid_file)?;
+        }
+        if let Some(audit_log) = &mut self.audit_log {
+            *audit_log = std::path::absolute(&audit_log)?;
+        }
+        #[cfg(feature = "unix-socket")]
+        if let Some(admin_socket) = &mut self.admin_socket {
+            *admin_socket = std::path::absolute(&admin_socket)?;
+        }
+        #[cfg(feature = "unix-socket")]
+        if let Some(user_socket) = &mut self.user_socket {
+            *user_socket = std::path::absolute(&user_socket)?;
+        }
+        #[cfg(feature = "unix-socket")]
+        if let Some(AnySocketAddr::Unix(path)) = &mut self.bind_to {
+            *path = std::path::absolute(&path)?;
+        }
+        #[cfg(feature = "seqpacket")]
+        if let Some(AnySocketAddr::SeqPacket(path)) = &mut self.bind_to {
+            *path = std::path::absolute(&path)?;
+        }
+        #[cfg(feature = "unix-socket")]
+        if let Some(AnySocketAddr::Unix(path)) = &mut self.whois_bind_to {
+            *path = std::path::absolute(&path)?;
+        }
+        Ok(())
+    }
+
+    /// Take the listeners of the supervisor through socket activation instead of binding them,
+    /// and leave the pid file and daemonization to it
+    #[cfg(all(unix, feature = "daemonize"))]
+    fn become_worker(&mut self) {
+        self.bind_to = None;
+        self.fallback_port = None;
+        self.listen_fd_name = None;
+        self.whois_bind_to = None;
+        self.metrics_bind_to = None;
+        #[cfg(feature = "tls")]
+        {
+            self.tls_bind_to = None;
+        }
+        self.pid_file = None;
+        self.daemonize = false;
+        self.workers = None;
+    }
+}
+
+/// Accept a connection on `listener`, or wait forever if there's none
+async fn accept_optional(listener: Option<&AnyListener>) -> io::Result<AnySocket> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Bind the finger listener, or take it from socket activation
+async fn finger_listener(
+    args: &Args,
+    activation: &mut Activation,
+) -> Result<(AnyListener, AnySocketAddr), ()> {
+    let finger_fd = match &args.listen_fd_name {
+        Some(name) => match activation.index(name) {
+            Some(index) => index,
+            None => {
+                error!("no socket descriptor named {name:?} in LISTEN_FDNAMES");
+                return Err(());
+            }
+        },
+        None => activation.index("finger").unwrap_or(0),
+    };
+
+    if let Some(bind_to) = args.bind_to.clone() {
+        let retry_for = Duration::from_secs(args.bind_retry_timeout);
+        bind_with_fallback(bind_to, args.fallback_port, retry_for).await
+    } else if let Ok(Some(bound)) = activation.take_listener(finger_fd) {
+        info!("socket descriptor given on LISTEN_FDS, listening on it");
+        Ok(bound)
     } else {
         // Fake a missing argument
         Args::parse_from(["", "--help"]);
         unreachable!();
+    }
+}
+
+/// Bind the WHOIS listener, or take it from socket activation, if there's one
+async fn whois_listener(
+    args: &Args,
+    activation: &mut Activation,
+) -> Result<Option<(AnyListener, AnySocketAddr)>, ()> {
+    if let Some(whois_bind_to) = &args.whois_bind_to {
+        let retry_for = Duration::from_secs(args.bind_retry_timeout);
+        match bind_retrying(whois_bind_to, retry_for).await {
+            Ok(listener) => Ok(Some((listener, whois_bind_to.clone()))),
+            Err(err) => {
+                error!("cannot bind whois listener to {whois_bind_to}: {err}");
+                Err(())
+            }
+        }
+    } else if let Some(index) = activation.index("whois") {
+        match activation.take_listener(index) {
+            Ok(Some(bound)) => Ok(Some(bound)),
+            _ => {
+                error!("socket descriptor \"whois\" isn't a tcp or unix socket");
+                Err(())
+            }
+        }
+    } else {
+        Ok(None)
+    }
+}
+
+/// Bind the listeners and run `workers` answering them, see [workers]
+#[cfg(all(unix, feature = "daemonize"))]
+async fn main_supervisor(args: Args, workers: workers::Workers) -> ExitCode {
+    info!("starting supervisor of {} workers", workers.count());
+
+    #[cfg(feature = "unix-socket")]
+    if args.admin_socket.is_some() || args.user_socket.is_some() {
+        error!("the admin and user sockets can't be used with workers");
+        return ExitCode::FAILURE;
+    }
+    if args.metrics_bind_to.is_some() {
+        error!("the metrics listener can't be used with workers");
+        return ExitCode::FAILURE;
+    }
+    #[cfg(feature = "tls")]
+    if args.tls_bind_to.is_some() {
+        error!("the TLS listener can't be used with workers");
+        return ExitCode::FAILURE;
+    }
+
+    let mut activation = Activation::from_env();
+    let Ok((server, local_addr)) = finger_listener(&args, &mut activation).await else {
+        return ExitCode::FAILURE;
+    };
+    let Ok(whois) = whois_listener(&args, &mut activation).await else {
+        return ExitCode::FAILURE;
+    };
+
+    let mut listeners = vec![("finger", server.activation_fd(), local_addr)];
+    listeners.extend(
+        whois
+            .iter()
+            .map(|(listener, addr)| ("whois", listener.activation_fd(), addr.clone())),
+    );
+    let mut fds = Vec::new();
+    for (name, fd, addr) in listeners {
+        let Some(fd) = fd else {
+            error!("the {name} listener on {addr} can't be shared with workers");
+            return ExitCode::FAILURE;
+        };
+        info!("{name} listening on {addr}");
+        fds.push((name, fd));
+    }
+
+    let shutdown_hooks = ShutdownHooks::default();
+    if let Some(pid_file) = &args.pid_file {
+        if let Err(err) = shutdown::create_pid_file(&shutdown_hooks, pid_file) {
+            error!("cannot write pid file {}: {err}", pid_file.display());
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let signals = Signals::new([SIGHUP, SIGINT, SIGQUIT, SIGTERM, SIGUSR1]).unwrap();
+    let exit_code = workers.supervise(&fds, signals).await;
+
+    shutdown_hooks.run();
+
+    info!("exited gracefully");
+
+    exit_code
+}
+
+async fn main_daemon(args: Args) -> ExitCode {
+    info!("starting daemon");
+
+    let mut activation = Activation::from_env();
+    let Ok((server, local_addr)) = finger_listener(&args, &mut activation).await else {
+        return ExitCode::FAILURE;
     };
 
     info!("listening on {}", local_addr);
 
-    let users_file = Arc::<Path>::from(args.users_file);
-    let users = tokio::fs::read_to_string(users_file.as_ref())
-        .await
-        .unwrap();
+    let shutdown_hooks = ShutdownHooks::default();
+    if let Some(pid_file) = &args.pid_file {
+        if let Err(err) = shutdown::create_pid_file(&shutdown_hooks, pid_file) {
+            error!("cannot write pid file {}: {err}", pid_file.display());
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let config_source = Arc::new(args.config_source());
+    let users = match config_source.read().await {
+        Ok(users) => users.unwrap(),
+        Err(err) => {
+            error!("cannot read config: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
 
-    let config = Arc::new(Config::new_parsed(&users).unwrap());
+    let clock = SharedClock::default();
+    let modified = config_source.modified().await;
+    let config = match Config::new_parsed(&users, modified, clock.clone()) {
+        Ok(config) => Arc::new(config),
+        Err(err) => {
+            error!("cannot parse config: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
     validate_config(config.get().await.as_ref());
+    log_loaded_config(&config).await;
+
+    if let Err(err) = logging::apply(&config.get().await.logging) {
+        error!("cannot configure logging: {err}");
+        return ExitCode::FAILURE;
+    }
+
+    let state = Arc::new(ServerState {
+        mirror: args.mirror_to.clone().map(Mirror::start),
+        ..ServerState::with_clock(clock)
+    });
+
+    // Services running as long as the daemon, and connections being answered
+    let mut background = Tasks::default();
+    let mut connections = Tasks::default();
+
+    let reloads = Arc::new(Reloads::default());
+    // Hash of the config source last loaded, so that polling doesn't load it again
+    let loaded_hash = Arc::new(AtomicU64::new(hash(&users)));
+    {
+        let reloads = Arc::clone(&reloads);
+        let config_source = Arc::clone(&config_source);
+        let config = Arc::clone(&config);
+        let state = Arc::clone(&state);
+        let loaded_hash = Arc::clone(&loaded_hash);
+        background.spawn("reload", async move {
+            (reloads.run(reload::DEBOUNCE, || {
+                let config = (&*config_source, &*config, &state.stats);
+                reload_config(config, &loaded_hash)
+            }))
+            .await
+        });
+    }
+
+    if let Some(poll_interval) = args.poll_interval {
+        let reloads = Arc::clone(&reloads);
+        let config_source = Arc::clone(&config_source);
+        let config = Arc::clone(&config);
+        let state = Arc::clone(&state);
+        let poll_interval = Duration::from_secs(poll_interval);
+        background.spawn("config polling", async move {
+            let config = (&*config_source, &*config, &state.stats);
+            poll_config(&reloads, config, poll_interval, &loaded_hash).await
+        });
+    }
+
+    if let Some(heartbeat_interval) = args.heartbeat_interval {
+        let state = Arc::clone(&state);
+        let heartbeat_interval = Duration::from_secs(heartbeat_interval * 60);
+        background.spawn("heartbeat", async move {
+            heartbeat::run(&state.stats, heartbeat_interval).await
+        });
+    }
+
+    #[cfg(feature = "kv-store")]
+    if let Some(user_store) = &args.user_store {
+        let watch = kvstore::watch(user_store.clone(), Arc::clone(&config));
+        background.spawn("user store", watch);
+    }
+
+    background.spawn("plans", plans::watch(Arc::clone(&config)));
+    let watch = state::watch_maintenance_file(Arc::clone(&config), Arc::clone(&state));
+    background.spawn("maintenance file", watch);
+
+    let audit_log = match &args.audit_log {
+        Some(path) => match AuditLog::open(path, args.audit_log_max_size).await {
+            Ok(audit_log) => Some(Arc::new(audit_log)),
+            Err(err) => {
+                error!("cannot open audit log {}: {err}", path.display());
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+
+    let total_write_limiter = Arc::new(FairLimiter::new(0, state.clock.clone()));
 
-    let mut signals = Signals::new([SIGHUP, SIGINT, SIGQUIT, SIGTERM]).unwrap();
+    #[cfg(feature = "testing")]
+    let chaos = chaos::Chaos::from_env();
+    let ban_list = Arc::new(BanList::default());
+
+    let mut signals = Signals::new([SIGHUP, SIGINT, SIGQUIT, SIGTERM, SIGUSR1]).unwrap();
+
+    #[cfg(all(unix, feature = "unix-socket"))]
+    if let Some(admin_socket) = &args.admin_socket {
+        let listener = match admin::bind(admin_socket) {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("cannot bind admin socket {}: {err}", admin_socket.display());
+                return ExitCode::FAILURE;
+            }
+        };
+
+        info!("admin socket listening on {}", admin_socket.display());
+
+        let admin_socket = admin_socket.clone();
+        shutdown_hooks.register("remove admin socket", move || {
+            let _ = std::fs::remove_file(admin_socket);
+        });
+
+        let serve = admin::serve(listener, Arc::clone(&config), Arc::clone(&state));
+        background.spawn("admin socket", serve);
+    } else if let Some(index) = activation.index("admin") {
+        let listener = match activation.take_unix_listener(index) {
+            Ok(Some(listener)) => tokio::net::UnixListener::from_std(listener).unwrap(),
+            _ => {
+                error!("socket descriptor \"admin\" isn't a unix socket");
+                return ExitCode::FAILURE;
+            }
+        };
+
+        info!("admin socket descriptor given on LISTEN_FDS, listening on it");
+        let serve = admin::serve(listener, Arc::clone(&config), Arc::clone(&state));
+        background.spawn("admin socket", serve);
+    }
+
+    #[cfg(all(unix, feature = "unix-socket"))]
+    if let Some(user_socket) = &args.user_socket {
+        let listener = match push::bind(user_socket) {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("cannot bind user socket {}: {err}", user_socket.display());
+                return ExitCode::FAILURE;
+            }
+        };
+
+        info!("user socket listening on {}", user_socket.display());
+
+        let user_socket = user_socket.clone();
+        shutdown_hooks.register("remove user socket", move || {
+            let _ = std::fs::remove_file(user_socket);
+        });
+
+        background.spawn("user socket", push::serve(listener, Arc::clone(&config)));
+    }
+
+    let Ok(whois_listener) = whois_listener(&args, &mut activation).await else {
+        return ExitCode::FAILURE;
+    };
+
+    if let Some((listener, whois_addr)) = whois_listener {
+        info!("whois listening on {whois_addr}");
+
+        let config = Arc::clone(&config);
+        let state = Arc::clone(&state);
+        background.spawn("whois", whois::serve(listener, config, state));
+    }
+
+    if let Some(metrics_bind_to) = args.metrics_bind_to {
+        let listener = match tokio::net::TcpListener::bind(metrics_bind_to).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("cannot bind metrics listener to {metrics_bind_to}: {err}");
+                return ExitCode::FAILURE;
+            }
+        };
+
+        info!("metrics listening on {metrics_bind_to}");
+        background.spawn("metrics", metrics::serve(listener, Arc::clone(&state)));
+    }
+
+    #[cfg(feature = "tls")]
+    let tls_listener = match args.tls_bind_to {
+        Some(tls_bind_to) => {
+            let certificates = match tls::Certificates::load(&config.get().await.tls).await {
+                Ok(certificates) => Arc::new(certificates),
+                Err(err) => {
+                    error!("cannot load TLS certificates: {err}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            let listener = match tokio::net::TcpListener::bind(tls_bind_to).await {
+                Ok(listener) => AnyListener::Tls(listener, certificates.acceptor()),
+                Err(err) => {
+                    error!("cannot bind TLS listener to {tls_bind_to}: {err}");
+                    return ExitCode::FAILURE;
+                }
+            };
+
+            info!("TLS listening on {tls_bind_to}");
+            let watch = tls::watch(Arc::clone(&config), certificates);
+            background.spawn("TLS certificates", watch);
+
+            #[cfg(feature = "acme")]
+            if config.get().await.tls.acme.is_some() {
+                if args.metrics_bind_to.is_none() {
+                    error!("ACME needs --metrics-bind-to to answer its HTTP-01 challenges");
+                    return ExitCode::FAILURE;
+                }
+                let renew = acme::renew(Arc::clone(&config), Arc::clone(&state));
+                background.spawn("ACME", renew);
+            }
+            Some(listener)
+        }
+        None => None,
+    };
+    #[cfg(not(feature = "tls"))]
+    let tls_listener = None;
+
+    // Logs are only written to the directory of the file given at startup
+    let log_file = config.get().await.logging.file.clone();
+    let fortune_files = config.get().await.fortune_files();
+    let mut readable = config_source.paths();
+    readable.extend(fortune_files.iter().map(PathBuf::as_path));
+    let maintenance_file = config.get().await.maintenance_file.clone();
+    readable.extend(maintenance_file.as_deref());
+    let plans_dir = config.get().await.plans_dir.clone();
+    readable.extend(plans_dir.as_deref());
+    #[cfg(feature = "scripting")]
+    let scripts = config.get().await.scripts.clone();
+    #[cfg(feature = "scripting")]
+    readable.extend(scripts.iter().map(PathBuf::as_path));
+    #[cfg(feature = "tls")]
+    let tls_paths = config.get().await.tls.paths();
+    #[cfg(feature = "tls")]
+    readable.extend(tls_paths.iter().map(PathBuf::as_path));
+    let audit_log_dir = args.audit_log.as_deref().map(log_dir);
+    #[allow(unused_mut)]
+    let mut writable = (args.pid_file.as_deref().into_iter())
+        .chain(audit_log_dir)
+        .chain(log_file.as_deref().map(log_dir))
+        .collect::<Vec<_>>();
+    #[cfg(all(unix, feature = "unix-socket"))]
+    writable.extend(args.admin_socket.as_deref());
+    #[cfg(all(unix, feature = "unix-socket"))]
+    writable.extend(args.user_socket.as_deref());
+    #[cfg(feature = "acme")]
+    let acme_config = config.get().await.tls.acme.clone();
+    #[cfg(feature = "acme")]
+    writable.extend(acme_config.as_ref().map(|acme| acme.state_dir.as_path()));
+    if let Err(err) = sandbox::restrict(&readable, &writable) {
+        error!("cannot restrict privileges: {err}");
+        return ExitCode::FAILURE;
+    }
+
+    let self_test = match args.self_test {
+        true => Some(tokio::task::spawn({
+            let local_addr = local_addr.clone();
+            let users = config.get().await;
+            async move { selftest::run(local_addr, &users).await }
+        })),
+        false => None,
+    };
+    let self_test = async move {
+        match self_test {
+            Some(self_test) => self_test.await.unwrap(),
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(self_test);
+
+    let mut exit_code = ExitCode::SUCCESS;
+    let log_tcp_info = args.log_tcp_info;
 
     loop {
         let client = select! { biased;
             Some(signal) = signals.next() => match signal {
                 SIGINT | SIGQUIT | SIGTERM => break,
                 SIGHUP => {
-                    let config = Arc::clone(&config);
-                    tokio::task::spawn(reload_config(Arc::clone(&users_file), config));
+                    reloads.request();
+                    continue;
+                },
+                SIGUSR1 => {
+                    match logging::cycle_level() {
+                        Ok(Some(level)) => warn!("log level forced to {level}"),
+                        Ok(None) => warn!("log level back to the config"),
+                        Err(err) => error!("cannot change log level: {err}"),
+                    }
                     continue;
                 },
                 _ => unreachable!()
             },
+            result = &mut self_test => {
+                match result {
+                    Ok(()) => info!("self-test passed"),
+                    Err(err) => {
+                        error!("self-test failed: {err}");
+                        exit_code = ExitCode::FAILURE;
+                    }
+                }
+                break;
+            },
+            name = background.join_next() => {
+                error!("{name} task stopped, the daemon keeps running without it");
+                continue;
+            },
+            _ = connections.join_next() => continue,
             accepted = server.accept() => accepted.unwrap(),
+            accepted = accept_optional(tls_listener.as_ref()) => match accepted {
+                Ok(client) => client,
+                Err(err) => {
+                    error!("cannot accept TLS connection: {err}");
+                    continue;
+                }
+            },
         };
 
-        let config = config.get().await;
-        tokio::task::spawn(async move {
-            let mut client = client;
-            let peer_display = client.peer_display();
-            let mut client = client.split();
+        let peer = client.peer();
+        if matches!(peer.ip(), Some(ip) if ban_list.is_banned(ip)) {
+            debug!("refused connection from banned peer {peer}");
+            continue;
+        }
+
+        let config = config.get_for(peer.ip()).await;
+        let timeout = Duration::from_secs(config.request_timeout);
+        let ctx = RequestContext::new(peer.transport(), &peer, peer.ip(), timeout);
+        total_write_limiter.set_rate(config.total_write_rate);
+        let total_write_limiter = Arc::clone(&total_write_limiter);
+        let state = Arc::clone(&state);
+        let audit_log = audit_log.clone();
+        let ban_list = Arc::clone(&ban_list);
+        connections.spawn("connection", async move {
+            let _active = state.stats.track_connection();
+            let mut socket = client;
+            if let Err(err) = ctx.enforce(socket.handshake()).await {
+                debug!("TLS handshake with {peer} failed: {err}");
+                state.stats.record_error();
+                return Err(err);
+            }
+            let mut client = socket.split();
             let (input, output) = client.as_parts();
-            handle_client(&peer_display, &config, input, output).await
+            let limiter = Arc::new(RateLimiter::new(config.write_rate, state.clock.clone()));
+            #[cfg_attr(feature = "testing", allow(unused_mut))]
+            let mut output =
+                Throttled::new(output, [limiter]).with_fair_share(total_write_limiter, peer.ip());
+
+            #[cfg(feature = "testing")]
+            if !chaos.before_connection().await {
+                return Ok(None);
+            }
+            #[cfg(feature = "testing")]
+            let mut output = chaos.wrap(output);
+
+            let result = match audit_log {
+                Some(audit_log) => {
+                    let mut input = Recording::new(input);
+                    let mut output = Recording::new(&mut output);
+                    let handling = handle_client(&ctx, &config, &state, &mut input, &mut output);
+                    let result = ctx.enforce(handling).await;
+                    let record = audit_log.record(
+                        &peer,
+                        config.generation,
+                        &input.recorded,
+                        &output.recorded,
+                    );
+                    if let Err(err) = record.await {
+                        error!("cannot write to audit log: {err}");
+                    }
+                    result
+                }
+                None => {
+                    let handling = handle_client(&ctx, &config, &state, input, &mut output);
+                    ctx.enforce(handling).await
+                }
+            };
+
+            match &result {
+                Ok(Some(denial)) => ban_list.report(&config.ban, &peer, *denial),
+                Ok(None) => {}
+                Err(_) => state.stats.record_error(),
+            }
+
+            if let (true, Some(socket)) = (log_tcp_info, socket.tcp_stream()) {
+                match tcpinfo::sample(socket) {
+                    Ok(info) => {
+                        info!(target: tcpinfo::LOG_TARGET, "connection from {peer} closed: {info}")
+                    }
+                    Err(err) => debug!("cannot read TCP info of {peer}: {err}"),
+                }
+            }
+
+            result
         });
     }
 
+    if !connections.is_empty() {
+        info!("waiting for {} connections to end", connections.len());
+        match connections.drain(SHUTDOWN_GRACE_PERIOD).await {
+            0 => {}
+            aborted => warn!("aborted {aborted} connections still open after the grace period"),
+        }
+    }
+
+    shutdown_hooks.run();
+
     info!("exited gracefully");
+
+    exit_code
+}
+
+async fn main_replay(args: &Args, audit_log: &Path, target: Option<&AnySocketAddr>) -> ExitCode {
+    if let Some(target) = target {
+        return replay::run(audit_log, replay::Target::Server(target)).await;
+    }
+
+    let users = match args.config_source().read().await {
+        Ok(users) => users.unwrap(),
+        Err(err) => {
+            eprintln!("cannot read config: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match config::Users::parse(&users) {
+        Ok(mut users) => {
+            users.set_generation(1, SystemTime::now());
+            replay::run(audit_log, replay::Target::InProcess(&users)).await
+        }
+        Err(err) => {
+            eprintln!("cannot parse config: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn main_export(args: &Args, dir: &Path, format: export::Format) -> ExitCode {
+    let users = match args.config_source().read().await {
+        Ok(users) => users.unwrap(),
+        Err(err) => {
+            eprintln!("cannot read config: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut users = match config::Users::parse(&users) {
+        Ok(users) => users,
+        Err(err) => {
+            eprintln!("cannot parse config: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    users.set_generation(1, SystemTime::now());
+
+    match export::run(&users, dir, format) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("cannot export to {}: {err}", dir.display());
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn main_render(args: &Args, user: &str, from: Option<IpAddr>, escape: bool) -> ExitCode {
+    let users = match args.config_source().read().await {
+        Ok(users) => users.unwrap(),
+        Err(err) => {
+            eprintln!("cannot read config: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut users = match config::Users::parse(&users) {
+        Ok(users) => users,
+        Err(err) => {
+            eprintln!("cannot parse config: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    users.set_generation(1, SystemTime::now());
+
+    preview::run(&users, user, from, escape).await
 }
 
-async fn main_inetd(_args: Args) {
+async fn main_inetd(args: Args) -> ExitCode {
     let mut input = tokio::io::stdin();
     let mut output = tokio::io::stdout();
 
-    // We're not bothering with the async runtime
-    let users = std::fs::read_to_string("./users.toml").unwrap();
-    let users = toml::from_str::<config::Users>(&users).unwrap();
-    handle_client(&"inetd", &users, &mut input, &mut output)
-        .await
-        .unwrap();
+    let users = match args.config_source().read().await {
+        Ok(users) => users.unwrap(),
+        Err(err) => {
+            error!("cannot read config: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut users = match config::Users::parse(&users) {
+        Ok(users) => users,
+        Err(err) => {
+            error!("cannot parse config: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    users.set_generation(1, SystemTime::now());
+
+    let audit_log = match &args.audit_log {
+        Some(path) => match AuditLog::open(path, args.audit_log_max_size).await {
+            Ok(audit_log) => Some(audit_log),
+            Err(err) => {
+                error!("cannot open audit log {}: {err}", path.display());
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+
+    // Checked once, before the sandbox hides the file
+    let state = ServerState::default();
+    state.check_maintenance_file(&users).await;
+
+    let audit_log_dir = args.audit_log.as_deref().map(log_dir);
+    if let Err(err) = sandbox::restrict(&[], audit_log_dir.as_slice()) {
+        error!("cannot restrict privileges: {err}");
+        return ExitCode::FAILURE;
+    }
+    if audit_log.is_none() && !users.has_upstreams() {
+        if let Err(err) = sandbox::enter_capability_mode() {
+            error!("cannot enter capability mode: {err}");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let limiter = Arc::new(RateLimiter::new(users.write_rate, state.clock.clone()));
+    let mut output = Throttled::new(&mut output, [limiter]);
+    let timeout = Duration::from_secs(users.request_timeout);
+    let ctx = RequestContext::new("inetd", &"inetd", None, timeout);
+
+    let result = if let Some(audit_log) = audit_log {
+        let mut input = Recording::new(&mut input);
+        let mut output = Recording::new(&mut output);
+        let result = ctx
+            .enforce(handle_client(&ctx, &users, &state, &mut input, &mut output))
+            .await;
+        let record = audit_log.record(
+            &"inetd",
+            users.generation,
+            &input.recorded,
+            &output.recorded,
+        );
+        if let Err(err) = record.await {
+            error!("cannot write to audit log: {err}");
+        }
+        result
+    } else {
+        ctx.enforce(handle_client(&ctx, &users, &state, &mut input, &mut output))
+            .await
+    };
+
+    match result {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(err) => {
+            error!("cannot answer the client: {err}");
+            ExitCode::FAILURE
+        }
+    }
 }
 
-#[instrument(skip_all, fields(peer = %_peer))]
+#[instrument(
+    skip_all,
+    fields(request = ctx.id, listener = ctx.listener, peer = %ctx.peer, config = field::Empty),
+)]
 async fn handle_client(
-    _peer: &(dyn std::fmt::Display + Sync),
+    ctx: &RequestContext,
     users: &(dyn Borrow<config::Users> + Sync),
+    state: &ServerState,
     input: &mut (dyn AsyncRead + Send + Unpin),
     output: &mut (dyn AsyncWrite + Send + Unpin),
-) -> io::Result<()> {
-    debug!("incoming request");
+) -> io::Result<Option<Denial>> {
     let users = users.borrow();
-    let mut reader = BufReader::new(input.take(SANE_REQUEST_LENGTH));
-    let mut writer = BufWriter::new(output);
-    let mut buffer = Vec::with_capacity(32);
-    reader.read_until(b'\n', &mut buffer).await?;
-    let buffer = std::str::from_utf8(&buffer).unwrap();
-    let req = Request::from_str(buffer).unwrap();
-
-    if req.forwarding.is_some() {
-        writer.write_all(REPLY_NO_FORWARDING).await?;
-    } else if let Some(username) = req.user {
-        if let Some(user) = users.find(username) {
-            debug!("requested user {username:?}");
-
-            let info = match req.verbose {
-                false => user.info(),
-                true => user.long_info(),
-            };
+    Span::current().record("config", users.generation);
+    debug!("incoming request");
 
-            writer.write_all(info.as_bytes()).await?;
-        } else {
-            debug!("requested nonexistent user {username:?}");
-            writer.write_all(REPLY_USER_NOT_FOUND).await?;
-        }
-    } else {
-        debug!("requested user list");
-        if users.enable_index {
-            for (name, user) in &users.users {
-                if !user.unlisted {
-                    writer.write_all(name.as_bytes()).await?;
-                    writer.write_all(b"\r\n").await?;
-                }
-            }
-        } else {
-            debug!("user list denied by config");
-            writer.write_all(REPLY_NO_LISTING).await?;
-        }
-    }
+    let Some(charset) = users.validate_replies else {
+        return Router::new(ctx, users, state).handle(input, output).await;
+    };
 
-    writer.flush().await.unwrap();
+    let mut input = Recording::new(input);
+    let mut output = Recording::new(output);
+    let router = Router::new(ctx, users, state);
+    let result = router.handle(&mut input, &mut output).await;
+    // Clients asking for UTF-8 get it, whatever charset replies are expected to use
+    let charset = match Request::asks_for_utf8(&input.recorded) {
+        true => validate::Charset::Utf8,
+        false => charset,
+    };
+    for problem in validate::check(&output.recorded, charset) {
+        let request = Escaped(&input.recorded);
+        warn!("invalid reply to {request}: {problem}");
+    }
+    result
+}
 
-    Ok(())
+/// Directory containing a log file, where rotated logs are also created
+fn log_dir(log_file: &Path) -> &Path {
+    log_file
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
 }
 
 fn validate_config(users: &config::Users) {
+    if let Some(stats_target) = &users.stats_target {
+        if users.users.contains_key(stats_target) {
+            warn!("user {stats_target:?} is shadowed by the stats target");
+        }
+    }
+    let Languages {
+        default,
+        tcp,
+        unix,
+        inetd,
+        whois,
+    } = &users.language;
+    for language in [default, tcp, unix, inetd, whois].into_iter().flatten() {
+        if !users.messages.contains_key(language) {
+            warn!("language {language:?} has no messages, English ones are sent instead");
+        }
+    }
+    if let Some(help_target) = &users.help_target {
+        if users.users.contains_key(help_target) {
+            warn!("user {help_target:?} is shadowed by the help target");
+        }
+    }
+
     for (name, user) in &users.users {
+        if users.tag_listing(name).is_some() {
+            warn!("user {name:?} is shadowed by the tag listing prefix");
+        }
+        if users.listing_page_number(name).is_some() {
+            warn!("user {name:?} is shadowed by the listing page prefix");
+        }
+        // Transliterated for legacy clients otherwise
+        if let validate::Charset::Ascii = users.legacy_charset {
+            continue;
+        }
         if matches!(&user.info, Some(info) if !info.is_ascii()) {
-            warn!("user {name:?}'s info contains non-ASCII characters; most clients won't render them correctly")
+            warn!("user {name:?}'s info contains non-ASCII characters; most clients won't render them correctly (see legacy-charset)")
         }
         if matches!(&user.long_info, Some(info) if !info.is_ascii()) {
-            warn!("user {name:?}'s long-info contains non-ASCII characters; most clients won't render them correctly")
+            warn!("user {name:?}'s long-info contains non-ASCII characters; most clients won't render them correctly (see legacy-charset)")
         }
     }
+
+    for (domain, namespace) in &users.domains {
+        let _span = info_span!("namespace", domain).entered();
+        validate_config(namespace);
+    }
+}
+
+/// Bind to `bind_to`, or to `fallback_port` on the same address if binding to a TCP port is denied
+///
+/// Errors are logged, with advice when the port is privileged.
+async fn bind_with_fallback(
+    bind_to: AnySocketAddr,
+    fallback_port: Option<u16>,
+    retry_for: Duration,
+) -> Result<(AnyListener, AnySocketAddr), ()> {
+    let err = match bind_retrying(&bind_to, retry_for).await {
+        Ok(server) => return Ok((server, bind_to)),
+        Err(err) => err,
+    };
+
+    let mut addr = match &bind_to {
+        AnySocketAddr::Tcp(addr) if err.kind() == std::io::ErrorKind::PermissionDenied => *addr,
+        _ => {
+            error!("cannot bind to {bind_to}: {err}");
+            return Err(());
+        }
+    };
+
+    let advice = "ports below 1024 need root or the CAP_NET_BIND_SERVICE capability (e.g. `setcap cap_net_bind_service=+ep fingered`)";
+    let Some(fallback_port) = fallback_port else {
+        error!("cannot bind to {bind_to}: {err}; {advice}, or use --fallback-port");
+        return Err(());
+    };
+
+    warn!("cannot bind to {bind_to}: {err}; {advice}");
+    addr.set_port(fallback_port);
+    let fallback = AnySocketAddr::Tcp(addr);
+    match bind_retrying(&fallback, retry_for).await {
+        Ok(server) => {
+            warn!("falling back to {fallback}");
+            Ok((server, fallback))
+        }
+        Err(err) => {
+            error!("cannot bind to fallback {fallback}: {err}");
+            Err(())
+        }
+    }
+}
+
+/// Bind to `bind_to`, trying again for up to `retry_for` while a listener uses the address
+///
+/// Errors caused by an address in use tell whether a listener uses it, or if it's a stale Unix
+/// socket file.
+async fn bind_retrying(bind_to: &AnySocketAddr, retry_for: Duration) -> io::Result<AnyListener> {
+    let deadline = tokio::time::Instant::now() + retry_for;
+    let mut delay = BIND_RETRY_FIRST_DELAY;
+
+    loop {
+        let err = match AnyListener::bind(bind_to).await {
+            Ok(listener) => return Ok(listener),
+            Err(err) if err.kind() == io::ErrorKind::AddrInUse => err,
+            Err(err) => return Err(err),
+        };
+
+        if bind_to.probe_in_use().await == InUse::Stale {
+            let advice = "nothing listens on the socket file, remove it if it was left by a daemon that didn't exit gracefully";
+            return Err(io::Error::new(err.kind(), format!("{err}; {advice}")));
+        }
+
+        let left = deadline.saturating_duration_since(tokio::time::Instant::now());
+        if left.is_zero() {
+            let advice = "another process is listening on it";
+            return Err(io::Error::new(err.kind(), format!("{err}; {advice}")));
+        }
+
+        warn!("{bind_to} is in use, trying again in {delay:?}");
+        tokio::time::sleep(delay.min(left)).await;
+        delay = (delay * 2).min(BIND_RETRY_MAX_DELAY);
+    }
 }
 
+/// Config source, live config and stats updated by reloads
+type Reloaded<'a> = (&'a ConfigSource, &'a Config, &'a Stats);
+
 #[instrument(skip_all)]
-async fn reload_config(config_file_path: impl AsRef<Path>, config: impl Borrow<Config>) {
+async fn reload_config(reloaded: Reloaded<'_>, loaded_hash: &AtomicU64) {
     info!("reloading config");
 
-    let source = match tokio::fs::read_to_string(config_file_path.as_ref()).await {
-        Ok(source) => source,
+    let source = match reloaded.0.read().await {
+        Ok(Some(source)) => source,
+        Ok(None) => {
+            info!("config unchanged");
+            return;
+        }
         Err(err) => {
-            error!("cannot open config file: {err}");
+            error!("cannot read config: {err}");
             return;
         }
     };
 
-    if let Err(err) = config.borrow().load(&source).await {
-        error!("cannot parse config file: {err}");
+    load_config(reloaded, &source, loaded_hash).await;
+}
+
+/// Load `source`, read from the config source, as the live config, and remember its hash in
+/// `loaded_hash`
+async fn load_config(
+    (config_source, config, stats): Reloaded<'_>,
+    source: &str,
+    loaded_hash: &AtomicU64,
+) {
+    let modified = config_source.modified().await;
+    match config.load(source, modified).await {
+        Ok(()) => {
+            loaded_hash.store(hash(source), Ordering::Relaxed);
+            stats.record_reload();
+            apply_logging(config).await;
+            log_loaded_config(config).await;
+        }
+        Err(err) => error!("cannot parse config file: {err}"),
     }
 }
+
+/// Reload the config whenever its content changes, checking every `interval` (give or take 10%)
+#[instrument(skip_all)]
+async fn poll_config(
+    reloads: &Reloads,
+    reloaded: Reloaded<'_>,
+    interval: Duration,
+    loaded_hash: &AtomicU64,
+) {
+    loop {
+        let jitter = random(2001);
+        let factor = 0.9 + jitter as f64 / 10000.0;
+        tokio::time::sleep(interval.mul_f64(factor)).await;
+
+        let source = match reloaded.0.read().await {
+            Ok(Some(source)) => source,
+            Ok(None) => continue,
+            Err(err) => {
+                warn!("cannot read config: {err}");
+                continue;
+            }
+        };
+
+        if hash(&source) == loaded_hash.load(Ordering::Relaxed) {
+            continue;
+        }
+
+        info!("config changed, reloading");
+        (reloads.exclusive(load_config(reloaded, &source, loaded_hash))).await;
+    }
+}
+
+/// Random number in `0..bound`, not suitable for cryptography
+fn random(bound: u64) -> u64 {
+    RandomState::new().build_hasher().finish() % bound
+}
+
+/// Reconfigure logging after a reload, keeping the previous setup if that fails
+async fn apply_logging(config: &Config) {
+    if let Err(err) = logging::apply(&config.get().await.logging) {
+        error!("cannot configure logging: {err}");
+    }
+}
+
+/// Log the generation of the loaded users and their estimated memory, see [memory]
+async fn log_loaded_config(config: &Config) {
+    let users = config.get().await;
+    let size = memory::Bytes(users.estimated_size());
+    info!(
+        "loaded config generation {}, users take about {size} of memory",
+        users.generation
+    );
+}
+
+fn hash(source: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    source.hash(&mut hasher);
+    hasher.finish()
+}
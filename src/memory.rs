@@ -0,0 +1,101 @@
+//! Interning of the strings repeated across users, and estimate of the memory used by users
+//!
+//! Configs with many users, often filled from a [crate::backend], repeat the same tags and info
+//! texts. Once interned, equal strings share a single allocation, which the snapshots of the users
+//! kept by [crate::config::Config] share too instead of copying it. The estimated size of the users
+//! is logged when the config is loaded, and can be capped with [crate::config::Limits::max_memory].
+
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+/// Strings interned so far, see [Interner::intern]
+#[derive(Default)]
+pub struct Interner(HashSet<Arc<str>>);
+
+impl Interner {
+    /// Make `string` point to the interned string equal to it, interning it if there's none
+    pub fn intern(&mut self, string: &mut Arc<str>) {
+        match self.0.get(string) {
+            Some(interned) => *string = Arc::clone(interned),
+            None => {
+                self.0.insert(Arc::clone(string));
+            }
+        }
+    }
+}
+
+/// Running estimate of the heap memory used by some values, counting shared allocations once
+#[derive(Default)]
+pub struct Estimate {
+    bytes: usize,
+    counted: HashSet<*const u8>,
+}
+
+impl Estimate {
+    /// Count `size` bytes owned by a value
+    pub fn add(&mut self, size: usize) {
+        self.bytes += size;
+    }
+
+    /// Count the content of `shared` unless it was already counted, returning whether it was new
+    pub fn add_shared<T: ?Sized>(&mut self, shared: &Arc<T>) -> bool {
+        let new = self.counted.insert(Arc::as_ptr(shared).cast());
+        if new {
+            self.bytes += size_of_val::<T>(shared);
+        }
+        new
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+/// Number of bytes displayed with a binary unit, e.g. `1.5 MiB`
+pub struct Bytes(pub usize);
+
+impl Display for Bytes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+        if self.0 < 1024 {
+            return write!(f, "{} B", self.0);
+        }
+
+        let mut size = self.0 as f64 / 1024.0;
+        let mut unit = 0;
+        while size >= 1024.0 && unit < UNITS.len() - 1 {
+            size /= 1024.0;
+            unit += 1;
+        }
+        write!(f, "{size:.1} {}", UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interns_equal_strings() {
+        let mut interner = Interner::default();
+        let mut a = Arc::<str>::from("staff");
+        let mut b = Arc::<str>::from("staff");
+        interner.intern(&mut a);
+        interner.intern(&mut b);
+        assert!(Arc::ptr_eq(&a, &b));
+
+        let mut estimate = Estimate::default();
+        assert!(estimate.add_shared(&a));
+        assert!(!estimate.add_shared(&b));
+        assert_eq!(estimate.bytes(), 5);
+    }
+
+    #[test]
+    fn displays_sizes() {
+        assert_eq!(Bytes(512).to_string(), "512 B");
+        assert_eq!(Bytes(1536).to_string(), "1.5 KiB");
+        assert_eq!(Bytes(3 << 20).to_string(), "3.0 MiB");
+    }
+}
@@ -0,0 +1,147 @@
+//! HTTP listener exposing the counters of [crate::stats] to Prometheus
+//!
+//! It answers `GET /metrics` with the [Prometheus text format][format], from the same counters as
+//! the stats finger target, and every other request with `404 Not Found`. Only the request line is
+//! looked at, and a connection is closed after each reply.
+//!
+//! With the `acme` feature, it also answers the HTTP-01 challenges of the ACME server under
+//! `/.well-known/acme-challenge/`, see [crate::acme].
+//!
+//! [format]: https://prometheus.io/docs/instrumenting/exposition_formats/
+
+use crate::state::ServerState;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Max length of the request head, past which the connection is closed
+const SANE_REQUEST_LENGTH: u64 = 8192;
+
+/// Time given to scrapers to send their request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accept and serve scrapes forever
+pub async fn serve(listener: TcpListener, state: Arc<ServerState>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                error!("cannot accept metrics connection: {err}");
+                continue;
+            }
+        };
+
+        let state = Arc::clone(&state);
+        tokio::task::spawn(async move {
+            match tokio::time::timeout(REQUEST_TIMEOUT, handle(stream, &state)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => debug!("metrics connection failed: {err}"),
+                Err(_) => debug!("metrics connection timed out"),
+            }
+        });
+    }
+}
+
+#[instrument(skip_all)]
+async fn handle(mut stream: TcpStream, state: &ServerState) -> io::Result<()> {
+    let (input, mut output) = stream.split();
+    let mut input = BufReader::new(input.take(SANE_REQUEST_LENGTH));
+
+    let mut request_line = String::new();
+    input.read_line(&mut request_line).await?;
+    // Read the headers so that the client doesn't see its request cut short
+    let mut header = String::new();
+    loop {
+        header.clear();
+        if input.read_line(&mut header).await? == 0 || header.trim_end().is_empty() {
+            break;
+        }
+    }
+
+    let not_found = || response("404 Not Found", "text/plain; charset=utf-8", "Not Found\n");
+    let mut parts = request_line.split_ascii_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => response(
+            "200 OK",
+            "text/plain; version=0.0.4; charset=utf-8",
+            &state.stats.render_prometheus(),
+        ),
+        #[cfg(feature = "acme")]
+        (Some("GET"), Some(path)) if path.starts_with(crate::acme::CHALLENGE_PATH) => {
+            let token = &path[crate::acme::CHALLENGE_PATH.len()..];
+            match state.acme_challenges.key_authorization(token) {
+                Some(key_authorization) => {
+                    response("200 OK", "application/octet-stream", &key_authorization)
+                }
+                None => not_found(),
+            }
+        }
+        _ => not_found(),
+    };
+
+    output.write_all(response.as_bytes()).await?;
+    output.shutdown().await
+}
+
+fn response(status: &str, content_type: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn get(state: Arc<ServerState>, path: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::task::spawn(serve(listener, state));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn serves_stats_counters() {
+        let state = Arc::new(ServerState::default());
+        state.stats.record_query();
+        state.stats.record_query();
+        state.stats.record_user("alice");
+        state.stats.record_unknown_user("nobody");
+        state.stats.record_user("quote\"d");
+
+        let response = get(Arc::clone(&state), "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let body = response.split_once("\r\n\r\n").unwrap().1;
+        assert!(body.contains("# TYPE fingered_queries_total counter\nfingered_queries_total 2\n"));
+        assert!(body.contains("fingered_user_queries_total{user=\"alice\"} 1\n"));
+        assert!(body.contains("fingered_user_queries_total{user=\"quote\\\"d\"} 1\n"));
+        assert!(!body.contains("nobody"));
+        assert!(!body.contains("last_reload"));
+
+        let response = get(state, "/").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[cfg(feature = "acme")]
+    #[tokio::test]
+    async fn answers_acme_challenges() {
+        let state = Arc::new(ServerState::default());
+        (state.acme_challenges).insert("token".into(), "token.thumbprint".into());
+
+        let response = get(Arc::clone(&state), "/.well-known/acme-challenge/token").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\ntoken.thumbprint"));
+
+        let response = get(state, "/.well-known/acme-challenge/other").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
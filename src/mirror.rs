@@ -0,0 +1,165 @@
+//! Copies of incoming queries sent to a shadow endpoint, for analytics or to test a staging server
+//!
+//! Request lines are handed to a background task through a queue of [QUEUE_SIZE] lines, and sent
+//! from there, so mirroring never delays a reply: when the queue is full because the endpoint is
+//! slow or down, the copy is dropped. Only queries are mirrored, and whatever the endpoint answers
+//! is ignored. The endpoint is given as a URL:
+//!
+//! - `udp://host:port`: one datagram per query
+//! - `tcp://host:port`: one connection per query, like a finger client would, e.g. to a staging
+//!   instance of `fingered`
+//! - `http://...` or `https://...` (with the `remote-config` feature): one `POST` request per
+//!   query, with the request line as its body
+
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+
+/// Number of queries waiting to be mirrored, after which new ones are dropped
+const QUEUE_SIZE: usize = 256;
+
+/// Max duration of each attempt to mirror a query over TCP or HTTP
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug)]
+pub enum Endpoint {
+    /// `udp://host:port`
+    Udp(String),
+    /// `tcp://host:port`
+    Tcp(String),
+    /// `http://...` or `https://...`
+    #[cfg(feature = "remote-config")]
+    Http(reqwest::Url),
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = s.split_once("://").ok_or("missing scheme")?;
+        match scheme {
+            "udp" => Ok(Self::Udp(rest.to_owned())),
+            "tcp" => Ok(Self::Tcp(rest.to_owned())),
+            #[cfg(feature = "remote-config")]
+            "http" | "https" => reqwest::Url::parse(s)
+                .map(Self::Http)
+                .map_err(|err| err.to_string()),
+            #[cfg(not(feature = "remote-config"))]
+            "http" | "https" => Err("HTTP endpoints require the remote-config feature".to_owned()),
+            scheme => Err(format!(
+                "unsupported scheme {scheme:?}, expected udp, tcp, http or https"
+            )),
+        }
+    }
+}
+
+pub struct Mirror {
+    queue: mpsc::Sender<Vec<u8>>,
+
+    /// Number of queries dropped because the queue was full
+    dropped: AtomicU64,
+}
+
+impl Mirror {
+    /// Start mirroring to `endpoint` in the background
+    pub fn start(endpoint: Endpoint) -> Self {
+        let (queue, lines) = mpsc::channel(QUEUE_SIZE);
+        tokio::task::spawn(run(endpoint, lines));
+        Self {
+            queue,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Queue a copy of the request `line`, or drop it if the queue is full
+    pub fn send(&self, line: &[u8]) {
+        if self.queue.try_send(line.to_vec()).is_err() {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            // Warns on the 1st, 2nd, 4th, 8th... dropped query, so that a dead endpoint doesn't
+            // flood the logs
+            if dropped.is_power_of_two() {
+                warn!("{dropped} queries not mirrored so far, the endpoint can't keep up");
+            }
+        }
+    }
+}
+
+/// Send the queued `lines` to `endpoint`, until the [Mirror] is dropped
+#[instrument(skip_all)]
+async fn run(endpoint: Endpoint, mut lines: mpsc::Receiver<Vec<u8>>) {
+    let udp = match &endpoint {
+        Endpoint::Udp(addr) => match connect_udp(addr).await {
+            Ok(socket) => Some(socket),
+            Err(err) => {
+                error!("cannot mirror queries to {addr}: {err}");
+                return;
+            }
+        },
+        _ => None,
+    };
+    #[cfg(feature = "remote-config")]
+    let client = reqwest::Client::new();
+
+    while let Some(line) = lines.recv().await {
+        let result = match &endpoint {
+            Endpoint::Udp(_) => udp.as_ref().unwrap().send(&line).await.map(drop),
+            Endpoint::Tcp(addr) => tokio::time::timeout(TIMEOUT, send_tcp(addr, &line))
+                .await
+                .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+            #[cfg(feature = "remote-config")]
+            Endpoint::Http(url) => (client.post(url.clone()).body(line).timeout(TIMEOUT))
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status)
+                .map(drop)
+                .map_err(io::Error::other),
+        };
+        if let Err(err) = result {
+            debug!("cannot mirror query: {err}");
+        }
+    }
+}
+
+async fn connect_udp(addr: &str) -> io::Result<UdpSocket> {
+    let target = tokio::net::lookup_host(addr)
+        .await?
+        .next()
+        .ok_or(io::ErrorKind::NotFound)?;
+    let local = match target {
+        std::net::SocketAddr::V4(_) => "0.0.0.0:0",
+        std::net::SocketAddr::V6(_) => "[::]:0",
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(target).await?;
+    Ok(socket)
+}
+
+/// Send `line` as a finger query to `addr`, and read the reply to the end without keeping it
+async fn send_tcp(addr: &str, line: &[u8]) -> io::Result<()> {
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(line).await?;
+    stream.shutdown().await?;
+    tokio::io::copy(&mut stream, &mut tokio::io::sink()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn mirrors_over_udp() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("udp://{}", receiver.local_addr().unwrap());
+        let mirror = Mirror::start(endpoint.parse().unwrap());
+
+        mirror.send(b"alice\r\n");
+        let mut buf = [0; 64];
+        let len = receiver.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"alice\r\n");
+    }
+}
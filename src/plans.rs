@@ -0,0 +1,262 @@
+//! Plan files of users, dropped in the [Users::plans_dir] and served as their long info
+//!
+//! Each `<username>.txt` file of the directory replaces the long info of the user named
+//! `username`, like a `~/.plan` file would, so users update their plan with a plain file edit. So
+//! that they can't edit the plans of others, a file is only used if it's a regular file (not a
+//! symlink, nor a hard link that could point to a file they can't read) that isn't writable by its
+//! group or others, and is owned either by the [User::uid] of the user, or by the owner of the
+//! directory (e.g. a file put there by the admin). Other files are ignored.
+//!
+//! The directory is scanned every [POLL_INTERVAL], and every plan is read again as soon as a file
+//! was added, removed or modified. Plans are fixed, signed and checked against [Limits] like the
+//! texts of the config, and survive its reloads. A plan over the limits is ignored, without
+//! affecting the others.
+//!
+//! [Users::plans_dir]: crate::config::Users::plans_dir
+//! [User::uid]: crate::config::User::uid
+//! [Limits]: crate::config::Limits
+
+use crate::config::{Config, Plan, Users};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncReadExt;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Extension of plan files, after the name of their user
+const EXTENSION: &str = ".txt";
+
+/// State of the plans directory, read again when it changes
+#[derive(Debug, Default, PartialEq)]
+struct Snapshot {
+    dir: Option<PathBuf>,
+
+    /// Owner of the directory, whose files are trusted for every user
+    owner: u32,
+    files: Vec<FileState>,
+}
+
+#[derive(Debug, PartialEq)]
+struct FileState {
+    name: OsString,
+    is_file: bool,
+    ino: u64,
+    nlink: u64,
+    owner: u32,
+    mode: u32,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+/// Keep the plans of `config` in sync with its plans directory, forever
+#[instrument(skip_all)]
+pub async fn watch(config: Arc<Config>) {
+    // Nothing to load until a directory is configured
+    let mut last = Snapshot::default();
+    let mut failing = false;
+
+    loop {
+        let dir = config.get().await.plans_dir.clone();
+        let snapshot = match &dir {
+            Some(dir) => match snapshot(dir).await {
+                Ok(snapshot) => {
+                    failing = false;
+                    snapshot
+                }
+                Err(err) => {
+                    // Warns once, not on every scan
+                    if !failing {
+                        warn!("cannot read plans directory {}: {err}", dir.display());
+                    }
+                    failing = true;
+                    Snapshot::default()
+                }
+            },
+            None => Snapshot::default(),
+        };
+
+        if snapshot != last {
+            let plans = load(&snapshot, &*config.get().await).await;
+            let count = plans.len();
+            match config.set_plans(plans).await {
+                Ok(()) if dir.is_some() => info!("{count} plan(s) loaded"),
+                Ok(()) => {}
+                Err(err) => warn!("ignoring plans: {err}"),
+            }
+            last = snapshot;
+        }
+
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+async fn snapshot(dir: &Path) -> io::Result<Snapshot> {
+    let owner = tokio::fs::metadata(dir).await?.uid();
+    let mut files = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        // Doesn't follow symlinks
+        let metadata = entry.metadata().await?;
+        files.push(FileState {
+            name: entry.file_name(),
+            is_file: metadata.is_file(),
+            ino: metadata.ino(),
+            nlink: metadata.nlink(),
+            owner: metadata.uid(),
+            mode: metadata.mode(),
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        });
+    }
+    files.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(Snapshot {
+        dir: Some(dir.to_owned()),
+        owner,
+        files,
+    })
+}
+
+/// Read the valid plans of `snapshot`, logging the files that aren't used
+async fn load(snapshot: &Snapshot, users: &Users) -> HashMap<String, Plan> {
+    let Some(dir) = &snapshot.dir else {
+        return HashMap::new();
+    };
+
+    let mut plans = HashMap::new();
+    for file in &snapshot.files {
+        let name = file
+            .name
+            .to_str()
+            .and_then(|name| name.strip_suffix(EXTENSION));
+        let Some(name) = name.filter(|name| !name.is_empty()) else {
+            continue;
+        };
+
+        let plan = read(&dir.join(&file.name), file, snapshot.owner, users).await;
+        match plan.and_then(|plan| plan.check_limits(name, users).map(|()| plan)) {
+            Ok(plan) => {
+                plans.insert(name.to_owned(), plan);
+            }
+            Err(err) => {
+                warn!("ignoring plan of {name:?}: {err}");
+                continue;
+            }
+        }
+
+        // Kept anyway, since the user may be added or given this uid by a reload
+        let owner = (users.users.get(name)).map(|user| user.uid);
+        match owner {
+            None => warn!("plan of {name:?} has no user"),
+            Some(uid) if file.owner != snapshot.owner && uid != Some(file.owner) => {
+                warn!(
+                    "plan of {name:?} is owned by uid {}, not by its user",
+                    file.owner
+                );
+            }
+            Some(_) => {}
+        }
+    }
+    plans
+}
+
+/// Read the plan `file`, found at `path`, checking that it can be trusted
+async fn read(
+    path: &Path,
+    file: &FileState,
+    dir_owner: u32,
+    users: &Users,
+) -> Result<Plan, String> {
+    if !file.is_file {
+        return Err("not a regular file".into());
+    }
+    if file.nlink != 1 {
+        return Err("hard linked".into());
+    }
+    if file.mode & 0o022 != 0 {
+        return Err("writable by its group or others".into());
+    }
+    let max = match users.limits.max_long_info_size {
+        0 => u64::MAX,
+        max => max as u64,
+    };
+    if file.len > max {
+        return Err(format!("size is {}, more than {max}", file.len));
+    }
+
+    let mut opened = (tokio::fs::OpenOptions::new().read(true))
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+        .await
+        .map_err(|err| err.to_string())?;
+    // The checked file may have been replaced (e.g. by a hard link) since the scan
+    let metadata = opened.metadata().await.map_err(|err| err.to_string())?;
+    if metadata.ino() != file.ino || metadata.uid() != file.owner || metadata.nlink() != 1 {
+        return Err("replaced while being read".into());
+    }
+
+    // It may also have grown since the scan
+    let mut long_info = String::new();
+    let mut limited = (&mut opened).take(max.saturating_add(1));
+    (limited.read_to_string(&mut long_info).await).map_err(|err| err.to_string())?;
+    if long_info.len() as u64 > max {
+        return Err(format!("size is more than {max}"));
+    }
+
+    let owner = (file.owner != dir_owner).then_some(file.owner);
+    Ok(Plan::new(owner, long_info))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[tokio::test]
+    async fn ignores_plans_writable_by_others() {
+        let dir = std::env::temp_dir().join(format!("fingered-plans-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, mode) in [("alice.txt", 0o644), ("bob.txt", 0o666), ("notes", 0o644)] {
+            let path = dir.join(name);
+            std::fs::write(&path, "Gone fishing").unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+        }
+
+        let users = Users::parse("users.alice = \"Alice\"\nusers.bob = \"Bob\"").unwrap();
+        let plans = load(&snapshot(&dir).await.unwrap(), &users).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(plans.keys().collect::<Vec<_>>(), ["alice"]);
+    }
+
+    #[tokio::test]
+    async fn ignores_hard_links_and_plans_over_the_limits() {
+        let dir = std::env::temp_dir().join(format!("fingered-links-{}", std::process::id()));
+        let plans_dir = dir.join("plans");
+        std::fs::create_dir_all(&plans_dir).unwrap();
+        let secret = dir.join("secret");
+        std::fs::write(&secret, "hunter2").unwrap();
+        std::fs::set_permissions(&secret, std::fs::Permissions::from_mode(0o640)).unwrap();
+        std::fs::hard_link(&secret, plans_dir.join("alice.txt")).unwrap();
+        // 8 bytes, but 12 once its line endings are fixed
+        std::fs::write(plans_dir.join("bob.txt"), "a\nb\nc\nd\n").unwrap();
+        std::fs::write(plans_dir.join("carol.txt"), "Gone").unwrap();
+
+        let config = r#"
+            limits.max-long-info-size = 8
+            users.alice = "Alice"
+            users.bob = "Bob"
+            users.carol = "Carol"
+        "#;
+        let users = Users::parse(config).unwrap();
+        let plans = load(&snapshot(&plans_dir).await.unwrap(), &users).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(plans.keys().collect::<Vec<_>>(), ["carol"]);
+    }
+}
@@ -0,0 +1,50 @@
+//! Printing the replies to the queries for a user, to check its formatting while writing the config
+//!
+//! Both queries (`<user>` and `/W <user>`) are answered in-process like a client's, so the reply
+//! shows snippets, sections, contact fields, signatures and scripts as they'd be sent.
+
+use crate::config::Users;
+use crate::context::RequestContext;
+use crate::escape::Escaped;
+use crate::state::ServerState;
+use std::net::IpAddr;
+use std::process::ExitCode;
+use std::time::Duration;
+
+/// Print the replies to the short and verbose queries for `user`, as a TCP client at `from` (or a
+/// local client) would get them
+///
+/// With `escape`, each line is printed quoted with its line ending and control characters escaped
+/// (see [Escaped]), instead of as it is.
+pub async fn run(users: &Users, user: &str, from: Option<IpAddr>, escape: bool) -> ExitCode {
+    let state = ServerState::default();
+    let peer = from.map_or("local".to_owned(), |ip| ip.to_string());
+    let listener = if from.is_some() { "tcp" } else { "unix" };
+
+    for query in [user.to_owned(), format!("/W {user}")] {
+        let request = format!("{query}\r\n");
+        let ctx = RequestContext::new(listener, &peer, from, Duration::ZERO);
+        let mut input = request.as_bytes();
+        let mut reply = Vec::new();
+        let handling = crate::handle_client(&ctx, users, &state, &mut input, &mut reply);
+        if let Err(err) = handling.await {
+            eprintln!("cannot answer {query:?}: {err}");
+            return ExitCode::FAILURE;
+        }
+
+        println!("=== {query}");
+        match escape {
+            false => print!("{}", String::from_utf8_lossy(&reply)),
+            true => {
+                for line in reply.split_inclusive(|byte| *byte == b'\n') {
+                    println!("{}", Escaped(line));
+                }
+            }
+        }
+        if !escape && !reply.ends_with(b"\n") {
+            println!();
+        }
+    }
+
+    ExitCode::SUCCESS
+}
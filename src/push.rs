@@ -0,0 +1,159 @@
+//! Socket where local users push their own info texts, without touching `users.toml`
+//!
+//! Any local user can connect to the socket, which identifies it with the credentials of its
+//! process (`SO_PEERCRED`). A user of the config whose [User::uid] is the client's uid is owned by
+//! it, and only its info texts can be replaced. Each connection carries a single command: a first
+//! line naming it, followed by its payload until the client shuts down its writing half, within
+//! [REQUEST_TIMEOUT]. The server answers with `OK` or `ERROR: <reason>`.
+//!
+//! Commands:
+//! - `info`: the payload replaces the info of the owned user.
+//! - `long-info`: the payload replaces its long info.
+//! - `reset`: no payload. The info texts of the config are used again.
+//!
+//! Pushed texts survive reloads of the config file, but not restarts. They're fixed, signed and
+//! checked against [Limits] like those of the config.
+//!
+//! [User::uid]: crate::config::User::uid
+//! [Limits]: crate::config::Limits
+
+use crate::config::{Config, Pushed};
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+/// Max length of a command and its payload, in bytes
+const SANE_COMMAND_LENGTH: u64 = 64 * 1024;
+
+/// Time given to clients to send their command and payload
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Bind the user socket at `path`, accessible to every local user
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o666))?;
+    Ok(listener)
+}
+
+/// Accept and serve connections forever
+pub async fn serve(listener: UnixListener, config: Arc<Config>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                error!("cannot accept user socket connection: {err}");
+                continue;
+            }
+        };
+
+        let config = Arc::clone(&config);
+        tokio::task::spawn(async move {
+            match tokio::time::timeout(REQUEST_TIMEOUT, handle(stream, &config)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => warn!("user socket connection failed: {err}"),
+                Err(_) => warn!("user socket connection timed out"),
+            }
+        });
+    }
+}
+
+#[instrument(skip_all)]
+async fn handle(mut stream: UnixStream, config: &Config) -> io::Result<()> {
+    let uid = stream.peer_cred()?.uid();
+    let (input, mut output) = stream.split();
+    // One more byte, to tell a payload of the max length from a longer one
+    let mut input = BufReader::new(input.take(SANE_COMMAND_LENGTH + 1));
+
+    let mut command = String::new();
+    input.read_line(&mut command).await?;
+    let mut payload = Vec::new();
+    input.read_to_end(&mut payload).await?;
+    if (command.len() + payload.len()) as u64 > SANE_COMMAND_LENGTH {
+        // Closing the socket before the client is done writing would lose the reply
+        tokio::io::copy(&mut input.into_inner().into_inner(), &mut tokio::io::sink()).await?;
+        output.write_all(b"ERROR: payload too long\n").await?;
+        return output.shutdown().await;
+    }
+    let payload = String::from_utf8(payload)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let payload: Arc<str> = payload.into();
+    let change: fn(&mut Pushed, Arc<str>) = match command.trim() {
+        "info" => |pushed, payload| pushed.info = Some(payload),
+        "long-info" => |pushed, payload| pushed.long_info = Some(payload),
+        "reset" => |pushed, _| {
+            pushed.info = None;
+            pushed.long_info = None;
+        },
+        command => {
+            let reply = format!("ERROR: unknown command {command:?}\n");
+            output.write_all(reply.as_bytes()).await?;
+            return output.shutdown().await;
+        }
+    };
+
+    match config.push(uid, |pushed| change(pushed, payload)).await {
+        Ok(name) => {
+            info!("uid {uid} pushed {} for user {name:?}", command.trim());
+            output.write_all(b"OK\n").await?;
+        }
+        Err(err) => {
+            output
+                .write_all(format!("ERROR: {err}\n").as_bytes())
+                .await?;
+        }
+    }
+
+    output.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SharedClock;
+
+    async fn send(config: &Arc<Config>, request: &str) -> String {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let config = Arc::clone(config);
+        let serve = tokio::task::spawn(async move { handle(server, &config).await });
+        client.write_all(request.as_bytes()).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).await.unwrap();
+        serve.await.unwrap().unwrap();
+        reply
+    }
+
+    #[tokio::test]
+    async fn replaces_the_info_of_the_owned_user() {
+        let uid = UnixStream::pair().unwrap().0.peer_cred().unwrap().uid();
+        let toml =
+            format!("users.alice = {{ info = \"Alice\", uid = {uid} }}\nusers.bob = \"Bob\"");
+        let config = Arc::new(Config::new_parsed(&toml, None, SharedClock::default()).unwrap());
+
+        assert_eq!(send(&config, "info\nAt the beach\n").await, "OK\n");
+        let users = config.get().await;
+        assert_eq!(
+            users.users["alice"].info.as_deref(),
+            Some("At the beach\r\n")
+        );
+        assert_eq!(users.users["bob"].info.as_deref(), Some("Bob\r\n"));
+
+        assert_eq!(send(&config, "reset\n").await, "OK\n");
+        assert_eq!(
+            config.get().await.users["alice"].info.as_deref(),
+            Some("Alice\r\n")
+        );
+
+        let reply = send(&config, "shell\n").await;
+        assert_eq!(reply, "ERROR: unknown command \"shell\"\n");
+
+        let long_info = "a".repeat(SANE_COMMAND_LENGTH as usize);
+        let reply = send(&config, &format!("long-info\n{long_info}")).await;
+        assert_eq!(reply, "ERROR: payload too long\n");
+        assert_eq!(config.get().await.users["alice"].long_info, None);
+    }
+}
@@ -0,0 +1,109 @@
+//! Sections of info texts that are only sent to some queries
+//!
+//! Lines between a `{verbose}` line and a `{/verbose}` line are only sent to verbose queries, and
+//! lines between `{internal}` and `{/internal}` only to clients whose address is in
+//! [Users::internal_networks]. Sections can be nested, and the marker lines themselves are never
+//! sent, so a single text can serve every kind of query.
+//!
+//! [Users::internal_networks]: crate::config::Users::internal_networks
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Marker {
+    Start(Section),
+    End(Section),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Section {
+    Verbose,
+    Internal,
+}
+
+/// Who a text is rendered for
+#[derive(Clone, Copy, Debug)]
+pub struct Audience {
+    pub verbose: bool,
+    pub internal: bool,
+}
+
+impl Audience {
+    fn can_see(self, section: Section) -> bool {
+        match section {
+            Section::Verbose => self.verbose,
+            Section::Internal => self.internal,
+        }
+    }
+}
+
+fn parse_marker(line: &str) -> Option<Marker> {
+    match line.trim_end_matches(['\r', '\n']) {
+        "{verbose}" => Some(Marker::Start(Section::Verbose)),
+        "{/verbose}" => Some(Marker::End(Section::Verbose)),
+        "{internal}" => Some(Marker::Start(Section::Internal)),
+        "{/internal}" => Some(Marker::End(Section::Internal)),
+        _ => None,
+    }
+}
+
+/// Whether `text` has sections, i.e. whether it needs to be rendered with [render]
+pub fn has_sections(text: &str) -> bool {
+    text.lines().any(|line| parse_marker(line).is_some())
+}
+
+/// Replace the lines of each internal section of `text` with a single `replacement` line, keeping
+/// the marker lines and the rest of `text`
+#[cfg(all(unix, feature = "unix-socket"))]
+pub fn redact_internal(text: &str, replacement: &str) -> String {
+    let mut redacted = String::with_capacity(text.len());
+    let mut depth = 0;
+
+    for line in text.split_inclusive('\n') {
+        match parse_marker(line) {
+            Some(Marker::Start(Section::Internal)) => {
+                if depth == 0 {
+                    let eol = if line.ends_with("\r\n") { "\r\n" } else { "\n" };
+                    redacted.push_str(line);
+                    if !line.ends_with('\n') {
+                        redacted.push_str(eol);
+                    }
+                    redacted.push_str(replacement);
+                    redacted.push_str(eol);
+                }
+                depth += 1;
+            }
+            Some(Marker::End(Section::Internal)) if depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    redacted.push_str(line);
+                }
+            }
+            _ if depth == 0 => redacted.push_str(line),
+            _ => {}
+        }
+    }
+
+    redacted
+}
+
+/// Keep the lines of `text` that `audience` can see, dropping the marker lines
+pub fn render(text: &str, audience: Audience) -> String {
+    let mut rendered = String::with_capacity(text.len());
+    let mut open = Vec::new();
+
+    for line in text.split_inclusive('\n') {
+        match parse_marker(line) {
+            Some(Marker::Start(section)) => open.push(section),
+            Some(Marker::End(section)) => {
+                if let Some(index) = open.iter().rposition(|open| *open == section) {
+                    open.truncate(index);
+                }
+            }
+            None if open.iter().all(|section| audience.can_see(*section)) => {
+                rendered.push_str(line);
+            }
+            None => {}
+        }
+    }
+
+    rendered
+}
@@ -0,0 +1,146 @@
+//! Config reloads, run one at a time however they're requested
+//!
+//! Reloads requested by SIGHUP are run by a single task. Requests arriving while a reload waits to
+//! start (for [DEBOUNCE]) are coalesced into it, and a request arriving during a reload causes
+//! another one afterwards, since the config may have changed after it was read. Config polling,
+//! which loads the config it read itself, runs its reloads through [Reloads::exclusive] so that
+//! they don't overlap either.
+
+use futures::FutureExt;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify};
+
+/// How long a reload waits for more requests before starting
+pub const DEBOUNCE: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Default)]
+pub struct Reloads {
+    requested: Notify,
+
+    /// Held during each reload
+    running: Mutex<()>,
+}
+
+impl Reloads {
+    /// Ask for a reload, started by [Reloads::run] after the debounce delay
+    pub fn request(&self) {
+        self.requested.notify_one();
+    }
+
+    /// Call `reload` for each batch of requests, never concurrently, forever
+    pub async fn run<F: Future<Output = ()>>(
+        &self,
+        debounce: Duration,
+        mut reload: impl FnMut() -> F,
+    ) {
+        loop {
+            self.requested.notified().await;
+            tokio::time::sleep(debounce).await;
+
+            // Requests made while waiting are covered by this reload
+            let _ = self.requested.notified().now_or_never();
+            self.exclusive(reload()).await;
+        }
+    }
+
+    /// Run the reload `f` once no other reload is running
+    pub async fn exclusive<T>(&self, f: impl Future<Output = T>) -> T {
+        let _running = self.running.lock().await;
+        f.await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    // Time is paused in these tests, and only advances when every task waits for it
+    const DEBOUNCE: Duration = Duration::from_secs(1);
+    const RELOAD_TIME: Duration = Duration::from_secs(5);
+
+    #[derive(Default)]
+    struct Log {
+        running: AtomicUsize,
+        events: Mutex<Vec<&'static str>>,
+    }
+
+    /// Start running reloads that take [RELOAD_TIME] and record when they start and end
+    fn spawn(reloads: &Arc<Reloads>, log: &Arc<Log>) {
+        let (reloads, log) = (Arc::clone(reloads), Arc::clone(log));
+        tokio::task::spawn(async move {
+            reloads
+                .run(DEBOUNCE, || {
+                    let log = Arc::clone(&log);
+                    async move {
+                        assert_eq!(log.running.fetch_add(1, Ordering::SeqCst), 0);
+                        log.events.lock().unwrap().push("start");
+                        tokio::time::sleep(RELOAD_TIME).await;
+                        log.events.lock().unwrap().push("end");
+                        log.running.fetch_sub(1, Ordering::SeqCst);
+                    }
+                })
+                .await
+        });
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn coalesces_requests() {
+        let (reloads, log) = Default::default();
+        spawn(&reloads, &log);
+
+        for _ in 0..10 {
+            reloads.request();
+        }
+        tokio::time::sleep(DEBOUNCE + RELOAD_TIME * 3).await;
+
+        assert_eq!(*log.events.lock().unwrap(), ["start", "end"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_for_exclusive_reloads() {
+        let (reloads, log) = Default::default();
+        spawn(&reloads, &log);
+
+        let exclusive = {
+            let (reloads, log) = (Arc::clone(&reloads), Arc::clone(&log));
+            tokio::task::spawn(async move {
+                reloads
+                    .exclusive(async {
+                        log.events.lock().unwrap().push("exclusive start");
+                        tokio::time::sleep(RELOAD_TIME).await;
+                        log.events.lock().unwrap().push("exclusive end");
+                    })
+                    .await
+            })
+        };
+        reloads.request();
+        exclusive.await.unwrap();
+        tokio::time::sleep(DEBOUNCE + RELOAD_TIME * 2).await;
+
+        let events = log.events.lock().unwrap();
+        assert_eq!(
+            *events,
+            ["exclusive start", "exclusive end", "start", "end"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn serializes_reloads() {
+        let (reloads, log) = Default::default();
+        spawn(&reloads, &log);
+
+        reloads.request();
+        tokio::time::sleep(DEBOUNCE + RELOAD_TIME / 2).await;
+
+        // During the first reload, so they're coalesced into a second one after it
+        reloads.request();
+        reloads.request();
+        tokio::time::sleep(DEBOUNCE + RELOAD_TIME * 3).await;
+
+        let events = log.events.lock().unwrap();
+        assert_eq!(*events, ["start", "end", "start", "end"]);
+    }
+}
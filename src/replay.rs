@@ -0,0 +1,184 @@
+//! Replaying the requests of an audit log (see [crate::audit]) and comparing the replies
+
+use crate::config::Users;
+use crate::context::RequestContext;
+use crate::escape::Escaped;
+use crate::listener::{AnySocket, AnySocketAddr};
+use crate::state::ServerState;
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// One exchange read back from an audit log
+pub struct Entry {
+    pub request: Vec<u8>,
+    pub reply: Vec<u8>,
+}
+
+/// Parse the entries of an audit log, in order
+pub fn parse_log(mut log: &[u8]) -> Result<Vec<Entry>, String> {
+    let mut entries = Vec::new();
+
+    while !log.is_empty() {
+        let header_end = log
+            .iter()
+            .position(|b| *b == b'\n')
+            .ok_or("truncated header")?;
+        let header =
+            std::str::from_utf8(&log[..header_end]).map_err(|_| "non-UTF-8 header".to_owned())?;
+        log = &log[header_end + 1..];
+
+        let (_, rest) = header
+            .split_once(" request=\"")
+            .ok_or_else(|| format!("malformed header {header:?}"))?;
+        let (request, reply_length) = rest
+            .rsplit_once("\" reply=")
+            .ok_or_else(|| format!("malformed header {header:?}"))?;
+        let reply_length = reply_length
+            .strip_suffix(" bytes")
+            .and_then(|length| length.parse::<usize>().ok())
+            .ok_or_else(|| format!("malformed header {header:?}"))?;
+
+        if log.len() < reply_length {
+            return Err("truncated reply".to_owned());
+        }
+        let reply = log[..reply_length].to_vec();
+        log = &log[reply_length..];
+        if !reply.ends_with(b"\n") {
+            log = log
+                .strip_prefix(b"\n")
+                .ok_or("missing newline after reply")?;
+        }
+
+        entries.push(Entry {
+            request: unescape(request)?,
+            reply,
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Reverse the escaping of [Escaped]
+fn unescape(escaped: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+            continue;
+        }
+
+        match chars.next() {
+            Some('r') => bytes.push(b'\r'),
+            Some('n') => bytes.push(b'\n'),
+            Some('t') => bytes.push(b'\t'),
+            Some('0') => bytes.push(b'\0'),
+            Some(c @ ('\\' | '"' | '\'')) => bytes.push(c as u8),
+            Some('x') => {
+                let hex = chars.by_ref().take(2).collect::<String>();
+                let byte = u8::from_str_radix(&hex, 16).map_err(|_| "bad \\x escape")?;
+                bytes.push(byte);
+            }
+            Some('u') => {
+                let hex = (chars.by_ref())
+                    .skip_while(|c| *c == '{')
+                    .take_while(|c| *c != '}')
+                    .collect::<String>();
+                let c = u32::from_str_radix(&hex, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or("bad \\u escape")?;
+                bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+            }
+            _ => return Err(format!("unknown escape in {escaped:?}")),
+        }
+    }
+
+    Ok(bytes)
+}
+
+/// What requests are replayed against
+pub enum Target<'a> {
+    /// A running server
+    Server(&'a AnySocketAddr),
+    /// The request handler of this process, with the given config
+    InProcess(&'a Users),
+}
+
+/// Replay every request of `log` against `target`
+///
+/// Mismatching replies are printed, and make the returned exit code a failure.
+pub async fn run(log: &Path, target: Target<'_>) -> ExitCode {
+    let log = match std::fs::read(log) {
+        Ok(log) => log,
+        Err(err) => {
+            eprintln!("cannot read {}: {err}", log.display());
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let entries = match parse_log(&log) {
+        Ok(entries) => entries,
+        Err(err) => {
+            eprintln!("cannot parse audit log: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let state = ServerState::default();
+    let mut mismatches = 0;
+
+    for (i, entry) in entries.iter().enumerate() {
+        let reply = match target {
+            Target::Server(addr) => query(addr, &entry.request).await,
+            Target::InProcess(users) => {
+                let mut reply = Vec::new();
+                let mut input = &entry.request[..];
+                let ctx = RequestContext::new("replay", &"replay", None, Duration::ZERO);
+                crate::handle_client(&ctx, users, &state, &mut input, &mut reply)
+                    .await
+                    .map(|_| reply)
+            }
+        };
+
+        let request = Escaped(&entry.request);
+        match reply {
+            Ok(reply) if reply == entry.reply => {}
+            Ok(reply) => {
+                mismatches += 1;
+                println!("#{i} {request}: reply changed");
+                println!("  was: {}", Escaped(&entry.reply));
+                println!("  now: {}", Escaped(&reply));
+            }
+            Err(err) => {
+                mismatches += 1;
+                println!("#{i} {request}: {err}");
+            }
+        }
+    }
+
+    println!(
+        "{} request(s) replayed, {mismatches} mismatch(es)",
+        entries.len()
+    );
+
+    match mismatches {
+        0 => ExitCode::SUCCESS,
+        _ => ExitCode::FAILURE,
+    }
+}
+
+async fn query(addr: &AnySocketAddr, request: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut socket = AnySocket::connect(addr).await?;
+    let mut socket = socket.split();
+    let (input, output) = socket.as_parts();
+    output.write_all(request).await?;
+    output.flush().await?;
+
+    let mut reply = Vec::new();
+    input.read_to_end(&mut reply).await?;
+    Ok(reply)
+}
@@ -1,23 +1,47 @@
 use nom::branch::alt;
-use nom::bytes::complete::{is_a, tag, take_while1};
-use nom::character::complete::not_line_ending;
-use nom::combinator::{all_consuming, cond, eof, map, opt, recognize, value};
+use nom::bytes::complete::{is_a, tag, take_while, take_while1};
+use nom::combinator::{all_consuming, cond, eof, map, opt, value};
 use nom::error::ErrorKind;
 use nom::error_position;
+use nom::multi::many0;
 use nom::sequence::{preceded, tuple};
+use std::fmt::{Display, Formatter};
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Request<'a> {
-    /// Whether the verbose "\W" flag is set or not
+    /// Whether the verbose "/W" flag is set or not
     pub verbose: bool,
 
+    /// Whether the "/U" flag is set, asking for replies in UTF-8
+    ///
+    /// This flag is an extension of this server, not part of RFC 1288. Other clients get replies in
+    /// the [Users::legacy_charset]. It may come before or after "/W", separated by spaces.
+    ///
+    /// [Users::legacy_charset]: crate::config::Users::legacy_charset
+    pub utf8: bool,
+
+    /// Whether the "/P" flag is set, asking to keep the connection open for another request
+    ///
+    /// This flag is an extension of this server, honored if [Users::persistent_connections] is
+    /// enabled.
+    ///
+    /// [Users::persistent_connections]: crate::config::Users::persistent_connections
+    pub persistent: bool,
+
     /// The user that was queried, if any
     ///
     /// If no user was given, this finger request should be treated as a user list request.
     pub user: Option<&'a str>,
 
-    /// The part after the optional `@` sign, used for forwarding finger requests
-    pub forwarding: Option<&'a str>,
+    /// Users queried after [Request::user], in a request naming several users separated by spaces
+    /// (e.g. `alice bob`)
+    pub more_users: Vec<&'a str>,
+
+    /// The hosts of the `@host1@host2...` part of the request, used for forwarding finger requests
+    ///
+    /// Empty if the request isn't forwarded. The last host is the first the request should be
+    /// forwarded to (which would receive `user@host1...`). Hosts may be empty strings.
+    pub forwarding: Vec<&'a str>,
 }
 
 impl<'a> Request<'a> {
@@ -25,8 +49,23 @@ impl<'a> Request<'a> {
     pub fn new_list(verbose: bool) -> Self {
         Self {
             verbose,
+            utf8: false,
+            persistent: false,
             user: None,
-            forwarding: None,
+            more_users: Vec::new(),
+            forwarding: Vec::new(),
+        }
+    }
+
+    /// Create a request for a single local user
+    pub fn new_user(verbose: bool, user: &'a str) -> Self {
+        Self {
+            verbose,
+            utf8: false,
+            persistent: false,
+            user: Some(user),
+            more_users: Vec::new(),
+            forwarding: Vec::new(),
         }
     }
 
@@ -42,6 +81,74 @@ impl<'a> Request<'a> {
             Err(err) => Err(err),
         }
     }
+
+    /// Parse a raw request `line`, if it's a valid request
+    pub fn from_line(line: &'a [u8]) -> Option<Self> {
+        std::str::from_utf8(line)
+            .ok()
+            .and_then(|line| Self::from_str(line).ok())
+    }
+
+    /// Whether `line` is a valid request with the "/U" flag
+    pub fn asks_for_utf8(line: &[u8]) -> bool {
+        Request::from_line(line).is_some_and(|request| request.utf8)
+    }
+
+    /// The exact line a client sends for this request, CRLF included
+    pub fn to_request_line(&self) -> String {
+        format!("{self}\r\n")
+    }
+
+    /// Drop the trailing `@host` hops that refer to this server
+    ///
+    /// A request `user@a@b` received by `b` should be relayed to `b` as `user@a`, so any hop at
+    /// the end of the chain for which `is_local` returns true can be peeled off. If the whole chain
+    /// is peeled off, the request becomes a plain local query.
+    pub fn strip_local_hosts(mut self, is_local: impl Fn(&str) -> bool) -> Self {
+        while let Some(host) = self.forwarding.last() {
+            if !is_local(host) {
+                break;
+            }
+
+            self.forwarding.pop();
+        }
+
+        self
+    }
+}
+
+/// Formats the request as sent on the wire, without the final CRLF
+///
+/// Parsing the output (with a CRLF appended) gives back an identical request.
+impl Display for Request<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let flags = [
+            (self.verbose, "/W"),
+            (self.utf8, "/U"),
+            (self.persistent, "/P"),
+        ];
+        let flags = flags.iter().filter(|(set, _)| *set).map(|(_, flag)| *flag);
+        let flags = flags.collect::<Vec<_>>();
+        if !flags.is_empty() {
+            f.write_str(&flags.join(" "))?;
+            if self.user.is_some() || !self.forwarding.is_empty() {
+                f.write_str(" ")?;
+            }
+        }
+
+        if let Some(user) = self.user {
+            f.write_str(user)?;
+        }
+        for user in &self.more_users {
+            write!(f, " {user}")?;
+        }
+
+        for host in &self.forwarding {
+            write!(f, "@{host}")?;
+        }
+
+        Ok(())
+    }
 }
 
 type IResult<'a, O> = nom::IResult<&'a str, O>;
@@ -49,36 +156,176 @@ type IResult<'a, O> = nom::IResult<&'a str, O>;
 const USERNAME_ALLOWED_CHARS: &str =
     "-.0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ_abcdefghijklmnopqrstuvwxyz";
 
-fn parse(input: &str) -> IResult<Request> {
-    let (input, verbose) = opt(verbose)(input)?;
-    let verbose = verbose.is_some();
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum Flag {
+    Verbose,
+    Utf8,
+    Persistent,
+}
+
+fn parse(input: &str) -> IResult<'_, Request<'_>> {
+    let (input, flags) = flags(input)?;
+    let verbose = flags.contains(&Flag::Verbose);
+    let utf8 = flags.contains(&Flag::Utf8);
+    let persistent = flags.contains(&Flag::Persistent);
+    let list = Request {
+        utf8,
+        persistent,
+        ..Request::new_list(verbose)
+    };
 
     alt((
-        all_consuming(value(Request::new_list(verbose), eof)),
+        all_consuming(value(list, eof)),
         all_consuming(preceded(
-            cond(verbose, space),
+            cond(!flags.is_empty(), space),
             map(
-                tuple((opt(is_a(USERNAME_ALLOWED_CHARS)), host_chain)),
-                move |(user, forwarding)| Request {
-                    verbose,
-                    user,
-                    forwarding,
+                tuple((opt(usernames), host_chain)),
+                move |(users, forwarding)| {
+                    let (user, more_users) = match users {
+                        Some((user, more_users)) => (Some(user), more_users),
+                        None => (None, Vec::new()),
+                    };
+                    Request {
+                        verbose,
+                        utf8,
+                        persistent,
+                        user,
+                        more_users,
+                        forwarding,
+                    }
                 },
             ),
         )),
     ))(input)
 }
 
-fn host_chain(input: &str) -> IResult<Option<&str>> {
-    opt(recognize(preceded(tag("@"), not_line_ending)))(input)
+/// Consumes zero or more flags, separated by spaces
+fn flags(input: &str) -> IResult<'_, Vec<Flag>> {
+    let (input, first) = opt(flag)(input)?;
+    let Some(first) = first else {
+        return Ok((input, Vec::new()));
+    };
+    let (input, mut flags) = many0(preceded(space, flag))(input)?;
+    flags.insert(0, first);
+    Ok((input, flags))
 }
 
-/// Consumes one verbose "/W" flag
-fn verbose(input: &str) -> IResult<()> {
-    value((), tag("/W"))(input)
+/// Consumes one verbose "/W", UTF-8 "/U" or persistent "/P" flag
+fn flag(input: &str) -> IResult<'_, Flag> {
+    alt((
+        value(Flag::Verbose, tag("/W")),
+        value(Flag::Utf8, tag("/U")),
+        value(Flag::Persistent, tag("/P")),
+    ))(input)
+}
+
+/// Consumes one or more usernames, separated by spaces
+fn usernames(input: &str) -> IResult<'_, (&str, Vec<&str>)> {
+    let username = || is_a(USERNAME_ALLOWED_CHARS);
+    tuple((username(), many0(preceded(space, username()))))(input)
+}
+
+/// Consumes zero or more `@host` hops
+fn host_chain(input: &str) -> IResult<'_, Vec<&str>> {
+    many0(preceded(
+        tag("@"),
+        take_while(|c| !matches!(c, '@' | '\r' | '\n')),
+    ))(input)
 }
 
 /// Consumes one or more space " " characters
-fn space(input: &str) -> IResult<()> {
+fn space(input: &str) -> IResult<'_, ()> {
     value((), take_while1(|c| c == ' '))(input)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn arb_request_line() -> impl Strategy<Value = String> {
+        let user = proptest::option::of(proptest::collection::vec("[-.0-9A-Za-z_]{1,16}", 1..3));
+        let forwarding = proptest::option::of(proptest::collection::vec("[-.0-9a-z]{1,12}", 1..4));
+
+        // Flags in any order
+        let flags = proptest::sample::subsequence(vec!["/W", "/U", "/P"], 0..=3).prop_shuffle();
+
+        (flags, 0..3usize, user, forwarding).prop_map(|(flags, spaces, user, forwarding)| {
+            let mut line = flags.join(" ");
+            if !flags.is_empty() && (user.is_some() || forwarding.is_some()) {
+                line.push_str(&" ".repeat(spaces + 1));
+            }
+            line.push_str(&user.unwrap_or_default().join(" "));
+            for host in forwarding.into_iter().flatten() {
+                line.push('@');
+                line.push_str(&host);
+            }
+            line.push_str("\r\n");
+            line
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn valid_requests_parse(line in arb_request_line()) {
+            prop_assert!(Request::from_str(&line).is_ok(), "{line:?}");
+        }
+
+        #[test]
+        fn format_round_trips(line in arb_request_line()) {
+            let req = Request::from_str(&line).unwrap();
+            let formatted = req.to_request_line();
+            prop_assert_eq!(Request::from_str(&formatted).unwrap(), req);
+        }
+
+        #[test]
+        fn parser_never_panics(bytes in proptest::collection::vec(any::<u8>(), 0..64)) {
+            if let Some(req) = Request::from_line(&bytes) {
+                let formatted = req.to_request_line();
+                prop_assert_eq!(Request::from_str(&formatted).unwrap(), req);
+            }
+        }
+
+        #[test]
+        fn parser_never_panics_on_crlf_lines(line in "[ -~]{0,32}") {
+            let line = format!("{line}\r\n");
+            if let Ok(req) = Request::from_str(&line) {
+                let formatted = req.to_request_line();
+                prop_assert_eq!(Request::from_str(&formatted).unwrap(), req);
+            }
+        }
+    }
+
+    #[test]
+    fn format() {
+        let req = |line| Request::from_str(line).unwrap().to_string();
+        assert_eq!(req("\r\n"), "");
+        assert_eq!(req("/W\r\n"), "/W");
+        assert_eq!(req("/W   alice\r\n"), "/W alice");
+        assert_eq!(req("alice@a@b\r\n"), "alice@a@b");
+        assert_eq!(req("/W @a\r\n"), "/W @a");
+        assert_eq!(req("/U  /W alice\r\n"), "/W /U alice");
+        assert_eq!(req("/U\r\n"), "/U");
+        assert_eq!(req("/P /W\r\n"), "/W /P");
+        assert_eq!(req("/W alice  bob@a\r\n"), "/W alice bob@a");
+    }
+
+    #[test]
+    fn host_list() {
+        let req = Request::from_str("alice@a@b\r\n").unwrap();
+        assert_eq!(req.forwarding, ["a", "b"]);
+        assert_eq!(req.strip_local_hosts(|host| host == "b").forwarding, ["a"]);
+
+        let req = Request::from_str("alice@\r\n").unwrap();
+        assert_eq!(req.forwarding, [""]);
+    }
+
+    #[test]
+    fn request_line() {
+        assert_eq!(Request::new_list(false).to_request_line(), "\r\n");
+        assert_eq!(
+            Request::new_user(true, "bob").to_request_line(),
+            "/W bob\r\n"
+        );
+    }
+}
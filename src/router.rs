@@ -0,0 +1,1182 @@
+//! Answering a request, in stages that can be tested and extended separately
+//!
+//! [Router::handle] runs each stage in turn: it [reads](Router::read) the request line,
+//! [authorizes](Router::authorize) it, [parses](Router::parse) it, [classifies](Router::classify)
+//! it, [resolves](Router::resolve) what it asks for, [renders](Router::render) the reply,
+//! [encodes](Router::encode) it for the client and [writes](Router::write) it. Each stage
+//! either hands its result to the next one or ends the request with a [Reply], usually a denial.
+//!
+//! While the reply is rendered, the router keeps [watching](disconnected) the client, so that
+//! requests whose client has gone away (e.g. reset the connection) stop without finishing work
+//! nobody will read, like relaying to an upstream server.
+
+use crate::abuse::Action;
+use crate::ban::Denial;
+use crate::config::{DefaultReply, Messages, User, Users};
+use crate::context::RequestContext;
+use crate::escape::Escaped;
+use crate::redact::Audience;
+use crate::request::Request;
+use crate::schedule::LocalTime;
+use crate::state::ServerState;
+use crate::validate::Charset;
+use crate::{
+    random, transliterate, upstream, REPLY_DID_YOU_MEAN, REPLY_MAINTENANCE, REPLY_MALFORMED,
+    REPLY_NO_FORWARDING, REPLY_NO_LISTING, REPLY_NO_NEWLINE, REPLY_REQUEST_TOO_LONG,
+    REPLY_USER_NOT_FOUND, SERVER_LINE,
+};
+use std::borrow::Cow;
+use std::io;
+use std::time::Duration;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::select;
+use tokio::time::Instant;
+
+/// Target of the logs of refused forwarding requests, naming the hosts they'd go through
+pub const FORWARDING_LOG_TARGET: &str = "fingered::forwarding";
+
+/// Reply to a request, and why the request was denied if it was
+///
+/// Replies rendered at load time (see [User::prerendered]) are borrowed from the config.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Reply<'a> {
+    pub text: Cow<'a, [u8]>,
+    pub denial: Option<Denial>,
+}
+
+impl<'a> Reply<'a> {
+    fn new(text: impl Into<Vec<u8>>) -> Self {
+        Self {
+            text: Cow::Owned(text.into()),
+            denial: None,
+        }
+    }
+
+    fn borrowed(text: &'a [u8]) -> Self {
+        Self {
+            text: Cow::Borrowed(text),
+            denial: None,
+        }
+    }
+
+    fn denied(text: impl Into<Vec<u8>>, denial: Denial) -> Self {
+        Self {
+            text: Cow::Owned(text.into()),
+            denial: Some(denial),
+        }
+    }
+}
+
+/// Request parsed by [Router::parse]
+#[derive(Debug)]
+pub struct Parsed<'a> {
+    pub request: Request<'a>,
+
+    /// Config of the namespace the request is made in, or the top-level config
+    pub users: &'a Users,
+}
+
+/// What a request asks for, found by [Router::resolve]
+#[derive(Debug)]
+pub enum Target<'a> {
+    /// Stats of the server, see [Users::stats_target]
+    Stats,
+
+    /// Usage information, see [Users::help_target]
+    Help {
+        /// Whether the client gets the listing when querying no user
+        index: bool,
+    },
+
+    /// Listing of the users with a tag
+    Tag(&'a str),
+
+    /// Page of the listing (from 1)
+    Page(usize),
+
+    /// User `name`, if it exists and isn't hidden from the client
+    User {
+        name: &'a str,
+        user: Option<&'a User>,
+        hidden: bool,
+    },
+
+    /// Reply to a query without a username, made of the MOTD and/or the first page of the listing
+    Index {
+        motd: Option<&'a str>,
+        listing: bool,
+    },
+}
+
+pub struct Router<'a> {
+    ctx: &'a RequestContext,
+
+    /// Top-level config
+    users: &'a Users,
+
+    state: &'a ServerState,
+}
+
+impl<'a> Router<'a> {
+    pub fn new(ctx: &'a RequestContext, users: &'a Users, state: &'a ServerState) -> Self {
+        Self { ctx, users, state }
+    }
+
+    /// Read a request from `input` and write the reply to `output`
+    ///
+    /// More requests are read from the connection while the client asks to keep it open (see
+    /// [Users::persistent_connections]). The first denial of its requests is returned.
+    pub async fn handle(
+        &self,
+        input: &mut (dyn AsyncRead + Send + Unpin),
+        output: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> io::Result<Option<Denial>> {
+        let buffer_size = self.users.requests.buffer_size(self.ctx.listener);
+        let mut input = BufReader::with_capacity(buffer_size, input);
+        let max_requests = self.users.persistent_connections.max_requests;
+        let mut denial = None;
+
+        for answered in 1.. {
+            let line = self.read(&mut input).await?;
+            let received_at = Instant::now();
+            if let Some(mirror) = &self.state.mirror {
+                mirror.send(&line);
+            }
+            self.state.stats.record_query();
+            let persistent =
+                max_requests > 0 && Request::from_line(&line).is_some_and(|req| req.persistent);
+
+            let reply = select! { biased;
+                reply = self.reply(&line, received_at) => reply,
+                err = disconnected(&mut input, persistent) => {
+                    debug!("client gone before the reply was rendered: {err}");
+                    return Err(err);
+                },
+            };
+            let mut reply = self.encode(&line, reply);
+            if persistent {
+                reply = frame(reply);
+            }
+            self.write(output, &reply).await?;
+            denial = denial.or(reply.denial);
+
+            if !persistent || answered >= max_requests || !self.next_request(&mut input).await? {
+                break;
+            }
+            debug!("reading request {} of the connection", answered + 1);
+        }
+        Ok(denial)
+    }
+
+    /// Wait for the next request of a persistent connection, for up to its idle timeout, and
+    /// return whether there's one
+    async fn next_request(
+        &self,
+        input: &mut (dyn AsyncBufRead + Send + Unpin),
+    ) -> io::Result<bool> {
+        let idle_timeout = self.users.persistent_connections.idle_timeout;
+        let waiting = tokio::time::timeout(Duration::from_secs(idle_timeout), input.fill_buf());
+        match waiting.await {
+            Ok(buffered) => Ok(!buffered?.is_empty()),
+            Err(_) => {
+                debug!("no request received within {idle_timeout}s, closing the connection");
+                Ok(false)
+            }
+        }
+    }
+
+    /// Run the stages between reading `line` and writing its reply
+    async fn reply<'l>(&self, line: &'l [u8], received_at: Instant) -> Reply<'l>
+    where
+        'a: 'l,
+    {
+        if self.state.in_maintenance() {
+            debug!("answered in maintenance mode");
+            let default =
+                (self.users.maintenance_reply.as_deref()).map_or(REPLY_MAINTENANCE, str::as_bytes);
+            return Reply::new(self.message(|messages| &messages.maintenance, default));
+        }
+
+        if let Some(reply) = self.authorize(line) {
+            // Requests denied by a deny rule get the reply of nonexistent users, and take as long
+            if reply.denial == Some(Denial::DenyRule) {
+                self.wait_reply_time(received_at).await;
+            }
+            return reply;
+        }
+
+        let parsed = match self.parse(line) {
+            Ok(parsed) => parsed,
+            Err(reply) => return reply,
+        };
+
+        if let Some(reply) = self.classify(line, &parsed).await {
+            return reply;
+        }
+
+        if !parsed.request.more_users.is_empty() {
+            return self.render_each(&parsed, received_at).await;
+        }
+
+        match self.resolve(&parsed) {
+            Ok(target) => self.render(&parsed, target, received_at).await,
+            Err(reply) => reply,
+        }
+    }
+
+    /// Resolve and render the reply to each user of `parsed`, which names several users, and join
+    /// them with the [Users::multi_user_separator]
+    ///
+    /// The reply is denied like the first denied reply, if any.
+    async fn render_each<'p>(&self, parsed: &Parsed<'p>, received_at: Instant) -> Reply<'p> {
+        let separator = (self.users.multi_user_separator.as_deref()).unwrap_or("\r\n");
+        let names = parsed.request.user.into_iter();
+        let names = names.chain(parsed.request.more_users.iter().copied());
+        debug!("requested {} users", parsed.request.more_users.len() + 1);
+
+        let mut joined = Reply::default();
+        for (i, name) in names.enumerate() {
+            let single = Parsed {
+                request: Request {
+                    user: Some(name),
+                    more_users: Vec::new(),
+                    ..parsed.request.clone()
+                },
+                users: parsed.users,
+            };
+            let reply = match self.resolve(&single) {
+                Ok(target) => self.render(&single, target, received_at).await,
+                Err(reply) => reply,
+            };
+
+            if i > 0 {
+                joined.text.to_mut().extend_from_slice(separator.as_bytes());
+            }
+            joined.text.to_mut().extend_from_slice(&reply.text);
+            joined.denial = joined.denial.or(reply.denial);
+        }
+        joined
+    }
+
+    /// Read the request line, up to its max length (see [Users::requests])
+    ///
+    /// The line is cut short if the client doesn't send a newline within [Users::line_timeout], but
+    /// keeps the bytes received until then.
+    pub async fn read(&self, input: &mut (dyn AsyncBufRead + Send + Unpin)) -> io::Result<Vec<u8>> {
+        let max_length = self.users.requests.max_length(self.ctx.listener);
+        let mut reader = input.take(max_length);
+        let mut line = Vec::with_capacity(32);
+
+        // Bytes read by `read_until` are kept in `line` even if it's cancelled
+        let reading = reader.read_until(b'\n', &mut line);
+        match self.users.line_timeout {
+            0 => _ = reading.await?,
+            timeout => match tokio::time::timeout(Duration::from_secs(timeout), reading).await {
+                Ok(result) => _ = result?,
+                Err(_) => debug!("no newline received within {timeout}s"),
+            },
+        }
+
+        Ok(line)
+    }
+
+    /// Denial of the raw request `line`, if it's too long or matches a [Users::deny] rule
+    pub fn authorize(&self, line: &[u8]) -> Option<Reply<'static>> {
+        let max_length = self.users.requests.max_length(self.ctx.listener);
+        if !line.ends_with(b"\n") && line.len() as u64 == max_length {
+            info!("request longer than {max_length} bytes");
+            self.state.stats.record_too_long();
+            let reply = self.message(|messages| &messages.too_long, REPLY_REQUEST_TOO_LONG);
+            return Some(Reply::denied(reply, Denial::TooLong));
+        }
+
+        if !line.ends_with(b"\n") {
+            info!("request without newline {}", Escaped(line));
+            let reply = self.message(|messages| &messages.no_newline, REPLY_NO_NEWLINE);
+            return Some(Reply::denied(reply, Denial::Malformed));
+        }
+
+        if self.users.deny.is_match(line) {
+            debug!("request denied by a deny rule");
+            let reply = self.message(|messages| &messages.user_not_found, REPLY_USER_NOT_FOUND);
+            return Some(Reply::denied(reply, Denial::DenyRule));
+        }
+
+        None
+    }
+
+    /// Parse `line` and pick the namespace it's made in, unless it's answered without being parsed
+    /// (malformed, or answered by a script)
+    pub fn parse<'l>(&self, line: &'l [u8]) -> Result<Parsed<'l>, Reply<'static>>
+    where
+        'a: 'l,
+    {
+        let users = self.users;
+        let raw = line;
+        let line = std::str::from_utf8(raw).ok();
+
+        #[cfg(feature = "scripting")]
+        if let (Some(hooks), Some(line)) = (&users.hooks, line) {
+            let line = line.trim_end_matches(['\r', '\n']);
+            if let Some(reply) = hooks.on_request(self.ctx, line) {
+                debug!("request answered by a script");
+                return Err(Reply::new(reply));
+            }
+        }
+
+        let Some(req) = line.and_then(|line| Request::from_str(line).ok()) else {
+            info!("malformed request {}", Escaped(raw));
+            return Err(self.malformed());
+        };
+        if !req.more_users.is_empty() && !users.multi_user_queries {
+            info!(
+                "request for several users {}, disabled by config",
+                Escaped(raw)
+            );
+            return Err(self.malformed());
+        }
+        let max = users.limits.max_users_per_request;
+        if max != 0 && req.more_users.len() + 1 > max {
+            info!("request for more than {max} users {}", Escaped(raw));
+            return Err(self.malformed());
+        }
+        let mut request = req.strip_local_hosts(|host| users.is_local_host(host));
+        request.verbose = users.verbose.apply(self.ctx.listener, request.verbose);
+
+        // A single `@domain` hop naming a namespace is a local query in that namespace
+        let namespace = match request.forwarding.as_slice() {
+            [domain] => users
+                .namespace(domain)
+                .map(|namespace| (*domain, namespace)),
+            _ => None,
+        };
+        let users = match namespace {
+            Some((domain, namespace)) => {
+                debug!("query in namespace {domain:?}");
+                request.forwarding.clear();
+                namespace
+            }
+            None => users,
+        };
+
+        Ok(Parsed { request, users })
+    }
+
+    /// Score `parsed` (read from `line`) with the [Users::abuse] policy, and delay it or deny it
+    /// depending on its score
+    #[cfg_attr(not(feature = "scripting"), allow(unused_variables))]
+    pub async fn classify(&self, line: &[u8], parsed: &Parsed<'_>) -> Option<Reply<'static>> {
+        let policy = &self.users.abuse;
+        if !policy.is_enabled() {
+            return None;
+        }
+
+        let requests = (self.ctx.ip).map_or(0, |ip| self.state.request_counts.record(ip));
+        let names = std::iter::once(parsed.request.user.unwrap_or_default());
+        let names = names.chain(parsed.request.more_users.iter().copied());
+        #[cfg_attr(not(feature = "scripting"), allow(unused_mut))]
+        let mut score = policy.score(self.ctx.ip, names, requests);
+
+        #[cfg(feature = "scripting")]
+        if let (Some(hooks), Ok(line)) = (&self.users.hooks, std::str::from_utf8(line)) {
+            let line = line.trim_end_matches(['\r', '\n']);
+            score += hooks.score_request(self.ctx, line, requests).unwrap_or(0);
+        }
+
+        let denial = match policy.action(score) {
+            Action::Allow => return None,
+            Action::Delay(delay) => {
+                debug!("request scored {score}, delayed by {delay:?}");
+                tokio::time::sleep(delay).await;
+                return None;
+            }
+            Action::Deny => Denial::Policy,
+            Action::Ban => Denial::PolicyBan,
+        };
+        debug!("request scored {score}, denied by the abuse policy");
+        let reply = self.message(|messages| &messages.user_not_found, REPLY_USER_NOT_FOUND);
+        Some(Reply::denied(reply, denial))
+    }
+
+    /// Find what `parsed` asks for, unless it's denied
+    pub fn resolve<'p>(&self, parsed: &Parsed<'p>) -> Result<Target<'p>, Reply<'static>> {
+        let Parsed { request, users } = parsed;
+        let users: &'p Users = users;
+
+        if !request.forwarding.is_empty() {
+            return Err(self.refuse_forwarding(&request.forwarding));
+        }
+
+        let listing_denied = || {
+            debug!("user list denied by config");
+            let reply = self.message(|messages| &messages.no_listing, REPLY_NO_LISTING);
+            Reply::denied(reply, Denial::Listing)
+        };
+
+        let default_reply = self.users.default_replies.get(self.ctx.family());
+        let index = users.enable_index && default_reply == DefaultReply::Index;
+
+        let Some(username) = request.user else {
+            debug!("requested user list");
+            let motd = (users.motd.as_deref()).filter(|_| default_reply != DefaultReply::Deny);
+            return match (motd, index) {
+                (None, false) => Err(listing_denied()),
+                (motd, listing) => Ok(Target::Index { motd, listing }),
+            };
+        };
+
+        if Some(username) == users.stats_target.as_deref() {
+            debug!("requested stats");
+            Ok(Target::Stats)
+        } else if Some(username) == users.help_target.as_deref() {
+            debug!("requested help");
+            Ok(Target::Help { index })
+        } else if let Some(tag) = users.tag_listing(username) {
+            debug!("requested user list for tag {tag:?}");
+            users
+                .enable_index
+                .then_some(Target::Tag(tag))
+                .ok_or_else(listing_denied)
+        } else if let Some(page) = users.listing_page_number(username) {
+            debug!("requested user list page {page}");
+            users
+                .enable_index
+                .then_some(Target::Page(page))
+                .ok_or_else(listing_denied)
+        } else {
+            let now = LocalTime::new(self.state.clock.system_now(), self.users.utc_offset);
+            let internal = self.users.is_internal(self.ctx.ip);
+            let user = users.find(username);
+            let hidden = user.is_some_and(|user| user.hidden && !internal);
+            let user = user.filter(|_| !hidden).map(|user| user.at(now));
+            Ok(Target::User {
+                name: username,
+                user,
+                hidden,
+            })
+        }
+    }
+
+    /// Wait until the [Users::min_reply_time] (plus some [Users::reply_jitter]) has passed since
+    /// the request was received at `received_at`
+    async fn wait_reply_time(&self, received_at: Instant) {
+        let reply_time = self.users.min_reply_time + random(self.users.reply_jitter + 1);
+        tokio::time::sleep_until(received_at + Duration::from_millis(reply_time)).await;
+    }
+
+    /// Render the reply to `parsed`, which asks for `target`
+    pub async fn render<'p>(
+        &self,
+        parsed: &Parsed<'p>,
+        target: Target<'p>,
+        received_at: Instant,
+    ) -> Reply<'p> {
+        let Parsed { request, users } = parsed;
+        let stats = &self.state.stats;
+
+        match target {
+            Target::Stats => {
+                let text = stats.render() + &self.users.render_generation();
+                Reply::new(self.with_server_line(text))
+            }
+            Target::Help { index } => {
+                let verbose = self.users.verbose.get(self.ctx.listener);
+                Reply::new(self.with_server_line(users.render_help(index, verbose)))
+            }
+            Target::Tag(tag) => Reply::new(users.render_tag_listing(tag, request.verbose)),
+            Target::Page(page) => Reply::new(users.render_listing_page(page, request.verbose)),
+            Target::Index { motd, listing } => {
+                let mut text = Vec::new();
+                if let Some(motd) = motd {
+                    text.extend_from_slice(motd.as_bytes());
+                }
+                if listing {
+                    text.extend_from_slice(
+                        users.render_listing_page(1, request.verbose).as_bytes(),
+                    );
+                }
+                Reply::new(text)
+            }
+            Target::User { name, user, hidden } => {
+                // Found and nonexistent users must be indistinguishable until the reply is sent
+                self.wait_reply_time(received_at).await;
+
+                match user {
+                    Some(user) => self.render_user(parsed, name, user).await,
+                    None => {
+                        if hidden {
+                            debug!("requested hidden user {name:?}");
+                            stats.record_hidden_user();
+                        } else {
+                            debug!("requested nonexistent user {name:?}");
+                            stats.record_unknown_user(name);
+                        }
+                        let mut text =
+                            self.message(|messages| &messages.user_not_found, REPLY_USER_NOT_FOUND);
+                        if let Some(suggestion) = users.suggestion(name) {
+                            text.extend_from_slice(
+                                (self.users)
+                                    .message(self.ctx.listener, |messages| &messages.did_you_mean)
+                                    .unwrap_or(REPLY_DID_YOU_MEAN)
+                                    .replace("{name}", suggestion)
+                                    .as_bytes(),
+                            );
+                        }
+                        Reply::denied(text, Denial::UnknownUser)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Denial of a request asking to be forwarded through `hosts` (see [Request::forwarding])
+    fn refuse_forwarding(&self, hosts: &[&str]) -> Reply<'static> {
+        let chain = hosts
+            .iter()
+            .map(|host| Escaped(host.as_bytes()).to_string());
+        let chain = chain.rev().collect::<Vec<_>>().join(" -> ");
+        info!(target: FORWARDING_LOG_TARGET, "refused forwarding through {chain} from {}", self.ctx.peer);
+
+        // Control characters of the hosts aren't sent back
+        let printable = |text: &str| text.replace(char::is_control, "");
+        let template = (self
+            .users
+            .message(self.ctx.listener, |messages| &messages.no_forwarding))
+        .or(self.users.forwarding_reply.as_deref())
+        .unwrap_or(REPLY_NO_FORWARDING);
+        let reply = template
+            .replace("{host}", &printable(hosts.last().unwrap()))
+            .replace("{chain}", &printable(&hosts.join("@")));
+        Reply::denied(reply, Denial::Forwarding)
+    }
+
+    /// Denial of a request that isn't a valid finger query
+    fn malformed(&self) -> Reply<'static> {
+        let users = self.users;
+        let default = (users.malformed_reply.as_deref()).map_or(REPLY_MALFORMED, str::as_bytes);
+        let reply = self.message(|messages| &messages.malformed, default);
+        Reply::denied(reply, Denial::Malformed)
+    }
+
+    /// Server message picked by `pick` in the client's language, or `default` if it's untranslated
+    fn message(&self, pick: fn(&Messages) -> &Option<String>, default: &[u8]) -> Vec<u8> {
+        let translated = self.users.message(self.ctx.listener, pick);
+        translated.map_or(default, str::as_bytes).to_vec()
+    }
+
+    /// Append the [SERVER_LINE] to `text` if the config allows disclosing it
+    fn with_server_line(&self, mut text: String) -> String {
+        if self.users.disclose_version {
+            text.push_str(SERVER_LINE);
+        }
+        text
+    }
+
+    /// Render the reply for the existing user `name`, relayed from its upstream if it has one
+    async fn render_user<'p>(&self, parsed: &Parsed<'_>, name: &str, user: &'p User) -> Reply<'p> {
+        let Parsed { request, users } = parsed;
+        debug!("requested user {name:?}");
+        self.state.stats.record_user(name);
+
+        let limits = user.expensive_long_info.filter(|_| request.verbose);
+        // Held until the reply is rendered
+        let rendering = limits.map(|limits| self.state.renders.start(name, limits));
+        let verbose = match rendering {
+            Some(None) => {
+                debug!("too many verbose queries for {name:?}, sending the short info");
+                false
+            }
+            _ => request.verbose,
+        };
+
+        if let Some(upstream) = &user.proxy_to {
+            debug!("relaying to {upstream:?}");
+            let ttl = Duration::from_secs(users.upstream_cache_ttl);
+            let negative_ttl = Duration::from_secs(users.upstream_negative_cache_ttl);
+            let reply = (self.state.upstream_cache)
+                .query(upstream, name, verbose, ttl, negative_ttl)
+                .await;
+            return match reply {
+                Ok(reply) => Reply::new(&reply[..]),
+                Err(err) => {
+                    warn!("cannot query upstream {upstream:?}: {err}");
+                    let failed = upstream::REPLY_UPSTREAM_FAILED;
+                    Reply::new(self.message(|messages| &messages.upstream_failed, failed))
+                }
+            };
+        }
+
+        // Nothing to add to the reply rendered at load time
+        #[cfg(feature = "scripting")]
+        let hooked = users.hooks.is_some();
+        #[cfg(not(feature = "scripting"))]
+        let hooked = false;
+        let last_modified_header = verbose && self.users.last_modified_header;
+        if let Some(prerendered) = user.prerendered.as_ref().filter(|_| !hooked) {
+            if !last_modified_header {
+                return Reply::borrowed(prerendered.get(verbose));
+            }
+        }
+
+        let audience = Audience {
+            verbose,
+            internal: self.users.is_internal(self.ctx.ip),
+        };
+        let (info, signature) = user.reply(name, &users.snippets, audience);
+        let (prefix, suffix) = user.frame(name, self.users.modified);
+
+        let mut text = Vec::new();
+        if last_modified_header {
+            if let Some(header) = user.last_modified_header(self.users.modified) {
+                text.extend_from_slice(header.as_bytes());
+            }
+        }
+        if let Some(prefix) = prefix {
+            text.extend_from_slice(prefix.as_bytes());
+        }
+
+        #[cfg(feature = "scripting")]
+        let rendered = (users.hooks.as_ref())
+            .and_then(|hooks| hooks.render_user(self.ctx, name, &info, verbose));
+        #[cfg(not(feature = "scripting"))]
+        let rendered = None::<String>;
+
+        match rendered {
+            // The signature is of the original info, so it's left out
+            Some(rendered) => text.extend_from_slice(rendered.as_bytes()),
+            None => {
+                text.extend_from_slice(info.as_bytes());
+                if let Some(signature) = signature {
+                    text.extend_from_slice(signature.as_bytes());
+                }
+            }
+        }
+        if let Some(suffix) = suffix {
+            text.extend_from_slice(suffix.as_bytes());
+        }
+
+        Reply::new(text)
+    }
+
+    /// Transliterate `reply` to the [Users::legacy_charset], unless the request `line` asks for
+    /// UTF-8
+    pub fn encode<'r>(&self, line: &[u8], mut reply: Reply<'r>) -> Reply<'r> {
+        if let Charset::Utf8 = self.users.legacy_charset {
+            return reply;
+        }
+        if Request::asks_for_utf8(line) {
+            return reply;
+        }
+
+        if let Cow::Owned(ascii) = transliterate::to_ascii(&reply.text) {
+            reply.text = Cow::Owned(ascii);
+        }
+        reply
+    }
+
+    /// Send `reply` to the client
+    pub async fn write(
+        &self,
+        output: &mut (dyn AsyncWrite + Send + Unpin),
+        reply: &Reply<'_>,
+    ) -> io::Result<()> {
+        output.write_all(&reply.text).await?;
+        output.flush().await
+    }
+}
+
+/// Wait until reading from the client fails, discarding anything it sends after the request line
+///
+/// A client that shut down its writing half is still waiting for its reply, so the end of `input`
+/// isn't taken as a disconnection. On `persistent` connections, what the client sends is its next
+/// requests, so it's kept and the client isn't watched anymore.
+async fn disconnected(
+    input: &mut (dyn AsyncBufRead + Send + Unpin),
+    persistent: bool,
+) -> io::Error {
+    loop {
+        let received = match input.fill_buf().await {
+            Ok([]) => return std::future::pending().await,
+            Ok(received) => received.len(),
+            Err(err) => return err,
+        };
+        if persistent {
+            return std::future::pending().await;
+        }
+        input.consume(received);
+    }
+}
+
+/// End `reply` with a `.` line, doubling the dots starting its lines, so that clients of
+/// persistent connections know where it ends
+fn frame(reply: Reply<'_>) -> Reply<'_> {
+    let mut text = Vec::with_capacity(reply.text.len() + 5);
+    for line in reply.text.split_inclusive(|&byte| byte == b'\n') {
+        if line.starts_with(b".") {
+            text.push(b'.');
+        }
+        text.extend_from_slice(line);
+    }
+    if !text.is_empty() && !text.ends_with(b"\n") {
+        text.extend_from_slice(b"\r\n");
+    }
+    text.extend_from_slice(b".\r\n");
+    Reply {
+        text: Cow::Owned(text),
+        denial: reply.denial,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::time::SystemTime;
+
+    const CONFIG: &str = r#"
+        default-replies = { local = "deny" }
+        users.alice = "Alice"
+    "#;
+
+    fn router_test(config: &str, test: impl FnOnce(Router)) {
+        let users = Users::parse(config).unwrap();
+        let ctx = RequestContext::new("replay", &"test", None, Duration::ZERO);
+        let state = ServerState::default();
+        test(Router::new(&ctx, &users, &state));
+    }
+
+    /// Like [router_test], for async tests of routers with the given `state`
+    async fn router_test_async(config: &str, state: ServerState, test: impl AsyncFnOnce(Router)) {
+        let users = Users::parse(config).unwrap();
+        let ctx = RequestContext::new("replay", &"test", None, Duration::ZERO);
+        test(Router::new(&ctx, &users, &state)).await;
+    }
+
+    #[test]
+    fn authorizes_long_requests() {
+        router_test(CONFIG, |router| {
+            let line = vec![b'a'; crate::SANE_REQUEST_LENGTH as usize];
+            let reply = router.authorize(&line).unwrap();
+            assert_eq!(reply.denial, Some(Denial::TooLong));
+            let reply = router.authorize(b"alice").unwrap();
+            assert_eq!(reply.text, REPLY_NO_NEWLINE);
+            assert_eq!(router.authorize(b"alice\r\n"), None);
+        });
+    }
+
+    #[test]
+    fn authorizes_requests_up_to_the_configured_length() {
+        let config = "requests.default.max-length = 8\nusers.alice = \"Alice\"";
+        router_test(config, |router| {
+            let reply = router.authorize(b"abcdefgh").unwrap();
+            assert_eq!(reply.denial, Some(Denial::TooLong));
+            assert_eq!(router.authorize(b"alice\r\n"), None);
+        });
+    }
+
+    #[test]
+    fn names_the_refused_hosts() {
+        let config = r#"
+            forwarding-reply = "Forwarding to '{host}' denied ({chain})"
+            users.alice = "Alice"
+        "#;
+        router_test(config, |router| {
+            let parsed = router.parse(b"alice@a.example@b\x1b.example\r\n").unwrap();
+            let reply = router.resolve(&parsed).unwrap_err();
+            assert_eq!(reply.denial, Some(Denial::Forwarding));
+            assert_eq!(
+                reply.text,
+                &b"Forwarding to 'b.example' denied (a.example@b.example)\r\n"[..]
+            );
+        });
+    }
+
+    #[test]
+    fn parses_requests() {
+        router_test(CONFIG, |router| {
+            let parsed = router.parse(b"/W alice\r\n").unwrap();
+            assert_eq!(parsed.request, Request::new_user(true, "alice"));
+
+            let reply = router.parse(b"alice\n").unwrap_err();
+            assert_eq!(reply.denial, Some(Denial::Malformed));
+        });
+    }
+
+    #[test]
+    fn resolves_targets() {
+        router_test(CONFIG, |router| {
+            let parsed = router.parse(b"alice\r\n").unwrap();
+            let target = router.resolve(&parsed).unwrap();
+            assert!(matches!(target, Target::User { user: Some(_), .. }));
+            let parsed = router.parse(b"bob\r\n").unwrap();
+            let target = router.resolve(&parsed).unwrap();
+            assert!(matches!(target, Target::User { user: None, .. }));
+
+            let parsed = router.parse(b"alice@example.com\r\n").unwrap();
+            let reply = router.resolve(&parsed).unwrap_err();
+            assert_eq!(reply.denial, Some(Denial::Forwarding));
+            let parsed = router.parse(b"\r\n").unwrap();
+            let reply = router.resolve(&parsed).unwrap_err();
+            assert_eq!(reply.denial, Some(Denial::Listing));
+        });
+    }
+
+    #[test]
+    fn resolves_requests_for_own_hostnames_locally() {
+        let config = r#"
+            hostnames = ["finger.example.", "Example.COM"]
+            users.alice = "Alice"
+        "#;
+        router_test(config, |router| {
+            for host in [
+                "finger.example",
+                "FINGER.example.",
+                "example.com",
+                "example.com.",
+            ] {
+                assert!(router.users.is_local_host(host), "{host}");
+            }
+            for host in [
+                "example",
+                "example.org",
+                "finger.example..",
+                "sub.example.com",
+            ] {
+                assert!(!router.users.is_local_host(host), "{host}");
+            }
+
+            let parsed = router.parse(b"alice@Finger.Example.\r\n").unwrap();
+            let target = router.resolve(&parsed).unwrap();
+            assert!(matches!(target, Target::User { user: Some(_), .. }));
+            let parsed = router.parse(b"alice@example.org\r\n").unwrap();
+            let reply = router.resolve(&parsed).unwrap_err();
+            assert_eq!(reply.denial, Some(Denial::Forwarding));
+        });
+    }
+
+    #[tokio::test]
+    async fn handles_requests() {
+        router_test_async(CONFIG, ServerState::default(), async |router| {
+            let mut output = Vec::new();
+            let denial = router.handle(&mut &b"alice\r\n"[..], &mut output).await;
+            assert_eq!(denial.unwrap(), None);
+            assert_eq!(output, b"Alice\r\n");
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn throttles_expensive_long_info() {
+        let config = r#"
+            [users.alice]
+            info = "Alice"
+            long-info = "Alice Doe"
+            expensive-long-info = { cooldown = 60 }
+        "#;
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+        router_test_async(
+            config,
+            ServerState::with_clock(clock.clone().into()),
+            async |router| {
+                for (elapsed, expected) in [
+                    (0, &b"Alice Doe\r\n"[..]),
+                    (59, b"Alice\r\n"),
+                    (1, b"Alice Doe\r\n"),
+                ] {
+                    clock.advance(Duration::from_secs(elapsed));
+                    let mut output = Vec::new();
+                    let denial = router.handle(&mut &b"/W alice\r\n"[..], &mut output).await;
+                    assert_eq!(denial.unwrap(), None);
+                    assert_eq!(output, expected);
+                }
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn scores_requests_with_the_abuse_policy() {
+        let config = r#"
+            users.alice = "Alice"
+            users.root = "Root"
+            [abuse]
+            rules = [
+                { users = ["^root$"], score = 60 },
+                { requests-per-minute = 3, score = 100 },
+                { peers = ["192.0.2.0/24"], score = 20 },
+            ]
+            deny-score = 60
+            ban-score = 100
+        "#;
+        let users = Users::parse(config).unwrap();
+        let ip = Some("192.0.2.1".parse().unwrap());
+        let ctx = RequestContext::new("tcp", &"192.0.2.1:1079", ip, Duration::ZERO);
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+        let state = ServerState::with_clock(clock.clone().into());
+        let router = Router::new(&ctx, &users, &state);
+
+        let replies = [
+            (0, "alice", None),
+            (0, "root", Some(Denial::Policy)),
+            (0, "alice", None),
+            (0, "alice", Some(Denial::PolicyBan)),
+            (60, "alice", None),
+        ];
+        for (elapsed, name, expected) in replies {
+            clock.advance(Duration::from_secs(elapsed));
+            let line = format!("{name}\r\n");
+            let mut output = Vec::new();
+            let denial = router.handle(&mut line.as_bytes(), &mut output).await;
+            assert_eq!(denial.unwrap(), expected, "{name} after {elapsed}s");
+        }
+    }
+
+    #[tokio::test]
+    async fn follows_the_schedule_of_users() {
+        let config = r#"
+            utc-offset = "+02:00"
+            [users.dave]
+            info = "Dave is offline"
+            [[users.dave.schedule]]
+            days = ["mon"]
+            hours = "09:00-17:30"
+            info = "Dave is at the office"
+        "#;
+        // Monday, January 5th 1970, 08:59 at UTC+2
+        let monday = SystemTime::UNIX_EPOCH + Duration::from_secs((4 * 24 + 6) * 3600 + 59 * 60);
+        let clock = ManualClock::new(monday);
+        router_test_async(
+            config,
+            ServerState::with_clock(clock.clone().into()),
+            async |router| {
+                let replies = [
+                    (0, "Dave is offline\r\n"),
+                    (60, "Dave is at the office\r\n"),
+                    (24 * 3600, "Dave is offline\r\n"),
+                ];
+                for (elapsed, expected) in replies {
+                    clock.advance(Duration::from_secs(elapsed));
+                    let mut output = Vec::new();
+                    router
+                        .handle(&mut &b"dave\r\n"[..], &mut output)
+                        .await
+                        .unwrap();
+                    assert_eq!(output, expected.as_bytes());
+                }
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn frames_the_info_of_users() {
+        let config = r#"
+            [users.alice]
+            info = "Alice"
+            prefix = "--- {name} ---"
+            suffix = "--- last edited {updated} ---"
+            updated = 2024-03-01
+        "#;
+        router_test_async(config, ServerState::default(), async |router| {
+            let mut output = Vec::new();
+            let denial = router.handle(&mut &b"alice\r\n"[..], &mut output).await;
+            assert_eq!(denial.unwrap(), None);
+            assert_eq!(
+                output,
+                b"--- alice ---\r\nAlice\r\n--- last edited 2024-03-01 ---\r\n"
+            );
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn answers_queries_for_several_users() {
+        let config = r#"
+            multi-user-queries = true
+            multi-user-separator = "--"
+            users.alice = "Alice"
+            users.bob = "Bob"
+        "#;
+        router_test_async(config, ServerState::default(), async |router| {
+            let mut output = Vec::new();
+            let denial = router
+                .handle(&mut &b"bob  carol alice\r\n"[..], &mut output)
+                .await;
+            assert_eq!(denial.unwrap(), Some(Denial::UnknownUser));
+            assert_eq!(output, b"Bob\r\n--\r\nUser not found\r\n--\r\nAlice\r\n");
+        })
+        .await;
+
+        router_test(CONFIG, |router| {
+            let reply = router.parse(b"alice bob\r\n").unwrap_err();
+            assert_eq!(reply.denial, Some(Denial::Malformed));
+        });
+
+        let config = format!("{config}\nlimits.max-users-per-request = 2");
+        router_test(&config, |router| {
+            assert!(router.parse(b"alice bob\r\n").is_ok());
+            let reply = router.parse(b"alice bob alice\r\n").unwrap_err();
+            assert_eq!(reply.denial, Some(Denial::Malformed));
+        });
+    }
+
+    #[tokio::test]
+    async fn keeps_persistent_connections_open() {
+        let config = r#"
+            persistent-connections.max-requests = 3
+            users.alice = ".Alice"
+            users.bob = "Bob"
+        "#;
+        router_test_async(config, ServerState::default(), async |router| {
+            // Requests after one without "/P" aren't read
+            let input = b"/P alice\r\n/P bob\r\nalice\r\nbob\r\n";
+            let mut output = Vec::new();
+            let denial = router.handle(&mut &input[..], &mut output).await;
+            assert_eq!(denial.unwrap(), None);
+            assert_eq!(output, b"..Alice\r\n.\r\nBob\r\n.\r\n.Alice\r\n");
+
+            // Nor requests past the max
+            let input = b"/P bob\r\n/P bob\r\n/P bob\r\n/P bob\r\n";
+            let mut output = Vec::new();
+            router.handle(&mut &input[..], &mut output).await.unwrap();
+            assert_eq!(output, b"Bob\r\n.\r\n".repeat(3));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn answers_every_query_in_maintenance_mode() {
+        let config = r#"
+            maintenance-reply = "Back soon"
+            users.alice.info = "Alice"
+        "#;
+        let state = ServerState::default();
+        state
+            .maintenance
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        router_test_async(config, state, async |router| {
+            for request in [&b"alice\r\n"[..], b"\r\n", b"nobody\r\n"] {
+                let mut output = Vec::new();
+                let denial = router.handle(&mut &request[..], &mut output).await;
+                assert_eq!(denial.unwrap(), None);
+                assert_eq!(output, b"Back soon\r\n");
+            }
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn relays_to_the_upstream_of_users() {
+        let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = format!(
+            "users.alice = {{ proxy-to = \"{}\" }}",
+            upstream.local_addr().unwrap()
+        );
+        router_test_async(&config, ServerState::default(), async |router| {
+            let answering = tokio::task::spawn(async move {
+                let (mut stream, _) = upstream.accept().await.unwrap();
+                let mut request = vec![0; b"/W alice\r\n".len()];
+                stream.read_exact(&mut request).await.unwrap();
+                stream.write_all(b"Alice, upstream\r\n").await.unwrap();
+                request
+            });
+
+            let mut output = Vec::new();
+            let denial = router.handle(&mut &b"/W alice\r\n"[..], &mut output).await;
+            assert_eq!(denial.unwrap(), None);
+            assert_eq!(output, b"Alice, upstream\r\n");
+            assert_eq!(answering.await.unwrap(), b"/W alice\r\n");
+        })
+        .await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_on_silent_upstreams() {
+        // Upstream server that accepts connections (in its backlog) but never replies
+        let upstream = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = format!(
+            "users.alice = {{ proxy-to = \"{}\" }}",
+            upstream.local_addr().unwrap()
+        );
+        router_test_async(&config, ServerState::default(), async |router| {
+            let mut output = Vec::new();
+            let denial = router.handle(&mut &b"alice\r\n"[..], &mut output).await;
+            assert_eq!(denial.unwrap(), None);
+            assert_eq!(output, upstream::REPLY_UPSTREAM_FAILED);
+        })
+        .await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn takes_as_long_to_deny_requests_as_to_miss_users() {
+        let config = "deny = [\"root\"]\nmin-reply-time = 50\nusers.alice = \"Alice\"";
+        router_test_async(config, ServerState::default(), async |router| {
+            let mut replies = Vec::new();
+            for request in [&b"root\r\n"[..], b"nobody\r\n"] {
+                let start = Instant::now();
+                let mut output = Vec::new();
+                router.handle(&mut &request[..], &mut output).await.unwrap();
+                replies.push((output, start.elapsed()));
+            }
+            assert_eq!(replies[0], replies[1]);
+            assert_eq!(replies[0].1, Duration::from_millis(50));
+        })
+        .await;
+    }
+
+    /// Client that resets the connection after sending its request
+    struct Resetting;
+
+    impl AsyncRead for Resetting {
+        fn poll_read(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            _: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()))
+        }
+    }
+
+    #[tokio::test]
+    async fn stops_when_the_client_is_gone() {
+        // Upstream server that accepts connections (in its backlog) but never replies
+        let upstream = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = format!(
+            "users.alice = {{ proxy-to = \"{}\" }}",
+            upstream.local_addr().unwrap()
+        );
+        router_test_async(&config, ServerState::default(), async |router| {
+            let mut input = (&b"alice\r\n"[..]).chain(Resetting);
+            let mut output = Vec::new();
+            let handling = router.handle(&mut input, &mut output);
+            let result = tokio::time::timeout(Duration::from_secs(1), handling).await;
+            let err = result.unwrap().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+            assert!(output.is_empty());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn answers_partial_lines_in_time() {
+        router_test_async(
+            "line-timeout = 1\nusers = {}",
+            ServerState::default(),
+            async |router| {
+                // The client stays connected without sending a newline
+                let (mut client, mut server) = tokio::io::duplex(64);
+                client.write_all(b"alice").await.unwrap();
+                let (mut input, mut output) = tokio::io::split(&mut server);
+                let denial = router.handle(&mut input, &mut output).await;
+                assert_eq!(denial.unwrap(), Some(Denial::Malformed));
+
+                let mut reply = vec![0; REPLY_NO_NEWLINE.len()];
+                client.read_exact(&mut reply).await.unwrap();
+                assert_eq!(reply, REPLY_NO_NEWLINE);
+            },
+        )
+        .await;
+    }
+}
@@ -0,0 +1,79 @@
+//! OS-specific privilege restriction, applied once setup is done
+//!
+//! On OpenBSD, the process is [pledged] and only the paths it still needs are [unveiled]. On
+//! FreeBSD, [Capsicum] capability mode is entered when the process won't need to open files or
+//! sockets anymore. Elsewhere, these functions do nothing.
+//!
+//! [pledged]: https://man.openbsd.org/pledge.2
+//! [unveiled]: https://man.openbsd.org/unveil.2
+//! [Capsicum]: https://man.freebsd.org/cgi/man.cgi?query=capsicum&sektion=4
+
+use std::io;
+use std::path::Path;
+
+/// Files read by the libc resolver, needed to reach upstream finger servers by name
+#[cfg(target_os = "openbsd")]
+const RESOLVER_FILES: &[&str] = &["/etc/resolv.conf", "/etc/hosts"];
+
+/// Restrict the daemon to networking, reading `readable` and creating/writing/deleting `writable`
+#[cfg(target_os = "openbsd")]
+pub fn restrict(readable: &[&Path], writable: &[&Path]) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    fn unveil(path: &Path, permissions: &str) -> io::Result<()> {
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        let permissions = CString::new(permissions)?;
+        // SAFETY: both pointers are valid NUL-terminated strings
+        match unsafe { libc::unveil(path.as_ptr(), permissions.as_ptr()) } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    for path in readable
+        .iter()
+        .copied()
+        .chain(RESOLVER_FILES.iter().map(Path::new))
+    {
+        unveil(path, "r")?;
+    }
+    for path in writable {
+        unveil(path, "rwc")?;
+    }
+
+    let promises = match writable.is_empty() {
+        true => "stdio rpath inet unix dns",
+        false => "stdio rpath wpath cpath inet unix dns",
+    };
+    let promises = CString::new(promises)?;
+    // SAFETY: `promises` is a valid NUL-terminated string, and a null `execpromises` is allowed.
+    // Pledging also locks unveil.
+    match unsafe { libc::pledge(promises.as_ptr(), std::ptr::null()) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(target_os = "openbsd"))]
+pub fn restrict(_readable: &[&Path], _writable: &[&Path]) -> io::Result<()> {
+    Ok(())
+}
+
+/// Forbid opening any new file or socket for the rest of the process' life
+///
+/// Only call this when the process has everything it needs, e.g. in inetd mode when no user has
+/// to be fetched from an upstream server.
+#[cfg(target_os = "freebsd")]
+pub fn enter_capability_mode() -> io::Result<()> {
+    // SAFETY: no preconditions
+    match unsafe { libc::cap_enter() } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(target_os = "freebsd"))]
+pub fn enter_capability_mode() -> io::Result<()> {
+    Ok(())
+}
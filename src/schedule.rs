@@ -0,0 +1,172 @@
+//! Info texts that depend on the time of the query, e.g. to tell when someone is at the office
+//!
+//! Each entry of [User::schedule] is matched against the current day of the week and time of day,
+//! in the [Users::utc_offset] time zone, and the first one that matches is used instead of the
+//! user. An entry is written like a user in the long config syntax, with `days` (e.g.
+//! `["mon", "tue"]`, every day if omitted) and `hours` (e.g. `"09:00-17:30"`, all day if omitted)
+//! keys. Hours may wrap past midnight (e.g. `"22:00-06:00"`), but days are always compared to the
+//! day of the query, so `days = ["sat"]` then covers Saturday's early morning and late evening.
+//!
+//! [User::schedule]: crate::config::User::schedule
+//! [Users::utc_offset]: crate::config::Users::utc_offset
+
+use crate::config::User;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::time::SystemTime;
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Scheduled {
+    /// Days on which this entry applies, every day if empty
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+
+    /// Time of day at which this entry applies, all day if unset
+    pub hours: Option<TimeRange>,
+
+    #[serde(flatten)]
+    pub user: User,
+}
+
+impl Scheduled {
+    pub fn matches(&self, now: LocalTime) -> bool {
+        (self.days.is_empty() || self.days.contains(&now.weekday))
+            && self.hours.is_none_or(|hours| hours.contains(now.minute))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl Weekday {
+    /// Day `days` days after the Unix epoch, which was a Thursday
+    fn from_days_since_epoch(days: i64) -> Self {
+        const WEEK: [Weekday; 7] = [
+            Weekday::Thu,
+            Weekday::Fri,
+            Weekday::Sat,
+            Weekday::Sun,
+            Weekday::Mon,
+            Weekday::Tue,
+            Weekday::Wed,
+        ];
+
+        WEEK[days.rem_euclid(7) as usize]
+    }
+}
+
+/// Range of minutes of the day, from `start` included to `end` excluded, wrapping past midnight if
+/// `end` is before `start`
+#[derive(Clone, Copy, Debug)]
+pub struct TimeRange {
+    start: u32,
+    end: u32,
+}
+
+impl TimeRange {
+    fn contains(self, minute: u32) -> bool {
+        match self.start <= self.end {
+            true => (self.start..self.end).contains(&minute),
+            false => minute >= self.start || minute < self.end,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeRange {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        let range = String::deserialize(de)?;
+        let invalid =
+            || serde::de::Error::custom(format!("invalid hours {range:?}, expected HH:MM-HH:MM"));
+
+        let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+        Ok(Self {
+            start: parse_time(start).ok_or_else(invalid)?,
+            end: parse_time(end).ok_or_else(invalid)?,
+        })
+    }
+}
+
+impl Serialize for TimeRange {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        let (start, end) = (format_time(self.start), format_time(self.end));
+        ser.serialize_str(&format!("{start}-{end}"))
+    }
+}
+
+/// Parse `HH:MM` into minutes since midnight, accepting `24:00` as the end of the day
+fn parse_time(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.trim().split_once(':')?;
+    let (hours, minutes) = (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?);
+    let time = hours * 60 + minutes;
+    (minutes < 60 && time <= MINUTES_PER_DAY).then_some(time)
+}
+
+fn format_time(time: u32) -> String {
+    format!("{:02}:{:02}", time / 60, time % 60)
+}
+
+/// Offset of a time zone from UTC, in minutes
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UtcOffset(i32);
+
+impl<'de> Deserialize<'de> for UtcOffset {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        let offset = String::deserialize(de)?;
+        let invalid = || {
+            serde::de::Error::custom(format!(
+                "invalid UTC offset {offset:?}, expected +HH:MM or -HH:MM"
+            ))
+        };
+
+        let (sign, time) = match offset.split_at_checked(1).ok_or_else(invalid)? {
+            ("+", time) => (1, time),
+            ("-", time) => (-1, time),
+            _ => return Err(invalid()),
+        };
+        Ok(Self(sign * parse_time(time).ok_or_else(invalid)? as i32))
+    }
+}
+
+impl Serialize for UtcOffset {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        let sign = if self.0 < 0 { '-' } else { '+' };
+        ser.serialize_str(&format!("{sign}{}", format_time(self.0.unsigned_abs())))
+    }
+}
+
+/// Day of the week and minute of the day at some instant, in some time zone
+#[derive(Clone, Copy, Debug)]
+pub struct LocalTime {
+    weekday: Weekday,
+    minute: u32,
+}
+
+impl LocalTime {
+    pub fn new(time: SystemTime, offset: UtcOffset) -> Self {
+        let seconds = match time.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(elapsed) => elapsed.as_secs() as i64,
+            Err(err) => -(err.duration().as_secs() as i64),
+        };
+        let minutes = seconds.div_euclid(60) + i64::from(offset.0);
+
+        Self {
+            weekday: Weekday::from_days_since_epoch(minutes.div_euclid(i64::from(MINUTES_PER_DAY))),
+            minute: minutes.rem_euclid(i64::from(MINUTES_PER_DAY)) as u32,
+        }
+    }
+
+    pub fn now(offset: UtcOffset) -> Self {
+        Self::new(SystemTime::now(), offset)
+    }
+}
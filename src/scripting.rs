@@ -0,0 +1,156 @@
+//! Lua hooks customizing the replies, loaded from the files of [Users::scripts]
+//!
+//! Scripts may define any of these global functions, whose `nil` results keep the default
+//! behavior:
+//!
+//! - `on_request(line, peer)`: called with the raw request line (without its CRLF) and the client
+//!   address, returns the whole reply to send instead of handling the request normally
+//! - `render_user(name, text, verbose)`: returns the text to send instead of the info of a user
+//! - `on_list(names)`: returns the names (a subset of `names`, in any order) to list
+//! - `score_request(line, peer, requests)`: called with the raw request line, the client address
+//!   and its number of requests within the current minute, returns a number added to the score of
+//!   the request by the abuse policy (see [crate::abuse])
+//!
+//! Scripts only get the `string`, `table`, `math` and `utf8` libraries, cannot read files, and
+//! each call is interrupted after [TIME_LIMIT]. All of them share a state limited to
+//! [MEMORY_LIMIT] bytes. They are loaded again each time the config is reloaded. `print` writes to
+//! the logs of the daemon (at the info level) instead of stdout, which is the client's socket in
+//! inetd mode.
+//!
+//! [Users::scripts]: crate::config::Users::scripts
+
+use crate::context::RequestContext;
+use mlua::{Function, HookTriggers, IntoLuaMulti, Lua, LuaOptions, StdLib, Value, Variadic};
+use std::fmt::{Debug, Formatter};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Max duration of a hook call
+const TIME_LIMIT: Duration = Duration::from_millis(100);
+
+/// Max memory used by the scripts
+const MEMORY_LIMIT: usize = 16 * 1024 * 1024;
+
+/// Number of instructions between checks of [TIME_LIMIT]
+const CHECK_INTERVAL: u32 = 1000;
+
+/// Deadline of the running call, stored in the app data of the Lua state
+struct Deadline(Instant);
+
+pub struct Hooks {
+    lua: Mutex<Lua>,
+}
+
+impl Debug for Hooks {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hooks").finish_non_exhaustive()
+    }
+}
+
+impl Hooks {
+    /// Run the scripts at `paths` in a new sandboxed state
+    pub fn load(paths: &[PathBuf]) -> Result<Self, String> {
+        let libs = StdLib::STRING | StdLib::TABLE | StdLib::MATH | StdLib::UTF8;
+        let lua = Lua::new_with(libs, LuaOptions::default()).map_err(|err| err.to_string())?;
+        lua.set_memory_limit(MEMORY_LIMIT)
+            .map_err(|err| err.to_string())?;
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(CHECK_INTERVAL),
+            |lua, _| match lua.app_data_ref::<Deadline>() {
+                Some(deadline) if Instant::now() > deadline.0 => {
+                    Err(mlua::Error::runtime("script took too long"))
+                }
+                _ => Ok(()),
+            },
+        );
+
+        for name in ["dofile", "loadfile", "require"] {
+            (lua.globals())
+                .set(name, Value::Nil)
+                .map_err(|err| err.to_string())?;
+        }
+        let print = lua.create_function(|_, args: Variadic<Value>| {
+            let args = (args.iter().map(Value::to_string)).collect::<mlua::Result<Vec<_>>>()?;
+            info!("script: {}", args.join("\t"));
+            Ok(())
+        });
+        (print.and_then(|print| lua.globals().set("print", print)))
+            .map_err(|err| err.to_string())?;
+
+        for path in paths {
+            let script = std::fs::read_to_string(path)
+                .map_err(|err| format!("cannot read {}: {err}", path.display()))?;
+            lua.set_app_data(Deadline(Instant::now() + TIME_LIMIT));
+            lua.load(script)
+                .set_name(format!("@{}", path.display()))
+                .exec()
+                .map_err(|err| format!("cannot run {}: {err}", path.display()))?;
+        }
+
+        Ok(Self {
+            lua: Mutex::new(lua),
+        })
+    }
+
+    /// Reply to send instead of handling `line` normally, if `on_request` returns one
+    pub fn on_request(&self, ctx: &RequestContext, line: &str) -> Option<String> {
+        let deadline = ctx.deadline.into_std();
+        self.call(deadline, "on_request", (line, ctx.peer.as_str()))
+    }
+
+    /// Text to send instead of the info of the user `name`, if `render_user` returns one
+    pub fn render_user(
+        &self,
+        ctx: &RequestContext,
+        name: &str,
+        text: &str,
+        verbose: bool,
+    ) -> Option<String> {
+        let deadline = ctx.deadline.into_std();
+        self.call(deadline, "render_user", (name, text, verbose))
+    }
+
+    /// Score added to the one of `line` by the abuse policy, if `score_request` returns one
+    pub fn score_request(&self, ctx: &RequestContext, line: &str, requests: u32) -> Option<i64> {
+        let deadline = ctx.deadline.into_std();
+        self.call(
+            deadline,
+            "score_request",
+            (line, ctx.peer.as_str(), requests),
+        )
+    }
+
+    /// Names to list instead of `names`, if `on_list` returns them
+    pub fn on_list(&self, names: Vec<&str>) -> Option<Vec<String>> {
+        let deadline = Instant::now() + TIME_LIMIT;
+        self.call(deadline, "on_list", names)
+    }
+
+    /// Call the global function `name` if it's defined, logging errors
+    ///
+    /// The call is interrupted after [TIME_LIMIT], or at `deadline` if it's sooner.
+    fn call<A, R>(&self, deadline: Instant, name: &str, args: A) -> Option<R>
+    where
+        A: for<'lua> IntoLuaMulti<'lua>,
+        R: for<'lua> mlua::FromLua<'lua>,
+    {
+        let lua = self.lua.lock().unwrap();
+        let function = match lua.globals().get::<_, Option<Function>>(name) {
+            Ok(function) => function?,
+            Err(err) => {
+                warn!("script hook {name} is not a function: {err}");
+                return None;
+            }
+        };
+
+        lua.set_app_data(Deadline(deadline.min(Instant::now() + TIME_LIMIT)));
+        match function.call::<_, Option<R>>(args) {
+            Ok(result) => result,
+            Err(err) => {
+                warn!("script hook {name} failed: {err}");
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! Encrypted config values
+//!
+//! Any `info` or `long-info` string in `users.toml` may be written as `enc:<base64>` (as printed
+//! by `fingered encrypt`), in which case it's decrypted at load time with the key given to the
+//! daemon. The payload is a random 12-byte nonce followed by the ChaCha20-Poly1305 ciphertext.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::fmt::{Display, Formatter};
+use std::sync::OnceLock;
+
+/// Prefix marking an encrypted config value
+pub const PREFIX: &str = "enc:";
+
+const NONCE_LENGTH: usize = 12;
+
+/// Key used to decrypt config values, set once at startup
+static KEY: OnceLock<Key> = OnceLock::new();
+
+#[derive(Debug)]
+pub enum Error {
+    /// An encrypted value was found but no key was given
+    NoKey,
+    /// The key isn't 32 base64-encoded bytes
+    MalformedKey,
+    /// The value isn't valid base64, or is too short to contain a nonce
+    MalformedValue,
+    /// The value wasn't encrypted with this key, or was tampered with
+    Decryption,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::NoKey => "encrypted value found but no secret key was given",
+            Self::MalformedKey => "secret key must be 32 base64-encoded bytes",
+            Self::MalformedValue => "malformed encrypted value",
+            Self::Decryption => "cannot decrypt value (wrong secret key?)",
+        })
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Parse a base64-encoded key, ignoring surrounding whitespace
+pub fn parse_key(encoded: &str) -> Result<Key, Error> {
+    let bytes = BASE64
+        .decode(encoded.trim())
+        .map_err(|_| Error::MalformedKey)?;
+
+    match bytes.len() {
+        32 => Ok(*Key::from_slice(&bytes)),
+        _ => Err(Error::MalformedKey),
+    }
+}
+
+/// Generate a new random base64-encoded key
+pub fn generate_key() -> String {
+    BASE64.encode(ChaCha20Poly1305::generate_key(&mut OsRng))
+}
+
+/// Set the key used by [decrypt_in_place]; only the first call has an effect
+pub fn set_key(key: Key) {
+    let _ = KEY.set(key);
+}
+
+/// Encrypt `plaintext` into a config value, including the [PREFIX]
+pub fn encrypt(key: &Key, plaintext: &str) -> String {
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = ChaCha20Poly1305::new(key)
+        .encrypt(&nonce, plaintext.as_bytes())
+        .unwrap();
+
+    let mut payload = nonce.to_vec();
+    payload.extend_from_slice(&ciphertext);
+    format!("{PREFIX}{}", BASE64.encode(payload))
+}
+
+/// Replace `value` with its decrypted content if it starts with [PREFIX]
+pub fn decrypt_in_place(value: &mut String) -> Result<(), Error> {
+    let Some(encoded) = value.strip_prefix(PREFIX) else {
+        return Ok(());
+    };
+
+    let key = KEY.get().ok_or(Error::NoKey)?;
+    let payload = BASE64
+        .decode(encoded.trim())
+        .map_err(|_| Error::MalformedValue)?;
+    if payload.len() < NONCE_LENGTH {
+        return Err(Error::MalformedValue);
+    }
+
+    let (nonce, ciphertext) = payload.split_at(NONCE_LENGTH);
+    let plaintext = ChaCha20Poly1305::new(key)
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| Error::Decryption)?;
+
+    *value = String::from_utf8(plaintext).map_err(|_| Error::MalformedValue)?;
+    Ok(())
+}
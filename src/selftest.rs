@@ -0,0 +1,152 @@
+use crate::config::{DefaultReply, Users};
+use crate::context;
+use crate::listener::{AnySocket, AnySocketAddr};
+use crate::redact;
+use crate::request::Request;
+use crate::schedule::LocalTime;
+use crate::snippet;
+use crate::{REPLY_NO_LISTING, SANE_REQUEST_LENGTH};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// How long the server has to answer each self-test query
+const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Query the server listening on `addr` like a client would, and check its replies against `users`
+///
+/// Every listed user is expected in the user listing, and every local user (neither relayed nor
+/// shadowed by a special target) is queried in both normal and verbose mode.
+#[instrument(skip_all)]
+pub async fn run(addr: AnySocketAddr, users: &Users) -> Result<(), String> {
+    // The server may not show the listing, or even the motd, to clients of this address family
+    let ip = match &addr {
+        AnySocketAddr::Tcp(addr) => Some(addr.ip()),
+        #[allow(unreachable_patterns)]
+        _ => None,
+    };
+    let default_reply = users.default_replies.get(context::family(ip));
+
+    // Replies are asked in UTF-8 (/U), so that they're compared with the config as written even
+    // with a legacy charset
+    let request = Request {
+        utf8: true,
+        ..Request::new_list(false)
+    };
+    let listing = query(&addr, &request.to_request_line()).await?;
+    let motd = (users.motd.as_deref())
+        .filter(|_| default_reply != DefaultReply::Deny)
+        .unwrap_or_default()
+        .as_bytes();
+    let Some(listing) = listing.strip_prefix(motd) else {
+        return Err(format!("expected listing to start with motd {motd:?}"));
+    };
+
+    // The server may force or forbid verbose replies on this kind of listener
+    let transport = match addr {
+        AnySocketAddr::Tcp(_) => "tcp",
+        #[cfg(all(unix, feature = "unix-socket"))]
+        AnySocketAddr::Unix(_) => "unix",
+        #[cfg(all(unix, feature = "seqpacket"))]
+        AnySocketAddr::SeqPacket(_) => "unix",
+    };
+
+    if users.enable_index && default_reply == DefaultReply::Index {
+        let verbose = users.verbose.apply(transport, false);
+        let expected = users.render_listing_page(1, verbose);
+        if listing != expected.as_bytes() {
+            let listing = String::from_utf8_lossy(listing);
+            return Err(format!("expected listing {expected:?}, got {listing:?}"));
+        }
+    } else {
+        // The motd replaces the denial message
+        let expected = match users.motd {
+            Some(_) if default_reply != DefaultReply::Deny => &[][..],
+            _ => (users.message(transport, |messages| &messages.no_listing))
+                .map_or(REPLY_NO_LISTING, str::as_bytes),
+        };
+        if listing != expected {
+            return Err(format!("expected listing to be denied, got {listing:?}"));
+        }
+    }
+    info!("listing ok");
+
+    let now = LocalTime::now(users.utc_offset);
+    // Hidden users may look nonexistent to the self-test, depending on the internal networks
+    for (name, user) in users.users.iter().filter(|(_, user)| !user.hidden) {
+        let user = user.at(now);
+        if user.proxy_to.is_some()
+            || user.fortune.is_some()
+            || user.contact.is_some()
+            || snippet::has_includes(user.info())
+            || snippet::has_includes(user.long_info())
+            || redact::has_sections(user.info())
+            || redact::has_sections(user.long_info())
+            || users.stats_target.as_ref() == Some(name)
+            || users.help_target.as_ref() == Some(name)
+            || users.tag_listing(name).is_some()
+            || users.listing_page_number(name).is_some()
+        {
+            continue;
+        }
+
+        let mut info = user.info().to_owned();
+        info.extend(user.signature(false));
+
+        let mut long_info = String::new();
+        if users.last_modified_header {
+            long_info.extend(user.last_modified_header(users.modified));
+        }
+        long_info.push_str(user.long_info());
+        long_info.extend(user.signature(true));
+
+        for verbose in [false, true] {
+            let request = Request {
+                utf8: true,
+                ..Request::new_user(verbose, name)
+            };
+            let request = request.to_request_line();
+            if users.deny.is_match(request.as_bytes()) {
+                continue;
+            }
+
+            let expected = match users.verbose.apply(transport, verbose) {
+                false => &info,
+                true => &long_info,
+            };
+
+            let reply = query(&addr, &request).await?;
+            if reply != expected.as_bytes() {
+                return Err(format!(
+                    "expected {expected:?} for {request:?}, got {:?}",
+                    String::from_utf8_lossy(&reply),
+                ));
+            }
+        }
+        info!("user {name:?} ok");
+    }
+
+    Ok(())
+}
+
+async fn query(addr: &AnySocketAddr, request: &str) -> Result<Vec<u8>, String> {
+    let exchange = async {
+        let mut socket = AnySocket::connect(addr).await?;
+        let mut socket = socket.split();
+        let (input, output) = socket.as_parts();
+        output.write_all(request.as_bytes()).await?;
+        output.flush().await?;
+
+        let mut reply = Vec::new();
+        input
+            .take(64 * SANE_REQUEST_LENGTH)
+            .read_to_end(&mut reply)
+            .await?;
+        std::io::Result::Ok(reply)
+    };
+
+    match tokio::time::timeout(QUERY_TIMEOUT, exchange).await {
+        Ok(Ok(reply)) => Ok(reply),
+        Ok(Err(err)) => Err(format!("cannot query {request:?}: {err}")),
+        Err(_) => Err(format!("timed out querying {request:?}")),
+    }
+}
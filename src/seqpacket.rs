@@ -0,0 +1,143 @@
+//! Unix sockets of the `SOCK_SEQPACKET` type, where a query and its reply are single packets
+//!
+//! Local clients can send a query in one packet and get the whole reply in one packet, without
+//! relying on shutdowns to tell where messages end. Only the first packet of a connection is read,
+//! and everything written is sent as one packet when flushed, so replies are limited by the size
+//! of the socket send buffer.
+
+use socket2::{Domain, SockAddr, Socket, Type};
+use std::io::{self, Read};
+use std::net::Shutdown;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, Interest, ReadBuf};
+
+/// Max number of connections waiting to be accepted
+const BACKLOG: i32 = 128;
+
+pub struct SeqPacketListener(AsyncFd<Socket>);
+
+impl SeqPacketListener {
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        let socket = Socket::new(Domain::UNIX, Type::SEQPACKET, None)?;
+        socket.bind(&SockAddr::unix(path)?)?;
+        socket.listen(BACKLOG)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self(AsyncFd::new(socket)?))
+    }
+
+    pub async fn accept(&self) -> io::Result<SeqPacketStream> {
+        let (socket, _) = (self.0)
+            .async_io(Interest::READABLE, |socket| socket.accept())
+            .await?;
+        SeqPacketStream::new(socket)
+    }
+}
+
+pub struct SeqPacketStream {
+    socket: AsyncFd<Socket>,
+
+    /// Whether the packet was read, after which reads return end-of-file
+    received: bool,
+
+    /// Bytes written since the last flush, sent as a packet on the next one
+    unsent: Vec<u8>,
+}
+
+impl SeqPacketStream {
+    fn new(socket: Socket) -> io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket: AsyncFd::new(socket)?,
+            received: false,
+            unsent: Vec::new(),
+        })
+    }
+
+    pub async fn connect(path: &Path) -> io::Result<Self> {
+        let socket = Socket::new(Domain::UNIX, Type::SEQPACKET, None)?;
+        // Connecting to a Unix socket doesn't block
+        socket.connect(&SockAddr::unix(path)?)?;
+        Self::new(socket)
+    }
+
+    pub fn split(&mut self) -> (ReadHalf<'_>, WriteHalf<'_>) {
+        let read = ReadHalf {
+            socket: &self.socket,
+            received: &mut self.received,
+        };
+        let write = WriteHalf {
+            socket: &self.socket,
+            unsent: &mut self.unsent,
+        };
+        (read, write)
+    }
+}
+
+pub struct ReadHalf<'a> {
+    socket: &'a AsyncFd<Socket>,
+    received: &'a mut bool,
+}
+
+impl AsyncRead for ReadHalf<'_> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if *this.received {
+            return Poll::Ready(Ok(()));
+        }
+
+        loop {
+            let mut guard = ready!(this.socket.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|socket| Read::read(&mut socket.get_ref(), unfilled)) {
+                Ok(Ok(length)) => {
+                    buf.advance(length);
+                    *this.received = true;
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(err)) => return Poll::Ready(Err(err)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+pub struct WriteHalf<'a> {
+    socket: &'a AsyncFd<Socket>,
+    unsent: &'a mut Vec<u8>,
+}
+
+impl AsyncWrite for WriteHalf<'_> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().unsent.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while !this.unsent.is_empty() {
+            let mut guard = ready!(this.socket.poll_write_ready(cx))?;
+            match guard.try_io(|socket| socket.get_ref().send(this.unsent)) {
+                Ok(Ok(_)) => this.unsent.clear(),
+                Ok(Err(err)) => return Poll::Ready(Err(err)),
+                Err(_would_block) => continue,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Poll::Ready(self.socket.get_ref().shutdown(Shutdown::Write))
+    }
+}
@@ -0,0 +1,44 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+type Hook = Box<dyn FnOnce() + Send>;
+
+/// Cleanup actions run when the daemon exits gracefully
+///
+/// Hooks run in reverse registration order, like destructors, so a hook can rely on everything
+/// that was set up before it still being there.
+#[derive(Default)]
+pub struct ShutdownHooks {
+    hooks: Mutex<Vec<(&'static str, Hook)>>,
+}
+
+impl ShutdownHooks {
+    /// Register a cleanup action; `name` only shows up in logs
+    pub fn register(&self, name: &'static str, hook: impl FnOnce() + Send + 'static) {
+        self.hooks.lock().unwrap().push((name, Box::new(hook)));
+    }
+
+    /// Run and forget every registered hook
+    pub fn run(&self) {
+        let hooks = std::mem::take(&mut *self.hooks.lock().unwrap());
+        for (name, hook) in hooks.into_iter().rev() {
+            debug!("running shutdown hook {name:?}");
+            hook();
+        }
+    }
+}
+
+/// Write the current process ID to `path`, and register a hook to remove it on exit
+pub fn create_pid_file(hooks: &ShutdownHooks, path: &Path) -> io::Result<()> {
+    std::fs::write(path, format!("{}\n", std::process::id()))?;
+
+    let path = PathBuf::from(path);
+    hooks.register("remove pid file", move || {
+        if let Err(err) = std::fs::remove_file(&path) {
+            warn!("cannot remove pid file {}: {err}", path.display());
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,113 @@
+//! Signed replies
+//!
+//! When the daemon is given a signing key, the info texts of every user are signed at load time,
+//! and each reply is followed by its detached signature in the [minisign][minisign] format, so that
+//! clients can check that it wasn't tampered with on its way. Replies can be verified by saving
+//! the text before the `untrusted comment:` line to a file, the rest to `<file>.minisig`, and
+//! running `minisign -V -P <public key> -m <file>`.
+//!
+//! The key file holds the base64-encoded 8-byte key ID followed by the 32-byte Ed25519 seed.
+//!
+//! [minisign]: https://jedisct1.github.io/minisign/
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use blake2::{Blake2b512, Digest};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use ed25519_dalek::{Signer as _, SigningKey};
+use std::fmt::{Display, Formatter};
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+const KEY_ID_LENGTH: usize = 8;
+
+/// Key used to sign info texts, set once at startup
+static SIGNER: OnceLock<Signer> = OnceLock::new();
+
+#[derive(Debug)]
+pub struct MalformedKey;
+
+impl Display for MalformedKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("signing key must be 40 base64-encoded bytes")
+    }
+}
+
+impl std::error::Error for MalformedKey {}
+
+pub struct Signer {
+    key_id: [u8; KEY_ID_LENGTH],
+    key: SigningKey,
+}
+
+impl Signer {
+    /// Parse a base64-encoded key, ignoring surrounding whitespace
+    pub fn parse(encoded: &str) -> Result<Self, MalformedKey> {
+        let bytes = BASE64.decode(encoded.trim()).map_err(|_| MalformedKey)?;
+        let (key_id, seed) = bytes
+            .split_first_chunk::<KEY_ID_LENGTH>()
+            .ok_or(MalformedKey)?;
+        let seed = <&[u8; 32]>::try_from(seed).map_err(|_| MalformedKey)?;
+
+        Ok(Self {
+            key_id: *key_id,
+            key: SigningKey::from_bytes(seed),
+        })
+    }
+
+    /// Public key in the format of minisign's `-P` option
+    pub fn public_key(&self) -> String {
+        let mut bytes = b"Ed".to_vec();
+        bytes.extend_from_slice(&self.key_id);
+        bytes.extend_from_slice(self.key.verifying_key().as_bytes());
+        BASE64.encode(bytes)
+    }
+
+    /// Detached signature of `text`, in the format of `.minisig` files with CRLF line endings
+    ///
+    /// `name` is recorded in the trusted comment, like the file name is by minisign.
+    pub fn sign(&self, text: &str, name: &str) -> String {
+        let signature = self.key.sign(&Blake2b512::digest(text));
+        let mut encoded = b"ED".to_vec();
+        encoded.extend_from_slice(&self.key_id);
+        encoded.extend_from_slice(&signature.to_bytes());
+
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let trusted_comment = format!("timestamp:{timestamp}\tfile:{name}");
+        let mut global = signature.to_bytes().to_vec();
+        global.extend_from_slice(trusted_comment.as_bytes());
+        let global = self.key.sign(&global);
+
+        format!(
+            "untrusted comment: signature from fingered secret key\r\n{}\r\ntrusted comment: {trusted_comment}\r\n{}\r\n",
+            BASE64.encode(encoded),
+            BASE64.encode(global.to_bytes()),
+        )
+    }
+}
+
+/// Generate a new random base64-encoded key
+pub fn generate_key() -> String {
+    let mut bytes = [0; KEY_ID_LENGTH + 32];
+    OsRng.fill_bytes(&mut bytes);
+    BASE64.encode(bytes)
+}
+
+/// Set the key used by [sign]; only the first call has an effect
+pub fn set_signer(signer: Signer) {
+    let _ = SIGNER.set(signer);
+}
+
+/// Public key matching the key given to the daemon, if any
+pub fn public_key() -> Option<String> {
+    Some(SIGNER.get()?.public_key())
+}
+
+/// Sign `text` with the key given to the daemon, if any
+pub fn sign(text: &str, name: &str) -> Option<String> {
+    Some(SIGNER.get()?.sign(text, name))
+}
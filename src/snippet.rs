@@ -0,0 +1,52 @@
+//! Reusable blocks of text shared by the info texts of several users
+//!
+//! A line containing only `{snippet:name}` is replaced by the snippet `name` of
+//! [Users::snippets] when the text is sent, so that boilerplate like a contact footer is
+//! maintained in one place. Snippets are expanded before [crate::redact] sections are rendered,
+//! so they can contain sections themselves, but they can't include other snippets.
+//!
+//! The built-in snippet [GENERATION] gives the generation of the config that produced the reply
+//! and when it was loaded (see [Users::generation]), replacing any snippet of the same name.
+//!
+//! [Users::snippets]: crate::config::Users::snippets
+//! [Users::generation]: crate::config::Users::generation
+
+use std::collections::HashMap;
+
+/// Name of the snippet set to the generation of the config, see [crate::config::Users::generation]
+pub const GENERATION: &str = "config-generation";
+
+fn parse_include(line: &str) -> Option<&str> {
+    (line.trim_end_matches(['\r', '\n']))
+        .strip_prefix("{snippet:")?
+        .strip_suffix('}')
+}
+
+/// Names of the snippets included by `text`, in order
+pub fn includes(text: &str) -> impl Iterator<Item = &str> {
+    text.lines().filter_map(parse_include)
+}
+
+/// Whether `text` includes snippets, i.e. whether it needs to be expanded with [expand]
+pub fn has_includes(text: &str) -> bool {
+    includes(text).next().is_some()
+}
+
+/// Replace the include lines of `text` by their snippet, keeping those of unknown snippets
+pub fn expand(text: &str, snippets: &HashMap<String, String>) -> String {
+    let mut expanded = String::with_capacity(text.len());
+
+    for line in text.split_inclusive('\n') {
+        match parse_include(line).and_then(|name| snippets.get(name)) {
+            Some(snippet) => {
+                expanded.push_str(snippet);
+                if !snippet.ends_with('\n') {
+                    expanded.push_str("\r\n");
+                }
+            }
+            None => expanded.push_str(line),
+        }
+    }
+
+    expanded
+}
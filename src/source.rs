@@ -0,0 +1,206 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+
+/// Where the content of `users.toml` comes from, and the values replacing some of it
+pub struct ConfigSource {
+    origin: Origin,
+    overrides: Vec<Override>,
+}
+
+enum Origin {
+    File(PathBuf),
+
+    #[cfg(feature = "remote-config")]
+    Url(remote::RemoteSource),
+}
+
+impl ConfigSource {
+    pub fn file(path: PathBuf) -> Self {
+        Self {
+            origin: Origin::File(path),
+            overrides: Vec::new(),
+        }
+    }
+
+    #[cfg(feature = "remote-config")]
+    pub fn url(remote: remote::RemoteSource) -> Self {
+        Self {
+            origin: Origin::Url(remote),
+            overrides: Vec::new(),
+        }
+    }
+
+    /// Apply `overrides` to the config each time it's read
+    pub fn with_overrides(mut self, overrides: Vec<Override>) -> Self {
+        self.overrides = overrides;
+        self
+    }
+
+    /// Read the config, or return `None` if it's known not to have changed since the last read
+    pub async fn read(&self) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        let toml = match &self.origin {
+            Origin::File(path) => Some(tokio::fs::read_to_string(path).await?),
+            #[cfg(feature = "remote-config")]
+            Origin::Url(remote) => remote.read().await?,
+        };
+
+        match toml {
+            Some(toml) if !self.overrides.is_empty() => {
+                Ok(Some(Override::apply_all(&self.overrides, &toml)?))
+            }
+            toml => Ok(toml),
+        }
+    }
+
+    /// Last modification time of the config, if known
+    pub async fn modified(&self) -> Option<SystemTime> {
+        match &self.origin {
+            Origin::File(path) => tokio::fs::metadata(path).await.ok()?.modified().ok(),
+            #[cfg(feature = "remote-config")]
+            Origin::Url(_) => None,
+        }
+    }
+
+    /// Local files that are read by [ConfigSource::read]
+    pub fn paths(&self) -> Vec<&Path> {
+        match &self.origin {
+            Origin::File(path) => vec![path],
+            #[cfg(feature = "remote-config")]
+            Origin::Url(remote) => remote.cache_file.as_deref().into_iter().collect(),
+        }
+    }
+}
+
+/// Value replacing one of the config, given as a TOML dotted key and value (`users.alice.info="Hi"`)
+///
+/// Values that aren't valid TOML are taken as strings, so quotes can be left out in most cases.
+/// Tables are merged with the ones of the config, and other values replace them.
+#[derive(Clone, Debug)]
+pub struct Override(toml::Table);
+
+impl FromStr for Override {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((key, value)) = s.split_once('=') else {
+            return Err(String::from("expected KEY=VALUE"));
+        };
+
+        let table = toml::from_str::<toml::Table>(s).or_else(|_| {
+            let value = toml::Value::String(value.to_owned());
+            toml::from_str::<toml::Table>(&format!("{key} = {value}"))
+                .map_err(|err| err.message().to_owned())
+        })?;
+
+        Ok(Self(table))
+    }
+}
+
+impl Override {
+    /// Shorthand for a top-level `key`
+    pub fn new(key: &str, value: impl Into<toml::Value>) -> Self {
+        Self(toml::Table::from_iter([(key.to_owned(), value.into())]))
+    }
+
+    /// Apply `overrides` in order to the `toml` config
+    pub fn apply_all(overrides: &[Override], toml: &str) -> Result<String, toml::de::Error> {
+        let mut config = toml::from_str::<toml::Table>(toml)?;
+        for r#override in overrides {
+            merge(&mut config, r#override.0.clone());
+        }
+        Ok(config.to_string())
+    }
+}
+
+fn merge(table: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (table.get_mut(&key), value) {
+            (Some(toml::Value::Table(table)), toml::Value::Table(overlay)) => merge(table, overlay),
+            (_, value) => {
+                table.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "remote-config")]
+pub mod remote {
+    use std::error::Error;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    /// How long the config server has to answer
+    const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Config fetched over HTTP(S), with a local copy used when the server is unreachable
+    pub struct RemoteSource {
+        client: reqwest::Client,
+        url: reqwest::Url,
+        pub cache_file: Option<PathBuf>,
+
+        /// ETag of the last successful fetch, sent back so the server can answer "304 Not Modified"
+        etag: Mutex<Option<String>>,
+    }
+
+    impl RemoteSource {
+        pub fn new(url: reqwest::Url, cache_file: Option<PathBuf>) -> Self {
+            Self {
+                client: reqwest::Client::builder()
+                    .timeout(FETCH_TIMEOUT)
+                    .user_agent(concat!("fingered/", env!("CARGO_PKG_VERSION")))
+                    .build()
+                    .unwrap(),
+                url,
+                cache_file,
+                etag: Mutex::new(None),
+            }
+        }
+
+        pub async fn read(&self) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+            match self.fetch().await {
+                Ok(Some(body)) => {
+                    if let Some(cache_file) = &self.cache_file {
+                        if let Err(err) = tokio::fs::write(cache_file, &body).await {
+                            warn!("cannot write config cache {}: {err}", cache_file.display());
+                        }
+                    }
+                    Ok(Some(body))
+                }
+                Ok(None) => Ok(None),
+                Err(err) => match &self.cache_file {
+                    Some(cache_file) => {
+                        warn!("cannot fetch {}: {err}; using cached config", self.url);
+                        Ok(Some(tokio::fs::read_to_string(cache_file).await?))
+                    }
+                    None => Err(err),
+                },
+            }
+        }
+
+        #[instrument(skip_all, fields(url = %self.url))]
+        async fn fetch(&self) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+            let mut request = self.client.get(self.url.clone());
+            if let Some(etag) = self.etag.lock().unwrap().as_deref() {
+                request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+            }
+
+            let response = request.send().await?;
+            if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+                debug!("config not modified");
+                return Ok(None);
+            }
+
+            let response = response.error_for_status()?;
+            let etag = (response.headers().get(reqwest::header::ETAG))
+                .and_then(|etag| etag.to_str().ok())
+                .map(str::to_owned);
+            let body = response.text().await?;
+
+            *self.etag.lock().unwrap() = etag;
+            Ok(Some(body))
+        }
+    }
+}
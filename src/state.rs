@@ -0,0 +1,119 @@
+use crate::abuse::RequestCounts;
+#[cfg(feature = "acme")]
+use crate::acme::Challenges;
+use crate::clock::SharedClock;
+use crate::config::{Config, Users};
+use crate::expensive::Renders;
+use crate::mirror::Mirror;
+use crate::stats::Stats;
+use crate::upstream::UpstreamCache;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Time between checks of the [Users::maintenance_file]
+const MAINTENANCE_FILE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// State shared by all connections, kept across config reloads
+pub struct ServerState {
+    pub stats: Stats,
+    pub upstream_cache: UpstreamCache,
+    pub renders: Renders,
+
+    /// Requests of each client, for the [Users::abuse] policy
+    pub request_counts: RequestCounts,
+
+    /// Whether maintenance mode was turned on through the admin socket
+    pub maintenance: AtomicBool,
+
+    /// Whether the [Users::maintenance_file] existed when it was last checked
+    pub maintenance_file: AtomicBool,
+
+    /// Where copies of the queries are sent, see `--mirror-to`
+    pub mirror: Option<Mirror>,
+
+    /// Pending challenges of the ACME server, answered by the metrics listener
+    #[cfg(feature = "acme")]
+    pub acme_challenges: Challenges,
+
+    /// Time read by scheduled info texts, and by the parts of the state above
+    pub clock: SharedClock,
+}
+
+impl Default for ServerState {
+    fn default() -> Self {
+        Self::with_clock(SharedClock::default())
+    }
+}
+
+impl ServerState {
+    /// State whose parts all read the time from `clock`
+    pub fn with_clock(clock: SharedClock) -> Self {
+        Self {
+            stats: Stats::with_clock(clock.clone()),
+            upstream_cache: UpstreamCache::with_clock(clock.clone()),
+            renders: Renders::with_clock(clock.clone()),
+            request_counts: RequestCounts::with_clock(clock.clone()),
+            maintenance: AtomicBool::default(),
+            maintenance_file: AtomicBool::default(),
+            mirror: None,
+            #[cfg(feature = "acme")]
+            acme_challenges: Challenges::default(),
+            clock,
+        }
+    }
+
+    /// Whether every query gets the [Users::maintenance_reply]
+    pub fn in_maintenance(&self) -> bool {
+        self.maintenance.load(Ordering::Relaxed) || self.maintenance_file.load(Ordering::Relaxed)
+    }
+
+    /// Check whether the [Users::maintenance_file] of `users` exists
+    pub async fn check_maintenance_file(&self, users: &Users) {
+        let exists = match &users.maintenance_file {
+            Some(path) => tokio::fs::try_exists(path).await.unwrap_or(false),
+            None => false,
+        };
+        if self.maintenance_file.swap(exists, Ordering::Relaxed) != exists {
+            match exists {
+                true => info!("maintenance file found, answering in maintenance mode"),
+                false => info!("maintenance file gone, answering normally"),
+            }
+        }
+    }
+}
+
+/// Keep the maintenance mode of `state` in sync with the [Users::maintenance_file], forever
+#[instrument(skip_all)]
+pub async fn watch_maintenance_file(config: Arc<Config>, state: Arc<ServerState>) {
+    loop {
+        state.check_maintenance_file(&*config.get().await).await;
+        tokio::time::sleep(MAINTENANCE_FILE_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn checks_the_maintenance_file() {
+        let path =
+            std::env::temp_dir().join(format!("fingered-maintenance-{}", std::process::id()));
+        let config = format!("maintenance-file = {path:?}\nusers.alice = \"Alice\"");
+        let users = Users::parse(&config).unwrap();
+        let state = ServerState::default();
+
+        state.check_maintenance_file(&users).await;
+        assert!(!state.in_maintenance());
+
+        std::fs::write(&path, "").unwrap();
+        state.check_maintenance_file(&users).await;
+        let created = state.in_maintenance();
+        std::fs::remove_file(&path).unwrap();
+        assert!(created);
+
+        state.check_maintenance_file(&users).await;
+        assert!(!state.in_maintenance());
+    }
+}
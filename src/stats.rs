@@ -0,0 +1,281 @@
+use crate::clock::SharedClock;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Number of users listed in the "top users" section of the stats reply
+const TOP_USERS: usize = 5;
+
+/// Max number of distinct nonexistent usernames counted, so clients can't grow the map at will
+const MAX_UNKNOWN_USERS: usize = 1000;
+
+/// Server-wide counters, kept across config reloads
+#[derive(Debug)]
+pub struct Stats {
+    started_at: Instant,
+    queries: AtomicU64,
+    reloads: AtomicU64,
+
+    /// Number of requests that didn't end within the max request length
+    too_long: AtomicU64,
+
+    /// Number of queries for hidden users answered as if they didn't exist
+    hidden: AtomicU64,
+
+    /// Number of connections that ended with an I/O error (including timeouts)
+    errors: AtomicU64,
+
+    /// Number of connections being handled
+    active: AtomicU64,
+
+    last_reload: Mutex<Option<SystemTime>>,
+
+    /// Number of queries for each existing user
+    ///
+    /// Nonexistent usernames aren't counted so clients can't grow this map at will.
+    users: Mutex<HashMap<String, u64>>,
+
+    /// Number of queries for each nonexistent user, up to [MAX_UNKNOWN_USERS] names
+    unknown_users: Mutex<HashMap<String, u64>>,
+
+    clock: SharedClock,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::with_clock(SharedClock::default())
+    }
+}
+
+impl Stats {
+    /// Counters whose uptime and reload times are read from `clock`
+    pub fn with_clock(clock: SharedClock) -> Self {
+        Self {
+            started_at: clock.now(),
+            queries: Default::default(),
+            reloads: Default::default(),
+            too_long: Default::default(),
+            hidden: Default::default(),
+            errors: Default::default(),
+            active: Default::default(),
+            last_reload: Default::default(),
+            users: Default::default(),
+            unknown_users: Default::default(),
+            clock,
+        }
+    }
+
+    pub fn record_query(&self) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_user(&self, name: &str) {
+        let mut users = self.users.lock().unwrap();
+        match users.get_mut(name) {
+            Some(count) => *count += 1,
+            None => {
+                users.insert(name.to_owned(), 1);
+            }
+        }
+    }
+
+    /// Count a query for a nonexistent user, unless too many names are already counted
+    pub fn record_unknown_user(&self, name: &str) {
+        let mut unknown_users = self.unknown_users.lock().unwrap();
+        if let Some(count) = unknown_users.get_mut(name) {
+            *count += 1;
+        } else if unknown_users.len() < MAX_UNKNOWN_USERS {
+            unknown_users.insert(name.to_owned(), 1);
+        }
+    }
+
+    pub fn record_hidden_user(&self) {
+        self.hidden.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_too_long(&self) {
+        self.too_long.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_reload(&self) {
+        self.reloads.fetch_add(1, Ordering::Relaxed);
+        *self.last_reload.lock().unwrap() = Some(self.clock.system_now());
+    }
+
+    pub fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a connection as active until the returned guard is dropped
+    pub fn track_connection(&self) -> ActiveConnection<'_> {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection(self)
+    }
+
+    /// One-line summary for heartbeat logs, see [crate::heartbeat]
+    pub fn summary(&self) -> String {
+        let last_reload = match *self.last_reload.lock().unwrap() {
+            Some(time) => humantime::format_rfc3339_seconds(time).to_string(),
+            None => "never".to_owned(),
+        };
+
+        format!(
+            "up {}, {} request(s), {} error(s), {} active connection(s), last reload {last_reload}",
+            humantime::format_duration(Duration::from_secs(self.uptime().as_secs())),
+            self.queries.load(Ordering::Relaxed),
+            self.errors.load(Ordering::Relaxed),
+            self.active.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.clock.now().saturating_duration_since(self.started_at)
+    }
+
+    /// Render the reply sent for the stats target, with CRLF line endings
+    pub fn render(&self) -> String {
+        let uptime = self.uptime().as_secs();
+        let mut reply = format!(
+            "Uptime: {}d {}h {}m {}s\r\nQueries: {}\r\nRequests too long: {}\r\nHidden user queries: {}\r\nReloads: {}\r\n",
+            uptime / 86400,
+            uptime / 3600 % 24,
+            uptime / 60 % 60,
+            uptime % 60,
+            self.queries.load(Ordering::Relaxed),
+            self.too_long.load(Ordering::Relaxed),
+            self.hidden.load(Ordering::Relaxed),
+            self.reloads.load(Ordering::Relaxed),
+        );
+
+        for (title, users) in [
+            ("Top users", &self.users),
+            ("Top unknown users", &self.unknown_users),
+        ] {
+            let top = top(&users.lock().unwrap());
+            if !top.is_empty() {
+                let _ = write!(reply, "{title}:\r\n");
+                for (name, count) in top {
+                    let _ = write!(reply, "  {name}: {count}\r\n");
+                }
+            }
+        }
+
+        reply
+    }
+
+    /// Render the counters in the [Prometheus text format][format], for [crate::metrics]
+    ///
+    /// Nonexistent usernames are left out, since clients choose them.
+    ///
+    /// [format]: https://prometheus.io/docs/instrumenting/exposition_formats/
+    pub fn render_prometheus(&self) -> String {
+        let mut metrics = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: &dyn std::fmt::Display| {
+            let _ = write!(
+                metrics,
+                "# HELP fingered_{name} {help}\n# TYPE fingered_{name} {kind}\nfingered_{name} {value}\n"
+            );
+        };
+
+        let counter = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        metric(
+            "uptime_seconds",
+            "gauge",
+            "Seconds since the daemon started.",
+            &self.uptime().as_secs(),
+        );
+        metric(
+            "queries_total",
+            "counter",
+            "Finger queries answered.",
+            &counter(&self.queries),
+        );
+        metric(
+            "requests_too_long_total",
+            "counter",
+            "Requests that didn't end within the max request length.",
+            &counter(&self.too_long),
+        );
+        metric(
+            "hidden_user_queries_total",
+            "counter",
+            "Queries for hidden users answered as if they didn't exist.",
+            &counter(&self.hidden),
+        );
+        metric(
+            "connection_errors_total",
+            "counter",
+            "Connections that ended with an I/O error, including timeouts.",
+            &counter(&self.errors),
+        );
+        metric(
+            "active_connections",
+            "gauge",
+            "Connections being handled.",
+            &counter(&self.active),
+        );
+        metric(
+            "reloads_total",
+            "counter",
+            "Config reloads.",
+            &counter(&self.reloads),
+        );
+        if let Some(time) = *self.last_reload.lock().unwrap() {
+            let since_epoch = time
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            metric(
+                "last_reload_timestamp_seconds",
+                "gauge",
+                "Unix time of the last config reload.",
+                &since_epoch.as_secs(),
+            );
+        }
+
+        let mut users = (self.users.lock().unwrap().iter())
+            .map(|(name, count)| (name.clone(), *count))
+            .collect::<Vec<_>>();
+        users.sort_unstable();
+        metrics.push_str(
+            "# HELP fingered_user_queries_total Queries for each existing user.\n# TYPE fingered_user_queries_total counter\n",
+        );
+        for (name, count) in users {
+            let _ = writeln!(
+                metrics,
+                "fingered_user_queries_total{{user=\"{}\"}} {count}",
+                escape_label(&name),
+            );
+        }
+
+        metrics
+    }
+}
+
+/// Escape a label value of the Prometheus text format
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The [TOP_USERS] most queried names of `users`, most queried first
+fn top(users: &HashMap<String, u64>) -> Vec<(String, u64)> {
+    let mut top = (users.iter())
+        .map(|(name, count)| (name.clone(), *count))
+        .collect::<Vec<_>>();
+    top.sort_unstable_by(|(a_name, a), (b_name, b)| b.cmp(a).then(a_name.cmp(b_name)));
+    top.truncate(TOP_USERS);
+    top
+}
+
+/// Guard returned by [Stats::track_connection]
+pub struct ActiveConnection<'a>(&'a Stats);
+
+impl Drop for ActiveConnection<'_> {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,100 @@
+//! Tracking of the tasks spawned by the daemon
+//!
+//! Connections and background services (reloads, config polling, the admin and WHOIS listeners...)
+//! are spawned in [Tasks] instead of being detached, so that a task that panics is logged instead
+//! of vanishing silently, the number of running tasks is known, and the connections still being
+//! answered when the daemon is asked to exit can be waited for.
+
+use futures::FutureExt;
+use std::any::Any;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::time::Duration;
+use tokio::task::JoinSet;
+
+/// Payload of a panic, usually its message
+type Panic = Box<dyn Any + Send>;
+
+/// Running tasks, each giving back its name (only shown in logs) and its panic if it panicked
+#[derive(Default)]
+pub struct Tasks {
+    set: JoinSet<(&'static str, Result<(), Panic>)>,
+}
+
+impl Tasks {
+    /// Run `task` in the background, discarding its output
+    pub fn spawn<T>(&mut self, name: &'static str, task: impl Future<Output = T> + Send + 'static) {
+        // Panics are caught in the task, where its name is known
+        self.set.spawn(async move {
+            let result = AssertUnwindSafe(task).catch_unwind().await;
+            (name, result.map(drop))
+        });
+    }
+
+    /// Number of running tasks
+    pub fn len(&self) -> usize {
+        self.set.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty()
+    }
+
+    /// Wait for a task to end, logging it if it panicked, and return its name
+    ///
+    /// Never returns while there's no task.
+    pub async fn join_next(&mut self) -> &'static str {
+        let (name, result) = match self.set.join_next().await {
+            Some(Ok(joined)) => joined,
+            // Tasks are only cancelled by [Tasks::drain], after which they're never joined
+            Some(Err(err)) => unreachable!("task cancelled: {err}"),
+            None => return std::future::pending().await,
+        };
+
+        if let Err(panic) = result {
+            error!("{name} task panicked: {}", panic_message(&*panic));
+        }
+        name
+    }
+
+    /// Wait for every task to end, for up to `timeout`, then abort the remaining ones and return
+    /// how many there were
+    pub async fn drain(&mut self, timeout: Duration) -> usize {
+        let all_ended = async {
+            while !self.set.is_empty() {
+                self.join_next().await;
+            }
+        };
+        if tokio::time::timeout(timeout, all_ended).await.is_ok() {
+            return 0;
+        }
+
+        let aborted = self.set.len();
+        self.set.shutdown().await;
+        aborted
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => payload.downcast_ref::<String>().map_or("?", String::as_str),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn survives_panics_and_aborts_late_tasks() {
+        let mut tasks = Tasks::default();
+        tasks.spawn("panicking", async { panic!("oops") });
+        assert_eq!(tasks.join_next().await, "panicking");
+
+        tasks.spawn("quick", async {});
+        tasks.spawn("slow", std::future::pending::<()>());
+        assert_eq!(tasks.drain(Duration::from_millis(50)).await, 1);
+        assert!(tasks.is_empty());
+    }
+}
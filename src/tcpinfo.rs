@@ -0,0 +1,74 @@
+//! Round-trip time and retransmissions of TCP connections, logged when they end
+//!
+//! With `--log-tcp-info`, the kernel's `TCP_INFO` of each TCP connection is sampled once it has
+//! been answered, and logged at the `info` level with the [LOG_TARGET] target, to tell whether
+//! distant clients have trouble with the plaintext protocol. Only Linux provides it; elsewhere
+//! nothing is logged.
+
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// Target of the log lines emitted for closed connections, for filtering them
+pub const LOG_TARGET: &str = "fingered::tcp";
+
+/// Figures of a TCP connection, see [sample]
+#[derive(Clone, Copy, Debug)]
+pub struct TcpInfo {
+    /// Smoothed round-trip time
+    pub rtt: Duration,
+
+    /// Variation of [TcpInfo::rtt]
+    pub rtt_var: Duration,
+
+    /// Number of segments sent again over the lifetime of the connection
+    pub retransmits: u32,
+}
+
+impl Display for TcpInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "rtt={:.3}ms rttvar={:.3}ms retransmits={}",
+            self.rtt.as_secs_f64() * 1000.0,
+            self.rtt_var.as_secs_f64() * 1000.0,
+            self.retransmits,
+        )
+    }
+}
+
+/// Read the `TCP_INFO` of `socket`
+#[cfg(target_os = "linux")]
+pub fn sample(socket: &TcpStream) -> io::Result<TcpInfo> {
+    use std::mem::MaybeUninit;
+    use std::os::fd::AsRawFd;
+
+    let mut info = MaybeUninit::<libc::tcp_info>::zeroed();
+    let mut length = size_of::<libc::tcp_info>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            info.as_mut_ptr().cast(),
+            &mut length,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Fields left out by older kernels stay zeroed
+    let info = unsafe { info.assume_init() };
+    Ok(TcpInfo {
+        rtt: Duration::from_micros(info.tcpi_rtt.into()),
+        rtt_var: Duration::from_micros(info.tcpi_rttvar.into()),
+        retransmits: info.tcpi_total_retrans,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn sample(_socket: &TcpStream) -> io::Result<TcpInfo> {
+    Err(io::ErrorKind::Unsupported.into())
+}
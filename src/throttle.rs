@@ -0,0 +1,351 @@
+use crate::clock::SharedClock;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWrite;
+use tokio::time::Sleep;
+
+/// Max bytes a writer takes from a [FairLimiter] in one turn
+const FAIR_QUANTUM: usize = 1024;
+
+/// Token bucket limiting a byte stream to a given rate
+///
+/// A rate of 0 disables the limit. The bucket holds at most a tenth of a second's worth of bytes,
+/// so slow rates produce a steady trickle rather than bursts.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: AtomicU32,
+    bucket: Mutex<Bucket>,
+    clock: SharedClock,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    /// Create a limiter allowing `rate` bytes per second, refilled as `clock` goes
+    pub fn new(rate: u32, clock: SharedClock) -> Self {
+        Self {
+            rate: AtomicU32::new(rate),
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                refilled_at: clock.now(),
+            }),
+            clock,
+        }
+    }
+
+    /// Change the rate, e.g. after the config is reloaded
+    pub fn set_rate(&self, rate: u32) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+
+    fn capacity(rate: u32) -> f64 {
+        (rate as f64 / 10.0).max(1.0)
+    }
+
+    /// How many bytes may be sent right now, or when to try again if none
+    fn available(&self) -> Result<usize, Instant> {
+        let rate = self.rate.load(Ordering::Relaxed);
+        if rate == 0 {
+            return Ok(usize::MAX);
+        }
+
+        let now = self.clock.now();
+        let mut bucket = self.bucket.lock().unwrap();
+        let elapsed = now.saturating_duration_since(bucket.refilled_at);
+        bucket.tokens =
+            (bucket.tokens + elapsed.as_secs_f64() * rate as f64).min(Self::capacity(rate));
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            Ok(bucket.tokens as usize)
+        } else {
+            let missing = 1.0 - bucket.tokens;
+            Err(now + Duration::from_secs_f64(missing / rate as f64))
+        }
+    }
+
+    fn consume(&self, bytes: usize) {
+        if self.rate.load(Ordering::Relaxed) != 0 {
+            self.bucket.lock().unwrap().tokens -= bytes as f64;
+        }
+    }
+}
+
+/// [RateLimiter] shared by many writers, whose bytes are handed out to their peers in turn
+///
+/// When the limit is reached, writers wait in one queue per peer, and the peers take turns, each
+/// getting at most [FAIR_QUANTUM] bytes for one of its writers. So a peer reading a huge reply, or
+/// opening many connections, only gets its share of the rate, and small replies to other peers
+/// are sent between its chunks instead of after it.
+#[derive(Debug)]
+pub struct FairLimiter {
+    limiter: RateLimiter,
+    turns: Mutex<Turns>,
+    next_writer: AtomicU64,
+}
+
+impl FairLimiter {
+    /// Create a limiter allowing `rate` bytes per second, 0 meaning unlimited
+    pub fn new(rate: u32, clock: SharedClock) -> Self {
+        Self {
+            limiter: RateLimiter::new(rate, clock),
+            turns: Mutex::default(),
+            next_writer: AtomicU64::new(0),
+        }
+    }
+
+    /// Change the rate, e.g. after the config is reloaded
+    pub fn set_rate(&self, rate: u32) {
+        self.limiter.set_rate(rate);
+    }
+}
+
+/// Writers of a peer waiting for their turn, with the waker of their task
+type Queue = VecDeque<(u64, Waker)>;
+
+/// Writers waiting for their turn to take bytes from a [FairLimiter]
+#[derive(Debug, Default)]
+struct Turns {
+    /// Writer allowed to take bytes, until it has written them
+    current: Option<u64>,
+
+    /// Peers whose writers wait for their turn, served in order, each with its own queue
+    waiting: VecDeque<(Option<IpAddr>, Queue)>,
+}
+
+impl Turns {
+    /// Whether it's the turn of `writer` (of `peer`), queuing it to be woken otherwise
+    fn take(&mut self, writer: u64, peer: Option<IpAddr>, waker: &Waker) -> bool {
+        if self.current == Some(writer) {
+            return true;
+        }
+        if self.current.is_none() && self.waiting.is_empty() {
+            self.current = Some(writer);
+            return true;
+        }
+
+        let queue = match self
+            .waiting
+            .iter()
+            .position(|(waiting, _)| *waiting == peer)
+        {
+            Some(i) => &mut self.waiting[i].1,
+            None => {
+                self.waiting.push_back((peer, Queue::new()));
+                &mut self.waiting.back_mut().unwrap().1
+            }
+        };
+        match queue.iter_mut().find(|(waiting, _)| *waiting == writer) {
+            Some((_, queued)) => queued.clone_from(waker),
+            None => queue.push_back((writer, waker.clone())),
+        }
+        false
+    }
+
+    /// End the turn of `writer`, or take it out of the queue, and wake the next writer if the turn
+    /// is free
+    fn release(&mut self, writer: u64) {
+        if self.current == Some(writer) {
+            self.current = None;
+        } else {
+            for (_, queue) in &mut self.waiting {
+                queue.retain(|(waiting, _)| *waiting != writer);
+            }
+            self.waiting.retain(|(_, queue)| !queue.is_empty());
+        }
+
+        if self.current.is_some() {
+            return;
+        }
+        if let Some((peer, mut queue)) = self.waiting.pop_front() {
+            let (next, waker) = queue.pop_front().unwrap();
+            // The peer waits for its next turn behind the others
+            if !queue.is_empty() {
+                self.waiting.push_back((peer, queue));
+            }
+            self.current = Some(next);
+            waker.wake();
+        }
+    }
+}
+
+/// Share of a [FairLimiter] used by a [Throttled] writer
+struct FairShare {
+    limiter: Arc<FairLimiter>,
+    peer: Option<IpAddr>,
+    writer: u64,
+}
+
+impl FairShare {
+    /// How many bytes may be sent right now, or when to try again if none, once it's the turn of
+    /// this writer
+    fn poll_available(&self, cx: &mut Context<'_>) -> Poll<Result<usize, Instant>> {
+        let limiter = &self.limiter.limiter;
+        if limiter.rate.load(Ordering::Relaxed) == 0 {
+            return Poll::Ready(Ok(usize::MAX));
+        }
+
+        let mut turns = self.limiter.turns.lock().unwrap();
+        if !turns.take(self.writer, self.peer, cx.waker()) {
+            return Poll::Pending;
+        }
+        Poll::Ready(
+            limiter
+                .available()
+                .map(|available| available.min(FAIR_QUANTUM)),
+        )
+    }
+
+    fn end_turn(&self) {
+        self.limiter.turns.lock().unwrap().release(self.writer);
+    }
+}
+
+impl Drop for FairShare {
+    fn drop(&mut self) {
+        self.end_turn();
+    }
+}
+
+/// Writer that doesn't exceed the rate of any of its [RateLimiter]s, nor its share of a
+/// [FairLimiter]
+pub struct Throttled<W> {
+    inner: W,
+    limiters: Vec<Arc<RateLimiter>>,
+    fair: Option<FairShare>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<W> Throttled<W> {
+    pub fn new(inner: W, limiters: impl IntoIterator<Item = Arc<RateLimiter>>) -> Self {
+        Self {
+            inner,
+            limiters: limiters.into_iter().collect(),
+            fair: None,
+            sleep: None,
+        }
+    }
+
+    /// Also take turns with the other writers of `limiter`, as a writer to `peer`
+    pub fn with_fair_share(mut self, limiter: Arc<FairLimiter>, peer: Option<IpAddr>) -> Self {
+        let writer = limiter.next_writer.fetch_add(1, Ordering::Relaxed);
+        self.fair = Some(FairShare {
+            limiter,
+            peer,
+            writer,
+        });
+        self
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Throttled<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+
+        let allowed = loop {
+            if let Some(sleep) = &mut this.sleep {
+                std::task::ready!(sleep.as_mut().poll(cx));
+                this.sleep = None;
+            }
+
+            let allowed = this
+                .limiters
+                .iter()
+                .map(|limiter| limiter.available())
+                .try_fold(buf.len(), |allowed, available| {
+                    available.map(|available| allowed.min(available))
+                });
+            // The turn is only waited for once the other limiters allow writing
+            let allowed = match (allowed, &this.fair) {
+                (Ok(allowed), Some(fair)) => std::task::ready!(fair.poll_available(cx))
+                    .map(|available| allowed.min(available)),
+                (allowed, _) => allowed,
+            };
+
+            match allowed {
+                Ok(allowed) => break allowed,
+                Err(retry_at) => {
+                    this.sleep = Some(Box::pin(tokio::time::sleep_until(retry_at.into())));
+                }
+            }
+        };
+
+        let written = Pin::new(&mut this.inner).poll_write(cx, &buf[..allowed]);
+        // Other writers aren't kept waiting while the client doesn't read
+        if let Some(fair) = &this.fair {
+            if let Poll::Ready(Ok(written)) = written {
+                fair.limiter.limiter.consume(written);
+            }
+            fair.end_turn();
+        }
+
+        let written = std::task::ready!(written)?;
+        for limiter in &this.limiters {
+            limiter.consume(written);
+        }
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::time::SystemTime;
+
+    #[test]
+    fn refills_as_the_clock_goes() {
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+        let limiter = RateLimiter::new(1000, clock.clone().into());
+        assert!(limiter.available().is_err());
+
+        clock.advance(Duration::from_millis(50));
+        assert_eq!(limiter.available(), Ok(50));
+        limiter.consume(50);
+
+        // The bucket holds at most a tenth of a second's worth of bytes
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(limiter.available(), Ok(100));
+    }
+
+    #[test]
+    fn peers_take_turns() {
+        let (alice, bob) = (Some([192, 0, 2, 1].into()), Some([192, 0, 2, 2].into()));
+        let mut turns = Turns::default();
+        assert!(turns.take(1, alice, Waker::noop()));
+        assert!(!turns.take(2, alice, Waker::noop()));
+        assert!(!turns.take(3, alice, Waker::noop()));
+        assert!(!turns.take(4, bob, Waker::noop()));
+
+        // Bob's writer goes between Alice's, however many she has
+        for writer in [1, 2, 4, 3] {
+            assert_eq!(turns.current, Some(writer));
+            turns.release(writer);
+        }
+        assert_eq!(turns.current, None);
+    }
+}
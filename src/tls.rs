@@ -0,0 +1,335 @@
+//! Finger over TLS, answered like queries of the TCP listener, with certificates picked by SNI
+//!
+//! ```toml
+//! [tls]
+//! certificates = [
+//!     { cert = "/etc/fingered/example.com.pem", key = "/etc/fingered/example.com.key" },
+//!     { cert = "/etc/fingered/example.org.pem", key = "/etc/fingered/example.org.key" },
+//! ]
+//! ```
+//!
+//! The listener is started with `--tls-bind-to`. Each client gets the first certificate valid for
+//! the hostname it asks for (SNI), or the first certificate if none is. Files are in the PEM
+//! format: the certificate chain, end-entity certificate first, and its private key (PKCS#8,
+//! PKCS#1 or SEC1). Connections count as `tcp` ones for the settings of each kind of listener.
+//!
+//! The files are checked every [POLL_INTERVAL], and every certificate is loaded again when one of
+//! them changed (e.g. renewed by certbot) or the config was reloaded. If one of them can't be
+//! loaded, the previous certificates are kept until the files change again.
+//!
+//! With the `acme` feature, a certificate can also be issued and renewed by an ACME server, see
+//! [crate::acme].
+
+#[cfg(feature = "acme")]
+use crate::acme::AcmeConfig;
+use crate::config::{Config, Users};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use serde::{Deserialize, Serialize};
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio_rustls::TlsAcceptor;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Certificates of the listener, the first one being served to clients none is valid for
+    pub certificates: Vec<CertificateFiles>,
+
+    /// Certificate issued by an ACME server, served after the ones above
+    #[cfg(feature = "acme")]
+    pub acme: Option<AcmeConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct CertificateFiles {
+    /// PEM file of the certificate chain, end-entity certificate first
+    pub cert: PathBuf,
+
+    /// PEM file of the private key of the certificate
+    pub key: PathBuf,
+}
+
+impl TlsConfig {
+    /// Files read by the listener
+    pub fn paths(&self) -> Vec<PathBuf> {
+        #[allow(unused_mut)]
+        let mut files = self.certificates.clone();
+        #[cfg(feature = "acme")]
+        files.extend(self.acme.as_ref().map(AcmeConfig::files));
+        (files.into_iter())
+            .flat_map(|files| [files.cert, files.key])
+            .collect()
+    }
+
+    /// Whether a certificate is issued by an ACME server
+    fn uses_acme(&self) -> bool {
+        #[cfg(feature = "acme")]
+        let uses_acme = self.acme.is_some();
+        #[cfg(not(feature = "acme"))]
+        let uses_acme = false;
+        uses_acme
+    }
+}
+
+/// Certificates of the listener, replaced when their files change
+#[derive(Debug)]
+pub struct Certificates {
+    keys: RwLock<Arc<[Arc<CertifiedKey>]>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl Certificates {
+    /// Load the certificates of `config`, failing if there's none
+    pub async fn load(config: &TlsConfig) -> Result<Self, String> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        Ok(Self {
+            keys: RwLock::new(load(config, &provider).await?),
+            provider,
+        })
+    }
+
+    /// Acceptor of TLS connections, serving these certificates as they're replaced
+    pub fn acceptor(self: &Arc<Self>) -> TlsAcceptor {
+        let config = rustls::ServerConfig::builder_with_provider(Arc::clone(&self.provider))
+            .with_safe_default_protocol_versions()
+            .expect("the provider supports the default protocol versions")
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::clone(self) as Arc<dyn ResolvesServerCert>);
+        TlsAcceptor::from(Arc::new(config))
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let keys = Arc::clone(&self.keys.read().unwrap());
+        let name = (client_hello.server_name()).and_then(|name| ServerName::try_from(name).ok());
+        let matching = name.and_then(|name| {
+            (keys.iter()).find(|key| {
+                key.cert
+                    .first()
+                    .is_some_and(|cert| is_valid_for(cert, &name))
+            })
+        });
+        matching.or(keys.first()).cloned()
+    }
+}
+
+/// Whether the end-entity certificate `cert` is valid for `name`
+pub fn is_valid_for(cert: &CertificateDer, name: &ServerName) -> bool {
+    webpki::EndEntityCert::try_from(cert)
+        .is_ok_and(|cert| cert.verify_is_valid_for_subject_name(name).is_ok())
+}
+
+/// Keep `certificates` in sync with their files and the config, forever
+#[instrument(skip_all)]
+pub async fn watch(config: Arc<Config>, certificates: Arc<Certificates>) {
+    let mut last = Snapshot::new(&*config.get().await).await;
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+
+        let users = config.get().await;
+        let snapshot = Snapshot::new(&users).await;
+        if snapshot == last {
+            continue;
+        }
+
+        match load(&users.tls, &certificates.provider).await {
+            Ok(keys) => {
+                info!("{} TLS certificate(s) loaded", keys.len());
+                *certificates.keys.write().unwrap() = keys;
+            }
+            Err(err) => warn!("keeping the previous TLS certificates: {err}"),
+        }
+        last = snapshot;
+    }
+}
+
+/// State of the certificate files and of the config, loaded again when it changes
+#[derive(Debug, PartialEq)]
+struct Snapshot {
+    generation: u64,
+    certificates: Vec<CertificateFiles>,
+
+    /// Modification time, size and inode of each file, if it can be read
+    files: Vec<Option<(Option<SystemTime>, u64, u64)>>,
+}
+
+impl Snapshot {
+    async fn new(users: &Users) -> Self {
+        let mut files = Vec::new();
+        for path in users.tls.paths() {
+            let metadata = tokio::fs::metadata(&path).await.ok();
+            files.push(
+                metadata.map(|metadata| (metadata.modified().ok(), metadata.len(), metadata.ino())),
+            );
+        }
+
+        Self {
+            generation: users.generation,
+            certificates: users.tls.certificates.clone(),
+            files,
+        }
+    }
+}
+
+async fn load(
+    config: &TlsConfig,
+    provider: &CryptoProvider,
+) -> Result<Arc<[Arc<CertifiedKey>]>, String> {
+    #[allow(unused_mut)]
+    let mut files = config.certificates.clone();
+    #[cfg(feature = "acme")]
+    if let Some(acme) = &config.acme {
+        // Until it's issued, only the other certificates are served
+        let acme = acme.files();
+        if tokio::fs::try_exists(&acme.cert).await.unwrap_or(true) {
+            files.push(acme);
+        }
+    }
+    if files.is_empty() && !config.uses_acme() {
+        return Err("no certificates in the config".into());
+    }
+
+    let mut keys = Vec::new();
+    for files in &files {
+        let key = load_key(files, provider).await;
+        keys.push(Arc::new(key.map_err(|err| {
+            format!("cannot load {}: {err}", files.cert.display())
+        })?));
+    }
+    Ok(keys.into())
+}
+
+/// Load the certificate chain of `files` and its private key, checking that they match
+async fn load_key(
+    files: &CertificateFiles,
+    provider: &CryptoProvider,
+) -> Result<CertifiedKey, String> {
+    let chain = tokio::fs::read(&files.cert)
+        .await
+        .map_err(|err| err.to_string())?;
+    let chain = (CertificateDer::pem_slice_iter(&chain))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| err.to_string())?;
+    if chain.is_empty() {
+        return Err("no certificate in the file".into());
+    }
+
+    let key = tokio::fs::read(&files.key).await;
+    let key = key.map_err(|err| format!("{}: {err}", files.key.display()))?;
+    let key = PrivateKeyDer::from_pem_slice(&key)
+        .map_err(|err| format!("{}: {err}", files.key.display()))?;
+
+    CertifiedKey::from_der(chain, key, provider).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Certificate and key of `host`, signed by the CA of `ca.pem`
+    fn files(host: &str) -> CertificateFiles {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/tls");
+        CertificateFiles {
+            cert: dir.join(format!("{host}.pem")),
+            key: dir.join(format!("{host}.key")),
+        }
+    }
+
+    fn tls_config(certificates: Vec<CertificateFiles>) -> TlsConfig {
+        TlsConfig {
+            certificates,
+            #[cfg(feature = "acme")]
+            acme: None,
+        }
+    }
+
+    /// Connect to `acceptor` asking for `name`, trusting the test CA, and return what it answers
+    async fn query(acceptor: TlsAcceptor, name: &'static str) -> std::io::Result<String> {
+        let ca = std::fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("src/tls/ca.pem"))?;
+        let mut roots = rustls::RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_slice(&ca).unwrap())
+            .unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+
+        let (client, server) = tokio::io::duplex(4096);
+        let serving = tokio::task::spawn(async move {
+            let mut stream = acceptor.accept(server).await?;
+            stream.write_all(b"Alice\r\n").await?;
+            stream.shutdown().await
+        });
+
+        let name = ServerName::try_from(name).unwrap();
+        let mut stream = connector.connect(name, client).await?;
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).await?;
+        serving.await.unwrap()?;
+        Ok(reply)
+    }
+
+    #[tokio::test]
+    async fn picks_certificates_by_name() {
+        let config = tls_config(vec![files("a.example"), files("b.example")]);
+        let certificates = Arc::new(Certificates::load(&config).await.unwrap());
+
+        for name in ["a.example", "b.example"] {
+            let reply = query(certificates.acceptor(), name).await.unwrap();
+            assert_eq!(reply, "Alice\r\n", "{name}");
+        }
+        // Gets the certificate of a.example, which isn't valid for this name
+        assert!(query(certificates.acceptor(), "c.example").await.is_err());
+
+        let config = TlsConfig::default();
+        assert!(Certificates::load(&config).await.is_err());
+        let config = tls_config(vec![CertificateFiles {
+            cert: files("a.example").cert,
+            key: files("b.example").key,
+        }]);
+        assert!(Certificates::load(&config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn notices_changes_of_the_files() {
+        let dir = std::env::temp_dir().join(format!("fingered-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let copy = CertificateFiles {
+            cert: dir.join("cert.pem"),
+            key: dir.join("key.pem"),
+        };
+        std::fs::copy(files("a.example").cert, &copy.cert).unwrap();
+        std::fs::copy(files("a.example").key, &copy.key).unwrap();
+
+        let mut users = Users::default();
+        users.tls.certificates = vec![copy.clone()];
+        let before = Snapshot::new(&users).await;
+        assert_eq!(Snapshot::new(&users).await, before);
+
+        // Renewed by writing new files and moving them in place, like certbot does
+        std::fs::copy(files("b.example").cert, dir.join("new.pem")).unwrap();
+        std::fs::rename(dir.join("new.pem"), &copy.cert).unwrap();
+        let after = Snapshot::new(&users).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_ne!(after, before);
+
+        users.generation += 1;
+        assert_ne!(Snapshot::new(&users).await, after);
+    }
+}
@@ -0,0 +1,104 @@
+//! Replies for clients that only handle ASCII, see [Users::legacy_charset]
+//!
+//! Non-ASCII characters are replaced with their closest ASCII spelling when there's an obvious one
+//! (accented Latin letters lose their accent, typographic quotes and dashes become plain ones...),
+//! and with `?` otherwise, like bytes that aren't valid UTF-8.
+//!
+//! [Users::legacy_charset]: crate::config::Users::legacy_charset
+
+use std::borrow::Cow;
+
+/// Transliterate `text` to ASCII, borrowing it if it's already ASCII
+pub fn to_ascii(text: &[u8]) -> Cow<'_, [u8]> {
+    if text.is_ascii() {
+        return Cow::Borrowed(text);
+    }
+
+    let mut ascii = Vec::with_capacity(text.len());
+    for chunk in text.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c.is_ascii() {
+                true => ascii.push(c as u8),
+                false => ascii.extend_from_slice(transliterate(c).as_bytes()),
+            }
+        }
+        if !chunk.invalid().is_empty() {
+            ascii.push(b'?');
+        }
+    }
+    Cow::Owned(ascii)
+}
+
+fn transliterate(c: char) -> &'static str {
+    match c {
+        'À'..='Å' | 'Ā' | 'Ă' | 'Ą' => "A",
+        'à'..='å' | 'ā' | 'ă' | 'ą' => "a",
+        'Æ' => "AE",
+        'æ' => "ae",
+        'Ç' | 'Ć' | 'Č' => "C",
+        'ç' | 'ć' | 'č' => "c",
+        'Ð' | 'Ď' | 'Đ' => "D",
+        'ð' | 'ď' | 'đ' => "d",
+        'È'..='Ë' | 'Ē' | 'Ė' | 'Ę' | 'Ě' => "E",
+        'è'..='ë' | 'ē' | 'ė' | 'ę' | 'ě' => "e",
+        'Ğ' => "G",
+        'ğ' => "g",
+        'Ì'..='Ï' | 'Ī' | 'Į' | 'İ' => "I",
+        'ì'..='ï' | 'ī' | 'į' | 'ı' => "i",
+        'Ł' => "L",
+        'ł' => "l",
+        'Ñ' | 'Ń' | 'Ň' => "N",
+        'ñ' | 'ń' | 'ň' => "n",
+        'Ò'..='Ö' | 'Ø' | 'Ō' | 'Ő' => "O",
+        'ò'..='ö' | 'ø' | 'ō' | 'ő' => "o",
+        'Œ' => "OE",
+        'œ' => "oe",
+        'Ř' => "R",
+        'ř' => "r",
+        'Ś' | 'Ş' | 'Š' => "S",
+        'ś' | 'ş' | 'š' => "s",
+        'ß' => "ss",
+        'Ť' => "T",
+        'ť' => "t",
+        'Þ' => "TH",
+        'þ' => "th",
+        'Ù'..='Ü' | 'Ū' | 'Ů' | 'Ű' => "U",
+        'ù'..='ü' | 'ū' | 'ů' | 'ű' => "u",
+        'Ý' | 'Ÿ' => "Y",
+        'ý' | 'ÿ' => "y",
+        'Ź' | 'Ż' | 'Ž' => "Z",
+        'ź' | 'ż' | 'ž' => "z",
+        '\u{a0}' | '\u{2002}'..='\u{200a}' | '\u{202f}' => " ",
+        '‘' | '’' | '‚' | '′' => "'",
+        '“' | '”' | '„' | '″' => "\"",
+        '«' => "<<",
+        '»' => ">>",
+        '‐'..='—' | '−' => "-",
+        '…' => "...",
+        '•' | '·' => "*",
+        '×' => "x",
+        '©' => "(C)",
+        '®' => "(R)",
+        '™' => "(TM)",
+        '€' => "EUR",
+        '£' => "GBP",
+        '¡' => "!",
+        '¿' => "?",
+        // Combining accents, left by decomposed letters
+        '\u{300}'..='\u{36f}' => "",
+        _ => "?",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transliterates_to_ascii() {
+        let text = "Zoë’s café – 20 €\r\n".as_bytes();
+        assert_eq!(&*to_ascii(text), b"Zoe's cafe - 20 EUR\r\n");
+        assert_eq!(&*to_ascii(b"\xffok \xe2\x9c\x93"), b"?ok ?");
+        assert!(matches!(to_ascii(b"plain"), Cow::Borrowed(_)));
+    }
+}
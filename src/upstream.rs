@@ -0,0 +1,185 @@
+use crate::clock::SharedClock;
+use crate::request::Request;
+use crate::FINGER_PORT;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+
+/// Max length of a reply relayed from an upstream finger server, in bytes
+const SANE_REPLY_LENGTH: u64 = 64 * 1024;
+
+/// How long an upstream finger server has to accept a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long an upstream finger server has to answer once connected, before we give up
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Max number of simultaneous connections to a single upstream finger server
+///
+/// Queries past this limit wait for a connection to finish.
+const MAX_CONNECTIONS_PER_HOST: usize = 4;
+
+/// Number of times a query is attempted again after failing to connect
+const CONNECT_RETRIES: u32 = 2;
+
+/// Delay before the first retry, doubled for each subsequent one
+const RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// Server-sent reply when an upstream finger server cannot be queried
+pub const REPLY_UPSTREAM_FAILED: &[u8] = b"Upstream finger server unreachable\r\n";
+
+/// Manager of the connections to upstream finger servers
+///
+/// Finger servers close the connection after each reply so connections can't be reused, but going
+/// through a single client caps the number of connections to each server, and applies the same
+/// timeouts and retries everywhere.
+#[derive(Default)]
+pub struct Client {
+    /// Connection slots of each host
+    hosts: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl Client {
+    /// Query `user` on the finger server at `host` and return its raw reply
+    ///
+    /// `host` is either a hostname or an IP address, optionally followed by a `:port` (defaults to
+    /// [FINGER_PORT]). The reply is truncated to [SANE_REPLY_LENGTH] bytes.
+    #[instrument(skip(self, verbose))]
+    pub async fn query(&self, host: &str, user: &str, verbose: bool) -> io::Result<Vec<u8>> {
+        let request = Request::new_user(verbose, user).to_request_line();
+
+        let slots = Arc::clone(
+            self.hosts
+                .lock()
+                .unwrap()
+                .entry(host.to_owned())
+                .or_insert_with(|| Arc::new(Semaphore::new(MAX_CONNECTIONS_PER_HOST))),
+        );
+        let _slot = slots.acquire().await.unwrap();
+
+        let mut stream = self.connect(host).await?;
+        let exchange = async {
+            stream.write_all(request.as_bytes()).await?;
+
+            let mut reply = Vec::new();
+            stream
+                .take(SANE_REPLY_LENGTH)
+                .read_to_end(&mut reply)
+                .await?;
+            Ok(reply)
+        };
+
+        match tokio::time::timeout(UPSTREAM_TIMEOUT, exchange).await {
+            Ok(result) => result,
+            Err(_) => Err(io::ErrorKind::TimedOut.into()),
+        }
+    }
+
+    /// Connect to `host`, retrying with a growing delay if that fails
+    async fn connect(&self, host: &str) -> io::Result<TcpStream> {
+        let addr = with_default_port(host);
+        let mut delay = RETRY_DELAY;
+        let mut retries = 0;
+
+        loop {
+            let result =
+                match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&*addr)).await {
+                    Ok(result) => result,
+                    Err(_) => Err(io::ErrorKind::TimedOut.into()),
+                };
+
+            match result {
+                Err(err) if retries < CONNECT_RETRIES => {
+                    debug!("cannot connect to {host:?}, retrying in {delay:?}: {err}");
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+fn with_default_port(host: &str) -> std::borrow::Cow<'_, str> {
+    let has_port = match host.strip_prefix('[') {
+        Some(bracketed) => bracketed.contains("]:"),
+        None => host.matches(':').count() == 1,
+    };
+
+    match has_port {
+        true => host.into(),
+        false if host.contains(':') => format!("[{host}]:{FINGER_PORT}").into(),
+        false => format!("{host}:{FINGER_PORT}").into(),
+    }
+}
+
+/// Host, user and verbose flag of a query
+type CacheKey = (String, String, bool);
+
+/// Expiry date and outcome of a query
+type CacheEntry = (Instant, Result<Arc<[u8]>, io::ErrorKind>);
+
+/// Replies of upstream servers, kept for a while so that they aren't queried over and over
+///
+/// Failures are cached too (negative caching), usually for a shorter time, so that an unreachable
+/// upstream doesn't slow every client down.
+#[derive(Default)]
+pub struct UpstreamCache {
+    client: Client,
+    entries: Mutex<HashMap<CacheKey, CacheEntry>>,
+    clock: SharedClock,
+}
+
+impl UpstreamCache {
+    /// Cache whose entries expire according to `clock`
+    pub fn with_clock(clock: SharedClock) -> Self {
+        Self {
+            clock,
+            ..Self::default()
+        }
+    }
+
+    /// Same as [Client::query], but going through the cache
+    ///
+    /// Successful replies are kept for `ttl` and failures for `negative_ttl`; a zero duration
+    /// disables caching.
+    pub async fn query(
+        &self,
+        host: &str,
+        user: &str,
+        verbose: bool,
+        ttl: Duration,
+        negative_ttl: Duration,
+    ) -> io::Result<Arc<[u8]>> {
+        let key = (host.to_owned(), user.to_owned(), verbose);
+        let now = self.clock.now();
+
+        {
+            let mut entries = self.entries.lock().unwrap();
+            entries.retain(|_, (expires_at, _)| *expires_at > now);
+            if let Some((_, result)) = entries.get(&key) {
+                debug!("upstream reply cached");
+                return result.clone().map_err(io::Error::from);
+            }
+        }
+
+        let result = self.client.query(host, user, verbose).await.map(Arc::from);
+        let ttl = match &result {
+            Ok(_) => ttl,
+            Err(_) => negative_ttl,
+        };
+
+        if !ttl.is_zero() {
+            let cached = result.as_ref().map(Arc::clone).map_err(io::Error::kind);
+            let mut entries = self.entries.lock().unwrap();
+            entries.insert(key, (self.clock.now() + ttl, cached));
+        }
+
+        result
+    }
+}
@@ -0,0 +1,63 @@
+//! Checks of the replies sent to clients, enabled by [Users::validate_replies]
+//!
+//! [Users::validate_replies]: crate::config::Users::validate_replies
+
+use serde::{Deserialize, Serialize};
+
+/// Characters allowed in replies, besides CRLF line endings
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub enum Charset {
+    /// Printable ASCII characters and tabs
+    #[serde(rename = "ascii")]
+    Ascii,
+
+    /// Printable UTF-8 characters and tabs
+    #[default]
+    #[serde(rename = "utf-8")]
+    Utf8,
+}
+
+/// Describe what's wrong with `reply`, or return an empty list if it's valid
+///
+/// An empty reply is valid. Only the first violation of each kind is reported.
+pub fn check(reply: &[u8], charset: Charset) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let bare_lf = (reply.iter().enumerate())
+        .find(|&(i, &byte)| byte == b'\n' && (i == 0 || reply[i - 1] != b'\r'));
+    if let Some((i, _)) = bare_lf {
+        problems.push(format!("line feed without carriage return at byte {i}"));
+    }
+
+    let bare_cr = (reply.iter().enumerate())
+        .find(|&(i, &byte)| byte == b'\r' && reply.get(i + 1) != Some(&b'\n'));
+    if let Some((i, _)) = bare_cr {
+        problems.push(format!("carriage return without line feed at byte {i}"));
+    }
+
+    if !reply.is_empty() && !reply.ends_with(b"\r\n") {
+        problems.push("missing final CRLF".to_owned());
+    }
+
+    let is_allowed = |c: char| c == '\t' || c == '\r' || c == '\n' || !c.is_control();
+    match charset {
+        Charset::Ascii => {
+            let invalid = (reply.iter().enumerate())
+                .find(|&(_, &byte)| !byte.is_ascii() || !is_allowed(byte as char));
+            if let Some((i, byte)) = invalid {
+                problems.push(format!("invalid ASCII character {byte:#04x} at byte {i}"));
+            }
+        }
+        Charset::Utf8 => match std::str::from_utf8(reply) {
+            Ok(reply) => {
+                let invalid = reply.char_indices().find(|&(_, c)| !is_allowed(c));
+                if let Some((i, c)) = invalid {
+                    problems.push(format!("control character {c:?} at byte {i}"));
+                }
+            }
+            Err(err) => problems.push(format!("invalid UTF-8: {err}")),
+        },
+    }
+
+    problems
+}
@@ -0,0 +1,134 @@
+//! WHOIS ([RFC 3912][rfc]) listener answering from the same users as the finger server
+//!
+//! A query is a single line holding a username, optionally followed by `@domain` to look it up in
+//! a namespace. The reply lists the user's verbose info as `key: value` lines, one `info` line per
+//! line of text. Deny rules, namespaces, statistics and reply times work like they do for finger
+//! queries, but users relayed to an upstream finger server aren't looked up.
+//!
+//! [rfc]: https://datatracker.ietf.org/doc/html/rfc3912
+
+use crate::config::{Config, Users};
+use crate::context::RequestContext;
+use crate::listener::AnyListener;
+use crate::redact::Audience;
+use crate::schedule::LocalTime;
+use crate::state::ServerState;
+use crate::REPLY_MAINTENANCE;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::time::Instant;
+
+pub const WHOIS_PORT: u16 = 43;
+
+/// Reply to queries for nonexistent or relayed users, and denied queries
+const REPLY_NO_ENTRIES: &str = "% No entries found\r\n";
+
+/// Accept and serve WHOIS connections forever
+pub async fn serve(listener: AnyListener, config: Arc<Config>, state: Arc<ServerState>) {
+    loop {
+        let mut client = match listener.accept().await {
+            Ok(client) => client,
+            Err(err) => {
+                error!("cannot accept whois connection: {err}");
+                continue;
+            }
+        };
+
+        let peer = client.peer();
+        let users = config.get_for(peer.ip()).await;
+        let state = Arc::clone(&state);
+        tokio::task::spawn(async move {
+            let _active = state.stats.track_connection();
+            let timeout = Duration::from_secs(users.request_timeout);
+            let ctx = RequestContext::new("whois", &peer, peer.ip(), timeout);
+            let mut client = client.split();
+            let (input, output) = client.as_parts();
+
+            let result = ctx
+                .enforce(async {
+                    let max_length = users.requests.max_length("whois");
+                    let buffer_size = users.requests.buffer_size("whois");
+                    let mut reader = BufReader::with_capacity(buffer_size, input.take(max_length));
+                    let mut query = Vec::with_capacity(32);
+                    reader.read_until(b'\n', &mut query).await?;
+                    let received_at = Instant::now();
+
+                    let reply = handle(&ctx, &users, &state, &query);
+
+                    // Like finger queries, found and nonexistent users must take the same time
+                    let jitter = crate::random(users.reply_jitter + 1);
+                    let reply_time = Duration::from_millis(users.min_reply_time + jitter);
+                    tokio::time::sleep_until(received_at + reply_time).await;
+                    output.write_all(reply.as_bytes()).await?;
+                    output.shutdown().await
+                })
+                .await;
+
+            if result.is_err() {
+                state.stats.record_error();
+            }
+            result
+        });
+    }
+}
+
+#[instrument(skip_all, fields(request = ctx.id, listener = ctx.listener, peer = %ctx.peer))]
+fn handle(ctx: &RequestContext, users: &Users, state: &ServerState, query: &[u8]) -> String {
+    debug!("incoming whois query");
+    state.stats.record_query();
+    if state.in_maintenance() {
+        debug!("answered in maintenance mode");
+        return (users.message(ctx.listener, |messages| &messages.maintenance))
+            .or(users.maintenance_reply.as_deref())
+            .map_or_else(
+                || String::from_utf8_lossy(REPLY_MAINTENANCE).into_owned(),
+                str::to_owned,
+            );
+    }
+
+    let not_found = (users.message(ctx.listener, |messages| &messages.no_entries))
+        .map_or_else(|| String::from(REPLY_NO_ENTRIES), str::to_owned);
+
+    if users.deny.is_match(query) {
+        debug!("query denied by a deny rule");
+        return not_found;
+    }
+
+    let Ok(query) = std::str::from_utf8(query) else {
+        return not_found;
+    };
+    let query = query.trim();
+    let now = LocalTime::new(state.clock.system_now(), users.utc_offset);
+    let audience = Audience {
+        verbose: users.verbose.apply(ctx.listener, true),
+        internal: users.is_internal(ctx.ip),
+    };
+
+    let (username, users) = match query.rsplit_once('@') {
+        Some((username, domain)) => match users.namespace(domain) {
+            Some(namespace) => (username, namespace),
+            None => return not_found,
+        },
+        None => (query, users),
+    };
+
+    let user = (users.find(username)).filter(|user| !user.hidden || audience.internal);
+    match user.map(|user| user.at(now)) {
+        Some(user) if user.proxy_to.is_none() => {
+            debug!("requested user {username:?}");
+            state.stats.record_user(username);
+
+            let mut reply = format!("username: {username}\r\n");
+            for line in user.reply(username, &users.snippets, audience).0.lines() {
+                let _ = write!(reply, "info:     {line}\r\n");
+            }
+            reply
+        }
+        _ => {
+            debug!("requested nonexistent user {username:?}");
+            not_found
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! Pre-fork worker mode: several processes answering the same listeners, kept running by a
+//! supervisor
+//!
+//! With `--workers`, the daemon binds its listeners and starts that many copies of itself, which
+//! take the listeners through socket activation (see [crate::activation]) and answer connections
+//! like a single daemon would. The supervisor only watches them: a worker that exits, panics,
+//! aborts or is killed (e.g. by the OOM killer after a memory spike) is logged and started again,
+//! while the others keep answering. A worker that ends within [CRASH_WINDOW] of its start is only
+//! started again after [RESTART_DELAY], so that a worker crashing at startup doesn't spin.
+//!
+//! Signals received by the supervisor are forwarded to every worker: SIGHUP reloads their config,
+//! SIGUSR1 cycles their log level, and SIGINT, SIGQUIT or SIGTERM make them exit gracefully, after
+//! which the supervisor exits too.
+//!
+//! Each worker has its own config, statistics and ban list. The admin and user sockets can't be
+//! shared by several processes, so they can't be used with workers.
+
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGQUIT, SIGTERM, SIGUSR1};
+use signal_hook_tokio::Signals;
+use std::ffi::OsString;
+use std::future::Future;
+use std::io;
+use std::num::NonZeroUsize;
+use std::os::fd::RawFd;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::process::{ExitCode, ExitStatus};
+use std::time::{Duration, Instant};
+use tokio::process::Command;
+use tokio::select;
+
+/// Environment variable set for workers, to the index of the worker
+const WORKER_ENV: &str = "FINGERED_WORKER";
+
+/// How long after its start a worker that ends is considered to be crashing at startup
+const CRASH_WINDOW: Duration = Duration::from_secs(10);
+
+/// Delay before a worker ending within [CRASH_WINDOW] is started again
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Whether this process is a worker started by a supervisor
+pub fn is_worker() -> bool {
+    std::env::var_os(WORKER_ENV).is_some()
+}
+
+/// Worker processes to start, with the command line and working directory of this process
+pub struct Workers {
+    count: NonZeroUsize,
+    program: PathBuf,
+    args: Vec<OsString>,
+    dir: PathBuf,
+}
+
+/// Worker that ended, with its index
+type Ended = Pin<Box<dyn Future<Output = (usize, io::Result<ExitStatus>)> + Send>>;
+
+/// Worker to start again after a delay, with its index
+type Restart = Pin<Box<dyn Future<Output = usize> + Send>>;
+
+impl Workers {
+    /// Must be called before the working directory is changed by [crate::daemon::daemonize], so
+    /// that workers resolve relative paths like this process did.
+    pub fn new(count: NonZeroUsize) -> io::Result<Self> {
+        Ok(Self {
+            count,
+            program: std::env::current_exe()?,
+            args: std::env::args_os().skip(1).collect(),
+            dir: std::env::current_dir()?,
+        })
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.get()
+    }
+
+    /// Start the workers with `listeners`, named after their role in socket activation, and keep
+    /// them running until a signal asks to exit and they all ended
+    #[instrument(skip_all)]
+    pub async fn supervise(&self, listeners: &[(&str, RawFd)], mut signals: Signals) -> ExitCode {
+        let mut pids = vec![None; self.count()];
+        let mut started = vec![Instant::now(); self.count()];
+        let mut running = FuturesUnordered::<Ended>::new();
+        let mut restarts = FuturesUnordered::<Restart>::new();
+        let mut exiting = false;
+
+        for index in 0..self.count() {
+            match self.start(index, listeners) {
+                Ok((pid, ended)) => {
+                    pids[index] = Some(pid);
+                    running.push(ended);
+                }
+                Err(err) => {
+                    error!("cannot start worker {index}: {err}");
+                    kill_all(&pids, SIGTERM);
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+
+        loop {
+            select! {
+                Some(signal) = signals.next() => match signal {
+                    SIGINT | SIGQUIT | SIGTERM => {
+                        info!("asking {} workers to exit", running.len());
+                        exiting = true;
+                        restarts.clear();
+                        // Workers waiting to be started again won't end by themselves
+                        if running.is_empty() {
+                            return ExitCode::SUCCESS;
+                        }
+                        kill_all(&pids, SIGTERM);
+                    }
+                    SIGHUP | SIGUSR1 => kill_all(&pids, signal),
+                    _ => unreachable!(),
+                },
+                Some((index, status)) = running.next() => {
+                    pids[index] = None;
+                    match status {
+                        Ok(status) if exiting => debug!("worker {index} exited: {status}"),
+                        Ok(status) => error!("worker {index} exited unexpectedly: {status}"),
+                        Err(err) => error!("cannot wait for worker {index}: {err}"),
+                    }
+
+                    if exiting {
+                        if running.is_empty() {
+                            return ExitCode::SUCCESS;
+                        }
+                    } else if started[index].elapsed() < CRASH_WINDOW {
+                        restarts.push(Box::pin(async move {
+                            tokio::time::sleep(RESTART_DELAY).await;
+                            index
+                        }));
+                    } else {
+                        restarts.push(Box::pin(async move { index }));
+                    }
+                },
+                Some(index) = restarts.next() => {
+                    started[index] = Instant::now();
+                    match self.start(index, listeners) {
+                        Ok((pid, ended)) => {
+                            info!("started worker {index} again");
+                            pids[index] = Some(pid);
+                            running.push(ended);
+                        }
+                        Err(err) => {
+                            error!("cannot start worker {index} again: {err}");
+                            restarts.push(Box::pin(async move {
+                                tokio::time::sleep(RESTART_DELAY).await;
+                                index
+                            }));
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    /// Start the worker `index`, and return its process ID and a future resolving when it ends
+    fn start(&self, index: usize, listeners: &[(&str, RawFd)]) -> io::Result<(i32, Ended)> {
+        let names = listeners.iter().map(|(name, _)| *name);
+        let fds = listeners.iter().map(|(_, fd)| *fd).collect::<Vec<_>>();
+
+        let mut command = Command::new(&self.program);
+        command
+            .args(&self.args)
+            .current_dir(&self.dir)
+            .env(WORKER_ENV, index.to_string())
+            .env("LISTEN_FDS", fds.len().to_string())
+            .env("LISTEN_FDNAMES", names.collect::<Vec<_>>().join(":"))
+            .env_remove("LISTEN_PID")
+            .env_remove("LISTEN_FDS_FIRST_FD");
+        // SAFETY: `pass_descriptors` only makes async-signal-safe calls, and doesn't allocate
+        unsafe { command.pre_exec(move || pass_descriptors(&fds)) };
+
+        let mut child = command.spawn()?;
+        let pid = child
+            .id()
+            .ok_or_else(|| io::Error::other("worker already reaped"))? as i32;
+        debug!("started worker {index} with pid {pid}");
+        Ok((pid, Box::pin(async move { (index, child.wait().await) })))
+    }
+}
+
+/// Move `fds` to the descriptors following stderr, where socket activation expects them
+///
+/// Run in the forked child, right before `exec`.
+fn pass_descriptors(fds: &[RawFd]) -> io::Result<()> {
+    let first = libc::STDERR_FILENO + 1;
+    let end = first + fds.len() as RawFd;
+
+    // Each descriptor is first duplicated past the target range, so that moving one to its target
+    // never closes another that's still to be moved. Copies are closed by `exec`. There are far
+    // fewer listeners than copies.
+    let mut copies = [0; 8];
+    let copies = &mut copies[..fds.len()];
+    for (fd, copy) in fds.iter().zip(copies.iter_mut()) {
+        // SAFETY: no pointer is involved, an invalid descriptor is reported as an error
+        *copy = unsafe { libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, end) };
+        if *copy < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    for (target, copy) in (first..).zip(copies.iter()) {
+        // SAFETY: same as above. Unlike the copy, the target doesn't close on `exec`.
+        if unsafe { libc::dup2(*copy, target) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Send `signal` to every running worker
+fn kill_all(pids: &[Option<i32>], signal: i32) {
+    for pid in pids.iter().flatten() {
+        // SAFETY: no preconditions. The worker may have just ended, but its pid can't have been
+        // reused since it's only reaped when the supervisor handles its end.
+        if unsafe { libc::kill(*pid, signal) } < 0 {
+            warn!("cannot signal worker {pid}: {}", io::Error::last_os_error());
+        }
+    }
+}
@@ -0,0 +1,255 @@
+//! Conformance of `fingered` to the behaviors required or suggested by [RFC 1288][rfc]
+//!
+//! Each case sends a query to the daemon run in inetd mode (the query on stdin, the reply on
+//! stdout) and to the daemon listening on a TCP socket, and checks the exact reply. A report of
+//! every case is printed, so `cargo test --test rfc1288 -- --nocapture` documents what's
+//! supported, and the test fails if any case does.
+//!
+//! [rfc]: https://datatracker.ietf.org/doc/html/rfc1288
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+const USERS: &str = r#"
+[users.alice]
+info = "Alice"
+long-info = "Alice Doe\nRoom 42"
+
+[users.bob]
+info = "Bob"
+"#;
+
+const LISTING: &[u8] = b"alice\r\nbob\r\n";
+const LONG_INFO: &[u8] = b"Alice Doe\r\nRoom 42\r\n";
+const NO_FORWARDING: &[u8] = b"Finger forwarding service denied\r\n";
+
+struct Case {
+    /// Section of the RFC, or element of its query grammar
+    section: &'static str,
+    behavior: &'static str,
+
+    /// Command-line arguments given to the daemon, on top of the config
+    args: &'static [&'static str],
+    request: &'static [u8],
+    reply: &'static [u8],
+}
+
+const CASES: &[Case] = &[
+    Case {
+        section: "2.3 {C}",
+        behavior: "an empty query lists the users",
+        args: &[],
+        request: b"\r\n",
+        reply: LISTING,
+    },
+    Case {
+        section: "2.3 {W}{C}",
+        behavior: "/W alone lists the users",
+        args: &[],
+        request: b"/W\r\n",
+        reply: LISTING,
+    },
+    Case {
+        section: "2.3 {U}{C}",
+        behavior: "a username gives the user's info",
+        args: &[],
+        request: b"alice\r\n",
+        reply: b"Alice\r\n",
+    },
+    Case {
+        section: "2.3 {W}{S}{U}{C}",
+        behavior: "/W before a username gives the user's long info",
+        args: &[],
+        request: b"/W alice\r\n",
+        reply: LONG_INFO,
+    },
+    Case {
+        section: "2.3 {S}",
+        behavior: "/W may be followed by several spaces",
+        args: &[],
+        request: b"/W   alice\r\n",
+        reply: LONG_INFO,
+    },
+    Case {
+        section: "2.3 {U}",
+        behavior: "an unknown username is answered",
+        args: &[],
+        request: b"carol\r\n",
+        reply: b"User not found\r\n",
+    },
+    Case {
+        section: "2.5.5, 3.2.1 {Q2}",
+        behavior: "forwarding a user query is refused with the suggested text",
+        args: &[],
+        request: b"alice@example.com\r\n",
+        reply: NO_FORWARDING,
+    },
+    Case {
+        section: "2.5.5, 3.2.1 {Q2}",
+        behavior: "forwarding through several hosts is refused",
+        args: &[],
+        request: b"/W alice@example.com@example.org\r\n",
+        reply: NO_FORWARDING,
+    },
+    Case {
+        section: "2.5.5, 3.2.1 {Q2}",
+        behavior: "forwarding a listing is refused",
+        args: &[],
+        request: b"@example.com\r\n",
+        reply: NO_FORWARDING,
+    },
+    Case {
+        section: "3.2.2",
+        behavior: "listings can be refused with the suggested text",
+        args: &["--enable-index", "false"],
+        request: b"\r\n",
+        reply: b"Finger online user list denied\r\n",
+    },
+    Case {
+        section: "2.2",
+        behavior: "replies end every line with CRLF, even if the config doesn't",
+        args: &[],
+        request: b"/W alice\r\n",
+        reply: LONG_INFO,
+    },
+    Case {
+        section: "2.3 {C}",
+        behavior: "a query ending with a bare LF is refused",
+        args: &[],
+        request: b"alice\n",
+        reply: b"Malformed finger query\r\n",
+    },
+    Case {
+        section: "2.3 {C}",
+        behavior: "a query without CRLF is refused",
+        args: &[],
+        request: b"alice",
+        reply: b"No newline received\r\n",
+    },
+];
+
+/// Directory holding the `users.toml` of the tests, removed when dropped
+struct ConfigDir(PathBuf);
+
+impl ConfigDir {
+    fn new() -> Self {
+        let dir = std::env::temp_dir().join(format!("fingered-rfc1288-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("users.toml"), USERS).unwrap();
+        Self(dir)
+    }
+}
+
+impl Drop for ConfigDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Daemon listening on a TCP socket, killed when dropped
+struct Daemon {
+    child: Child,
+    addr: SocketAddr,
+}
+
+impl Daemon {
+    fn start(dir: &Path, args: &[&str]) -> Self {
+        // Ask the OS for a free port, released right before the daemon binds it
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_fingered"))
+            .arg(addr.to_string())
+            .arg("--users-file")
+            .arg(dir.join("users.toml"))
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        let started = Instant::now();
+        while TcpStream::connect(addr).is_err() {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "daemon not listening"
+            );
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        Self { child, addr }
+    }
+
+    fn query(&self, request: &[u8]) -> Vec<u8> {
+        let mut stream = TcpStream::connect(self.addr).unwrap();
+        stream.write_all(request).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).unwrap();
+        reply
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Reply of the daemon run in inetd mode with the `users.toml` of `dir`
+fn query_inetd(dir: &Path, args: &[&str], request: &[u8]) -> Vec<u8> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_fingered"))
+        .arg("--inetd")
+        .arg("--users-file")
+        .arg(dir.join("users.toml"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(request).unwrap();
+    child.wait_with_output().unwrap().stdout
+}
+
+#[test]
+fn conforms_to_rfc1288() {
+    let dir = ConfigDir::new();
+    let mut daemons = HashMap::new();
+    let mut failures = Vec::new();
+
+    println!("{:<20} {:<62} {:<6} tcp", "section", "behavior", "inetd");
+    for case in CASES {
+        let daemon = (daemons.entry(case.args)).or_insert_with(|| Daemon::start(&dir.0, case.args));
+        let replies = [
+            ("inetd", query_inetd(&dir.0, case.args, case.request)),
+            ("tcp", daemon.query(case.request)),
+        ];
+
+        let mut results = Vec::new();
+        for (transport, reply) in replies {
+            let passed = reply == case.reply;
+            results.push(if passed { "ok" } else { "FAIL" });
+            if !passed {
+                failures.push(format!(
+                    "{} ({transport}): {:?} got {:?}, expected {:?}",
+                    case.behavior,
+                    String::from_utf8_lossy(case.request),
+                    String::from_utf8_lossy(&reply),
+                    String::from_utf8_lossy(case.reply),
+                ));
+            }
+        }
+        println!(
+            "{:<20} {:<62} {:<6} {}",
+            case.section, case.behavior, results[0], results[1]
+        );
+    }
+
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}